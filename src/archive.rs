@@ -0,0 +1,483 @@
+//! Tar and zip snapshots of a tree, written straight from the object database.
+//!
+//! Both formats are produced by hand since only a small, write-only subset of each is needed:
+//! regular files, executables, symlinks and directories. Like `git archive`, submodules show up
+//! as empty directories and every entry carries the commit time.
+
+use std::io::{self, Write};
+
+use flate2::{Compression, write::DeflateEncoder};
+use git2::{ObjectType, Oid, Repository, Tree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// Splits a requested file name such as `v1.0.tar.gz` into the ref and the format.
+    pub fn from_file_name(file_name: &str) -> Option<(&str, Self)> {
+        if let Some(reference) = file_name.strip_suffix(".tar.gz") {
+            Some((reference, Self::TarGz))
+        } else if let Some(reference) = file_name.strip_suffix(".tgz") {
+            Some((reference, Self::TarGz))
+        } else {
+            file_name
+                .strip_suffix(".zip")
+                .map(|reference| (reference, Self::Zip))
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::TarGz => "application/gzip",
+            Self::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::TarGz => "tar.gz",
+            Self::Zip => "zip",
+        }
+    }
+}
+
+enum Kind<'a> {
+    Directory,
+    File { executable: bool, data: &'a [u8] },
+    Symlink { target: &'a [u8] },
+}
+
+struct Entry<'a> {
+    /// Full path inside the archive, with a trailing slash for directories.
+    path: String,
+    kind: Kind<'a>,
+}
+
+/// Writes the tree of `commit` as an archive in `format`, with every path under `prefix`.
+pub fn write(
+    repo: &Repository,
+    commit: Oid,
+    prefix: &str,
+    format: Format,
+    out: impl Write,
+) -> io::Result<()> {
+    let commit = repo.find_commit(commit).map_err(io::Error::other)?;
+    let tree = commit.tree().map_err(io::Error::other)?;
+    let mtime = commit.committer().when().seconds();
+
+    match format {
+        Format::TarGz => {
+            let mut tar = Tar {
+                out: flate2::write::GzEncoder::new(out, Compression::default()),
+                mtime,
+            };
+
+            tar.global_header(&commit.id().to_string())?;
+            walk(repo, &tree, prefix, &mut |entry| tar.entry(entry))?;
+            tar.finish()
+        }
+        Format::Zip => {
+            let mut zip = Zip {
+                out: Counting { out, written: 0 },
+                time: dos_time(mtime),
+                central_directory: Vec::new(),
+                entries: 0,
+            };
+
+            walk(repo, &tree, prefix, &mut |entry| zip.entry(entry))?;
+            zip.finish()
+        }
+    }
+}
+
+fn walk(
+    repo: &Repository,
+    tree: &Tree,
+    prefix: &str,
+    emit: &mut dyn FnMut(Entry) -> io::Result<()>,
+) -> io::Result<()> {
+    let blob = |oid| repo.find_blob(oid).map_err(io::Error::other);
+
+    emit(Entry {
+        path: format!("{prefix}/"),
+        kind: Kind::Directory,
+    })?;
+
+    for entry in tree {
+        let path = format!("{prefix}/{}", String::from_utf8_lossy(entry.name_bytes()));
+
+        match (entry.kind(), entry.filemode()) {
+            (Some(ObjectType::Tree), _) => {
+                let subtree = repo.find_tree(entry.id()).map_err(io::Error::other)?;
+                walk(repo, &subtree, &path, emit)?;
+            }
+            (Some(ObjectType::Blob), mode) => {
+                let blob = blob(entry.id())?;
+
+                let kind = match mode {
+                    0o120000 => Kind::Symlink {
+                        target: blob.content(),
+                    },
+                    _ => Kind::File {
+                        executable: mode == 0o100755,
+                        data: blob.content(),
+                    },
+                };
+
+                emit(Entry { path, kind })?;
+            }
+            // Submodules
+            _ => emit(Entry {
+                path: format!("{path}/"),
+                kind: Kind::Directory,
+            })?,
+        }
+    }
+
+    Ok(())
+}
+
+const TAR_BLOCK: usize = 512;
+
+struct Tar<W: Write> {
+    out: flate2::write::GzEncoder<W>,
+    mtime: i64,
+}
+
+impl<W: Write> Tar<W> {
+    /// Records the commit id the way `git archive` does, so `git get-tar-commit-id` works.
+    fn global_header(&mut self, commit: &str) -> io::Result<()> {
+        let records = pax_record("comment", commit.as_bytes());
+
+        self.header("pax_global_header", b'g', 0o666, records.len() as u64, b"")?;
+        self.data(&records)
+    }
+
+    fn entry(&mut self, entry: Entry) -> io::Result<()> {
+        let (typeflag, mode, data, link): (u8, u32, &[u8], &[u8]) = match entry.kind {
+            Kind::Directory => (b'5', 0o775, b"", b""),
+            Kind::File { executable, data } => {
+                (b'0', if executable { 0o775 } else { 0o664 }, data, b"")
+            }
+            Kind::Symlink { target } => (b'2', 0o777, b"", target),
+        };
+
+        // Names and link targets that don't fit the fixed fields go in a PAX extended header.
+        let mut records = Vec::new();
+
+        if entry.path.len() > 100 {
+            records.extend(pax_record("path", entry.path.as_bytes()));
+        }
+
+        if link.len() > 100 {
+            records.extend(pax_record("linkpath", link));
+        }
+
+        if !records.is_empty() {
+            self.header("pax_header", b'x', 0o666, records.len() as u64, b"")?;
+            self.data(&records)?;
+        }
+
+        self.header(&entry.path, typeflag, mode, data.len() as u64, link)?;
+        self.data(data)
+    }
+
+    fn header(
+        &mut self,
+        path: &str,
+        typeflag: u8,
+        mode: u32,
+        size: u64,
+        link: &[u8],
+    ) -> io::Result<()> {
+        let mut header = [0u8; TAR_BLOCK];
+
+        let field = |header: &mut [u8; TAR_BLOCK], offset: usize, len: usize, value: &[u8]| {
+            let len = value.len().min(len);
+            header[offset..offset + len].copy_from_slice(&value[..len]);
+        };
+
+        let octal = |header: &mut [u8; TAR_BLOCK], offset: usize, len: usize, value: u64| {
+            let value = format!("{value:0width$o}", width = len - 1);
+            field(header, offset, len - 1, value.as_bytes());
+        };
+
+        field(&mut header, 0, 100, path.as_bytes());
+        octal(&mut header, 100, 8, mode.into());
+        octal(&mut header, 108, 8, 0);
+        octal(&mut header, 116, 8, 0);
+        octal(&mut header, 124, 12, size);
+        octal(&mut header, 136, 12, self.mtime.max(0) as u64);
+        header[156] = typeflag;
+        field(&mut header, 157, 100, link);
+        field(&mut header, 257, 8, b"ustar\x0000");
+        field(&mut header, 265, 32, b"root");
+        field(&mut header, 297, 32, b"root");
+
+        // The checksum is computed with its own field filled with spaces.
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        field(&mut header, 148, 8, format!("{checksum:06o}\0 ").as_bytes());
+
+        self.out.write_all(&header)
+    }
+
+    /// Writes entry data, padded to a whole number of blocks.
+    fn data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+
+        let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+        self.out.write_all(&[0; TAR_BLOCK][..padding])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(&[0; TAR_BLOCK * 2])?;
+        self.out.finish()?.flush()
+    }
+}
+
+/// Encodes a PAX record, whose length prefix counts itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = format!("{len} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Tracks how much has been written, as zip headers refer to each other by offset.
+struct Counting<W> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct Zip<W> {
+    out: Counting<W>,
+    /// Modification time and date in MS-DOS format.
+    time: (u16, u16),
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+/// "Made by" Unix, spec version 2.0, so the external attributes carry Unix modes.
+const ZIP_MADE_BY: u16 = (3 << 8) | 20;
+const ZIP_VERSION_NEEDED: u16 = 20;
+/// Marks file names as UTF-8.
+const ZIP_UTF8: u16 = 1 << 11;
+
+impl<W: Write> Zip<W> {
+    fn entry(&mut self, entry: Entry) -> io::Result<()> {
+        let too_large = || io::Error::other("Archive is too large for zip");
+
+        let (mode, data): (u32, &[u8]) = match entry.kind {
+            Kind::Directory => (0o040775, b""),
+            Kind::File { executable, data } => (if executable { 0o100775 } else { 0o100664 }, data),
+            Kind::Symlink { target } => (0o120777, target),
+        };
+
+        let crc = crc32fast::hash(data);
+
+        let (method, compressed) = if data.is_empty() {
+            (ZIP_STORED, Vec::new())
+        } else {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            (ZIP_DEFLATED, encoder.finish()?)
+        };
+
+        let offset = u32::try_from(self.out.written).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let compressed_size = u32::try_from(compressed.len()).map_err(|_| too_large())?;
+        let name = entry.path.as_bytes();
+        let name_len = u16::try_from(name.len()).map_err(|_| too_large())?;
+
+        let (time, date) = self.time;
+
+        let mut local = Vec::with_capacity(30 + name.len());
+        local.extend(0x04034b50u32.to_le_bytes());
+        local.extend(ZIP_VERSION_NEEDED.to_le_bytes());
+        local.extend(ZIP_UTF8.to_le_bytes());
+        local.extend(method.to_le_bytes());
+        local.extend(time.to_le_bytes());
+        local.extend(date.to_le_bytes());
+        local.extend(crc.to_le_bytes());
+        local.extend(compressed_size.to_le_bytes());
+        local.extend(size.to_le_bytes());
+        local.extend(name_len.to_le_bytes());
+        local.extend(0u16.to_le_bytes());
+        local.extend(name);
+
+        self.out.write_all(&local)?;
+        self.out.write_all(&compressed)?;
+
+        let central = &mut self.central_directory;
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(ZIP_MADE_BY.to_le_bytes());
+        central.extend(ZIP_VERSION_NEEDED.to_le_bytes());
+        central.extend(ZIP_UTF8.to_le_bytes());
+        central.extend(method.to_le_bytes());
+        central.extend(time.to_le_bytes());
+        central.extend(date.to_le_bytes());
+        central.extend(crc.to_le_bytes());
+        central.extend(compressed_size.to_le_bytes());
+        central.extend(size.to_le_bytes());
+        central.extend(name_len.to_le_bytes());
+        // Extra field, comment, disk number and internal attributes.
+        central.extend([0; 8]);
+        central.extend((mode << 16).to_le_bytes());
+        central.extend(offset.to_le_bytes());
+        central.extend(name);
+
+        self.entries = self.entries.checked_add(1).ok_or_else(too_large)?;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let too_large = || io::Error::other("Archive is too large for zip");
+
+        let offset = u32::try_from(self.out.written).map_err(|_| too_large())?;
+        let size = u32::try_from(self.central_directory.len()).map_err(|_| too_large())?;
+
+        self.out.write_all(&self.central_directory)?;
+
+        let mut end = Vec::with_capacity(22);
+        end.extend(0x06054b50u32.to_le_bytes());
+        // Disk numbers.
+        end.extend([0; 4]);
+        end.extend(self.entries.to_le_bytes());
+        end.extend(self.entries.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(offset.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+
+        self.out.write_all(&end)?;
+        self.out.flush()
+    }
+}
+
+/// Converts a Unix timestamp to MS-DOS time and date, clamped to the 1980 epoch.
+fn dos_time(timestamp: i64) -> (u16, u16) {
+    let days = timestamp.max(315_532_800).div_euclid(86_400);
+    let seconds = timestamp.max(315_532_800).rem_euclid(86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = ((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | ((seconds % 60) / 2);
+    let date = ((year - 1980).min(127) << 9) | (month << 5) | day;
+
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+    use axum::http::{Method, StatusCode};
+
+    use crate::tests::TestServer;
+
+    #[tokio::test]
+    async fn archives_unpack_to_the_tree() {
+        let server = TestServer::new("archive");
+        let repo = server.init_repo("test", "r.git");
+
+        let long = "long-".repeat(30);
+
+        let mut dir = repo.treebuilder(None).unwrap();
+        dir.insert(&long, repo.blob(b"nested").unwrap(), 0o100644)
+            .unwrap();
+        let dir = dir.write().unwrap();
+
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("dir", dir, 0o040000).unwrap();
+        tree.insert("run.sh", repo.blob(b"#!/bin/sh\n").unwrap(), 0o100755)
+            .unwrap();
+        tree.insert("link", repo.blob(b"run.sh").unwrap(), 0o120000)
+            .unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        repo.commit(
+            Some("refs/heads/main"),
+            &signature,
+            &signature,
+            "commit",
+            &tree,
+            &[],
+        )
+        .unwrap();
+
+        for (file, unpack) in [
+            ("main.tar.gz", ["tar", "-xzf"]),
+            ("main.zip", ["unzip", "-q"]),
+        ] {
+            let (status, archive) = server
+                .send(
+                    Method::GET,
+                    &format!("/repo/test/r.git/archive/{file}"),
+                    None,
+                    None,
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK, "{file}");
+
+            let out = server.root().join(format!("unpacked-{file}"));
+            fs::create_dir(&out).unwrap();
+            fs::write(out.join(file), archive).unwrap();
+
+            let status = Command::new(unpack[0])
+                .args(&unpack[1..])
+                .arg(file)
+                .current_dir(&out)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{file}");
+
+            let top = out.join("r-main");
+            assert_eq!(fs::read(top.join("dir").join(&long)).unwrap(), b"nested");
+            assert_eq!(
+                fs::read_link(top.join("link")).unwrap().to_str(),
+                Some("run.sh")
+            );
+
+            let mode = fs::metadata(top.join("run.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_ne!(mode & 0o100, 0, "{file}");
+        }
+    }
+}
@@ -0,0 +1,647 @@
+//! Credentials for write access and private repositories.
+//!
+//! Clients authenticate with either the admin token or an access token issued by the admin,
+//! sent as `Authorization: Bearer <token>` or, for git clients, as the password of HTTP basic
+//! auth. Access tokens are stored hashed in a file under the repository root, so issuing and
+//! revoking them takes effect immediately and survives restarts.
+//!
+//! Tokens issued to a registered user only cover that user's repositories, for reading private
+//! ones as well as for changing any. Tokens issued to no one in particular cover every
+//! repository, like the admin token.
+//!
+//! Deploy tokens live in the same store but are bound to a single repository, which they may
+//! only clone and fetch from, or also push to. Those that may push may also commit to the
+//! repository through the API, by merging and editing files, but grant nothing else: the rest
+//! of the API treats them as anonymous, or rejects them where credentials are required.
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts, State},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::info;
+
+use crate::{AppState, Error, Json, Name, Path, blocking, hidden_sibling};
+
+/// Name of the token store within the repository root.
+pub const TOKENS_FILE: &str = ".tokens";
+
+/// Who a request was made by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Admin,
+    Token {
+        id: String,
+        name: String,
+        /// The registered user the token was issued to, if any.
+        user: Option<String>,
+    },
+    Deploy {
+        id: String,
+        scope: Scope,
+    },
+}
+
+impl Principal {
+    /// Whether the principal may manage the repositories of `user`, and create new ones for
+    /// them.
+    pub fn owns(&self, user: &str) -> bool {
+        match self {
+            Principal::Admin | Principal::Token { user: None, .. } => true,
+            Principal::Token {
+                user: Some(owner), ..
+            } => *owner == user.to_lowercase(),
+            Principal::Deploy { .. } => false,
+        }
+    }
+
+    /// Whether the principal may clone and fetch from the repository `name` of `user`.
+    pub fn can_read(&self, user: &str, name: &str) -> bool {
+        match self {
+            Principal::Deploy { scope, .. } => scope.covers(user, name),
+            _ => self.owns(user),
+        }
+    }
+
+    /// Whether the principal may push to the repository `name` of `user`.
+    pub fn can_write(&self, user: &str, name: &str) -> bool {
+        match self {
+            Principal::Deploy { scope, .. } => scope.write && scope.covers(user, name),
+            _ => self.owns(user),
+        }
+    }
+}
+
+/// The repository a deploy token is bound to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    user: String,
+    name: String,
+    /// Whether pushes are allowed as well as clones and fetches.
+    write: bool,
+}
+
+impl Scope {
+    fn covers(&self, user: &str, name: &str) -> bool {
+        self.user == user.to_lowercase() && self.name == name.to_lowercase()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenRecord {
+    id: String,
+    name: String,
+    /// Hex SHA-1 of the secret part of the token.
+    hash: String,
+    created: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// Set for deploy tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<Scope>,
+}
+
+/// Issued access tokens, mirrored to disk on every change.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    /// Where the tokens are persisted. Without one they only live in memory.
+    path: Option<PathBuf>,
+    records: Mutex<Vec<TokenRecord>>,
+}
+
+impl Tokens {
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let records = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            records: Mutex::new(records),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    fn save(&self, records: &[TokenRecord]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Replace the file in one step so a crash never leaves a truncated store behind.
+        let staging = hidden_sibling(path, "new");
+        fs::write(&staging, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&staging, path)
+    }
+
+    /// Revokes every token issued to `user`, returning how many there were.
+    pub fn revoke_user(&self, user: &str) -> io::Result<usize> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+
+        let kept: Vec<TokenRecord> = records
+            .iter()
+            .filter(|record| record.user.as_deref() != Some(user))
+            .cloned()
+            .collect();
+
+        if kept.len() < before {
+            self.save(&kept)?;
+            *records = kept;
+        }
+
+        Ok(before - records.len())
+    }
+
+    /// Moves the deploy tokens of a repository along with it, or revokes them if it is gone.
+    pub fn rebind(&self, user: &str, name: &str, to: Option<(&str, &str)>) -> io::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        let mut updated = records.clone();
+
+        updated.retain_mut(|record| match &mut record.scope {
+            Some(scope) if scope.covers(user, name) => match to {
+                Some((user, name)) => {
+                    scope.user = user.to_lowercase();
+                    scope.name = name.to_lowercase();
+                    true
+                }
+                None => false,
+            },
+            _ => true,
+        });
+
+        self.save(&updated)?;
+        *records = updated;
+
+        Ok(())
+    }
+
+    /// Finds the token matching `token`, which has the form `<id>_<secret>`.
+    fn verify(&self, token: &str) -> Option<Principal> {
+        let (id, secret) = token.split_once('_')?;
+
+        let records = self.records.lock().unwrap();
+        let record = records.iter().find(|record| record.id == id)?;
+
+        if !constant_time_eq(&hash(secret), &record.hash) {
+            return None;
+        }
+
+        Some(match &record.scope {
+            Some(scope) => Principal::Deploy {
+                id: record.id.clone(),
+                scope: scope.clone(),
+            },
+            None => Principal::Token {
+                id: record.id.clone(),
+                name: record.name.clone(),
+                user: record.user.clone(),
+            },
+        })
+    }
+}
+
+fn hash(secret: &str) -> String {
+    hex(&Sha1::digest(secret.as_bytes()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Compares every byte so the time taken doesn't reveal how much of a secret matched.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The token a request carries, if any.
+fn credential(headers: &HeaderMap) -> Result<Option<String>, Error> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+
+    let value = value.to_str().map_err(|_| Error::Unauthorized)?;
+
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Ok(Some(token.trim().to_string()));
+    }
+
+    let Some(encoded) = value.strip_prefix("Basic ") else {
+        return Err(Error::Unauthorized);
+    };
+
+    let decoded = BASE64_STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(Error::Unauthorized)?;
+
+    // Git prompts for a username and password; the token usually goes in the password, but
+    // some tools pass it as the username instead.
+    let (username, password) = decoded.split_once(':').unwrap_or((&decoded, ""));
+
+    Ok(Some(
+        if password.is_empty() {
+            username
+        } else {
+            password
+        }
+        .to_string(),
+    ))
+}
+
+/// Identifies who sent a request. Invalid credentials are rejected rather than treated as
+/// anonymous, so typos don't silently downgrade access.
+pub fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Option<Principal>, Error> {
+    let Some(token) = credential(headers)? else {
+        return Ok(None);
+    };
+
+    if let Some(admin_token) = &state.settings.read().unwrap().admin_token
+        && constant_time_eq(&token, admin_token)
+    {
+        return Ok(Some(Principal::Admin));
+    }
+
+    state
+        .tokens
+        .verify(&token)
+        .map(Some)
+        .ok_or(Error::Unauthorized)
+}
+
+/// Checks the request's credentials against the configured admin token. Administrative
+/// endpoints don't exist at all without one.
+pub fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), Error> {
+    if state.settings.read().unwrap().admin_token.is_none() {
+        return Err(Error::NotFound);
+    }
+
+    match authenticate(state, headers)? {
+        Some(Principal::Admin) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Checks that the request's credentials allow pushing to the repository `name` of `user`.
+pub fn require_write(
+    state: &AppState,
+    headers: &HeaderMap,
+    user: &str,
+    name: &str,
+) -> Result<(), Error> {
+    match authenticate(state, headers)? {
+        Some(principal) if principal.can_write(user, name) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Extracts the principal of a request, rejecting anonymous ones and deploy tokens.
+#[derive(Debug)]
+pub struct Authenticated(pub Principal);
+
+impl Authenticated {
+    /// Checks that the principal may manage the repositories of `user`.
+    pub fn require_owner(&self, user: &str) -> Result<(), Error> {
+        if self.0.owns(user) {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    }
+
+    fn from_headers(state: &AppState, headers: &HeaderMap) -> Result<Option<Self>, Error> {
+        Ok(authenticate(state, headers)?
+            .filter(|principal| !matches!(principal, Principal::Deploy { .. }))
+            .map(Self))
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for Authenticated {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Self::from_headers(state, &parts.headers)?.ok_or(Error::Unauthorized)
+    }
+}
+
+/// `Option<Authenticated>` accepts anonymous requests but still rejects invalid credentials.
+/// Deploy tokens count as anonymous.
+impl OptionalFromRequestParts<Arc<AppState>> for Authenticated {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Self::from_headers(state, &parts.headers)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueToken {
+    name: String,
+    /// Registered user to issue the token to.
+    user: Option<Name>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenInfo {
+    id: String,
+    name: String,
+    created: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// The token itself, only ever shown when it is issued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<&TokenRecord> for TokenInfo {
+    fn from(record: &TokenRecord) -> Self {
+        Self {
+            id: record.id.clone(),
+            name: record.name.clone(),
+            created: record.created,
+            user: record.user.clone(),
+            token: None,
+        }
+    }
+}
+
+pub async fn issue_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<IssueToken>,
+) -> Result<(StatusCode, Json<TokenInfo>), Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let user = payload.user.map(|user| user.to_lowercase());
+
+        if let Some(user) = &user
+            && !state.users.exists(user)
+        {
+            return Err(Error::BadRequest(format!("Unknown user: {user}")));
+        }
+
+        let mut rng = rand::rng();
+        let id = hex(&rng.random::<[u8; 8]>());
+        let secret = hex(&rng.random::<[u8; 32]>());
+
+        let record = TokenRecord {
+            id: id.clone(),
+            name: payload.name,
+            hash: hash(&secret),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+            user,
+            scope: None,
+        };
+
+        let mut records = state.tokens.records.lock().unwrap();
+        records.push(record.clone());
+
+        if let Err(error) = state.tokens.save(&records) {
+            records.pop();
+            return Err(error.into());
+        }
+
+        info!("Issued access token {id} ({})", record.name);
+
+        Ok((
+            StatusCode::CREATED,
+            Json(TokenInfo {
+                token: Some(format!("{id}_{secret}")),
+                ..TokenInfo::from(&record)
+            }),
+        ))
+    })
+    .await
+}
+
+pub async fn list_tokens(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TokenInfo>>, Error> {
+    require_admin(&state, &headers)?;
+
+    let records = state.tokens.records.lock().unwrap();
+
+    Ok(Json(
+        records
+            .iter()
+            .filter(|record| record.scope.is_none())
+            .map(TokenInfo::from)
+            .collect(),
+    ))
+}
+
+/// Revokes a token. Holders of a token may revoke it themselves, e.g. after leaking it.
+pub async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Authenticated(principal): Authenticated,
+) -> Result<StatusCode, Error> {
+    blocking(move || {
+        match &principal {
+            Principal::Admin => {}
+            Principal::Token { id: own, .. } if *own == id => {}
+            _ => return Err(Error::Unauthorized),
+        }
+
+        let mut records = state.tokens.records.lock().unwrap();
+
+        let Some(index) = records.iter().position(|record| record.id == id) else {
+            return Err(Error::NotFound);
+        };
+
+        let record = records.remove(index);
+
+        if let Err(error) = state.tokens.save(&records) {
+            records.insert(index, record);
+            return Err(error.into());
+        }
+
+        info!("Revoked access token {id}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueDeployToken {
+    name: String,
+    /// Allows pushing as well as cloning and fetching.
+    #[serde(default)]
+    write: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeployTokenInfo {
+    id: String,
+    name: String,
+    created: i64,
+    write: bool,
+    /// The token itself, only ever shown when it is issued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl DeployTokenInfo {
+    fn new(record: &TokenRecord, scope: &Scope) -> Self {
+        Self {
+            id: record.id.clone(),
+            name: record.name.clone(),
+            created: record.created,
+            write: scope.write,
+            token: None,
+        }
+    }
+}
+
+pub async fn issue_deploy_token(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<IssueDeployToken>,
+) -> Result<(StatusCode, Json<DeployTokenInfo>), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        state.open_repo(&user, &name)?;
+
+        let mut rng = rand::rng();
+        let id = hex(&rng.random::<[u8; 8]>());
+        let secret = hex(&rng.random::<[u8; 32]>());
+
+        let scope = Scope {
+            user: user.to_lowercase(),
+            name: name.to_lowercase(),
+            write: payload.write,
+        };
+
+        let record = TokenRecord {
+            id: id.clone(),
+            name: payload.name,
+            hash: hash(&secret),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+            user: None,
+            scope: Some(scope.clone()),
+        };
+
+        let mut records = state.tokens.records.lock().unwrap();
+        records.push(record.clone());
+
+        if let Err(error) = state.tokens.save(&records) {
+            records.pop();
+            return Err(error.into());
+        }
+
+        info!(
+            "Issued deploy token {id} ({}) for {user}/{name}",
+            record.name
+        );
+
+        Ok((
+            StatusCode::CREATED,
+            Json(DeployTokenInfo {
+                token: Some(format!("{id}_{secret}")),
+                ..DeployTokenInfo::new(&record, &scope)
+            }),
+        ))
+    })
+    .await
+}
+
+pub async fn list_deploy_tokens(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+) -> Result<Json<Vec<DeployTokenInfo>>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        state.open_repo(&user, &name)?;
+
+        let records = state.tokens.records.lock().unwrap();
+
+        Ok(Json(
+            records
+                .iter()
+                .filter_map(|record| {
+                    let scope = record.scope.as_ref()?;
+                    scope
+                        .covers(&user, &name)
+                        .then(|| DeployTokenInfo::new(record, scope))
+                })
+                .collect(),
+        ))
+    })
+    .await
+}
+
+pub async fn revoke_deploy_token(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, id)): Path<(Name, Name, String)>,
+    caller: Authenticated,
+) -> Result<StatusCode, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let mut records = state.tokens.records.lock().unwrap();
+
+        let Some(index) = records.iter().position(|record| {
+            record.id == id
+                && record
+                    .scope
+                    .as_ref()
+                    .is_some_and(|scope| scope.covers(&user, &name))
+        }) else {
+            return Err(Error::NotFound);
+        };
+
+        let record = records.remove(index);
+
+        if let Err(error) = state.tokens.save(&records) {
+            records.insert(index, record);
+            return Err(error.into());
+        }
+
+        info!("Revoked deploy token {id} of {user}/{name}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
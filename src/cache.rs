@@ -0,0 +1,83 @@
+//! Bounded caches for results that are expensive to compute.
+//!
+//! Results are keyed by what they were computed from, such as a tree or commit oid, so entries
+//! never go stale. Any reader picks the keys though, so each cache only holds so many entries
+//! and makes room by evicting the one that was used least recently.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::Mutex,
+};
+
+/// Caches up to `CAPACITY` entries.
+#[derive(Debug)]
+pub struct Cache<K, V, const CAPACITY: usize = 1024> {
+    inner: Mutex<Inner<K, V>>,
+}
+
+#[derive(Debug)]
+struct Inner<K, V> {
+    /// Values along with when they were last used.
+    entries: HashMap<K, (u64, V)>,
+    /// Keys by when they were last used, least recently first.
+    uses: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K, V, const CAPACITY: usize> Default for Cache<K, V, CAPACITY> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                uses: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone, const CAPACITY: usize> Cache<K, V, CAPACITY> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            entries,
+            uses,
+            clock,
+        } = &mut *inner;
+
+        let (used, value) = entries.get_mut(key)?;
+
+        *clock += 1;
+        uses.remove(used);
+        uses.insert(*clock, key.clone());
+        *used = *clock;
+
+        Some(value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            entries,
+            uses,
+            clock,
+        } = &mut *inner;
+
+        *clock += 1;
+
+        if let Some((used, _)) = entries.insert(key.clone(), (*clock, value)) {
+            uses.remove(&used);
+        }
+
+        uses.insert(*clock, key);
+
+        while entries.len() > CAPACITY {
+            let Some((_, key)) = uses.pop_first() else {
+                break;
+            };
+
+            entries.remove(&key);
+        }
+    }
+}
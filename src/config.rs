@@ -0,0 +1,365 @@
+//! Server configuration.
+//!
+//! Values come from `GIT_SERVER_*` environment variables, optionally overridden by a file of
+//! `KEY=VALUE` lines named by `GIT_SERVER_CONFIG`. [`Settings`] can change while the server is
+//! running: reloading re-reads that file and any referenced secret files. [`Options`] are baked
+//! into the listener and can additionally be given on the command line, but need a restart.
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    process,
+};
+
+use anyhow::{Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+
+pub const CONFIG_FILE_VAR: &str = "GIT_SERVER_CONFIG";
+pub const PORT_VAR: &str = "GIT_SERVER_PORT";
+pub const BIND_VAR: &str = "GIT_SERVER_BIND";
+pub const LISTEN_VAR: &str = "GIT_SERVER_LISTEN";
+pub const REPO_ROOT_VAR: &str = "GIT_SERVER_REPO_ROOT";
+pub const LFS_ROOT_VAR: &str = "GIT_SERVER_LFS_ROOT";
+pub const CREATE_HOOK_URL_VAR: &str = "GIT_SERVER_CREATE_HOOK_URL";
+pub const ADMIN_TOKEN_VAR: &str = "GIT_SERVER_ADMIN_TOKEN";
+pub const ADMIN_TOKEN_FILE_VAR: &str = "GIT_SERVER_ADMIN_TOKEN_FILE";
+pub const CORS_ORIGINS_VAR: &str = "GIT_SERVER_CORS_ORIGINS";
+pub const CORS_METHODS_VAR: &str = "GIT_SERVER_CORS_METHODS";
+pub const CORS_HEADERS_VAR: &str = "GIT_SERVER_CORS_HEADERS";
+pub const RATE_LIMIT_VAR: &str = "GIT_SERVER_RATE_LIMIT";
+pub const TOKEN_RATE_LIMIT_VAR: &str = "GIT_SERVER_TOKEN_RATE_LIMIT";
+pub const RATE_LIMIT_BURST_VAR: &str = "GIT_SERVER_RATE_LIMIT_BURST";
+pub const AUTO_GC_LOOSE_OBJECTS_VAR: &str = "GIT_SERVER_AUTO_GC_LOOSE_OBJECTS";
+
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 3344;
+
+/// Cross-origin callers may only read unless configured otherwise.
+const DEFAULT_CORS_METHODS: &str = "GET,HEAD";
+const DEFAULT_CORS_HEADERS: &str = "authorization,content-type,if-none-match";
+
+const USAGE: &str = "\
+Usage: git-server [OPTIONS]
+
+Options:
+      --port <PORT>       Port to listen on [env: GIT_SERVER_PORT] [default: 3344]
+      --bind <ADDRESS>    Address to listen on [env: GIT_SERVER_BIND] [default: 0.0.0.0]
+      --listen <SOCKET>   Socket to listen on instead, as HOST:PORT or unix:PATH [env: GIT_SERVER_LISTEN]
+      --repo-root <PATH>  Directory holding the repositories [env: GIT_SERVER_REPO_ROOT] [default: repos]
+  -h, --help              Print this help
+";
+
+/// Options fixed for the lifetime of the process.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub listen: Listen,
+    /// Directory holding one directory per user, each holding that user's bare repositories.
+    pub repo_root: PathBuf,
+    /// Directory holding the Git LFS objects of every repository. Without one, each repository
+    /// keeps its own in `lfs/objects`.
+    pub lfs_root: Option<PathBuf>,
+    /// Cross-origin access for browsers, disabled without any allowed origins.
+    pub cors: Option<Cors>,
+}
+
+/// Where connections are accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, for reverse proxies on the same host.
+    Unix(PathBuf),
+}
+
+impl Listen {
+    fn parse(value: &str) -> Result<Self> {
+        match value.strip_prefix("unix:") {
+            Some("") => anyhow::bail!("Missing socket path in {value}"),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => {
+                Ok(Self::Tcp(value.parse().with_context(|| {
+                    format!("Invalid listen address {value}")
+                })?))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// Origins allowed to make requests. `None` allows any.
+    pub origins: Option<Vec<HeaderValue>>,
+    pub methods: Vec<Method>,
+    /// Request headers browsers may send along.
+    pub headers: Vec<HeaderName>,
+}
+
+impl Cors {
+    fn load(values: &Values) -> Result<Option<Self>> {
+        let Some(origins) = values.get(CORS_ORIGINS_VAR) else {
+            return Ok(None);
+        };
+
+        let list = |value: &str| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let origins = if origins.trim() == "*" {
+            None
+        } else {
+            Some(
+                list(origins)
+                    .iter()
+                    .map(|origin| {
+                        HeaderValue::from_str(origin)
+                            .with_context(|| format!("Invalid CORS origin {origin}"))
+                    })
+                    .collect::<Result<_>>()?,
+            )
+        };
+
+        let methods = list(values.get(CORS_METHODS_VAR).unwrap_or(DEFAULT_CORS_METHODS))
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .with_context(|| format!("Invalid CORS method {method}"))
+            })
+            .collect::<Result<_>>()?;
+
+        let headers = list(values.get(CORS_HEADERS_VAR).unwrap_or(DEFAULT_CORS_HEADERS))
+            .iter()
+            .map(|header| {
+                HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("Invalid CORS header {header}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self {
+            origins,
+            methods,
+            headers,
+        }))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            listen: Listen::Tcp(SocketAddr::from((DEFAULT_BIND, DEFAULT_PORT))),
+            repo_root: PathBuf::from("repos"),
+            lfs_root: None,
+            cors: None,
+        }
+    }
+}
+
+impl Options {
+    /// Reads the options from the command line, falling back to the configuration values and
+    /// then the defaults. Exits after printing the usage for `--help`.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut values = Values::load()?;
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                print!("{USAGE}");
+                process::exit(0);
+            }
+
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+
+            let key = match flag {
+                "--port" => PORT_VAR,
+                "--bind" => BIND_VAR,
+                "--listen" => LISTEN_VAR,
+                "--repo-root" => REPO_ROOT_VAR,
+                _ => anyhow::bail!("Unknown argument {flag}\n\n{USAGE}"),
+            };
+
+            let value = match value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .with_context(|| format!("Missing value for {flag}\n\n{USAGE}"))?,
+            };
+
+            values.0.insert(key.to_string(), value);
+        }
+
+        let defaults = Self::default();
+
+        let listen = match values.get(LISTEN_VAR) {
+            Some(listen) => Listen::parse(listen)?,
+            None => {
+                let bind = match values.get(BIND_VAR) {
+                    Some(bind) => bind
+                        .parse()
+                        .with_context(|| format!("Invalid bind address {bind}"))?,
+                    None => DEFAULT_BIND,
+                };
+
+                let port = match values.get(PORT_VAR) {
+                    Some(port) => port
+                        .parse()
+                        .with_context(|| format!("Invalid port {port}"))?,
+                    None => DEFAULT_PORT,
+                };
+
+                Listen::Tcp(SocketAddr::from((bind, port)))
+            }
+        };
+
+        Ok(Self {
+            listen,
+            repo_root: values
+                .get(REPO_ROOT_VAR)
+                .map_or(defaults.repo_root, PathBuf::from),
+            lfs_root: values.get(LFS_ROOT_VAR).map(PathBuf::from),
+            cors: Cors::load(&values)?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
+    /// Where to announce newly created repositories, if anywhere.
+    pub create_hook_url: Option<String>,
+    /// Bearer token guarding administrative endpoints, which are disabled without one.
+    pub admin_token: Option<String>,
+    /// Limit for anonymous requests, per client address.
+    pub rate_limit: Option<RateLimit>,
+    /// Limit for requests made with an access token, per token. The admin is never limited.
+    pub token_rate_limit: Option<RateLimit>,
+    /// Loose objects a push may leave behind before maintenance runs in the background.
+    /// Disabled without one.
+    pub auto_gc_loose_objects: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate.
+    pub per_minute: u32,
+    /// Requests allowed in a quick burst before the rate kicks in.
+    pub burst: u32,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self> {
+        let values = Values::load()?;
+
+        let admin_token = match values.get(ADMIN_TOKEN_FILE_VAR) {
+            Some(path) => {
+                let token = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read admin token from {path}"))?;
+
+                Some(token.trim().to_string()).filter(|token| !token.is_empty())
+            }
+            None => values.get(ADMIN_TOKEN_VAR).map(str::to_string),
+        };
+
+        let burst = values
+            .get(RATE_LIMIT_BURST_VAR)
+            .map(|burst| {
+                burst
+                    .parse::<u32>()
+                    .with_context(|| format!("Invalid rate limit burst {burst}"))
+            })
+            .transpose()?;
+
+        let rate_limit = |key| -> Result<Option<RateLimit>> {
+            let Some(limit) = values.get(key) else {
+                return Ok(None);
+            };
+
+            let per_minute: u32 = limit
+                .parse()
+                .ok()
+                .filter(|&per_minute| per_minute > 0)
+                .with_context(|| {
+                    format!("Invalid rate limit {limit}, expected requests per minute")
+                })?;
+
+            Ok(Some(RateLimit {
+                per_minute,
+                // A minute's worth by default.
+                burst: burst.unwrap_or(per_minute).max(1),
+            }))
+        };
+
+        let auto_gc_loose_objects = values
+            .get(AUTO_GC_LOOSE_OBJECTS_VAR)
+            .map(|count| {
+                count
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .with_context(|| format!("Invalid auto gc loose object count {count}"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            create_hook_url: values.get(CREATE_HOOK_URL_VAR).map(str::to_string),
+            admin_token,
+            rate_limit: rate_limit(RATE_LIMIT_VAR)?,
+            token_rate_limit: rate_limit(TOKEN_RATE_LIMIT_VAR)?,
+            auto_gc_loose_objects,
+        })
+    }
+}
+
+/// Raw configuration values, with the config file taking precedence over the environment.
+struct Values(HashMap<String, String>);
+
+impl Values {
+    fn load() -> Result<Self> {
+        let mut values: HashMap<_, _> = env::vars()
+            .filter(|(key, _)| key.starts_with("GIT_SERVER_"))
+            .collect();
+
+        if let Some(path) = values.get(CONFIG_FILE_VAR).cloned() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {path}"))?;
+
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let Some((key, value)) = line.split_once('=') else {
+                    anyhow::bail!("Invalid line in {path}: {line}");
+                };
+
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        Ok(Self(values))
+    }
+
+    /// Looks up `key`, treating empty values as unset.
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get(key)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+}
@@ -0,0 +1,606 @@
+//! Commits made through the API, so files can be edited without a clone.
+//!
+//! `PUT /repo/{user}/{name}/contents/{branch}/{*path}` creates or replaces a file and `DELETE`
+//! on the same path deletes one, each as a new commit on top of the branch. The new tree is
+//! built from the branch's without a working tree or an index. Requests may name the commit
+//! they expect the branch to be at as `parent` and are refused if it has moved on, so editors
+//! working on the same branch don't overwrite each other's changes unknowingly. Committing
+//! takes the same credentials as pushing to the repository.
+//!
+//! `POST /repo/{user}/{name}/commits` does the same for a batch of changes, creating,
+//! updating, deleting and moving files in a single commit. Either every change applies or the
+//! branch stays as it was.
+
+use std::{collections::HashSet, path::Path as FsPath, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use git2::{FileMode, ObjectType, Oid, Repository, build::TreeUpdateBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    AppState, BlobEncoding, Error, Json, Name, Path, SignatureSpec, advance_branch,
+    auth::require_write, blocking, find_public_branch, hooks::RefUpdate, normalize_path,
+    server_signature,
+};
+
+/// What every commit made through the API needs.
+#[derive(Debug, Deserialize)]
+pub struct CommitSpec {
+    message: String,
+    /// Who the commit is recorded as authored by. It is always committed by the repository's
+    /// configured identity.
+    author: Option<SignatureSpec>,
+    /// Commit the branch is expected to be at.
+    parent: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PutContents {
+    content: String,
+    #[serde(default)]
+    encoding: BlobEncoding,
+    /// Whether the file is executable. Defaults to what the file being replaced was.
+    executable: Option<bool>,
+    #[serde(flatten)]
+    commit: CommitSpec,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteContents {
+    #[serde(flatten)]
+    commit: CommitSpec,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Committed {
+    branch: String,
+    /// Where the branch was before, if it existed.
+    previous: Option<String>,
+    /// The new commit, where the branch is now.
+    commit: String,
+}
+
+/// What a write expects to find at its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Existing {
+    Either,
+    File,
+    Nothing,
+}
+
+/// A change to a single file.
+enum Change {
+    Write {
+        path: String,
+        content: Vec<u8>,
+        executable: Option<bool>,
+        existing: Existing,
+    },
+    Delete {
+        path: String,
+    },
+    /// Moves a file, replacing its content if there is any.
+    Move {
+        from: String,
+        to: String,
+        content: Option<Vec<u8>>,
+    },
+}
+
+impl Change {
+    fn paths(&self) -> Vec<&str> {
+        match self {
+            Change::Write { path, .. } | Change::Delete { path } => vec![path],
+            Change::Move { from, to, .. } => vec![from, to],
+        }
+    }
+}
+
+/// The mode a file with the raw `mode` keeps when its content changes.
+fn kept_mode(mode: i32) -> FileMode {
+    if mode == i32::from(FileMode::BlobExecutable) {
+        FileMode::BlobExecutable
+    } else if mode == i32::from(FileMode::Link) {
+        FileMode::Link
+    } else {
+        FileMode::Blob
+    }
+}
+
+fn decode(content: String, encoding: &BlobEncoding) -> Result<Vec<u8>, Error> {
+    match encoding {
+        BlobEncoding::Utf8 => Ok(content.into_bytes()),
+        BlobEncoding::Base64 => BASE64_STANDARD
+            .decode(&content)
+            .map_err(|_| Error::BadRequest("Invalid base64 content".to_string())),
+    }
+}
+
+/// Normalizes a path to write to, refusing anything git wouldn't check out.
+fn checked_path(path: &str) -> Result<String, Error> {
+    let path = normalize_path(path);
+
+    let invalid = path.is_empty()
+        || path.chars().any(char::is_control)
+        || path.split('/').any(|component| {
+            matches!(component, "." | "..") || component.eq_ignore_ascii_case(".git")
+        });
+
+    if invalid {
+        return Err(Error::BadRequest(format!("Invalid path: {path}")));
+    }
+
+    Ok(path)
+}
+
+/// Applies `changes` to the tree of `branch` and commits the result on top of it.
+fn commit_changes(
+    state: &AppState,
+    repo: &Repository,
+    (user, name): (&str, &str),
+    branch: &str,
+    changes: Vec<Change>,
+    spec: CommitSpec,
+) -> Result<Committed, Error> {
+    let (reference, tip) = match find_public_branch(repo, branch) {
+        Ok((reference, tip)) => (reference, Some(tip)),
+        // The first commit of an empty repository creates its branch.
+        Err(Error::NotFound) if repo.is_empty()? && git2::Branch::name_is_valid(branch)? => {
+            (format!("refs/heads/{branch}"), None)
+        }
+        Err(error) => return Err(error),
+    };
+
+    if let Some(parent) = &spec.parent {
+        let parent = Oid::from_str(parent)
+            .map_err(|_| Error::BadRequest(format!("Invalid parent commit: {parent}")))?;
+
+        if tip.as_ref().map(|tip| tip.id()) != Some(parent) {
+            return Err(Error::Conflict);
+        }
+    }
+
+    let base = match &tip {
+        Some(tip) => tip.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+
+    let kind_at = |path: &str| {
+        base.get_path(FsPath::new(path))
+            .ok()
+            .map(|entry| (entry.kind(), entry.filemode()))
+    };
+
+    // The mode of the file at `path`, if there is one.
+    let file_at = |path: &str| match kind_at(path) {
+        None => Ok(None),
+        Some((Some(ObjectType::Blob), mode)) => Ok(Some(mode)),
+        Some(_) => Err(Error::BadRequest(format!("{path} is not a file"))),
+    };
+
+    // Every directory on the way to `path` has to be one, or not exist yet.
+    let check_directories = |path: &str| {
+        for (index, _) in path.match_indices('/') {
+            if kind_at(&path[..index]).is_some_and(|(kind, _)| kind != Some(ObjectType::Tree)) {
+                return Err(Error::BadRequest(format!(
+                    "{} is not a directory",
+                    &path[..index]
+                )));
+            }
+        }
+
+        Ok(())
+    };
+
+    let mut seen = HashSet::new();
+
+    for change in &changes {
+        for path in change.paths() {
+            if !seen.insert(path) {
+                return Err(Error::BadRequest(format!(
+                    "{path} is changed more than once"
+                )));
+            }
+        }
+    }
+
+    let mut builder = TreeUpdateBuilder::new();
+
+    for change in changes {
+        match change {
+            Change::Write {
+                path,
+                content,
+                executable,
+                existing,
+            } => {
+                check_directories(&path)?;
+
+                let current = file_at(&path)?;
+
+                match (existing, current) {
+                    (Existing::File, None) => return Err(Error::NotFound),
+                    (Existing::Nothing, Some(_)) => return Err(Error::Conflict),
+                    _ => {}
+                }
+
+                let mode = match (executable, current) {
+                    (Some(true), _) => FileMode::BlobExecutable,
+                    (Some(false), _) | (None, None) => FileMode::Blob,
+                    (None, Some(mode)) => kept_mode(mode),
+                };
+
+                builder.upsert(path.as_str(), repo.blob(&content)?, mode);
+            }
+            Change::Delete { path } => {
+                if file_at(&path)?.is_none() {
+                    return Err(Error::NotFound);
+                }
+
+                builder.remove(path.as_str());
+            }
+            Change::Move { from, to, content } => {
+                let entry = base
+                    .get_path(FsPath::new(&from))
+                    .map_err(|_| Error::NotFound)?;
+
+                if entry.kind() != Some(ObjectType::Blob) {
+                    return Err(Error::BadRequest(format!("{from} is not a file")));
+                }
+
+                check_directories(&to)?;
+
+                if file_at(&to)?.is_some() {
+                    return Err(Error::Conflict);
+                }
+
+                let blob = match content {
+                    Some(content) => repo.blob(&content)?,
+                    None => entry.id(),
+                };
+
+                builder.remove(from.as_str());
+                builder.upsert(to.as_str(), blob, kept_mode(entry.filemode()));
+            }
+        }
+    }
+
+    let tree = repo.find_tree(builder.create_updated(repo, &base)?)?;
+
+    if tree.id() == base.id() && tip.is_some() {
+        return Err(Error::BadRequest("Nothing to commit".to_string()));
+    }
+
+    let committer = server_signature(repo)?;
+    let author = match &spec.author {
+        Some(identity) => git2::Signature::now(&identity.name, &identity.email)?,
+        None => committer.clone(),
+    };
+
+    let parents: Vec<_> = tip.iter().collect();
+    let commit = repo.commit(None, &author, &committer, &spec.message, &tree, &parents)?;
+
+    advance_branch(
+        state,
+        repo,
+        (user, name),
+        RefUpdate {
+            old: tip.as_ref().map_or_else(Oid::zero, |tip| tip.id()),
+            new: commit,
+            name: reference,
+        },
+        &format!(
+            "commit: {}",
+            spec.message.lines().next().unwrap_or_default()
+        ),
+    )?;
+
+    info!("Committed {commit} to {branch} of {user}/{name}");
+
+    Ok(Committed {
+        branch: branch.to_string(),
+        previous: tip.map(|tip| tip.id().to_string()),
+        commit: commit.to_string(),
+    })
+}
+
+pub async fn put_contents(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, path)): Path<(Name, Name, String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<PutContents>,
+) -> Result<(StatusCode, Json<Committed>), Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+        let path = checked_path(&path)?;
+
+        let content = decode(payload.content, &payload.encoding)?;
+
+        let created = find_public_branch(&repo, &branch)
+            .ok()
+            .and_then(|(_, tip)| tip.tree().ok()?.get_path(FsPath::new(&path)).ok())
+            .is_none();
+
+        let committed = commit_changes(
+            &state,
+            &repo,
+            (&user, &name),
+            &branch,
+            vec![Change::Write {
+                path,
+                content,
+                executable: payload.executable,
+                existing: Existing::Either,
+            }],
+            payload.commit,
+        )?;
+
+        let status = if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        };
+
+        Ok((status, Json(committed)))
+    })
+    .await
+}
+
+pub async fn delete_contents(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, path)): Path<(Name, Name, String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteContents>,
+) -> Result<Json<Committed>, Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+        let path = checked_path(&path)?;
+
+        let committed = commit_changes(
+            &state,
+            &repo,
+            (&user, &name),
+            &branch,
+            vec![Change::Delete { path }],
+            payload.commit,
+        )?;
+
+        Ok(Json(committed))
+    })
+    .await
+}
+
+/// A change in a batch, as requests name them.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Adds a file that doesn't exist yet.
+    Create {
+        path: String,
+        content: String,
+        #[serde(default)]
+        encoding: BlobEncoding,
+        executable: Option<bool>,
+    },
+    /// Replaces the content of an existing file.
+    Update {
+        path: String,
+        content: String,
+        #[serde(default)]
+        encoding: BlobEncoding,
+        executable: Option<bool>,
+    },
+    Delete {
+        path: String,
+    },
+    /// Moves a file to `path`, which mustn't exist yet, optionally replacing its content.
+    Move {
+        from: String,
+        path: String,
+        content: Option<String>,
+        #[serde(default)]
+        encoding: BlobEncoding,
+    },
+}
+
+impl Action {
+    fn into_change(self) -> Result<Change, Error> {
+        Ok(match self {
+            Action::Create {
+                path,
+                content,
+                encoding,
+                executable,
+            } => Change::Write {
+                path: checked_path(&path)?,
+                content: decode(content, &encoding)?,
+                executable,
+                existing: Existing::Nothing,
+            },
+            Action::Update {
+                path,
+                content,
+                encoding,
+                executable,
+            } => Change::Write {
+                path: checked_path(&path)?,
+                content: decode(content, &encoding)?,
+                executable,
+                existing: Existing::File,
+            },
+            Action::Delete { path } => Change::Delete {
+                path: checked_path(&path)?,
+            },
+            Action::Move {
+                from,
+                path,
+                content,
+                encoding,
+            } => Change::Move {
+                from: checked_path(&from)?,
+                to: checked_path(&path)?,
+                content: content
+                    .map(|content| decode(content, &encoding))
+                    .transpose()?,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCommit {
+    branch: String,
+    changes: Vec<Action>,
+    #[serde(flatten)]
+    commit: CommitSpec,
+}
+
+pub async fn create_commit(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateCommit>,
+) -> Result<(StatusCode, Json<Committed>), Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if payload.changes.is_empty() {
+            return Err(Error::BadRequest("Nothing to commit".to_string()));
+        }
+
+        let changes = payload
+            .changes
+            .into_iter()
+            .map(Action::into_change)
+            .collect::<Result<_, _>>()?;
+
+        let committed = commit_changes(
+            &state,
+            &repo,
+            (&user, &name),
+            &payload.branch,
+            changes,
+            payload.commit,
+        )?;
+
+        Ok((StatusCode::CREATED, Json(committed)))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::tests::{TestServer, commit};
+
+    #[tokio::test]
+    async fn file_edits_need_push_access_and_a_current_parent() {
+        let server = TestServer::new("contents");
+        let repo = server.init_repo("alice", "r.git");
+        let main = commit(&repo, Some("refs/heads/main"), &[("a", b"1")], &[]);
+
+        let reader = server.deploy_token("alice", "r.git", false).await;
+        let writer = server.deploy_token("alice", "r.git", true).await;
+
+        let put = |parent: String| json!({ "content": "2", "message": "Edit", "parent": parent });
+        let uri = "/repo/alice/r.git/contents/main/a";
+
+        for (method, body) in [
+            (Method::PUT, put(main.to_string())),
+            (Method::DELETE, json!({ "message": "Delete" })),
+        ] {
+            let (status, _) = server.send(method, uri, Some(&reader), Some(body)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), main);
+
+        let (status, committed) = server
+            .json(Method::PUT, uri, Some(&writer), Some(put(main.to_string())))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(committed["previous"], main.to_string());
+
+        // The branch moved on since, so an edit expecting it where it was is refused.
+        let (status, _) = server
+            .send(Method::PUT, uri, Some(&writer), Some(put(main.to_string())))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            repo.refname_to_id("refs/heads/main").unwrap().to_string(),
+            committed["commit"]
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_need_push_access_and_a_current_parent() {
+        let server = TestServer::new("batches");
+        let repo = server.init_repo("alice", "r.git");
+        let main = commit(&repo, Some("refs/heads/main"), &[("a", b"1")], &[]);
+
+        let reader = server.deploy_token("alice", "r.git", false).await;
+        let writer = server.deploy_token("alice", "r.git", true).await;
+
+        let batch = |parent: String| {
+            json!({
+                "branch": "main",
+                "message": "Rework",
+                "parent": parent,
+                "changes": [
+                    { "action": "create", "path": "b", "content": "2" },
+                    { "action": "move", "from": "a", "path": "c" },
+                ],
+            })
+        };
+        let uri = "/repo/alice/r.git/commits";
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                uri,
+                Some(&reader),
+                Some(batch(main.to_string())),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, committed) = server
+            .json(
+                Method::POST,
+                uri,
+                Some(&writer),
+                Some(batch(main.to_string())),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let tip = repo.find_reference("refs/heads/main").unwrap();
+        let tree = tip.peel_to_tree().unwrap();
+        assert_eq!(tip.target().unwrap().to_string(), committed["commit"]);
+        assert!(tree.get_name("a").is_none());
+        assert!(tree.get_name("b").is_some() && tree.get_name("c").is_some());
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                uri,
+                Some(&writer),
+                Some(batch(main.to_string())),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,241 @@
+//! Live repository activity, streamed to clients as server-sent events.
+//!
+//! Everything that changes a repository publishes an [`Event`] on a broadcast channel, which
+//! `GET /events` and `GET /repo/{user}/{name}/events` relay to their subscribers. Nothing is
+//! kept: subscribers only see what happens while they are connected, and ones that fall too
+//! far behind are told how many events they missed.
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::sse::{self, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use git2::Oid;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::{
+    AppState, Error, Name, Path,
+    auth::Authenticated,
+    blocking,
+    hooks::{Hook, Push, RefUpdate},
+    is_private,
+};
+
+/// Events buffered per subscriber before it starts missing some.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    RefUpdate {
+        user: String,
+        name: String,
+        #[serde(rename = "ref")]
+        reference: String,
+        /// All zeros if the ref was created.
+        before: String,
+        /// All zeros if the ref was deleted.
+        after: String,
+    },
+    RepoCreated {
+        user: String,
+        name: String,
+    },
+    RepoDeleted {
+        user: String,
+        name: String,
+    },
+}
+
+impl Event {
+    pub fn ref_update(user: &str, name: &str, reference: &str, before: Oid, after: Oid) -> Self {
+        Self::RefUpdate {
+            user: user.to_lowercase(),
+            name: name.to_lowercase(),
+            reference: reference.to_string(),
+            before: before.to_string(),
+            after: after.to_string(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::RefUpdate { .. } => "ref_update",
+            Self::RepoCreated { .. } => "repo_created",
+            Self::RepoDeleted { .. } => "repo_deleted",
+        }
+    }
+
+    fn repo(&self) -> (&str, &str) {
+        match self {
+            Self::RefUpdate { user, name, .. }
+            | Self::RepoCreated { user, name }
+            | Self::RepoDeleted { user, name } => (user, name),
+        }
+    }
+}
+
+/// An event along with whether it concerns a private repository, which decides who may see it.
+#[derive(Debug, Clone)]
+struct Published {
+    event: Event,
+    private: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Published>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Announces `event` to current subscribers. Without any it goes nowhere.
+    pub fn publish(&self, event: Event, private: bool) {
+        debug!("Publishing {} event", event.kind());
+
+        let _ = self.0.send(Published { event, private });
+    }
+
+    /// Relays the events passing `filter` as they are published.
+    fn stream<F>(&self, filter: F) -> impl Stream<Item = Result<sse::Event, Infallible>> + use<F>
+    where
+        F: Fn(&Published) -> bool + Send + 'static,
+    {
+        stream::unfold(
+            (self.0.subscribe(), filter),
+            |(mut receiver, filter)| async move {
+                loop {
+                    let published = match receiver.recv().await {
+                        Ok(published) => published,
+                        Err(RecvError::Lagged(missed)) => {
+                            let event = sse::Event::default()
+                                .event("lagged")
+                                .data(missed.to_string());
+
+                            return Some((Ok(event), (receiver, filter)));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    };
+
+                    if !filter(&published) {
+                        continue;
+                    }
+
+                    let event = sse::Event::default()
+                        .event(published.event.kind())
+                        .json_data(&published.event)
+                        .expect("events serialize to JSON");
+
+                    return Some((Ok(event), (receiver, filter)));
+                }
+            },
+        )
+        .fuse()
+    }
+}
+
+/// Publishes the refs moved by pushes.
+impl Hook for Events {
+    fn post_receive(&self, push: &mut Push, updates: &[&RefUpdate]) {
+        let private = is_private(push.repo).unwrap_or(true);
+
+        for update in updates {
+            self.publish(
+                Event::ref_update(push.user, push.name, &update.name, update.old, update.new),
+                private,
+            );
+        }
+    }
+}
+
+/// Activity across every repository the caller can see.
+pub async fn all_events(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Authenticated>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    Sse::new(state.events.stream(move |published| {
+        let (user, name) = published.event.repo();
+
+        !published.private
+            || caller
+                .as_ref()
+                .is_some_and(|caller| caller.0.can_read(user, name))
+    }))
+    .keep_alive(KeepAlive::default())
+}
+
+/// Activity of a single repository. Visibility is already checked by the time this runs.
+pub async fn repo_events(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, Error> {
+    let events = state.events.clone();
+
+    let (user, name) = blocking(move || {
+        state.open_repo(&user, &name)?;
+
+        Ok((user.to_lowercase(), name.to_lowercase()))
+    })
+    .await?;
+
+    Ok(Sse::new(
+        events.stream(move |published| published.event.repo() == (user.as_str(), name.as_str())),
+    )
+    .keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::tests::{ADMIN_TOKEN, TestServer, commit};
+
+    #[tokio::test]
+    async fn repo_streams_relay_only_their_own_activity() {
+        let server = TestServer::new("events");
+        let repo = server.init_repo("test", "r.git");
+        commit(&repo, Some("refs/heads/main"), &[("a", b"a")], &[]);
+        server.init_repo("test", "other.git");
+
+        let request = Request::get("/repo/test/r.git/events")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut events = response.into_body().into_data_stream();
+
+        for uri in ["/repo/test/other.git", "/repo/test/r.git"] {
+            let (status, _) = server
+                .send(Method::DELETE, uri, Some(ADMIN_TOKEN), None)
+                .await;
+            assert_eq!(status, StatusCode::NO_CONTENT, "{uri}");
+        }
+
+        let event = events.next().await.unwrap().unwrap();
+        let event = String::from_utf8(event.to_vec()).unwrap();
+
+        let mut lines = event.lines();
+        assert_eq!(lines.next(), Some("event: repo_deleted"));
+
+        let data: serde_json::Value =
+            serde_json::from_str(lines.next().unwrap().strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(
+            data,
+            json!({ "kind": "repo_deleted", "user": "test", "name": "r.git" })
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Forks: repositories created from another one, borrowing its objects.
+//!
+//! `POST /repo/{user}/{name}/fork` creates a bare repository under another user with the
+//! branches and tags of the original and an `objects/info/alternates` entry pointing at the
+//! original's object database, so forking takes next to no time or space however big the
+//! original is. Whatever is pushed to the fork afterwards is stored in the fork.
+//!
+//! The alternates are the only record of which repositories are forks of which, so nothing can
+//! get out of sync with them. The original has to keep every object its forks may use: once it
+//! has forks maintenance stops pruning it, moving it updates the forks' alternates, and
+//! deleting it first copies what the forks need into them.
+
+use std::{
+    fs, io,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
+
+use axum::{extract::State, http::StatusCode};
+use git2::{ObjectType, Oid, Repository};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::{
+    AppState, Error, Json, Name, PRIVATE_KEY, Path, RepoLocation, announce_created,
+    auth::Authenticated, blocking, init_bare_atomic, is_branch_public, is_private, listing_name,
+    maintenance, redirect_target,
+};
+
+/// The object databases the repository at `path` borrows objects from.
+fn alternates(path: &FsPath) -> io::Result<Vec<PathBuf>> {
+    match fs::read_to_string(path.join("objects").join("info").join("alternates")) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(PathBuf::from)
+            .collect()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// The repositories below `root` borrowing from the object database at `objects`, which has to
+/// be canonical as the alternates of forks are.
+pub fn forks_of(root: &FsPath, objects: &FsPath) -> io::Result<Vec<PathBuf>> {
+    let mut forks = Vec::new();
+
+    let users = match fs::read_dir(root) {
+        Ok(users) => users,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(forks),
+        Err(error) => return Err(error),
+    };
+
+    for user in users {
+        let user = user?;
+
+        if listing_name(&user)?.is_none() {
+            continue;
+        }
+
+        for repo in fs::read_dir(user.path())? {
+            let repo = repo?;
+
+            if listing_name(&repo)?.is_some()
+                && alternates(&repo.path())?.iter().any(|path| path == objects)
+            {
+                forks.push(repo.path());
+            }
+        }
+    }
+
+    Ok(forks)
+}
+
+/// The branch and tag tips of the repositories `repo` borrows objects from, all of which it
+/// can rely on them having.
+pub fn borrowed_tips(repo: &Repository) -> Result<Vec<Oid>, Error> {
+    let mut tips = Vec::new();
+
+    for objects in alternates(repo.path())? {
+        let Some(Ok(original)) = objects.parent().map(Repository::open_bare) else {
+            continue;
+        };
+
+        for reference in original.references()? {
+            let reference = reference?;
+
+            if let Ok(commit) = reference.peel(ObjectType::Commit) {
+                tips.push(commit.id());
+            }
+        }
+    }
+
+    Ok(tips)
+}
+
+/// Points the forks of the object database at `from` to `to`, after the original moved.
+pub fn retarget(root: &FsPath, from: &FsPath, to: &FsPath) -> io::Result<()> {
+    for fork in forks_of(root, from)? {
+        let alternates: Vec<String> = alternates(&fork)?
+            .into_iter()
+            .map(|path| if path == from { to } else { &path }.display().to_string())
+            .collect();
+
+        debug!("Pointing {} at {}", fork.display(), to.display());
+
+        fs::write(
+            fork.join("objects").join("info").join("alternates"),
+            alternates.join("\n") + "\n",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Copies everything the forks of the repository at `path` use into them, so it can be
+/// deleted.
+pub fn dissociate(root: &FsPath, path: &FsPath) -> Result<(), Error> {
+    for fork in forks_of(root, &path.join("objects").canonicalize()?)? {
+        debug!("Dissociating {} from {}", fork.display(), path.display());
+
+        maintenance::dissociate(&Repository::open_bare(&fork)?)?;
+    }
+
+    Ok(())
+}
+
+/// Hard links the LFS objects of `from` into `to`, copying them where links aren't possible.
+fn link_lfs_objects(from: &FsPath, to: &FsPath) -> io::Result<()> {
+    let entries = match fs::read_dir(from) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    fs::create_dir_all(to)?;
+
+    for entry in entries {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            link_lfs_objects(&entry.path(), &target)?;
+        } else if fs::hard_link(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateFork {
+    user: Name,
+    /// Defaults to the name of the original.
+    name: Option<Name>,
+    /// Forks of private repositories are always private.
+    #[serde(default)]
+    private: bool,
+}
+
+pub async fn create_fork(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<CreateFork>,
+) -> Result<(StatusCode, Json<RepoLocation>), Error> {
+    // Private originals have already been checked to be readable by the caller.
+    caller.require_owner(&payload.user)?;
+
+    blocking(move || {
+        let original = state.open_repo(&user, &name)?;
+        let objects = original.path().join("objects").canonicalize()?;
+        let private = payload.private || is_private(&original)?;
+
+        let fork_user = payload.user.to_lowercase();
+        let fork_name = payload.name.unwrap_or_else(|| name.clone());
+
+        let path = state.new_repo_path(&fork_user, &fork_name);
+
+        // A repository that moved away gives up its old name.
+        if redirect_target(&path).is_some() {
+            fs::remove_file(&path)?;
+        }
+
+        debug!("Forking {user}/{name} to {}", path.display());
+
+        let message = format!("fork: from {user}/{name}");
+
+        init_bare_atomic(&path, |fork| {
+            let objects = objects.display().to_string();
+
+            fs::write(
+                fork.path().join("objects").join("info").join("alternates"),
+                format!("{objects}\n"),
+            )?;
+
+            // The fork was opened before it had alternates.
+            fork.odb()?.add_disk_alternate(&objects)?;
+
+            for reference in original.references()? {
+                let reference = reference?;
+
+                let (Some(ref_name), Some(target)) = (reference.name(), reference.target()) else {
+                    continue;
+                };
+
+                let copy = match ref_name.strip_prefix("refs/heads/") {
+                    Some(branch) => is_branch_public(&original, branch)?,
+                    None => ref_name.starts_with("refs/tags/"),
+                };
+
+                if copy {
+                    fork.reference(ref_name, target, false, &message)?;
+                }
+            }
+
+            if let Some(head) = original.find_reference("HEAD")?.symbolic_target() {
+                fork.set_head(head)?;
+            }
+
+            if private {
+                fork.config()?.set_bool(PRIVATE_KEY, true)?;
+            }
+
+            if state.options.lfs_root.is_none() {
+                link_lfs_objects(
+                    &original.path().join("lfs").join("objects"),
+                    &fork.path().join("lfs").join("objects"),
+                )?;
+            }
+
+            Ok(())
+        })?;
+
+        let fork_name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+        info!("Forked {user}/{name} to {fork_user}/{fork_name}");
+
+        announce_created(&state, &fork_user, &fork_name, &path, private);
+
+        Ok((
+            StatusCode::CREATED,
+            Json(RepoLocation {
+                user: fork_user,
+                name: fork_name,
+            }),
+        ))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{TestServer, commit};
+
+    #[tokio::test]
+    async fn forks_borrow_objects_in_namespaces_the_caller_owns() {
+        let server = TestServer::new("forks");
+        let original = server.init_repo("alice", "r.git");
+        let main = commit(&original, Some("refs/heads/main"), &[("a", b"1")], &[]);
+
+        let bob = server.user_token("bob").await;
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/repo/alice/r.git/fork",
+                Some(&bob),
+                Some(json!({ "user": "carol" })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!server.root().join("carol").exists());
+
+        let (status, location) = server
+            .json(
+                Method::POST,
+                "/repo/alice/r.git/fork",
+                Some(&bob),
+                Some(json!({ "user": "bob", "name": "r.fork" })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(location["name"], "r.fork.git");
+
+        let path = server.root().join("bob").join("r.fork.git");
+        let fork = Repository::open_bare(&path).unwrap();
+
+        assert_eq!(fork.refname_to_id("refs/heads/main").unwrap(), main);
+        assert_eq!(
+            forks_of(
+                server.root(),
+                &original.path().join("objects").canonicalize().unwrap()
+            )
+            .unwrap(),
+            [path.as_path()]
+        );
+
+        // Once dissociated, the fork keeps working without the original.
+        dissociate(server.root(), original.path()).unwrap();
+        fs::remove_dir_all(original.path()).unwrap();
+
+        let fork = Repository::open_bare(&path).unwrap();
+        assert!(alternates(&path).unwrap().is_empty());
+        assert_eq!(fork.find_commit(main).unwrap().tree().unwrap().len(), 1);
+    }
+}
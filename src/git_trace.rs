@@ -0,0 +1,67 @@
+//! Opt-in, per-request libgit2 tracing.
+//!
+//! libgit2 only supports a single global trace callback, so the callback is installed once at
+//! startup (in debug mode only) and forwards messages to the log only while polling a request
+//! that asked for tracing. Those messages end up inside that request's span. Note that libgit2
+//! mostly traces its network transports, so purely local operations log little beyond timing.
+
+use std::{sync::Arc, time::Instant};
+
+use axum::{extract::Request, extract::State, middleware::Next, response::Response};
+use git2::TraceLevel;
+use tracing::{debug, warn};
+
+use crate::AppState;
+
+/// Requests carrying this header get their libgit2 activity logged when in debug mode.
+pub const TRACE_HEADER: &str = "x-git-trace";
+
+tokio::task_local! {
+    static TRACING: ();
+}
+
+/// Installs the global libgit2 trace callback.
+pub fn install() {
+    if let Err(error) = git2::trace_set(TraceLevel::Trace, forward) {
+        warn!("Failed to enable libgit2 tracing: {error}");
+    }
+}
+
+fn forward(level: TraceLevel, message: &[u8]) {
+    if TRACING.try_with(|_| ()).is_ok() {
+        debug!(?level, "{}", String::from_utf8_lossy(message).trim_end());
+    }
+}
+
+/// Carries the current request's tracing opt-in over to `work`, for running it on another
+/// thread.
+pub fn propagate<F, R>(work: F) -> impl FnOnce() -> R + Send
+where
+    F: FnOnce() -> R + Send,
+{
+    let tracing = TRACING.try_with(|_| ()).is_ok();
+
+    move || {
+        if tracing {
+            TRACING.sync_scope((), work)
+        } else {
+            work()
+        }
+    }
+}
+
+/// Middleware enabling libgit2 tracing for the request when the server runs in debug mode and
+/// the request carries [`TRACE_HEADER`].
+pub async fn layer(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if state.debug && request.headers().contains_key(TRACE_HEADER) {
+        let start = Instant::now();
+
+        let response = TRACING.scope((), next.run(request)).await;
+
+        debug!(elapsed = ?start.elapsed(), "Traced request finished");
+
+        response
+    } else {
+        next.run(request).await
+    }
+}
@@ -0,0 +1,441 @@
+//! A small lexical highlighter producing scoped tokens for client-side rendering.
+//!
+//! This is not a parser: it only knows each language's keywords, comment markers and string
+//! delimiters, which is enough for a readable highlight without shipping any theme.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Plain,
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Token {
+    pub text: String,
+    pub scope: Scope,
+}
+
+#[derive(Debug)]
+pub struct Language {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    /// Programs named by a `#!` line, without any version suffix.
+    interpreters: &'static [&'static str],
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "class",
+    "namespace",
+    "template",
+    "typename",
+    "public",
+    "private",
+    "protected",
+    "virtual",
+    "new",
+    "delete",
+    "this",
+    "true",
+    "false",
+];
+
+static LANGUAGES: &[Language] = &[
+    Language {
+        name: "rust",
+        extensions: &["rs"],
+        interpreters: &[],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        // Single quotes are left out since they mostly introduce lifetimes.
+        quotes: &['"'],
+    },
+    Language {
+        name: "c",
+        extensions: &["c", "h"],
+        interpreters: &[],
+        keywords: C_KEYWORDS,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "cpp",
+        extensions: &["cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+        interpreters: &[],
+        keywords: C_KEYWORDS,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "go",
+        extensions: &["go"],
+        interpreters: &[],
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "fallthrough",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "type",
+            "var",
+            "nil",
+            "true",
+            "false",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+    },
+    Language {
+        name: "java",
+        extensions: &["java", "kt"],
+        interpreters: &[],
+        keywords: &[
+            "abstract",
+            "boolean",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "continue",
+            "default",
+            "do",
+            "else",
+            "enum",
+            "extends",
+            "final",
+            "finally",
+            "for",
+            "if",
+            "implements",
+            "import",
+            "instanceof",
+            "interface",
+            "new",
+            "null",
+            "package",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "static",
+            "super",
+            "switch",
+            "this",
+            "throw",
+            "throws",
+            "try",
+            "void",
+            "while",
+            "true",
+            "false",
+            "fun",
+            "val",
+            "var",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "javascript",
+        extensions: &["js", "mjs", "cjs", "jsx", "ts", "tsx"],
+        interpreters: &["node", "deno", "bun"],
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "export",
+            "extends",
+            "false",
+            "finally",
+            "for",
+            "from",
+            "function",
+            "if",
+            "import",
+            "in",
+            "instanceof",
+            "interface",
+            "let",
+            "new",
+            "null",
+            "return",
+            "super",
+            "switch",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "type",
+            "typeof",
+            "undefined",
+            "var",
+            "void",
+            "while",
+            "yield",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+    },
+    Language {
+        name: "python",
+        extensions: &["py"],
+        interpreters: &["python"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+            "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return",
+            "True", "try", "while", "with", "yield",
+        ],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "shell",
+        extensions: &["sh", "bash", "zsh"],
+        interpreters: &["sh", "bash", "zsh", "dash", "ksh"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "toml",
+        extensions: &["toml"],
+        interpreters: &[],
+        keywords: &["true", "false"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+    Language {
+        name: "json",
+        extensions: &["json"],
+        interpreters: &[],
+        keywords: &["true", "false", "null"],
+        line_comments: &[],
+        block_comment: None,
+        quotes: &['"'],
+    },
+];
+
+/// Picks a language from the extension of `path`, or failing that from the interpreter on
+/// the `#!` line of `content`.
+pub fn detect(path: &str, content: &[u8]) -> Option<&'static Language> {
+    let by_extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| {
+            LANGUAGES
+                .iter()
+                .find(|language| language.extensions.contains(&extension))
+        });
+
+    by_extension.or_else(|| {
+        let interpreter = interpreter(content)?;
+
+        LANGUAGES
+            .iter()
+            .find(|language| language.interpreters.contains(&interpreter))
+    })
+}
+
+/// The program a `#!` line runs, looking through `env` and dropping version suffixes like
+/// the `3.12` of `python3.12`.
+fn interpreter(content: &[u8]) -> Option<&str> {
+    let line = content.strip_prefix(b"#!")?;
+    let line = &line[..line
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .unwrap_or(line.len())];
+    let mut words = std::str::from_utf8(line).ok()?.split_whitespace();
+
+    let mut program = words.next()?.rsplit('/').next()?;
+
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+
+    Some(program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+/// Splits `content` into scoped tokens. Unknown languages and binary content come back as a
+/// single plain token.
+pub fn tokenize(language: Option<&Language>, content: &[u8]) -> Vec<Token> {
+    let (Some(language), Ok(source)) = (language, std::str::from_utf8(content)) else {
+        return vec![Token {
+            text: String::from_utf8_lossy(content).into_owned(),
+            scope: Scope::Plain,
+        }];
+    };
+
+    let mut tokens: Vec<Token> = Vec::new();
+
+    let mut push = |text: &str, scope: Scope| match tokens.last_mut() {
+        Some(last) if last.scope == scope && scope == Scope::Plain => last.text.push_str(text),
+        _ => tokens.push(Token {
+            text: text.to_string(),
+            scope,
+        }),
+    };
+
+    let mut index = 0;
+
+    while index < source.len() {
+        let rest = &source[index..];
+
+        let end = if language
+            .line_comments
+            .iter()
+            .any(|marker| rest.starts_with(marker))
+        {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            push(&rest[..end], Scope::Comment);
+            end
+        } else if let Some((open, close)) = language
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let end = rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |end| open.len() + end + close.len());
+            push(&rest[..end], Scope::Comment);
+            end
+        } else {
+            let first = rest.chars().next().unwrap();
+
+            if language.quotes.contains(&first) {
+                let end = string_end(rest, first);
+                push(&rest[..end], Scope::String);
+                end
+            } else if first.is_ascii_digit() {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                    .unwrap_or(rest.len());
+                push(&rest[..end], Scope::Number);
+                end
+            } else if first.is_alphabetic() || first == '_' {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let word = &rest[..end];
+
+                if language.keywords.contains(&word) {
+                    push(word, Scope::Keyword);
+                } else {
+                    push(word, Scope::Plain);
+                }
+
+                end
+            } else {
+                push(&rest[..first.len_utf8()], Scope::Plain);
+                first.len_utf8()
+            }
+        };
+
+        index += end;
+    }
+
+    tokens
+}
+
+/// Finds the end of a string literal opened by `quote` at the start of `rest`, honoring
+/// backslash escapes. Only backtick strings may span lines.
+fn string_end(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+
+    for (offset, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\n' if quote != '`' => return offset,
+            _ if c == quote => return offset + c.len_utf8(),
+            _ => {}
+        }
+    }
+
+    rest.len()
+}
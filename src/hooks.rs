@@ -0,0 +1,224 @@
+//! Hooks run while a push is received.
+//!
+//! They mirror git's own: `pre-receive` sees every ref update up front and can reject the
+//! whole push, `update` runs once per ref and can reject just that ref, and `post-receive`
+//! learns about the refs that actually moved. Hooks are either in-process callbacks
+//! implementing [`Hook`] or, through [`Executables`], the executable files git itself would
+//! run from the repository's hooks directory.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+};
+
+use git2::{Oid, Repository};
+use tracing::{debug, warn};
+
+use crate::AppState;
+
+/// A single ref update requested by a client.
+#[derive(Debug)]
+pub struct RefUpdate {
+    pub old: Oid,
+    pub new: Oid,
+    pub name: String,
+}
+
+/// The push hooks are run for.
+pub struct Push<'a> {
+    pub state: &'a AppState,
+    pub repo: &'a Repository,
+    pub user: &'a str,
+    pub name: &'a str,
+    /// What hooks have to say to the pushing user.
+    output: Vec<u8>,
+}
+
+impl<'a> Push<'a> {
+    pub fn new(state: &'a AppState, repo: &'a Repository, user: &'a str, name: &'a str) -> Self {
+        Self {
+            state,
+            repo,
+            user,
+            name,
+            output: Vec::new(),
+        }
+    }
+
+    /// Shows `message` to the pushing user, the way git relays a hook's output.
+    pub fn print(&mut self, message: &[u8]) {
+        self.output.extend_from_slice(message);
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+/// Callbacks run during a push. Errors are reported to the client as the reason a ref was
+/// rejected.
+pub trait Hook: fmt::Debug + Send + Sync {
+    /// Runs before any ref moves. An error rejects the whole push.
+    fn pre_receive(&self, _push: &mut Push, _updates: &[&RefUpdate]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs for each ref about to move. An error rejects only that ref.
+    fn update(&self, _push: &mut Push, _update: &RefUpdate) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs once the refs moved, with the updates that were applied.
+    fn post_receive(&self, _push: &mut Push, _updates: &[&RefUpdate]) {}
+}
+
+/// The hooks run for every push, in registration order.
+#[derive(Debug, Default)]
+pub struct Hooks(Vec<Box<dyn Hook>>);
+
+impl Hooks {
+    pub fn register(&mut self, hook: impl Hook + 'static) {
+        self.0.push(Box::new(hook));
+    }
+
+    /// Stops at the first hook that rejects the push.
+    pub fn pre_receive(&self, push: &mut Push, updates: &[&RefUpdate]) -> Result<(), String> {
+        self.0
+            .iter()
+            .try_for_each(|hook| hook.pre_receive(push, updates))
+    }
+
+    pub fn update(&self, push: &mut Push, update: &RefUpdate) -> Result<(), String> {
+        self.0.iter().try_for_each(|hook| hook.update(push, update))
+    }
+
+    pub fn post_receive(&self, push: &mut Push, updates: &[&RefUpdate]) {
+        for hook in &self.0 {
+            hook.post_receive(push, updates);
+        }
+    }
+}
+
+/// Runs the `pre-receive`, `update` and `post-receive` executables found in `core.hooksPath`,
+/// or the repository's `hooks` directory without one, with the same arguments and input git
+/// passes them. Their output is relayed to the client.
+#[derive(Debug)]
+pub struct Executables;
+
+impl Executables {
+    fn dir(repo: &Repository) -> PathBuf {
+        match repo
+            .config()
+            .and_then(|config| config.get_path("core.hooksPath"))
+        {
+            Ok(path) => repo.path().join(path),
+            Err(_) => repo.path().join("hooks"),
+        }
+    }
+
+    /// Runs the hook called `hook` if there is an executable one, succeeding if it exits
+    /// successfully.
+    fn run(push: &mut Push, hook: &str, args: &[String], input: &[u8]) -> io::Result<bool> {
+        let path = Self::dir(push.repo).join(hook);
+
+        match path.metadata() {
+            Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => {}
+            _ => return Ok(true),
+        }
+
+        debug!("Running {hook} hook of {}/{}", push.user, push.name);
+
+        let mut child = Command::new(&path)
+            .args(args)
+            .current_dir(push.repo.path())
+            .env("GIT_DIR", push.repo.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take();
+
+        // Feed the input from another thread so a hook that talks before reading can't
+        // deadlock against a full pipe.
+        let output = thread::scope(|scope| {
+            scope.spawn(move || {
+                if let Some(stdin) = &mut stdin {
+                    // Hooks are free to ignore their input.
+                    let _ = stdin.write_all(input);
+                }
+            });
+
+            child.wait_with_output()
+        })?;
+
+        push.print(&output.stdout);
+        push.print(&output.stderr);
+
+        Ok(output.status.success())
+    }
+
+    fn check(
+        push: &mut Push,
+        hook: &str,
+        args: &[String],
+        input: &[u8],
+        reason: &str,
+    ) -> Result<(), String> {
+        match Self::run(push, hook, args, input) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(reason.to_string()),
+            Err(error) => {
+                warn!(
+                    "Failed to run {hook} hook of {}/{}: {error}",
+                    push.user, push.name
+                );
+                Err(reason.to_string())
+            }
+        }
+    }
+
+    fn input(updates: &[&RefUpdate]) -> Vec<u8> {
+        updates
+            .iter()
+            .map(|update| format!("{} {} {}\n", update.old, update.new, update.name))
+            .collect::<String>()
+            .into_bytes()
+    }
+}
+
+impl Hook for Executables {
+    fn pre_receive(&self, push: &mut Push, updates: &[&RefUpdate]) -> Result<(), String> {
+        Self::check(
+            push,
+            "pre-receive",
+            &[],
+            &Self::input(updates),
+            "pre-receive hook declined",
+        )
+    }
+
+    fn update(&self, push: &mut Push, update: &RefUpdate) -> Result<(), String> {
+        let args = [
+            update.name.clone(),
+            update.old.to_string(),
+            update.new.to_string(),
+        ];
+
+        Self::check(push, "update", &args, &[], "hook declined")
+    }
+
+    fn post_receive(&self, push: &mut Push, updates: &[&RefUpdate]) {
+        // The refs already moved, so all that's left to do about failures is tell.
+        if let Err(error) = Self::run(push, "post-receive", &[], &Self::input(updates)) {
+            warn!(
+                "Failed to run post-receive hook of {}/{}: {error}",
+                push.user, push.name
+            );
+        }
+    }
+}
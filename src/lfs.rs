@@ -0,0 +1,507 @@
+//! Git LFS: the batch API and the `basic` transfer adapter.
+//!
+//! Clients find the server at `<clone url>/info/lfs`. They first ask `objects/batch` what to do
+//! about the objects they want to push or pull, then upload or download each one from
+//! `objects/{oid}`. Objects are named by the SHA-256 of their contents, which uploads are
+//! checked against. They are kept in the repository's `lfs/objects` directory, so they move and
+//! get deleted along with it, unless a shared directory is configured, in which case any
+//! repository the object was pushed to can serve it.
+
+use std::{collections::BTreeMap, fs, io, path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{
+    AppState, BodyWriter, Error, Json, Name, Path,
+    auth::{hex, require_write},
+    blocking, hidden_sibling, octet_stream,
+};
+
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+const TRANSFER: &str = "basic";
+const HASH_ALGO: &str = "sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Operation {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    operation: Operation,
+    /// Transfer adapters the client supports. Missing means just `basic`.
+    #[serde(default)]
+    transfers: Vec<String>,
+    objects: Vec<Pointer>,
+    hash_algo: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Pointer {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    transfer: &'static str,
+    objects: Vec<ObjectResponse>,
+    hash_algo: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ObjectResponse {
+    #[serde(flatten)]
+    pointer: Pointer,
+    authenticated: bool,
+    /// What the client has to do about the object. Nothing for uploads the server already has.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    actions: BTreeMap<&'static str, Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ObjectError>,
+}
+
+#[derive(Debug, Serialize)]
+struct Action {
+    href: String,
+    /// Headers to send along, carrying over the credentials of the batch request.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    header: BTreeMap<&'static str, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ObjectError {
+    code: u16,
+    message: &'static str,
+}
+
+fn is_oid(oid: &str) -> bool {
+    oid.len() == 64
+        && oid
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Where the object named `oid` is stored for `repo`, fanned out like git-lfs does locally.
+fn object_path(state: &AppState, repo: &Repository, oid: &str) -> PathBuf {
+    let dir = match &state.options.lfs_root {
+        Some(root) => root.clone(),
+        None => repo.path().join("lfs").join("objects"),
+    };
+
+    dir.join(&oid[..2]).join(&oid[2..4]).join(oid)
+}
+
+/// The URL of the repository's object endpoint as the client reached it.
+fn objects_url(headers: &HeaderMap, user: &str, name: &str) -> Result<String, Error> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .ok_or_else(|| Error::BadRequest("Missing host".to_string()))?;
+
+    // TLS is terminated by a proxy, if at all.
+    let scheme = match headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+    {
+        Some("https") => "https",
+        _ => "http",
+    };
+
+    Ok(format!(
+        "{scheme}://{host}/repo/{user}/{name}/info/lfs/objects"
+    ))
+}
+
+pub async fn batch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(request): Json<BatchRequest>,
+) -> Result<Response, Error> {
+    if request.operation == Operation::Upload {
+        require_write(&state, &headers, &user, &name)?;
+    }
+
+    if !request.transfers.is_empty() && !request.transfers.iter().any(|name| name == TRANSFER) {
+        return Err(Error::BadRequest(
+            "Only the basic transfer adapter is supported".to_string(),
+        ));
+    }
+
+    if request
+        .hash_algo
+        .as_deref()
+        .is_some_and(|algo| algo != HASH_ALGO)
+    {
+        return Err(Error::BadRequest(
+            "Only sha256 object ids are supported".to_string(),
+        ));
+    }
+
+    let url = objects_url(&headers, &user, &name)?;
+
+    let mut action_headers = BTreeMap::new();
+
+    if let Some(authorization) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    {
+        action_headers.insert("Authorization", authorization.to_string());
+    }
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!(
+            "Answering LFS batch of {} objects for {user}/{name}",
+            request.objects.len()
+        );
+
+        let objects = request
+            .objects
+            .into_iter()
+            .map(|pointer| {
+                let mut response = ObjectResponse {
+                    pointer,
+                    authenticated: true,
+                    actions: BTreeMap::new(),
+                    error: None,
+                };
+
+                if !is_oid(&response.pointer.oid) {
+                    response.error = Some(ObjectError {
+                        code: StatusCode::UNPROCESSABLE_ENTITY.as_u16(),
+                        message: "Invalid object id",
+                    });
+
+                    return response;
+                }
+
+                let stored = fs::metadata(object_path(&state, &repo, &response.pointer.oid))
+                    .ok()
+                    .filter(|metadata| metadata.is_file());
+
+                let action = Action {
+                    href: format!("{url}/{}", response.pointer.oid),
+                    header: action_headers.clone(),
+                };
+
+                match (request.operation, stored) {
+                    (Operation::Download, Some(metadata)) => {
+                        response.pointer.size = metadata.len();
+                        response.actions.insert("download", action);
+                    }
+                    (Operation::Download, None) => {
+                        response.error = Some(ObjectError {
+                            code: StatusCode::NOT_FOUND.as_u16(),
+                            message: "Object does not exist",
+                        });
+                    }
+                    (Operation::Upload, Some(_)) => {}
+                    (Operation::Upload, None) => {
+                        response.actions.insert("upload", action);
+                    }
+                }
+
+                response
+            })
+            .collect();
+
+        let body = serde_json::to_vec(&BatchResponse {
+            transfer: TRANSFER,
+            objects,
+            hash_algo: HASH_ALGO,
+        })
+        .map_err(io::Error::from)?;
+
+        Ok(([(header::CONTENT_TYPE, MEDIA_TYPE)], body).into_response())
+    })
+    .await
+}
+
+pub async fn download(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, oid)): Path<(Name, Name, String)>,
+) -> Result<Response, Error> {
+    if !is_oid(&oid) {
+        return Err(Error::NotFound);
+    }
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let mut file =
+            fs::File::open(object_path(&state, &repo, &oid)).map_err(|_| Error::NotFound)?;
+        let metadata = file.metadata()?;
+
+        if !metadata.is_file() {
+            return Err(Error::NotFound);
+        }
+
+        debug!("Serving LFS object {oid} of {user}/{name}");
+
+        let body = BodyWriter::spawn(move |writer| io::copy(&mut file, writer).map(|_| ()));
+
+        Ok(octet_stream(metadata.len(), body))
+    })
+    .await
+}
+
+pub async fn upload(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, oid)): Path<(Name, Name, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, Error> {
+    if !is_oid(&oid) {
+        return Err(Error::BadRequest("Invalid object id".to_string()));
+    }
+
+    blocking(move || {
+        require_write(&state, &headers, &user, &name)?;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        if hex(&sha256(&body)) != oid {
+            return Err(Error::BadRequest(
+                "Object contents don't match its id".to_string(),
+            ));
+        }
+
+        let path = object_path(&state, &repo, &oid);
+
+        if path.is_file() {
+            return Ok(StatusCode::OK);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Written aside first, so concurrent downloads never see a partial object.
+        let temp_path = hidden_sibling(&path, "upload");
+
+        let result = fs::write(&temp_path, &body).and_then(|()| fs::rename(&temp_path, &path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        result?;
+
+        info!(
+            "Stored LFS object {oid} of {} bytes for {user}/{name}",
+            body.len()
+        );
+
+        Ok(StatusCode::OK)
+    })
+    .await
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of `data`, as LFS names objects by it.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let blocks = data.chunks_exact(64);
+
+    // The message is padded with a one bit, then zeros up to its length in bits at the end of
+    // the last block.
+    let remainder = blocks.remainder();
+    let mut tail = remainder.to_vec();
+    tail.push(0x80);
+    tail.resize((remainder.len() + 8) / 64 * 64 + 64, 0);
+
+    let len = tail.len();
+    tail[len - 8..].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in blocks.chain(tail.chunks_exact(64)) {
+        compress(&mut state, block);
+    }
+
+    let mut digest = [0; 32];
+
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut schedule = [0u32; 64];
+
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+
+    for i in 16..64 {
+        let (early, late) = (schedule[i - 15], schedule[i - 2]);
+        let s0 = early.rotate_right(7) ^ early.rotate_right(18) ^ (early >> 3);
+        let s1 = late.rotate_right(17) ^ late.rotate_right(19) ^ (late >> 10);
+
+        schedule[i] = schedule[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(schedule[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+    for (constant, word) in ROUND_CONSTANTS.into_iter().zip(schedule) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(choice)
+            .wrapping_add(constant)
+            .wrapping_add(word);
+
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(majority);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Method, Request},
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::tests::TestServer;
+
+    /// Sends an LFS request the way git-lfs does, answering with the status and body.
+    async fn send(
+        server: &TestServer,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Vec<u8>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::HOST, "git.example.com")
+            .header(header::CONTENT_TYPE, MEDIA_TYPE);
+
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let response = server
+            .app
+            .clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn objects_round_trip_through_the_batch_api() {
+        const BATCH: &str = "/repo/test/r.git/info/lfs/objects/batch";
+
+        let server = TestServer::new("lfs");
+        server.init_repo("test", "r.git");
+        let token = server.deploy_token("test", "r.git", true).await;
+
+        let content = b"large file";
+        let oid = hex(&sha256(content));
+        let batch = |operation| {
+            json!({ "operation": operation, "objects": [{ "oid": oid, "size": content.len() }] })
+                .to_string()
+                .into_bytes()
+        };
+
+        let (status, _) = send(&server, Method::POST, BATCH, None, batch("upload")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) =
+            send(&server, Method::POST, BATCH, Some(&token), batch("upload")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let upload = &body["objects"][0]["actions"]["upload"];
+        assert_eq!(upload["header"]["Authorization"], format!("Bearer {token}"));
+
+        let href = upload["href"].as_str().unwrap();
+        let uri = href.strip_prefix("http://git.example.com").unwrap();
+        assert_eq!(uri, format!("/repo/test/r.git/info/lfs/objects/{oid}"));
+
+        let (status, _) = send(
+            &server,
+            Method::PUT,
+            uri,
+            Some(&token),
+            b"tampered".to_vec(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(&server, Method::PUT, uri, Some(&token), content.to_vec()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Once stored, there's nothing left to upload.
+        let (_, body) = send(&server, Method::POST, BATCH, Some(&token), batch("upload")).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["objects"][0].get("actions").is_none());
+
+        let (status, body) = send(&server, Method::POST, BATCH, None, batch("download")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["objects"][0]["size"], content.len());
+        assert_eq!(body["objects"][0]["actions"]["download"]["href"], href);
+
+        let (status, body) = send(&server, Method::GET, uri, None, Vec::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, content);
+    }
+}
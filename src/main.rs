@@ -1,24 +1,141 @@
-use std::{fs, net::Ipv4Addr, path::PathBuf};
+mod archive;
+mod auth;
+mod cache;
+mod config;
+mod contents;
+mod events;
+mod forks;
+mod git_trace;
+mod highlight;
+mod hooks;
+mod lfs;
+mod maintenance;
+mod markdown;
+mod merge;
+mod metadata;
+mod metrics;
+mod mirror;
+mod notify;
+mod protocol;
+mod rate_limit;
+mod secrets;
+mod signals;
+mod signatures;
+mod users;
+mod webhooks;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque, hash_map::Entry},
+    env, fs, io,
+    os::unix::fs::FileTypeExt,
+    panic,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use axum::{
-    Json, Router,
-    extract::Path,
-    http::StatusCode,
+    Router,
+    body::{Body, Bytes},
+    extract::{
+        DefaultBodyLimit, FromRequest, FromRequestParts, Request, State,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures_util::stream;
+use git2::{
+    Blob, BranchType, ConfigLevel, Delta, Diff, FileMode, ObjectType, Odb, Oid, Repository,
+    TreeWalkMode, TreeWalkResult,
 };
-use git2::{BlameOptions, BranchType, ObjectType, Repository};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
-use tower::ServiceBuilder;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::{Notify, mpsc},
+};
+use tower::{
+    ServiceBuilder,
+    layer::util::{Identity, Stack},
+};
 use tower_http::{
-    compression::CompressionLayer, decompression::RequestDecompressionLayer, trace::TraceLayer,
+    compression::{
+        CompressionLayer,
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+    },
+    cors::{AllowOrigin, CorsLayer},
+    decompression::RequestDecompressionLayer,
+    limit::RequestBodyLimitLayer,
+    trace::TraceLayer,
 };
-use tracing::debug;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{debug, info, warn};
 
-const PORT: u16 = 3344;
+use crate::{
+    auth::{Authenticated, Tokens, require_admin},
+    cache::Cache,
+    config::{Listen, Options, Settings},
+    events::Events,
+    hooks::{Hooks, RefUpdate},
+    metrics::Metrics,
+    rate_limit::{ClientAddr, Limiter},
+};
+use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Top-level route patterns advertised by the index handler.
+const ROUTES: &[&str] = &[
+    "/",
+    "/repo",
+    "/repos",
+    "/events",
+    "/metrics",
+    "/healthz",
+    "/readyz",
+    "/repo/{user}",
+    "/repo/{user}/{name}",
+    "/tokens",
+    "/keys",
+    "/users",
+];
+
+const MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Pushes carry whole packfiles, so git routes get a far larger budget than the API.
+const MAX_PUSH_BODY_BYTES: usize = 1024 * 1024 * 1024;
+const DEFAULT_PREVIEW_BYTES: usize = 4096;
+const MAX_AUTHOR_STATS_COMMITS: usize = 10_000;
+/// Most activity buckets returned at once, a little over 27 years of days.
+const MAX_ACTIVITY_BUCKETS: usize = 10_000;
+const DEFAULT_TREE_PAGE: usize = 1000;
+const MAX_TREE_PAGE: usize = 10_000;
+const DEFAULT_SEARCH_RESULTS: usize = 100;
+const MAX_SEARCH_RESULTS: usize = 1000;
+/// Files larger than this are skipped by code search, as they are rarely source.
+const MAX_SEARCH_FILE_BYTES: usize = 1024 * 1024;
+/// Matching lines are cut off after this many characters.
+const MAX_SNIPPET_CHARS: usize = 200;
+/// Blobs shown as text rather than raw bytes are cut off after this much.
+const MAX_TEXT_BLOB_BYTES: usize = 1024 * 1024;
+const DEFAULT_RECENT_BRANCHES: usize = 10;
+const DEFAULT_BRANCHES_PAGE: usize = 100;
+const MAX_BRANCHES_PAGE: usize = 1000;
+const MAX_ANCESTRY_DEPTH: usize = 10_000;
+const DEFAULT_COMMITS_PAGE: usize = 30;
+const MAX_COMMITS_PAGE: usize = 100;
+const PUBLIC_BRANCHES_KEY: &str = "gitserver.publicbranches";
+/// Repository config key hiding a repository from anonymous clients.
+const PRIVATE_KEY: &str = "gitserver.private";
+const DEBUG_VAR: &str = "GIT_SERVER_DEBUG";
+/// Logs every span as it closes, along with how long it was busy and idle.
+const SPAN_TIMINGS_VAR: &str = "GIT_SERVER_SPAN_TIMINGS";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -32,218 +149,6426 @@ async fn main() -> Result<()> {
                 .into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_span_events(
+            if env::var(SPAN_TIMINGS_VAR).is_ok_and(|value| value == "1" || value == "true") {
+                FmtSpan::CLOSE
+            } else {
+                FmtSpan::NONE
+            },
+        ))
         .try_init()?;
 
-    let app = Router::new()
-        .route("/repo", post(create_repo))
+    let options = Options::load(env::args().skip(1))?;
+    let tokens = Tokens::load(options.repo_root.join(auth::TOKENS_FILE))?;
+    let secrets = secrets::Secrets::load(options.repo_root.join(secrets::SECRETS_FILE))?;
+    let keys = signatures::Keys::load(options.repo_root.join(signatures::KEYS_FILE))?;
+    let users = users::Users::load(options.repo_root.join(users::USERS_FILE))?;
+
+    let events = Events::default();
+    let push_mirrors = mirror::PushMirrors::default();
+
+    let mut hooks = Hooks::default();
+    hooks.register(hooks::Executables);
+    hooks.register(webhooks::Deliveries);
+    hooks.register(events.clone());
+    hooks.register(push_mirrors.clone());
+
+    let state = Arc::new(AppState {
+        options,
+        tokens,
+        secrets,
+        keys,
+        users,
+        hooks,
+        events,
+        push_mirrors,
+        settings: RwLock::new(Settings::load()?),
+        debug: env::var(DEBUG_VAR).is_ok_and(|value| value == "1" || value == "true"),
+        ..Default::default()
+    });
+
+    if state.debug {
+        git_trace::install();
+    }
+
+    log_config(&state);
+
+    let shutdown = Arc::new(Notify::new());
+
+    let signal_state = state.clone();
+    let signal_shutdown = shutdown.clone();
+    let shutting_down = AtomicBool::new(false);
+
+    signals::spawn(
+        &[libc::SIGHUP, libc::SIGTERM, libc::SIGINT],
+        move |signal| {
+            if signal == libc::SIGHUP {
+                reload_settings(&signal_state);
+            } else if shutting_down.swap(true, Ordering::Relaxed) {
+                warn!("Received another termination signal, exiting without waiting");
+                process::exit(128 + signal);
+            } else {
+                info!("Shutting down once in-flight requests finish");
+                signal_shutdown.notify_one();
+            }
+        },
+    )?;
+
+    let socket = match &state.options.listen {
+        Listen::Tcp(address) => metrics::Socket::Tcp(TcpListener::bind(address).await?),
+        Listen::Unix(path) => {
+            remove_stale_socket(path)?;
+            metrics::Socket::Unix(UnixListener::bind(path)?)
+        }
+    };
+    let listener = metrics::Listener::new(socket, state.clone());
+    let listen = state.options.listen.clone();
+
+    debug!("Started server on {}", state.options.listen);
+
+    // Stops accepting connections on the first SIGTERM or SIGINT and returns once the open
+    // ones are done.
+    axum::serve(
+        listener,
+        app(state).into_make_service_with_connect_info::<ClientAddr>(),
+    )
+    .with_graceful_shutdown(async move { shutdown.notified().await })
+    .await?;
+
+    if let Listen::Unix(path) = listen {
+        let _ = fs::remove_file(path);
+    }
+
+    info!("Server stopped");
+
+    Ok(())
+}
+
+/// Removes the socket left behind by a previous run that didn't get to clean up, which would
+/// otherwise keep the listener from binding. Anything that isn't a socket is left alone.
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Re-reads the reloadable settings, keeping the current ones if that fails.
+fn reload_settings(state: &AppState) {
+    match Settings::load() {
+        Ok(settings) => {
+            *state.settings.write().unwrap() = settings;
+
+            info!("Reloaded configuration");
+            log_config(state);
+        }
+        Err(error) => warn!("Failed to reload configuration, keeping the current one: {error:#}"),
+    }
+}
+
+/// Logs the effective configuration, with credentials redacted.
+fn log_config(state: &AppState) {
+    let settings = state.settings.read().unwrap();
+
+    let create_hook_url = settings
+        .create_hook_url
+        .as_deref()
+        .map(redact_url)
+        .unwrap_or_else(|| "disabled".to_string());
+
+    info!(
+        listen = %state.options.listen,
+        repos = %state.options.repo_root.display(),
+        lfs_objects = state
+            .options
+            .lfs_root
+            .as_ref()
+            .map_or("per repository".to_string(), |root| root.display().to_string()),
+        config_file = env::var(config::CONFIG_FILE_VAR).ok(),
+        dumb_protocol = true,
+        max_request_body_bytes = MAX_REQUEST_BODY_BYTES,
+        max_push_body_bytes = MAX_PUSH_BODY_BYTES,
+        default_preview_bytes = DEFAULT_PREVIEW_BYTES,
+        cors = ?state.options.cors,
+        caches = "bounded, evicting the least recently used",
+        create_hook_url,
+        admin_token = if settings.admin_token.is_some() {
+            "set"
+        } else {
+            "unset"
+        },
+        access_tokens = state.tokens.len(),
+        signing_keys = state.keys.len(),
+        users = state.users.len(),
+        rate_limit = ?settings.rate_limit,
+        token_rate_limit = ?settings.token_rate_limit,
+        auto_gc_loose_objects = ?settings.auto_gc_loose_objects,
+        debug = state.debug,
+        "Effective configuration"
+    );
+}
+
+/// Strips the password and query string from `url`, where tokens usually hide.
+fn redact_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut url) => {
+            if url.password().is_some() {
+                let _ = url.set_password(Some("redacted"));
+            }
+
+            if url.query().is_some() {
+                url.set_query(Some("redacted"));
+            }
+
+            url.to_string()
+        }
+        Err(_) => "<invalid url>".to_string(),
+    }
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    let cors = state.options.cors.as_ref().map(cors_layer);
+
+    let git_routes = Router::new()
         .route("/repo/{user}/{name}", get(handle_git))
+        .route("/repo/{user}/{name}/info/refs", get(protocol::info_refs))
+        .route(
+            "/repo/{user}/{name}/git-upload-pack",
+            post(protocol::upload_pack),
+        )
+        .route(
+            "/repo/{user}/{name}/git-receive-pack",
+            post(protocol::receive_pack),
+        )
+        .route(
+            "/repo/{user}/{name}/info/lfs/objects/batch",
+            post(lfs::batch),
+        )
+        .route(
+            "/repo/{user}/{name}/info/lfs/objects/{oid}",
+            get(lfs::download).put(lfs::upload),
+        )
         .route("/repo/{user}/{name}/{*path}", get(handle_dumb_protocol))
+        .route_layer(middleware::from_fn(browser_notice))
+        .layer(DefaultBodyLimit::disable())
+        .layer(body_layers(MAX_PUSH_BODY_BYTES));
+
+    let api_routes = Router::new()
+        .route("/", get(index))
+        .route("/repo", post(create_repo))
+        .route("/repos", get(list_all_repos))
+        .route("/events", get(events::all_events))
+        .route("/metrics", get(metrics::serve_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/tokens", get(auth::list_tokens).post(auth::issue_token))
+        .route("/tokens/{id}", delete(auth::revoke_token))
+        .route(
+            "/keys",
+            get(signatures::list_keys).post(signatures::add_key),
+        )
+        .route("/keys/{id}", delete(signatures::delete_key))
+        .route("/users", get(users::list_users).post(users::create_user))
+        .route(
+            "/users/{user}",
+            get(users::get_user).delete(users::delete_user),
+        )
+        .route("/repo/{user}", get(list_user_repos))
+        .route("/repo/{user}/{name}", delete(delete_repo).patch(move_repo))
+        .route("/repo/{user}/{name}/visibility", put(set_visibility))
+        .route(
+            "/repo/{user}/{name}/push-mirrors",
+            get(mirror::list_push_mirrors).post(mirror::create_push_mirror),
+        )
+        .route(
+            "/repo/{user}/{name}/push-mirrors/{id}",
+            delete(mirror::delete_push_mirror),
+        )
+        .route(
+            "/repo/{user}/{name}/import",
+            get(mirror::import_status).post(mirror::start_import),
+        )
+        .route("/repo/{user}/{name}/events", get(events::repo_events))
+        .route(
+            "/repo/{user}/{name}/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::create_webhook),
+        )
+        .route(
+            "/repo/{user}/{name}/webhooks/{id}",
+            delete(webhooks::delete_webhook),
+        )
+        .route(
+            "/repo/{user}/{name}/deploy-tokens",
+            get(auth::list_deploy_tokens).post(auth::issue_deploy_token),
+        )
+        .route(
+            "/repo/{user}/{name}/deploy-tokens/{id}",
+            delete(auth::revoke_deploy_token),
+        )
+        .route("/repo/{user}/{name}/fork", post(forks::create_fork))
+        .route(
+            "/repo/{user}/{name}/merge",
+            get(merge::check_merge).post(merge::merge),
+        )
+        .route("/repo/{user}/{name}/cherry-pick", post(merge::cherry_pick))
+        .route("/repo/{user}/{name}/revert", post(merge::revert))
+        .route(
+            "/repo/{user}/{name}/metadata",
+            get(metadata::get_metadata)
+                .put(metadata::set_metadata)
+                .delete(metadata::delete_metadata),
+        )
+        .route(
+            "/repo/{user}/{name}/default-branch",
+            get(get_default_branch).put(set_default_branch),
+        )
+        .route("/repo/{user}/{name}/stats", get(get_stats))
         .route("/repo/{user}/{name}/files", get(fetch_repo))
-        .route("/repo/{user}/{name}/branches", get(get_branches))
+        .route("/repo/{user}/{name}/search", get(search_code))
+        .route(
+            "/repo/{user}/{name}/branches",
+            get(get_branches).post(create_branch),
+        )
+        .route(
+            "/repo/{user}/{name}/branches/{branch}",
+            delete(delete_branch),
+        )
+        .route(
+            "/repo/{user}/{name}/branches/recent",
+            get(get_recent_branches),
+        )
+        .route(
+            "/repo/{user}/{name}/branches/ahead-behind",
+            get(get_branches_ahead_behind),
+        )
+        .route(
+            "/repo/{user}/{name}/branches/{branch}/rename",
+            post(rename_branch),
+        )
         .route("/repo/{user}/{name}/blob/{branch}/{*path}", get(get_blob))
+        .route(
+            "/repo/{user}/{name}/contents/{branch}/{*path}",
+            put(contents::put_contents).delete(contents::delete_contents),
+        )
+        .route("/repo/{user}/{name}/readme", get(get_readme))
+        .route("/repo/{user}/{name}/blame/{branch}/{*path}", get(get_blame))
+        .route(
+            "/repo/{user}/{name}/preview/{branch}/{*path}",
+            get(get_preview),
+        )
+        .route("/repo/{user}/{name}/tree-size/{ref}", get(get_tree_size))
+        .route(
+            "/repo/{user}/{name}/path/{from}/{to}",
+            get(get_ancestry_path),
+        )
+        .route("/repo/{user}/{name}/diverged/{a}/{b}", get(get_divergence))
+        .route("/repo/{user}/{name}/extensions/{ref}", get(get_extensions))
+        .route(
+            "/repo/{user}/{name}/commit/{oid}/tree",
+            get(get_commit_tree),
+        )
+        .route(
+            "/repo/{user}/{name}/commit/{oid}/tree/{*path}",
+            get(get_commit_tree),
+        )
+        .route("/repo/{user}/{name}/age", get(get_age))
+        .route(
+            "/repo/{user}/{name}/dir-diff/{base}/{head}",
+            get(get_dir_diff),
+        )
+        .route(
+            "/repo/{user}/{name}/dir-diff/{base}/{head}/{*path}",
+            get(get_dir_diff),
+        )
+        .route("/repo/{user}/{name}/authors/stats", get(get_author_stats))
+        .route("/repo/{user}/{name}/contributors", get(get_contributors))
+        .route("/repo/{user}/{name}/activity", get(get_activity))
+        .route(
+            "/repo/{user}/{name}/can-fast-forward/{branch}/{oid}",
+            get(get_can_fast_forward),
+        )
+        .route("/repo/{user}/{name}/gitconfig", get(get_git_config))
+        .route(
+            "/repo/{user}/{name}/commit/{oid}/refs",
+            get(get_commit_refs),
+        )
+        .route(
+            "/repo/{user}/{name}/release-diff/{tag}",
+            get(get_release_diff),
+        )
+        .route("/repo/{user}/{name}/compare/{*spec}", get(get_compare))
+        .route("/repo/{user}/{name}/debug/refs", get(protocol::debug_refs))
+        .route(
+            "/repo/{user}/{name}/commits",
+            get(get_commits).post(contents::create_commit),
+        )
+        .route("/repo/{user}/{name}/commits/search", get(search_commits))
+        .route("/repo/{user}/{name}/commit/{oid}", get(get_commit))
+        .route("/repo/{user}/{name}/tags", get(get_tags).post(create_tag))
+        .route("/repo/{user}/{name}/archive/{*file}", get(get_archive))
+        .route("/repo/{user}/{name}/bundle", get(get_bundle))
+        .route(
+            "/repo/{user}/{name}/maintenance",
+            post(maintenance::run_maintenance),
+        )
+        .layer(body_layers(MAX_REQUEST_BODY_BYTES));
+
+    Router::new()
+        .merge(git_routes)
+        .merge(api_routes)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_visible,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            follow_redirects,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            git_trace::layer,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ))
+        .with_state(state)
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
-                .layer(RequestDecompressionLayer::new())
-                .layer(CompressionLayer::new()),
-        );
+                // Answers preflight requests before they reach any route.
+                .option_layer(cors)
+                // Archives are compressed already.
+                .layer(
+                    CompressionLayer::new().compress_when(
+                        DefaultPredicate::new()
+                            .and(NotForContentType::const_new("application/gzip"))
+                            .and(NotForContentType::const_new("application/zip")),
+                    ),
+                ),
+        )
+}
 
-    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, PORT)).await?;
+fn cors_layer(cors: &config::Cors) -> CorsLayer {
+    let origins = match &cors.origins {
+        Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+        None => AllowOrigin::any(),
+    };
 
-    debug!("Started server on port {PORT}");
-    axum::serve(listener, app).await?;
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(cors.methods.clone())
+        .allow_headers(cors.headers.clone())
+        // Lets frontends revalidate listings and back off when rate limited.
+        .expose_headers([header::ETAG, header::RETRY_AFTER])
+        .max_age(Duration::from_secs(600))
+}
 
-    Ok(())
+/// Limits the body as sent on the wire to `limit` bytes, before it gets inflated.
+fn body_layers(
+    limit: usize,
+) -> ServiceBuilder<Stack<RequestDecompressionLayer, Stack<RequestBodyLimitLayer, Identity>>> {
+    ServiceBuilder::new()
+        .layer(RequestBodyLimitLayer::new(limit))
+        .layer(RequestDecompressionLayer::new())
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct CreateRepo {
-    user: String,
-    name: String,
+/// State shared between all handlers.
+#[derive(Debug, Default)]
+struct AppState {
+    options: Options,
+    /// Settings that can be swapped out at runtime by sending the process `SIGHUP`.
+    settings: RwLock<Settings>,
+    tokens: Tokens,
+    /// Webhook secrets and push mirror passwords.
+    secrets: secrets::Secrets,
+    /// Keys commit signatures are verified against.
+    keys: signatures::Keys,
+    users: users::Users,
+    /// Run for every push.
+    hooks: Hooks,
+    events: Events,
+    metrics: Metrics,
+    limiter: Limiter,
+    imports: mirror::Imports,
+    push_mirrors: mirror::PushMirrors,
+    /// Repositories undergoing maintenance.
+    maintenance: maintenance::Locks,
+    /// Enables debugging aids such as per-request libgit2 tracing. Never enable in production.
+    debug: bool,
+    /// Total blob size of a tree, keyed by tree oid. Trees are immutable so entries never go
+    /// stale. Every subtree gets an entry, so there are plenty of them, but they are small.
+    tree_sizes: Cache<Oid, u64, 65536>,
+    /// Per-extension file counts of a tree, keyed by tree oid.
+    extensions: Cache<Oid, BTreeMap<String, usize>>,
+    /// Oldest committer timestamp reachable from HEAD, along with the commit HEAD was at,
+    /// keyed by repository path.
+    oldest_commits: Cache<PathBuf, (Oid, i64)>,
+    /// Per-author diff stats, keyed by tip commit and the requested time range.
+    author_stats: Cache<AuthorStatsKey, Vec<AuthorStats>, 256>,
+    /// Contributors of a commit's history, keyed by that commit and whether lines were counted.
+    contributors: Cache<(Oid, bool), Vec<Contributor>>,
+    /// Commits per bucket reachable from a commit, keyed by that commit and the bucket size.
+    activity: Cache<(Oid, Interval), Activity>,
+    /// Last commit to modify each file, keyed by the commit whose tree was listed. These hold
+    /// an entry per file, so fewer of them are kept.
+    last_modified: Cache<Oid, Arc<LastModified>, 64>,
 }
 
-async fn create_repo(Json(payload): Json<CreateRepo>) -> Result<(), Error> {
-    let CreateRepo { user, name } = payload;
+type AuthorStatsKey = (Oid, Option<i64>, Option<i64>);
+type Activity = Arc<BTreeMap<i64, usize>>;
 
-    let mut path = PathBuf::from("repos").join(&user).join(&name);
-    path.set_extension("git");
+impl AppState {
+    /// Location of a repository on disk. Users and repository names are case-insensitive, so
+    /// they are lowercased to resolve identically on case-sensitive and case-insensitive
+    /// filesystems.
+    fn repo_path(&self, user: &str, name: &str) -> PathBuf {
+        self.options
+            .repo_root
+            .join(user.to_lowercase())
+            .join(name.to_lowercase())
+    }
 
-    debug!("Creating repo {name} for {user}");
+    /// Where a repository named `name` is created. Its directory always ends in `.git`, and any
+    /// other dots are part of the name.
+    fn new_repo_path(&self, user: &str, name: &str) -> PathBuf {
+        let mut path = self.repo_path(user, name);
 
-    Repository::init_bare(path)?;
+        if path.extension().is_none_or(|extension| extension != "git") {
+            path.as_mut_os_string().push(".git");
+        }
 
-    Ok(())
-}
+        path
+    }
 
-#[derive(Debug)]
-enum Error {
-    Git(git2::Error),
-    NotFound,
+    /// Opens a repository, refusing anything that resolves outside the repository root, such
+    /// as a symlink pointing elsewhere.
+    fn open_repo(&self, user: &str, name: &str) -> Result<Repository, Error> {
+        let path = contained(&self.options.repo_root, &self.repo_path(user, name))?;
+
+        Repository::open_bare(path).map_err(|_| Error::NotFound)
+    }
 }
 
-impl From<git2::Error> for Error {
-    fn from(error: git2::Error) -> Self {
-        Self::Git(error)
+/// Longest user or repository name accepted.
+const MAX_NAME_LEN: usize = 100;
+
+/// A user or repository name taken from a request. Names are restricted to ASCII letters,
+/// digits, `-`, `_` and `.` and may not start with a dot, so they always name a single visible
+/// directory below the repository root.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+struct Name(String);
+
+impl Name {
+    fn is_valid(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_NAME_LEN
+            && !name.starts_with('.')
+            && name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
     }
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        match self {
-            Error::Git(error) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Something went wrong when: {}", error),
-            )
-                .into_response(),
-            Error::NotFound => StatusCode::NOT_FOUND.into_response(),
+impl TryFrom<String> for Name {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if Self::is_valid(&name) {
+            Ok(Self(name))
+        } else {
+            Err(format!("Invalid name: {name}"))
         }
     }
 }
 
-async fn handle_git(Path((user, name)): Path<(String, String)>) -> Result<(), Error> {
-    let path = PathBuf::from("repos").join(&user).join(&name);
+impl std::ops::Deref for Name {
+    type Target = str;
 
-    debug!("Handling {}", path.display());
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
 
-    Ok(())
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-async fn handle_dumb_protocol(
-    Path((user, name, path)): Path<(String, String, String)>,
-) -> Result<Vec<u8>, Error> {
-    let path = PathBuf::from("repos").join(&user).join(&name).join(path);
+/// Resolves `path`, following symlinks, and checks that it stays within `dir`. Paths that don't
+/// exist or escape are reported as missing.
+fn contained(dir: &std::path::Path, path: &std::path::Path) -> Result<PathBuf, Error> {
+    let dir = dir.canonicalize().map_err(|_| Error::NotFound)?;
+    let path = path.canonicalize().map_err(|_| Error::NotFound)?;
 
-    debug!("Handling dumb protocol: {}", path.display());
+    if path.starts_with(&dir) {
+        Ok(path)
+    } else {
+        Err(Error::NotFound)
+    }
+}
 
-    let res = fs::read(path).map_err(|_| Error::NotFound)?;
+#[derive(Debug, Serialize)]
+struct ServerInfo {
+    name: &'static str,
+    version: &'static str,
+    routes: &'static [&'static str],
+}
 
-    Ok(res)
+async fn index() -> Json<ServerInfo> {
+    Json(ServerInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        routes: ROUTES,
+    })
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-#[serde(tag = "type", rename_all = "lowercase")]
-enum Node {
-    File {
-        name: String,
-        commit: String,
-        message: String,
-        modified: i64,
-    },
-    Directory {
-        name: String,
-        childs: Vec<Node>,
-    },
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+/// Liveness probe. Answering at all means the process is up.
+async fn healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
-async fn fetch_repo(Path((user, name)): Path<(String, String)>) -> Result<Json<Node>, Error> {
-    let path = PathBuf::from("repos").join(&user).join(&name);
+impl<E: std::fmt::Display> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            error: result.err().map(|error| error.to_string()),
+        }
+    }
+}
 
-    let repo = Repository::open_bare(path)?;
+#[derive(Debug, Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
 
-    let tree = repo.head()?.peel_to_tree()?;
+/// Readiness probe: the repository root has to be writable and libgit2 able to store and read
+/// back objects. Responds with `503 Service Unavailable` if anything is off.
+async fn readyz(State(state): State<Arc<AppState>>) -> Result<Response, Error> {
+    blocking(move || {
+        let checks = BTreeMap::from([
+            (
+                "repo_root",
+                Check::from(check_repo_root(&state.options.repo_root)),
+            ),
+            ("libgit2", Check::from(check_libgit2())),
+        ]);
 
-    let mut root = Vec::new();
+        let ready = checks.values().all(|check| check.ok);
 
-    process_tree(&repo, &tree, &mut root, "")?;
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
 
-    Ok(Json(Node::Directory {
-        name: "root".to_string(),
-        childs: root,
-    }))
+        Ok((
+            status,
+            Json(Readiness {
+                status: if ready { "ready" } else { "unavailable" },
+                checks,
+            }),
+        )
+            .into_response())
+    })
+    .await
 }
 
-fn process_tree<P: AsRef<std::path::Path>>(
-    repo: &Repository,
-    tree: &git2::Tree,
-    parent: &mut Vec<Node>,
-    prefix: P,
+fn check_repo_root(root: &std::path::Path) -> io::Result<()> {
+    if !root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", root.display()),
+        ));
+    }
+
+    let probe = hidden_sibling(&root.join("readyz"), "probe");
+
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
+
+fn check_libgit2() -> Result<(), git2::Error> {
+    let odb = Odb::new()?;
+    odb.add_new_mempack_backend(1)?;
+
+    let oid = odb.write(ObjectType::Blob, b"readyz")?;
+
+    if odb.read(oid)?.data() != b"readyz" {
+        return Err(git2::Error::from_str("Object read back differs"));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateRepo {
+    user: Name,
+    name: Name,
+    #[serde(default)]
+    remotes: Vec<RemoteSpec>,
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RemoteSpec {
+    name: String,
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RepoCreated<'a> {
+    user: &'a str,
+    name: &'a str,
+    created_at: u64,
+}
+
+async fn create_repo(
+    State(state): State<Arc<AppState>>,
+    caller: Authenticated,
+    Json(payload): Json<CreateRepo>,
 ) -> Result<(), Error> {
-    for entry in tree {
-        let name = entry.name().unwrap().to_string();
+    blocking(move || {
+        let CreateRepo {
+            user,
+            name,
+            remotes,
+            private,
+        } = payload;
 
-        let full_path = prefix.as_ref().join(&name);
+        caller.require_owner(&user)?;
 
-        let node = if let Some(subtree) = entry.to_object(&repo)?.as_tree() {
-            let mut childs = Vec::new();
+        for remote in &remotes {
+            if !git2::Remote::is_valid_name(&remote.name) {
+                return Err(Error::BadRequest(format!(
+                    "Invalid remote name: {}",
+                    remote.name
+                )));
+            }
 
-            process_tree(repo, subtree, &mut childs, &full_path)?;
+            if !is_valid_remote_url(&remote.url) {
+                return Err(Error::BadRequest(format!(
+                    "Invalid remote url: {}",
+                    remote.url
+                )));
+            }
+        }
 
-            Node::Directory { name, childs }
-        } else {
-            let mut blame_options = BlameOptions::new();
+        let path = state.new_repo_path(&user, &name);
 
-            let blame = repo.blame_file(&full_path, Some(&mut blame_options))?;
-            let hunk = blame.get_index(0).unwrap();
-            let commit_id = hunk.final_commit_id();
-            let commit = repo.find_commit(commit_id)?;
-            let message = commit.message().unwrap().to_string();
-            let modified = commit.committer().when().seconds();
+        // A repository that moved away gives up its old name.
+        if redirect_target(&path).is_some() {
+            fs::remove_file(&path)?;
+        }
 
-            Node::File {
-                name,
-                commit: commit_id.to_string(),
-                message,
-                modified,
+        debug!("Creating repo {name} for {user}");
+
+        init_bare_atomic(&path, |repo| {
+            for remote in &remotes {
+                repo.remote(&remote.name, &remote.url)?;
             }
-        };
 
-        parent.push(node);
-    }
+            if private {
+                repo.config()?.set_bool(PRIVATE_KEY, true)?;
+            }
 
-    Ok(())
+            Ok(())
+        })?;
+
+        announce_created(&state, &user, &name, &path, private);
+
+        Ok(())
+    })
+    .await
 }
 
-async fn get_branches(
-    Path((user, name)): Path<(String, String)>,
-) -> Result<Json<Vec<String>>, Error> {
-    let path = PathBuf::from("repos").join(&user).join(&name);
+/// Tells event subscribers and the create hook, if there is one, about the new repository at
+/// `path`.
+fn announce_created(
+    state: &AppState,
+    user: &str,
+    name: &str,
+    path: &std::path::Path,
+    private: bool,
+) {
+    state.events.publish(
+        events::Event::RepoCreated {
+            user: user.to_lowercase(),
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+        },
+        private,
+    );
 
-    let repo = Repository::open_bare(path)?;
+    let create_hook_url = state.settings.read().unwrap().create_hook_url.clone();
 
-    let mut branches = Vec::new();
+    if let Some(url) = create_hook_url {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
 
-    for branch in repo.branches(None)? {
-        let (branch, _) = branch?;
+        notify::spawn(
+            url,
+            &RepoCreated {
+                user,
+                name,
+                created_at,
+            },
+        );
+    }
+}
 
-        branches.push(branch.name()?.unwrap().to_string());
+/// Accepts `scheme://...` urls for the transports git understands and scp-like `host:path`.
+fn is_valid_remote_url(url: &str) -> bool {
+    if url.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
     }
 
-    Ok(Json(branches))
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            matches!(scheme, "http" | "https" | "ssh" | "git" | "file") && !rest.is_empty()
+        }
+        None => url
+            .split_once(':')
+            .is_some_and(|(host, path)| !host.is_empty() && !path.is_empty()),
+    }
 }
 
-async fn get_blob(
-    Path((user, name, branch, path)): Path<(String, String, String, String)>,
-) -> Result<Vec<u8>, Error> {
-    let repo_path = PathBuf::from("repos").join(&user).join(&name);
+#[derive(Debug, Serialize)]
+struct RepoListing {
+    user: String,
+    name: String,
+    default_branch: Option<String>,
+    private: bool,
+    /// Newest commit time among the branch tips.
+    last_activity: Option<i64>,
+    /// Size on disk in bytes.
+    size: u64,
+    #[serde(flatten)]
+    metadata: metadata::Metadata,
+}
+
+async fn list_all_repos(
+    State(state): State<Arc<AppState>>,
+    caller: Option<Authenticated>,
+) -> Result<Json<Vec<RepoListing>>, Error> {
+    blocking(move || {
+        let mut repos = Vec::new();
+
+        let users = match fs::read_dir(&state.options.repo_root) {
+            Ok(users) => users,
+            // Nothing has been created yet.
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Json(repos)),
+            Err(error) => return Err(error.into()),
+        };
+
+        for user in users {
+            let user = user?;
+
+            if let Some(user) = listing_name(&user)? {
+                let include_private = caller.as_ref().is_some_and(|caller| caller.0.owns(&user));
+
+                repos.extend(user_repos(&state, &user, include_private)?);
+            }
+        }
+
+        repos.sort_by(|a, b| (&a.user, &a.name).cmp(&(&b.user, &b.name)));
+
+        Ok(Json(repos))
+    })
+    .await
+}
 
-    let repo = Repository::open_bare(repo_path)?;
+async fn list_user_repos(
+    State(state): State<Arc<AppState>>,
+    Path(user): Path<Name>,
+    caller: Option<Authenticated>,
+) -> Result<Json<Vec<RepoListing>>, Error> {
+    blocking(move || {
+        let user = user.to_lowercase();
 
-    debug!("Opening {path} at branch {branch}");
+        let include_private = caller.is_some_and(|caller| caller.0.owns(&user));
 
-    let blob = read_blob_from_branch(&repo, &path, &branch).map_err(|_| Error::NotFound)?;
+        let mut repos = user_repos(&state, &user, include_private)?;
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
 
-    Ok(blob)
+        Ok(Json(repos))
+    })
+    .await
 }
 
-fn read_blob_from_branch(
-    repo: &Repository,
-    file_path: &str,
-    branch_name: &str,
-) -> Result<Vec<u8>, git2::Error> {
-    let branch = repo.find_branch(branch_name, BranchType::Local)?;
+/// Name of a directory entry to list, skipping files and hidden entries such as staging
+/// directories and the trash.
+fn listing_name(entry: &fs::DirEntry) -> Result<Option<String>, io::Error> {
+    let name = entry.file_name().to_string_lossy().into_owned();
 
-    let commit = branch.get().peel_to_commit()?;
+    Ok((!name.starts_with('.') && entry.file_type()?.is_dir()).then_some(name))
+}
 
-    let tree = commit.tree()?;
+/// The repositories of `user`, leaving out private ones unless `include_private` is set.
+fn user_repos(
+    state: &AppState,
+    user: &str,
+    include_private: bool,
+) -> Result<Vec<RepoListing>, Error> {
+    let mut repos = Vec::new();
 
-    let entry = tree.get_path(std::path::Path::new(file_path))?;
+    let entries = match fs::read_dir(state.options.repo_root.join(user)) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(repos),
+        Err(error) => return Err(error.into()),
+    };
 
-    if entry.kind() != Some(ObjectType::Blob) {
-        return Err(git2::Error::from_str("Path does not point to a blob"));
+    for entry in entries {
+        let entry = entry?;
+
+        let Some(name) = listing_name(&entry)? else {
+            continue;
+        };
+
+        let Ok(repo) = Repository::open_bare(entry.path()) else {
+            continue;
+        };
+
+        let private = is_private(&repo)?;
+
+        if private && !include_private {
+            continue;
+        }
+
+        let default_branch = default_branch(&repo).ok().map(|head| head.branch);
+
+        let mut last_activity = None;
+
+        for branch in repo.branches(Some(BranchType::Local))? {
+            let (branch, _) = branch?;
+            let time = branch.get().peel_to_commit()?.committer().when().seconds();
+
+            last_activity = last_activity.max(Some(time));
+        }
+
+        repos.push(RepoListing {
+            user: user.to_string(),
+            name,
+            default_branch,
+            private,
+            last_activity,
+            size: dir_size(&entry.path())?,
+            metadata: metadata::read(&repo)?,
+        });
     }
 
-    let blob = repo.find_blob(entry.id())?;
+    Ok(repos)
+}
+
+/// Total size of the files below `path`.
+fn dir_size(path: &std::path::Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
 
-    Ok(blob.content().to_vec())
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Visibility {
+    private: bool,
+}
+
+async fn set_visibility(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<Visibility>,
+) -> Result<Json<Visibility>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+        config.set_bool(PRIVATE_KEY, payload.private)?;
+
+        info!(
+            "Made {user}/{name} {}",
+            if payload.private { "private" } else { "public" }
+        );
+
+        Ok(Json(payload))
+    })
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct DefaultBranch {
+    branch: String,
+    /// False until the first commit lands on a freshly created or switched branch.
+    exists: bool,
+}
+
+fn default_branch(repo: &Repository) -> Result<DefaultBranch, Error> {
+    let head = repo.find_reference("HEAD")?;
+
+    let branch = head
+        .symbolic_target()
+        .and_then(|target| target.strip_prefix("refs/heads/"))
+        .ok_or(Error::NotFound)?
+        .to_string();
+
+    let exists = repo.find_branch(&branch, BranchType::Local).is_ok();
+
+    Ok(DefaultBranch { branch, exists })
+}
+
+async fn get_default_branch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Json<DefaultBranch>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        Ok(Json(default_branch(&repo)?))
+    })
+    .await
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ObjectCounts {
+    commits: usize,
+    trees: usize,
+    blobs: usize,
+    tags: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RepoStats {
+    /// Bytes taken up on disk.
+    size: u64,
+    /// Every object stored, reachable or not.
+    objects: ObjectCounts,
+    branches: usize,
+    tags: usize,
+    default_branch: Option<String>,
+}
+
+async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Json<RepoStats>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+        let odb = repo.odb()?;
+
+        // Objects can be both loose and packed, or in several packs.
+        let mut ids = HashSet::new();
+        odb.foreach(|&oid| {
+            ids.insert(oid);
+            true
+        })?;
+
+        let mut objects = ObjectCounts::default();
+
+        for oid in ids {
+            match odb.read_header(oid)?.1 {
+                ObjectType::Commit => objects.commits += 1,
+                ObjectType::Tree => objects.trees += 1,
+                ObjectType::Blob => objects.blobs += 1,
+                ObjectType::Tag => objects.tags += 1,
+                _ => {}
+            }
+        }
+
+        let mut branches = 0;
+
+        for branch in repo.branches(Some(BranchType::Local))? {
+            if let Some(name) = branch?.0.name()?
+                && is_branch_public(&repo, name)?
+            {
+                branches += 1;
+            }
+        }
+
+        Ok(Json(RepoStats {
+            size: dir_size(repo.path())?,
+            objects,
+            branches,
+            tags: repo.tag_names(None)?.len(),
+            default_branch: default_branch(&repo).ok().map(|default| default.branch),
+        }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct SetDefaultBranch {
+    branch: String,
+}
+
+/// Points HEAD at another branch. The branch doesn't need to exist yet, so empty repositories
+/// can be switched before their first push.
+async fn set_default_branch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<SetDefaultBranch>,
+) -> Result<Json<DefaultBranch>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if !git2::Branch::name_is_valid(&payload.branch)? {
+            return Err(Error::BadRequest(format!(
+                "Invalid branch name: {}",
+                payload.branch
+            )));
+        }
+
+        info!(
+            "Setting default branch of {user}/{name} to {}",
+            payload.branch
+        );
+
+        repo.set_head(&format!("refs/heads/{}", payload.branch))?;
+
+        Ok(Json(default_branch(&repo)?))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteRepoQuery {
+    /// Moves the repository into the trash directory instead of removing it.
+    #[serde(default)]
+    trash: bool,
+}
+
+/// Directory under the repository root where soft-deleted repositories are kept.
+const TRASH_DIR: &str = ".trash";
+
+async fn delete_repo(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<DeleteRepoQuery>,
+    headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let root = state.options.repo_root.canonicalize()?;
+        let path = state
+            .repo_path(&user, &name)
+            .canonicalize()
+            .map_err(|_| Error::NotFound)?;
+
+        // Anything that isn't a repository directly inside a user directory is off limits,
+        // however the url managed to point at it.
+        if path.parent().and_then(|user_dir| user_dir.parent()) != Some(root.as_path())
+            || path.starts_with(root.join(TRASH_DIR))
+        {
+            return Err(Error::NotFound);
+        }
+
+        let Ok(repo) = Repository::open_bare(&path) else {
+            return Err(Error::NotFound);
+        };
+
+        let private = is_private(&repo).unwrap_or(true);
+
+        // Trashed repositories keep theirs, to work as before once restored.
+        if !query.trash {
+            state.secrets.forget(&repo)?;
+        }
+
+        drop(repo);
+
+        forks::dissociate(&root, &path)?;
+
+        if query.trash {
+            let trashed = root.join(TRASH_DIR).join(user.to_lowercase());
+            fs::create_dir_all(&trashed)?;
+
+            let trashed = hidden_sibling(&trashed.join(path.file_name().unwrap()), "deleted");
+
+            debug!("Moving {} to {}", path.display(), trashed.display());
+
+            fs::rename(&path, trashed)?;
+        } else {
+            // Move the repository out of the way first so it never appears half deleted.
+            let doomed = hidden_sibling(&path, "deleting");
+
+            debug!("Deleting {}", path.display());
+
+            fs::rename(&path, &doomed)?;
+            fs::remove_dir_all(doomed)?;
+        }
+
+        // A repository restored from the trash starts without deploy tokens.
+        state.tokens.rebind(&user, &name, None)?;
+
+        state.events.publish(
+            events::Event::RepoDeleted {
+                user: user.to_lowercase(),
+                name: name.to_lowercase(),
+            },
+            private,
+        );
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct MoveRepo {
+    /// New owner, if transferring.
+    user: Option<Name>,
+    /// New name, if renaming.
+    name: Option<Name>,
+    /// Leaves a record at the old location that redirects clients to the new one.
+    #[serde(default = "default_true")]
+    redirect: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+struct RepoLocation {
+    user: String,
+    name: String,
+}
+
+/// Renames a repository and/or transfers it to another user.
+async fn move_repo(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(payload): Json<MoveRepo>,
+) -> Result<Json<RepoLocation>, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let new_user = payload.user.unwrap_or_else(|| user.clone()).to_lowercase();
+        let new_name = payload.name.unwrap_or_else(|| name.clone());
+
+        let path = state.repo_path(&user, &name);
+        let new_path = state.new_repo_path(&new_user, &new_name);
+        let new_name = new_path.file_name().unwrap().to_string_lossy().into_owned();
+
+        let private = is_private(&state.open_repo(&user, &name)?)?;
+
+        if new_path != path {
+            let objects = path.join("objects").canonicalize()?;
+
+            if new_path.exists() && redirect_target(&new_path).is_none() {
+                return Err(Error::Conflict);
+            }
+
+            fs::create_dir_all(new_path.parent().unwrap())?;
+
+            // Taking over a name that only redirects somewhere else is fine.
+            if redirect_target(&new_path).is_some() {
+                fs::remove_file(&new_path)?;
+            }
+
+            debug!("Moving {} to {}", path.display(), new_path.display());
+
+            // A single rename keeps the repository whole at one location or the other. It
+            // can't clobber another repository either, as those are never empty directories.
+            fs::rename(&path, &new_path).map_err(|error| match error.kind() {
+                io::ErrorKind::AlreadyExists | io::ErrorKind::DirectoryNotEmpty => Error::Conflict,
+                _ => Error::Io(error),
+            })?;
+
+            if payload.redirect {
+                fs::write(&path, format!("{new_user}/{new_name}\n"))?;
+            }
+
+            state
+                .tokens
+                .rebind(&user, &name, Some((&new_user, &new_name)))?;
+            forks::retarget(
+                &state.options.repo_root,
+                &objects,
+                &new_path.join("objects").canonicalize()?,
+            )?;
+
+            // To subscribers the repository is gone from the old location and new at the new one.
+            state.events.publish(
+                events::Event::RepoDeleted {
+                    user: user.to_lowercase(),
+                    name: name.to_lowercase(),
+                },
+                private,
+            );
+            state.events.publish(
+                events::Event::RepoCreated {
+                    user: new_user.clone(),
+                    name: new_name.clone(),
+                },
+                private,
+            );
+        }
+
+        Ok(Json(RepoLocation {
+            user: new_user,
+            name: new_name,
+        }))
+    })
+    .await
+}
+
+/// Where a moved repository went, if `path` holds a redirect record left behind by a move.
+fn redirect_target(path: &std::path::Path) -> Option<String> {
+    if !path.is_file() {
+        return None;
+    }
+
+    let target = fs::read_to_string(path).ok()?;
+
+    Some(target.trim().to_string()).filter(|target| !target.is_empty())
+}
+
+/// Sends requests for repositories that moved to their new location. Git follows these for the
+/// initial request of a clone or fetch and warns about the redirect.
+async fn follow_redirects(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+
+    let mut segments = path.splitn(5, '/').skip(1);
+
+    let (Some("repo"), Some(user), Some(name)) =
+        (segments.next(), segments.next(), segments.next())
+    else {
+        return next.run(request).await;
+    };
+
+    if !Name::is_valid(user) || !Name::is_valid(name) {
+        return next.run(request).await;
+    }
+
+    let Some(target) = redirect_target(&state.repo_path(user, name)) else {
+        return next.run(request).await;
+    };
+
+    let mut location = format!("/repo/{target}");
+
+    if let Some(rest) = segments.next() {
+        location.push('/');
+        location.push_str(rest);
+    }
+
+    if let Some(query) = request.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    // Only GET and HEAD may be turned into a GET by clients following a 301.
+    let status = if matches!(*request.method(), Method::GET | Method::HEAD) {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+
+    match HeaderValue::from_str(&location) {
+        Ok(location) => (status, [(header::LOCATION, location)]).into_response(),
+        Err(_) => next.run(request).await,
+    }
+}
+
+/// Initializes a bare repository in a sibling temporary directory, runs `setup` on it and renames
+/// it into place, so `path` either doesn't exist or holds a fully initialized repository.
+fn init_bare_atomic<F>(path: &std::path::Path, setup: F) -> Result<(), Error>
+where
+    F: FnOnce(&Repository) -> Result<(), Error>,
+{
+    if path.exists() {
+        return Err(Error::Conflict);
+    }
+
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    let temp_path = hidden_sibling(path, "tmp");
+
+    let result = Repository::init_bare(&temp_path)
+        .map_err(Error::from)
+        .and_then(|repo| setup(&repo))
+        .and_then(|_| fs::rename(&temp_path, path).map_err(Error::from));
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&temp_path);
+    }
+
+    result
+}
+
+/// A unique, hidden path next to `path` for staging changes to it.
+fn hidden_sibling(path: &std::path::Path, label: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{label}-{}-{nanos}", process::id()));
+
+    path.with_file_name(name)
+}
+
+/// Strips redundant slashes from a path taken from the url, so `docs/` resolves like `docs`.
+fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Debug)]
+enum Error {
+    Git(git2::Error),
+    Io(io::Error),
+    BadRequest(String),
+    Unauthorized,
+    NotFound,
+    Conflict,
+    /// A merge would conflict in these paths.
+    MergeConflict(Vec<String>),
+    /// The client exceeded its rate limit and may retry after this many seconds.
+    TooManyRequests {
+        retry_after: u64,
+    },
+    /// An extractor refused the request, with axum's explanation as the details.
+    Rejected {
+        status: StatusCode,
+        message: &'static str,
+        details: String,
+    },
+}
+
+impl From<git2::Error> for Error {
+    fn from(error: git2::Error) -> Self {
+        Self::Git(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::Rejected {
+            status: rejection.status(),
+            message: "Invalid request body",
+            details: rejection.body_text(),
+        }
+    }
+}
+
+impl From<PathRejection> for Error {
+    fn from(rejection: PathRejection) -> Self {
+        Self::Rejected {
+            status: rejection.status(),
+            message: "Invalid path parameters",
+            details: rejection.body_text(),
+        }
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::Rejected {
+            status: rejection.status(),
+            message: "Invalid query string",
+            details: rejection.body_text(),
+        }
+    }
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    /// Stable, machine readable identifier derived from the status, e.g. `not_found`.
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let retry_after = match &self {
+            Error::TooManyRequests { retry_after } => Some(*retry_after),
+            _ => None,
+        };
+
+        let (status, message, details) = match self {
+            Error::Git(error) if error.code() == git2::ErrorCode::NotFound => {
+                debug!("Git object not found: {error}");
+
+                (StatusCode::NOT_FOUND, "Not found".to_string(), None)
+            }
+            // The underlying messages describe the server's internals, so they only go to the
+            // log.
+            Error::Git(error) => {
+                tracing::error!("Git error: {error}");
+
+                internal_error()
+            }
+            Error::Io(error) => {
+                tracing::error!("I/O error: {error}");
+
+                internal_error()
+            }
+            Error::BadRequest(message) => (StatusCode::BAD_REQUEST, message, None),
+            Error::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid credentials".to_string(),
+                None,
+            ),
+            Error::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string(), None),
+            Error::Conflict => (
+                StatusCode::CONFLICT,
+                "Conflicts with the current state".to_string(),
+                None,
+            ),
+            Error::MergeConflict(paths) => (
+                StatusCode::CONFLICT,
+                "Merge conflicts".to_string(),
+                Some(paths.join(", ")),
+            ),
+            Error::TooManyRequests { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Rate limit exceeded".to_string(),
+                None,
+            ),
+            Error::Rejected {
+                status,
+                message,
+                details,
+            } => (status, message.to_string(), Some(details)),
+        };
+
+        let code = status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_lowercase()
+            .replace([' ', '-'], "_");
+
+        let mut response = (
+            status,
+            axum::Json(ErrorBody {
+                code,
+                message,
+                details,
+            }),
+        )
+            .into_response();
+
+        // Git clients only prompt for credentials when challenged.
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"git-server\""),
+            );
+        }
+
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+
+        response
+    }
+}
+
+fn internal_error() -> (StatusCode, String, Option<String>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error".to_string(),
+        None,
+    )
+}
+
+/// [`axum::extract::Path`] reporting rejections as an [`Error`].
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(Error))]
+struct Path<T>(T);
+
+/// [`axum::extract::Query`] reporting rejections as an [`Error`].
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+struct Query<T>(T);
+
+/// [`axum::Json`] reporting rejections as an [`Error`]. Responds like the original.
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+struct Json<T>(T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Runs repository work on tokio's blocking pool, since libgit2 blocks the calling thread. The
+/// request's span and libgit2 tracing carry over to the work.
+async fn blocking<T, F>(work: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    let work = git_trace::propagate(work);
+
+    match tokio::task::spawn_blocking(move || span.in_scope(work)).await {
+        Ok(result) => result,
+        Err(error) => panic::resume_unwind(error.into_panic()),
+    }
+}
+
+/// Buffers written bytes and forwards them to a streaming response body in chunks, from a
+/// blocking thread. Writes fail once the client has gone away.
+struct BodyWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn new(sender: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(Self::CHUNK_SIZE),
+        }
+    }
+
+    /// Creates a writer together with the response body it feeds.
+    fn channel() -> (Self, Body) {
+        let (sender, mut receiver) = mpsc::channel(4);
+
+        // Body wrappers such as compression may poll again after the end, which
+        // `stream::unfold` doesn't allow.
+        let body = Body::from_stream(stream::poll_fn(move |cx| receiver.poll_recv(cx)));
+
+        (Self::new(sender), body)
+    }
+
+    /// Runs `write` on the blocking pool and streams what it writes as the returned body. If
+    /// it fails midway, the response is aborted.
+    fn spawn<F>(write: F) -> Body
+    where
+        F: FnOnce(&mut Self) -> io::Result<()> + Send + 'static,
+    {
+        let (mut writer, body) = Self::channel();
+
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+
+            let result = write(&mut writer).and_then(|()| io::Write::flush(&mut writer));
+
+            if let Err(error) = result {
+                if error.kind() != io::ErrorKind::BrokenPipe {
+                    warn!("Failed to stream response: {error}");
+                }
+
+                writer.fail(error);
+            }
+        });
+
+        body
+    }
+
+    /// Aborts the response, so the client can tell the body is incomplete.
+    fn fail(self, error: io::Error) {
+        let _ = self.sender.blocking_send(Err(error));
+    }
+}
+
+impl io::Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Take at most a chunk at a time, so large writes don't get buffered whole.
+        let len = buf.len().min(Self::CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(Self::CHUNK_SIZE),
+        ));
+
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away"))
+    }
+}
+
+fn is_private(repo: &Repository) -> Result<bool, Error> {
+    let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+    Ok(config.get_bool(PRIVATE_KEY).unwrap_or(false))
+}
+
+/// Whether a request comes from a git client rather than a browser or API consumer.
+fn is_git_client(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .is_some_and(|agent| agent.starts_with("git/") || agent.starts_with("git-lfs/"))
+        || request
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|pair| pair.starts_with("service=")))
+}
+
+/// Hides private repositories from anonymous requests. API clients get a 404 as if the
+/// repository didn't exist, git clients a challenge so they ask for credentials.
+async fn require_visible(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let mut segments = request.uri().path().split('/').skip(1);
+
+    let (Some("repo"), Some(user), Some(name)) =
+        (segments.next(), segments.next(), segments.next())
+    else {
+        return Ok(next.run(request).await);
+    };
+
+    // Invalid names are rejected by the handlers' extractors.
+    if !Name::is_valid(user) || !Name::is_valid(name) {
+        return Ok(next.run(request).await);
+    }
+
+    let path = state.repo_path(user, name);
+
+    let private = blocking(move || match Repository::open_bare(path) {
+        Ok(repo) => is_private(&repo),
+        // Missing repositories are left for the handler to report.
+        Err(_) => Ok(false),
+    })
+    .await?;
+
+    let allowed = auth::authenticate(&state, request.headers())?
+        .is_some_and(|principal| principal.can_read(user, name));
+
+    if private && !allowed {
+        return Err(if is_git_client(&request) {
+            Error::Unauthorized
+        } else {
+            Error::NotFound
+        });
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Intercepts browsers that wander onto a git protocol endpoint and explains what it is,
+/// while anything that looks like a git client gets the real protocol response.
+async fn browser_notice(request: Request, next: Next) -> Response {
+    let is_git_client = is_git_client(&request);
+
+    let wants_html = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    if wants_html && !is_git_client {
+        // `/repo/{user}/{name}` is the clone URL regardless of the protocol file requested.
+        let repo_path = request
+            .uri()
+            .path()
+            .split('/')
+            .take(4)
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let message = format!(
+            "This is a git endpoint and is meant to be used by a git client, not a browser.\n\n\
+             Clone the repository with:\n\n    git clone <server>{repo_path}\n"
+        );
+
+        return (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            message,
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn handle_git(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<(), Error> {
+    blocking(move || {
+        let path = state.repo_path(&user, &name);
+
+        debug!("Handling {}", path.display());
+
+        Ok(())
+    })
+    .await
+}
+
+async fn handle_dumb_protocol(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, path)): Path<(Name, Name, String)>,
+) -> Result<Response, Error> {
+    blocking(move || serve_repo_file(&state, &user, &name, &path)).await
+}
+
+/// Whether the dumb protocol serves the file at `path` within a repository directory. Only
+/// what clients fetch is served, leaving out the config and anything else kept alongside.
+fn is_dumb_protocol_file(path: &std::path::Path) -> bool {
+    let components: Vec<_> = path.components().map(|part| part.as_os_str()).collect();
+
+    match components.as_slice() {
+        [file] => *file == "HEAD",
+        [dir, file] if *dir == "info" => *file == "refs" || *file == "packs",
+        [dir, _, ..] => *dir == "objects" || *dir == "refs",
+        _ => false,
+    }
+}
+
+/// Streams a file straight out of the repository directory, as the dumb protocol expects.
+fn serve_repo_file(
+    state: &AppState,
+    user: &str,
+    name: &str,
+    path: &str,
+) -> Result<Response, Error> {
+    let repo_path = contained(&state.options.repo_root, &state.repo_path(user, name))?;
+    let path = contained(&repo_path, &repo_path.join(path))?;
+
+    if !path
+        .strip_prefix(&repo_path)
+        .is_ok_and(is_dumb_protocol_file)
+    {
+        return Err(Error::NotFound);
+    }
+
+    // Dumb clients fetch refs and objects by name, so there is no keeping hidden branches from
+    // them. Repositories hiding any only speak the smart protocol.
+    let repo = Repository::open_bare(&repo_path).map_err(|_| Error::NotFound)?;
+
+    if RefFilter::new(&repo)?.is_active() {
+        return Err(Error::NotFound);
+    }
+
+    debug!("Handling dumb protocol: {}", path.display());
+
+    let mut file = fs::File::open(&path).map_err(|_| Error::NotFound)?;
+    let metadata = file.metadata()?;
+
+    if !metadata.is_file() {
+        return Err(Error::NotFound);
+    }
+
+    let body = BodyWriter::spawn(move |writer| io::copy(&mut file, writer).map(|_| ()));
+
+    Ok(octet_stream(metadata.len(), body))
+}
+
+/// Answers with `304 Not Modified` if the request's `If-None-Match` already names `tag`, and
+/// with the response of `build` otherwise. Tags are weak, as compression changes the bytes
+/// but not the meaning of a response.
+fn conditional<R: IntoResponse>(
+    headers: &HeaderMap,
+    tag: &str,
+    build: impl FnOnce() -> Result<R, Error>,
+) -> Result<Response, Error> {
+    let etag = HeaderValue::from_str(&format!("W/\"{tag}\""))
+        .map_err(|_| Error::BadRequest("Invalid entity tag".to_string()))?;
+
+    let matches = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| {
+            candidate == "*"
+                || candidate.strip_prefix("W/").unwrap_or(candidate) == format!("\"{tag}\"")
+        });
+
+    let mut response = if matches {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        build()?.into_response()
+    };
+
+    response.headers_mut().insert(header::ETAG, etag);
+
+    Ok(response)
+}
+
+/// A binary response of known length.
+fn octet_stream(len: u64, body: Body) -> Response {
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            ),
+            (header::CONTENT_LENGTH, HeaderValue::from(len)),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Node {
+    File {
+        name: String,
+        commit: String,
+        message: String,
+        modified: i64,
+        oid: String,
+        /// Bytes of content.
+        size: u64,
+        /// `0o100755` for executables, `0o100644` otherwise.
+        mode: i32,
+    },
+    Directory {
+        name: String,
+        childs: Vec<Node>,
+    },
+    Symlink {
+        name: String,
+        /// Path the link points at, as stored.
+        target: String,
+        commit: String,
+        message: String,
+        modified: i64,
+    },
+    /// A gitlink, pinning a commit of another repository.
+    Submodule {
+        name: String,
+        oid: String,
+        /// As configured in `.gitmodules`, if it is.
+        url: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct FilesQuery {
+    /// Branch, tag or (abbreviated) commit id to list instead of HEAD.
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+}
+
+async fn fetch_repo(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<FilesQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = match &query.reference {
+            Some(reference) => repo.find_commit(resolve_public_commit(&repo, reference)?)?,
+            None => {
+                let head = repo.head()?;
+
+                if let Some(branch) = head.shorthand()
+                    && head.is_branch()
+                    && !is_branch_public(&repo, branch)?
+                {
+                    return Err(Error::NotFound);
+                }
+
+                head.peel_to_commit()?
+            }
+        };
+
+        // The listing is entirely determined by the commit.
+        conditional(&headers, &format!("files-{}", commit.id()), || {
+            let last_modified = match state.last_modified.get(&commit.id()) {
+                Some(last_modified) => last_modified,
+                None => {
+                    let last_modified = Arc::new(
+                        state
+                            .metrics
+                            .time("last-modified", || last_modified(&repo, &commit))?,
+                    );
+
+                    state
+                        .last_modified
+                        .insert(commit.id(), last_modified.clone());
+
+                    last_modified
+                }
+            };
+
+            let tree = commit.tree()?;
+            let submodules = submodules(&repo, &tree)?;
+            let mut root = Vec::new();
+
+            state.metrics.time("tree-walk", || {
+                process_tree(&repo, &tree, &mut root, "", &last_modified, &submodules)
+            })?;
+
+            Ok(Json(Node::Directory {
+                name: "root".to_string(),
+                childs: root,
+            }))
+        })
+    })
+    .await
+}
+
+/// Urls of the submodules configured in the `.gitmodules` of `tree`, keyed by path.
+fn submodules(repo: &Repository, tree: &git2::Tree) -> Result<HashMap<String, String>, Error> {
+    let Some(entry) = tree.get_name(".gitmodules") else {
+        return Ok(HashMap::new());
+    };
+
+    let Ok(blob) = repo.find_blob(entry.id()) else {
+        return Ok(HashMap::new());
+    };
+
+    Ok(parse_gitmodules(&String::from_utf8_lossy(blob.content())))
+}
+
+/// Reads the `path` and `url` of each `[submodule "name"]` section, ignoring anything else.
+fn parse_gitmodules(content: &str) -> HashMap<String, String> {
+    let mut submodules = HashMap::new();
+    let (mut path, mut url): (Option<String>, Option<String>) = (None, None);
+
+    let mut flush = |path: &mut Option<String>, url: &mut Option<String>| {
+        if let (Some(path), Some(url)) = (path.take(), url.take()) {
+            submodules.insert(normalize_path(&path), url);
+        }
+    };
+
+    let mut in_submodule = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            flush(&mut path, &mut url);
+            in_submodule = line
+                .trim_start_matches('[')
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("submodule");
+            continue;
+        }
+
+        if !in_submodule || line.starts_with(['#', ';']) {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value)
+            .to_string();
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "path" => path = Some(value),
+            "url" => url = Some(value),
+            _ => {}
+        }
+    }
+
+    flush(&mut path, &mut url);
+
+    submodules
+}
+
+/// Collects the entries of `tree` into `parent`, along with the commit that last modified
+/// each file.
+fn process_tree<P: AsRef<std::path::Path>>(
+    repo: &Repository,
+    tree: &git2::Tree,
+    parent: &mut Vec<Node>,
+    prefix: P,
+    last_modified: &LastModified,
+    submodules: &HashMap<String, String>,
+) -> Result<(), Error> {
+    let odb = repo.odb()?;
+
+    for entry in tree {
+        let name = entry.name().unwrap().to_string();
+
+        let full_path = prefix.as_ref().join(&name);
+
+        let node = if entry.kind() == Some(ObjectType::Commit) {
+            // The commit lives in another repository, so there is nothing to look into.
+            Node::Submodule {
+                name,
+                oid: entry.id().to_string(),
+                url: submodules
+                    .get(full_path.to_string_lossy().as_ref())
+                    .cloned(),
+            }
+        } else if entry.kind() == Some(ObjectType::Tree) {
+            let subtree = repo.find_tree(entry.id())?;
+            let mut childs = Vec::new();
+
+            process_tree(
+                repo,
+                &subtree,
+                &mut childs,
+                &full_path,
+                last_modified,
+                submodules,
+            )?;
+
+            Node::Directory { name, childs }
+        } else {
+            let commit_id = *last_modified
+                .get(full_path.to_string_lossy().as_ref())
+                .ok_or(Error::NotFound)?;
+            let commit = repo.find_commit(commit_id)?;
+            let message = commit.message().unwrap().to_string();
+            let modified = commit.committer().when().seconds();
+
+            if entry.filemode() == i32::from(FileMode::Link) {
+                Node::Symlink {
+                    name,
+                    target: symlink_target(&repo.find_blob(entry.id())?),
+                    commit: commit_id.to_string(),
+                    message,
+                    modified,
+                }
+            } else {
+                let (size, _) = odb.read_header(entry.id())?;
+
+                Node::File {
+                    name,
+                    commit: commit_id.to_string(),
+                    message,
+                    modified,
+                    oid: entry.id().to_string(),
+                    size: size as u64,
+                    mode: entry.filemode(),
+                }
+            }
+        };
+
+        parent.push(node);
+    }
+
+    Ok(())
+}
+
+/// The path a symlink's blob points at.
+fn symlink_target(blob: &Blob) -> String {
+    String::from_utf8_lossy(blob.content()).into_owned()
+}
+
+/// Last commit to modify each file, keyed by path.
+type LastModified = HashMap<String, Oid>;
+
+/// Finds the last commit to modify every file in the tree of `commit` in a single walk over
+/// its history, diffing each commit against its parents the way `git log --name-only` does.
+/// Every file follows the history `git log -- <file>` shows: a merge that kept the version of
+/// one of its parents only leads on to that parent, and counts as modifying the file if it
+/// differs from every parent.
+fn last_modified(repo: &Repository, commit: &git2::Commit) -> Result<LastModified, Error> {
+    let mut paths = Vec::new();
+
+    commit.tree()?.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            paths.push(format!("{root}{}", entry.name().unwrap_or_default()));
+        }
+
+        TreeWalkResult::Ok
+    })?;
+
+    // Files still to be attributed, by the commit their history continues at. Each file only
+    // ever follows a single line of history, so it is only ever pending at one commit.
+    let mut pending = HashMap::from([(commit.id(), (0..paths.len()).collect::<Vec<_>>())]);
+    let mut found = LastModified::new();
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    revwalk.push(commit.id())?;
+
+    for oid in revwalk {
+        if pending.is_empty() {
+            break;
+        }
+
+        let oid = oid?;
+
+        let Some(files) = pending.remove(&oid) else {
+            continue;
+        };
+
+        let current = repo.find_commit(oid)?;
+        let tree = current.tree()?;
+
+        let parents = current
+            .parents()
+            .map(|parent| {
+                Ok((
+                    parent.id(),
+                    changed_paths(repo, Some(&parent.tree()?), &tree)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+
+        for file in files {
+            // Like history simplification, follow the first parent with the same version.
+            match parents
+                .iter()
+                .find(|(_, changed)| !changed.contains(&paths[file]))
+            {
+                Some((parent, _)) => pending.entry(*parent).or_default().push(file),
+                None => {
+                    found.insert(paths[file].clone(), oid);
+                }
+            }
+        }
+    }
+
+    // Only reachable with incomplete history, such as in shallow repositories.
+    for file in pending.into_values().flatten() {
+        found.insert(paths[file].clone(), commit.id());
+    }
+
+    Ok(found)
+}
+
+/// Paths of the files that differ between `old` and `new`.
+fn changed_paths(
+    repo: &Repository,
+    old: Option<&git2::Tree>,
+    new: &git2::Tree,
+) -> Result<HashSet<String>, git2::Error> {
+    if old.is_some_and(|old| old.id() == new.id()) {
+        return Ok(HashSet::new());
+    }
+
+    let diff = repo.diff_tree_to_tree(old, Some(new), None)?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect())
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BranchSort {
+    #[default]
+    Name,
+    /// Most recently committed to first.
+    Updated,
+}
+
+#[derive(Debug, Deserialize)]
+struct BranchesQuery {
+    #[serde(default)]
+    sort: BranchSort,
+    /// 1-based page number.
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct BranchInfo {
+    name: String,
+    commit: String,
+    author: Person,
+    summary: String,
+    /// When the tip was committed.
+    date: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct BranchPage {
+    branches: Vec<BranchInfo>,
+    /// Page to request next, if there are more branches.
+    next_page: Option<usize>,
+}
+
+async fn get_branches(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<BranchesQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_BRANCHES_PAGE)
+            .clamp(1, MAX_BRANCHES_PAGE);
+
+        let mut branches = Vec::new();
+        let mut tips = Sha1::new();
+
+        for branch in repo.branches(Some(BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = branch.name()?.unwrap();
+
+            if is_branch_public(&repo, name)? {
+                tips.update(format!("{name} {:?}\n", branch.get().target()));
+                branches.push((name.to_string(), branch.get().peel_to_commit()?));
+            }
+        }
+
+        // Changes whenever a listed branch is added, removed or moves.
+        let tips: String = tips
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        let etag = format!("branches-{tips}-{:?}-{page}-{per_page}", query.sort);
+
+        conditional(&headers, &etag, || {
+            if let BranchSort::Updated = query.sort {
+                branches.sort_by(|(a_name, a), (b_name, b)| {
+                    let date = |commit: &git2::Commit| commit.committer().when().seconds();
+
+                    date(b).cmp(&date(a)).then_with(|| a_name.cmp(b_name))
+                });
+            }
+
+            let next_page = (branches.len() > page.saturating_mul(per_page)).then_some(page + 1);
+
+            let branches = branches
+                .into_iter()
+                .skip((page - 1).saturating_mul(per_page))
+                .take(per_page)
+                .map(|(name, commit)| BranchInfo {
+                    name,
+                    commit: commit.id().to_string(),
+                    author: commit.author().into(),
+                    summary: commit.summary().unwrap_or_default().to_string(),
+                    date: commit.committer().when().seconds(),
+                })
+                .collect();
+
+            Ok(Json(BranchPage {
+                branches,
+                next_page,
+            }))
+        })
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct RecentBranchesQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct BranchTip {
+    name: String,
+    commit: String,
+    summary: String,
+    date: i64,
+}
+
+async fn get_recent_branches(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<RecentBranchesQuery>,
+) -> Result<Json<Vec<BranchTip>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_RECENT_BRANCHES)
+            .clamp(1, MAX_BRANCHES_PAGE);
+
+        let mut branches = Vec::new();
+
+        for branch in repo.branches(Some(BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = branch.name()?.unwrap();
+
+            if !is_branch_public(&repo, name)? {
+                continue;
+            }
+
+            let commit = branch.get().peel_to_commit()?;
+
+            branches.push(BranchTip {
+                name: name.to_string(),
+                commit: commit.id().to_string(),
+                summary: commit.summary().unwrap_or_default().to_string(),
+                date: commit.committer().when().seconds(),
+            });
+        }
+
+        branches.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.name.cmp(&b.name)));
+        branches.truncate(limit);
+
+        Ok(Json(branches))
+    })
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct TagInfo {
+    name: String,
+    /// The object the tag ultimately points at, usually a commit.
+    target: String,
+    /// Tagger and message are only recorded by annotated tags.
+    tagger: Option<Person>,
+    message: Option<String>,
+}
+
+async fn get_tags(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Json<Vec<TagInfo>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let mut tags = Vec::new();
+
+        for reference in repo.references_glob("refs/tags/*")? {
+            let reference = reference?;
+
+            let (Some(name), Some(oid)) = (reference.shorthand(), reference.target()) else {
+                continue;
+            };
+
+            let (target, tagger, message) = match repo.find_tag(oid) {
+                Ok(tag) => (
+                    tag.as_object().peel(ObjectType::Any)?.id(),
+                    tag.tagger().map(Person::from),
+                    tag.message().map(str::to_string),
+                ),
+                Err(_) => (oid, None, None),
+            };
+
+            tags.push(TagInfo {
+                name: name.to_string(),
+                target: target.to_string(),
+                tagger,
+                message,
+            });
+        }
+
+        Ok(Json(tags))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateTag {
+    name: String,
+    /// Branch, tag or commit to tag.
+    target: String,
+    /// Makes the tag annotated.
+    message: Option<String>,
+    /// Who the annotated tag is recorded as created by. Defaults to the repository's
+    /// configured identity.
+    tagger: Option<SignatureSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignatureSpec {
+    name: String,
+    email: String,
+}
+
+/// Fallback identity for objects the server writes on its own behalf.
+fn server_signature(repo: &Repository) -> Result<git2::Signature<'static>, git2::Error> {
+    repo.signature()
+        .or_else(|_| git2::Signature::now(env!("CARGO_PKG_NAME"), "git-server@localhost"))
+}
+
+/// Announces a ref moved through the API to webhooks and event subscribers.
+fn ref_changed(
+    state: &AppState,
+    repo: &Repository,
+    user: &str,
+    name: &str,
+    event: webhooks::Event,
+    update: RefUpdate,
+) {
+    webhooks::dispatch(state, repo, (user, name), event, &update);
+
+    let RefUpdate {
+        old,
+        new,
+        name: reference,
+    } = update;
+
+    state
+        .push_mirrors
+        .refs_changed(state, repo, vec![reference.clone()]);
+
+    state.events.publish(
+        events::Event::ref_update(user, name, &reference, old, new),
+        is_private(repo).unwrap_or(true),
+    );
+}
+
+/// The public branch `name` as a ref name, and the commit it points at. Branches that aren't
+/// public can't be changed through the API either.
+fn find_public_branch<'r>(
+    repo: &'r Repository,
+    name: &str,
+) -> Result<(String, git2::Commit<'r>), Error> {
+    let branch = repo
+        .find_branch(name, BranchType::Local)
+        .map_err(|_| Error::NotFound)?;
+
+    if !is_branch_public(repo, name)? {
+        return Err(Error::NotFound);
+    }
+
+    let reference = branch.get().name().unwrap_or_default().to_string();
+
+    Ok((reference, branch.get().peel_to_commit()?))
+}
+
+/// Moves a branch to a commit written through the API, or creates it if `update.old` is zero,
+/// unless something else moved or created it in the meantime.
+fn advance_branch(
+    state: &AppState,
+    repo: &Repository,
+    (user, name): (&str, &str),
+    update: RefUpdate,
+    log_message: &str,
+) -> Result<(), Error> {
+    let result = if update.old.is_zero() {
+        repo.reference(&update.name, update.new, false, log_message)
+    } else {
+        repo.reference_matching(&update.name, update.new, true, update.old, log_message)
+    };
+
+    result.map_err(|error| match error.code() {
+        git2::ErrorCode::Modified | git2::ErrorCode::Exists => Error::Conflict,
+        _ => Error::Git(error),
+    })?;
+
+    let event = if update.old.is_zero() {
+        webhooks::Event::BranchCreate
+    } else {
+        webhooks::Event::Push
+    };
+
+    ref_changed(state, repo, user, name, event, update);
+
+    Ok(())
+}
+
+async fn create_tag(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<CreateTag>,
+) -> Result<(StatusCode, Json<TagInfo>), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let reference = format!("refs/tags/{}", payload.name);
+
+        if !git2::Reference::is_valid_name(&reference) {
+            return Err(Error::BadRequest(format!(
+                "Invalid tag name: {}",
+                payload.name
+            )));
+        }
+
+        if repo.find_reference(&reference).is_ok() {
+            return Err(Error::Conflict);
+        }
+
+        let target = repo.find_object(resolve_public_commit(&repo, &payload.target)?, None)?;
+
+        let tagger = match (&payload.message, payload.tagger) {
+            (None, _) => None,
+            (Some(_), Some(identity)) => {
+                Some(git2::Signature::now(&identity.name, &identity.email)?)
+            }
+            (Some(_), None) => Some(server_signature(&repo)?),
+        };
+
+        let tag = match (&payload.message, &tagger) {
+            (Some(message), Some(tagger)) => {
+                repo.tag(&payload.name, &target, tagger, message, false)?
+            }
+            _ => repo.tag_lightweight(&payload.name, &target, false)?,
+        };
+
+        ref_changed(
+            &state,
+            &repo,
+            &user,
+            &name,
+            webhooks::Event::TagCreate,
+            RefUpdate {
+                old: Oid::zero(),
+                new: tag,
+                name: reference.clone(),
+            },
+        );
+
+        debug!("Created tag {} at {}", payload.name, target.id());
+
+        Ok((
+            StatusCode::CREATED,
+            Json(TagInfo {
+                name: payload.name,
+                target: target.id().to_string(),
+                tagger: tagger.map(Person::from),
+                message: payload.message,
+            }),
+        ))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct BlobQuery {
+    format: Option<String>,
+    /// Describes the blob without any of its content.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    stat: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BlobStat {
+    path: String,
+    oid: String,
+    size: usize,
+    mode: i32,
+    binary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+/// What the blob endpoint answers for a symlink, whatever the format asked for.
+#[derive(Debug, Serialize)]
+struct SymlinkBlob {
+    path: String,
+    #[serde(rename = "type")]
+    kind: EntryKind,
+    target: String,
+}
+
+#[derive(Debug, Serialize)]
+struct HighlightedBlob {
+    language: Option<&'static str>,
+    tokens: Vec<highlight::Token>,
+    truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BlobInfo {
+    path: String,
+    oid: String,
+    size: usize,
+    language: Option<&'static str>,
+    binary: bool,
+    /// Left out unless the content is text.
+    lines: Option<usize>,
+    encoding: BlobEncoding,
+    content: String,
+    truncated: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum BlobEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+/// The content of a text blob, cut off after [`MAX_TEXT_BLOB_BYTES`] at a character boundary,
+/// and whether it was.
+fn blob_text(blob: &Blob) -> (String, bool) {
+    let content = blob.content();
+    let truncated = content.len() > MAX_TEXT_BLOB_BYTES;
+    let mut text = String::from_utf8_lossy(&content[..content.len().min(MAX_TEXT_BLOB_BYTES)]);
+
+    // Cutting through a character leaves a replacement character at the end.
+    if truncated && text.ends_with(char::REPLACEMENT_CHARACTER) {
+        text.to_mut().pop();
+    }
+
+    (text.into_owned(), truncated)
+}
+
+/// Refuses to show binary content as text.
+fn require_text(blob: &Blob) -> Result<(), Error> {
+    if blob.is_binary() {
+        return Err(Error::BadRequest(
+            "Binary files can only be fetched raw".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Lines in `content`, the last counting whether or not it ends in a newline.
+fn count_lines(content: &[u8]) -> usize {
+    let newlines = content.iter().filter(|&&byte| byte == b'\n').count();
+
+    newlines + usize::from(content.last().is_some_and(|&byte| byte != b'\n'))
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameBranch {
+    new_name: String,
+}
+
+async fn rename_branch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch)): Path<(Name, Name, String)>,
+    caller: Authenticated,
+    Json(payload): Json<RenameBranch>,
+) -> Result<(), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let new_name = payload.new_name;
+
+        if !git2::Branch::name_is_valid(&new_name)? {
+            return Err(Error::BadRequest(format!(
+                "Invalid branch name: {new_name}"
+            )));
+        }
+
+        find_public_branch(&repo, &branch)?;
+
+        let mut branch = repo.find_branch(&branch, BranchType::Local)?;
+
+        if repo.find_branch(&new_name, BranchType::Local).is_ok() {
+            return Err(Error::Conflict);
+        }
+
+        let was_head = branch.is_head();
+
+        debug!("Renaming branch {:?} to {new_name}", branch.name()?);
+
+        let old_reference = branch.get().name().unwrap_or_default().to_string();
+        let tip = branch.get().target().unwrap_or_else(Oid::zero);
+
+        let renamed = branch.rename(&new_name, false)?;
+
+        if was_head {
+            repo.set_head(&format!("refs/heads/{new_name}"))?;
+        }
+
+        ref_changed(
+            &state,
+            &repo,
+            &user,
+            &name,
+            webhooks::Event::BranchDelete,
+            RefUpdate {
+                old: tip,
+                new: Oid::zero(),
+                name: old_reference,
+            },
+        );
+        ref_changed(
+            &state,
+            &repo,
+            &user,
+            &name,
+            webhooks::Event::BranchCreate,
+            RefUpdate {
+                old: Oid::zero(),
+                new: tip,
+                name: renamed.get().name().unwrap_or_default().to_string(),
+            },
+        );
+
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateBranch {
+    name: String,
+    /// Branch, tag or commit to start from. Defaults to HEAD.
+    source: Option<String>,
+}
+
+async fn create_branch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<CreateBranch>,
+) -> Result<(StatusCode, Json<BranchTip>), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if !git2::Branch::name_is_valid(&payload.name)? {
+            return Err(Error::BadRequest(format!(
+                "Invalid branch name: {}",
+                payload.name
+            )));
+        }
+
+        if repo.find_branch(&payload.name, BranchType::Local).is_ok() {
+            return Err(Error::Conflict);
+        }
+
+        let source = payload.source.as_deref().unwrap_or("HEAD");
+        let commit = repo.find_commit(resolve_public_commit(&repo, source)?)?;
+
+        debug!("Creating branch {} at {}", payload.name, commit.id());
+
+        let branch = repo.branch(&payload.name, &commit, false)?;
+
+        ref_changed(
+            &state,
+            &repo,
+            &user,
+            &name,
+            webhooks::Event::BranchCreate,
+            RefUpdate {
+                old: Oid::zero(),
+                new: commit.id(),
+                name: branch.get().name().unwrap_or_default().to_string(),
+            },
+        );
+
+        Ok((
+            StatusCode::CREATED,
+            Json(BranchTip {
+                name: payload.name,
+                commit: commit.id().to_string(),
+                summary: commit.summary().unwrap_or_default().to_string(),
+                date: commit.committer().when().seconds(),
+            }),
+        ))
+    })
+    .await
+}
+
+/// Deletes a branch. The one HEAD points at can't be deleted, as that would leave the
+/// repository without a default branch.
+async fn delete_branch(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch)): Path<(Name, Name, String)>,
+    caller: Authenticated,
+) -> Result<StatusCode, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        find_public_branch(&repo, &branch)?;
+
+        let mut branch = repo.find_branch(&branch, BranchType::Local)?;
+
+        if branch.is_head() {
+            return Err(Error::Conflict);
+        }
+
+        debug!("Deleting branch {:?}", branch.name()?);
+
+        let reference = branch.get().name().unwrap_or_default().to_string();
+        let tip = branch.get().target().unwrap_or_else(Oid::zero);
+
+        branch.delete()?;
+
+        ref_changed(
+            &state,
+            &repo,
+            &user,
+            &name,
+            webhooks::Event::BranchDelete,
+            RefUpdate {
+                old: tip,
+                new: Oid::zero(),
+                name: reference.clone(),
+            },
+        );
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+async fn get_blob(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, path)): Path<(Name, Name, String, String)>,
+    Query(query): Query<BlobQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!("Opening {path} at branch {branch}");
+
+        let (blob, mode) = state
+            .metrics
+            .time("blob-read", || find_entry_in_branch(&repo, &path, &branch))
+            .and_then(|entry| Ok((repo.find_blob(entry.id())?, entry.filemode())))
+            .map_err(|_| Error::NotFound)?;
+
+        let symlink = mode == i32::from(FileMode::Link);
+
+        if query.stat {
+            let etag = format!("{}-{mode:o}-stat", blob.id());
+
+            return conditional(&headers, &etag, || {
+                Ok(Json(BlobStat {
+                    path,
+                    oid: blob.id().to_string(),
+                    size: blob.size(),
+                    mode,
+                    binary: blob.is_binary(),
+                    target: symlink.then(|| symlink_target(&blob)),
+                })
+                .into_response())
+            });
+        }
+
+        // Serving the target as if it were the file's content would mislead clients.
+        if symlink {
+            return conditional(&headers, &format!("{}-symlink", blob.id()), || {
+                Ok(Json(SymlinkBlob {
+                    path,
+                    kind: EntryKind::Symlink,
+                    target: symlink_target(&blob),
+                })
+                .into_response())
+            });
+        }
+
+        let wants_json = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+
+        let format = match query.format.as_deref() {
+            Some(format) => format,
+            None if wants_json => "json",
+            None => "raw",
+        };
+        let etag = format!("{}-{format}", blob.id());
+
+        let mut response = conditional(&headers, &etag, || match format {
+            "raw" => {
+                let (oid, size) = (blob.id(), blob.size() as u64);
+                let repo_path = repo.path().to_path_buf();
+
+                // The blob borrows the repository, so the writer looks it up again on its own
+                // thread.
+                let body = BodyWriter::spawn(move |writer| {
+                    let repo = Repository::open_bare(repo_path).map_err(io::Error::other)?;
+                    let blob = repo.find_blob(oid).map_err(io::Error::other)?;
+
+                    io::Write::write_all(writer, blob.content())
+                });
+
+                Ok(octet_stream(size, body))
+            }
+            "json" => {
+                let binary = blob.is_binary();
+                let text = !binary && std::str::from_utf8(blob.content()).is_ok();
+
+                let (encoding, content, truncated) = if text {
+                    let (content, truncated) = blob_text(&blob);
+                    (BlobEncoding::Utf8, content, truncated)
+                } else {
+                    let content = blob.content();
+                    let len = content.len().min(MAX_TEXT_BLOB_BYTES);
+
+                    (
+                        BlobEncoding::Base64,
+                        BASE64_STANDARD.encode(&content[..len]),
+                        len < content.len(),
+                    )
+                };
+
+                Ok(Json(BlobInfo {
+                    oid: blob.id().to_string(),
+                    size: blob.size(),
+                    language: highlight::detect(&path, blob.content())
+                        .map(|language| language.name),
+                    binary,
+                    lines: text.then(|| count_lines(blob.content())),
+                    encoding,
+                    content,
+                    truncated,
+                    path,
+                })
+                .into_response())
+            }
+            "tokens" => {
+                require_text(&blob)?;
+
+                let language = highlight::detect(&path, blob.content());
+                let (text, truncated) = blob_text(&blob);
+
+                Ok(Json(HighlightedBlob {
+                    language: language.map(|language| language.name),
+                    tokens: highlight::tokenize(language, text.as_bytes()),
+                    truncated,
+                })
+                .into_response())
+            }
+            "html" => {
+                if !markdown::is_markdown(&path) {
+                    return Err(Error::BadRequest(
+                        "Only markdown files can be rendered".to_string(),
+                    ));
+                }
+
+                require_text(&blob)?;
+
+                let commit = repo
+                    .find_branch(&branch, BranchType::Local)?
+                    .get()
+                    .peel_to_commit()?;
+                let tree = commit.tree()?;
+
+                let resolve = |url: &str, image: bool| {
+                    let (target, suffix) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+                    let target = relative_path(&path, target);
+
+                    let is_dir = !target.is_empty()
+                        && tree
+                            .get_path(std::path::Path::new(&target))
+                            .is_ok_and(|entry| entry.kind() == Some(ObjectType::Tree));
+
+                    // Images are fetched raw; anything else links to what a repository page
+                    // would show for it.
+                    if target.is_empty() || is_dir && !image {
+                        format!(
+                            "/repo/{user}/{name}/commit/{}/tree/{}{suffix}",
+                            commit.id(),
+                            encode_path(&target)
+                        )
+                    } else {
+                        format!(
+                            "/repo/{user}/{name}/blob/{}/{}{suffix}",
+                            encode_path(&branch).replace('/', "%2F"),
+                            encode_path(&target)
+                        )
+                    }
+                };
+
+                let html = markdown::render(&blob_text(&blob).0, &resolve);
+
+                Ok((
+                    [
+                        (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+                        // Belt and braces should the page be opened directly.
+                        (header::CONTENT_SECURITY_POLICY, "sandbox"),
+                    ],
+                    html,
+                )
+                    .into_response())
+            }
+            format => Err(Error::BadRequest(format!("Unknown format: {format}"))),
+        })?;
+
+        if query.format.is_none() {
+            response
+                .headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept"));
+        }
+
+        Ok(response)
+    })
+    .await
+}
+
+/// Resolves `target`, relative to the file at `from` or to the root if it starts with a slash,
+/// into a path from the root of the tree. Parents of the root are the root.
+fn relative_path(from: &str, target: &str) -> String {
+    let from = normalize_path(from);
+
+    let mut components: Vec<&str> = if target.starts_with('/') {
+        Vec::new()
+    } else {
+        from.split('/').collect()
+    };
+
+    // Relative to the directory holding the file, not the file itself.
+    if !target.starts_with('/') {
+        components.pop();
+    }
+
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components.join("/")
+}
+
+/// Percent-encodes a path for use in a url, keeping its slashes.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    encoded
+}
+
+/// README names looked for, most preferred first, with the markup each is written in.
+const README_NAMES: &[(&str, &str)] = &[
+    ("README.md", "markdown"),
+    ("README.markdown", "markdown"),
+    ("README.rst", "rst"),
+    ("README.txt", "text"),
+    ("README", "text"),
+];
+
+#[derive(Debug, Deserialize)]
+struct ReadmeQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Readme {
+    /// Name as spelled in the tree.
+    path: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    oid: String,
+    content: String,
+}
+
+/// Finds the README at the root of a ref's tree, whatever the case of its name.
+async fn get_readme(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<ReadmeQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+        let tree = repo.find_commit(commit)?.tree()?;
+
+        let (entry, kind) = README_NAMES
+            .iter()
+            .find_map(|&(readme, kind)| {
+                tree.iter()
+                    .find(|entry| {
+                        entry.kind() == Some(ObjectType::Blob)
+                            && entry
+                                .name()
+                                .is_some_and(|name| name.eq_ignore_ascii_case(readme))
+                    })
+                    .map(|entry| (entry, kind))
+            })
+            .ok_or(Error::NotFound)?;
+
+        conditional(&headers, &format!("readme-{}-{kind}", entry.id()), || {
+            let blob = repo.find_blob(entry.id())?;
+
+            Ok(Json(Readme {
+                path: entry.name().unwrap_or_default().to_string(),
+                kind,
+                oid: entry.id().to_string(),
+                content: String::from_utf8_lossy(blob.content()).into_owned(),
+            }))
+        })
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct BlameQuery {
+    /// Returns runs of lines sharing a commit instead of one entry per line.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    group: bool,
+}
+
+/// Accepts `1`/`0` as well as `true`/`false` for boolean query parameters.
+fn deserialize_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        value => Err(serde::de::Error::custom(format!("invalid flag: {value}"))),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BlameLine {
+    /// 1-based line number.
+    line: usize,
+    content: String,
+    commit: String,
+    author: Person,
+}
+
+#[derive(Debug, Serialize)]
+struct BlameHunk {
+    commit: String,
+    summary: String,
+    author: Person,
+    /// 1-based number of the first line in the hunk.
+    start_line: usize,
+    line_count: usize,
+}
+
+async fn get_blame(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, path)): Path<(Name, Name, String, String)>,
+    Query(query): Query<BlameQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!("Blaming {path} at branch {branch}");
+
+        let blob = find_blob_in_branch(&repo, &path, &branch).map_err(|_| Error::NotFound)?;
+        let tip = repo
+            .find_branch(&branch, BranchType::Local)?
+            .get()
+            .peel_to_commit()?;
+
+        let etag = format!("blame-{}-{}-{}", tip.id(), blob.id(), query.group);
+
+        conditional(&headers, &etag, || {
+            let mut options = git2::BlameOptions::new();
+            options.newest_commit(tip.id());
+
+            let blame = repo.blame_file(
+                std::path::Path::new(&normalize_path(&path)),
+                Some(&mut options),
+            )?;
+
+            if query.group {
+                let mut hunks = Vec::new();
+                let mut summaries = HashMap::new();
+
+                for hunk in blame.iter() {
+                    let commit = hunk.final_commit_id();
+
+                    let summary = match summaries.entry(commit) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let summary = repo.find_commit(commit)?.summary().map(str::to_string);
+                            entry.insert(summary.unwrap_or_default())
+                        }
+                    };
+
+                    hunks.push(BlameHunk {
+                        commit: commit.to_string(),
+                        summary: summary.clone(),
+                        author: hunk.final_signature().into(),
+                        start_line: hunk.final_start_line(),
+                        line_count: hunk.lines_in_hunk(),
+                    });
+                }
+
+                return Ok(Json(hunks).into_response());
+            }
+
+            let content = blob.content();
+            let content = content.strip_suffix(b"\n").unwrap_or(content);
+
+            let mut lines = Vec::new();
+
+            for (index, line) in content.split(|&byte| byte == b'\n').enumerate() {
+                let Some(hunk) = blame.get_line(index + 1) else {
+                    break;
+                };
+
+                lines.push(BlameLine {
+                    line: index + 1,
+                    content: String::from_utf8_lossy(line).into_owned(),
+                    commit: hunk.final_commit_id().to_string(),
+                    author: hunk.final_signature().into(),
+                });
+            }
+
+            Ok(Json(lines).into_response())
+        })
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct PreviewQuery {
+    bytes: Option<usize>,
+}
+
+async fn get_preview(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, path)): Path<(Name, Name, String, String)>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let limit = query.bytes.unwrap_or(DEFAULT_PREVIEW_BYTES);
+
+        debug!("Previewing {limit} bytes of {path} at branch {branch}");
+
+        let oid = find_entry_in_branch(&repo, &path, &branch)
+            .map_err(|_| Error::NotFound)?
+            .id();
+
+        // Loose objects can be inflated as far as needed, but packed ones may be deltas that
+        // only come out whole.
+        let (preview, size) = match repo.odb()?.reader(oid) {
+            Ok((reader, size, _)) => {
+                let mut preview = Vec::with_capacity(size.min(limit));
+                io::Read::read_to_end(&mut io::Read::take(reader, limit as u64), &mut preview)?;
+
+                (preview, size)
+            }
+            Err(_) => {
+                let blob = repo.find_blob(oid)?;
+                let content = blob.content();
+
+                (content[..content.len().min(limit)].to_vec(), content.len())
+            }
+        };
+
+        let mut response = preview.into_response();
+
+        if size > limit {
+            response
+                .headers_mut()
+                .insert("truncated", HeaderValue::from_static("true"));
+        }
+
+        Ok(response)
+    })
+    .await
+}
+
+fn find_blob_in_branch<'repo>(
+    repo: &'repo Repository,
+    file_path: &str,
+    branch_name: &str,
+) -> Result<Blob<'repo>, git2::Error> {
+    repo.find_blob(find_entry_in_branch(repo, file_path, branch_name)?.id())
+}
+
+/// The tree entry of the blob at `file_path` on a public branch.
+fn find_entry_in_branch(
+    repo: &Repository,
+    file_path: &str,
+    branch_name: &str,
+) -> Result<git2::TreeEntry<'static>, git2::Error> {
+    if !is_branch_public(repo, branch_name)? {
+        return Err(git2::Error::from_str("Branch is not public"));
+    }
+
+    let branch = repo.find_branch(branch_name, BranchType::Local)?;
+
+    let commit = branch.get().peel_to_commit()?;
+
+    let tree = commit.tree()?;
+
+    let entry = tree.get_path(std::path::Path::new(&normalize_path(file_path)))?;
+
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Err(git2::Error::from_str("Path does not point to a blob"));
+    }
+
+    Ok(entry)
+}
+
+#[derive(Debug, Serialize)]
+struct TreeSize {
+    tree: String,
+    size: u64,
+}
+
+async fn get_tree_size(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, reference)): Path<(Name, Name, String)>,
+) -> Result<Json<TreeSize>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tree = repo
+            .find_commit(resolve_public_commit(&repo, &reference)?)?
+            .tree()?;
+
+        let size = tree_size(&repo, &repo.odb()?, &tree, &state.tree_sizes)?;
+
+        Ok(Json(TreeSize {
+            tree: tree.id().to_string(),
+            size,
+        }))
+    })
+    .await
+}
+
+/// Sums the size of every blob reachable from `tree`, memoizing each subtree by oid.
+fn tree_size<const CAPACITY: usize>(
+    repo: &Repository,
+    odb: &Odb,
+    tree: &git2::Tree,
+    cache: &Cache<Oid, u64, CAPACITY>,
+) -> Result<u64, git2::Error> {
+    if let Some(size) = cache.get(&tree.id()) {
+        return Ok(size);
+    }
+
+    let mut size = 0;
+
+    for entry in tree {
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                let subtree = repo.find_tree(entry.id())?;
+
+                size += tree_size(repo, odb, &subtree, cache)?;
+            }
+            Some(ObjectType::Blob) => {
+                let (length, _) = odb.read_header(entry.id())?;
+
+                size += length as u64;
+            }
+            // Submodule commits live in another repository.
+            _ => {}
+        }
+    }
+
+    cache.insert(tree.id(), size);
+
+    Ok(size)
+}
+
+async fn get_ancestry_path(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, from, to)): Path<(Name, Name, String, String)>,
+) -> Result<Json<Vec<String>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let from = resolve_public_commit(&repo, &from)?;
+        let to = resolve_public_commit(&repo, &to)?;
+
+        let path = ancestry_path(&repo, from, to)?.ok_or(Error::NotFound)?;
+
+        Ok(Json(path.iter().map(Oid::to_string).collect()))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitTreeParams {
+    user: Name,
+    name: Name,
+    oid: String,
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Submodule,
+}
+
+#[derive(Debug, Serialize)]
+struct TreeEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: EntryKind,
+    oid: String,
+    mode: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct TreePageQuery {
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct TreePage {
+    entries: Vec<TreeEntry>,
+    /// Name of the last returned entry, to be passed as `after` for the next page.
+    next: Option<String>,
+}
+
+async fn get_commit_tree(
+    State(state): State<Arc<AppState>>,
+    Path(params): Path<CommitTreeParams>,
+    Query(query): Query<TreePageQuery>,
+) -> Result<Json<TreePage>, Error> {
+    blocking(move || {
+        let CommitTreeParams {
+            user,
+            name,
+            oid,
+            path,
+        } = params;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = find_public_commit(&repo, &oid)?;
+
+        let tree = subtree(&repo, commit.tree()?, path.as_deref())?.ok_or(Error::NotFound)?;
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_TREE_PAGE)
+            .clamp(1, MAX_TREE_PAGE);
+
+        // Git orders directories as if their name ended in a slash, so sort plainly by name to keep
+        // the cursor comparison meaningful.
+        let mut entries = list_tree(&tree);
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let start = match &query.after {
+            Some(after) => entries.partition_point(|entry| entry.name <= *after),
+            None => 0,
+        };
+
+        let has_more = entries.len() - start > limit;
+
+        let entries: Vec<_> = entries.into_iter().skip(start).take(limit).collect();
+
+        let next = has_more
+            .then(|| entries.last().map(|entry| entry.name.clone()))
+            .flatten();
+
+        Ok(Json(TreePage { entries, next }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    ignore_case: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchMatch {
+    path: String,
+    /// 1-based.
+    line: usize,
+    snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResults {
+    matches: Vec<SearchMatch>,
+    /// Whether the limit cut the results short.
+    truncated: bool,
+}
+
+/// Finds the lines containing `q` in the files of a ref's tree, like `git grep -F`. Binary and
+/// very large files are skipped.
+async fn search_code(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResults>, Error> {
+    blocking(move || {
+        if query.q.is_empty() {
+            return Err(Error::BadRequest("Empty search query".to_string()));
+        }
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+        let tree = repo.find_commit(commit)?.tree()?;
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_RESULTS)
+            .clamp(1, MAX_SEARCH_RESULTS);
+
+        let needle = if query.ignore_case {
+            query.q.to_lowercase()
+        } else {
+            query.q.clone()
+        };
+
+        let mut matches = Vec::new();
+        let mut truncated = false;
+        let mut error = None;
+
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+
+            let blob = match repo.find_blob(entry.id()) {
+                Ok(blob) => blob,
+                Err(blob_error) => {
+                    error = Some(blob_error);
+                    return TreeWalkResult::Abort;
+                }
+            };
+
+            if blob.size() > MAX_SEARCH_FILE_BYTES || blob.is_binary() {
+                return TreeWalkResult::Ok;
+            }
+
+            let path = format!("{root}{}", entry.name().unwrap_or_default());
+
+            for (index, line) in String::from_utf8_lossy(blob.content()).lines().enumerate() {
+                let found = if query.ignore_case {
+                    line.to_lowercase().contains(&needle)
+                } else {
+                    line.contains(&needle)
+                };
+
+                if !found {
+                    continue;
+                }
+
+                if matches.len() == limit {
+                    truncated = true;
+                    return TreeWalkResult::Abort;
+                }
+
+                matches.push(SearchMatch {
+                    path: path.clone(),
+                    line: index + 1,
+                    snippet: line.trim().chars().take(MAX_SNIPPET_CHARS).collect(),
+                });
+            }
+
+            TreeWalkResult::Ok
+        })
+        .or_else(|walk_error| match error.take() {
+            Some(error) => Err(error),
+            // Aborting stops the walk with an error of its own.
+            None if truncated => Ok(()),
+            None => Err(walk_error),
+        })?;
+
+        Ok(Json(SearchResults { matches, truncated }))
+    })
+    .await
+}
+
+async fn get_commit_refs(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, oid)): Path<(Name, Name, String)>,
+) -> Result<Json<Vec<String>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let target = find_public_commit(&repo, &oid)?.id();
+
+        let mut refs = Vec::new();
+
+        for reference in repo.references()? {
+            let reference = reference?;
+
+            let Some(name) = reference.name() else {
+                continue;
+            };
+
+            if reference.is_branch()
+                && !is_branch_public(&repo, reference.shorthand().unwrap_or_default())?
+            {
+                continue;
+            }
+
+            // Annotated tags point at a tag object, so compare what they ultimately tag.
+            let points_at = reference
+                .peel_to_commit()
+                .is_ok_and(|commit| commit.id() == target);
+
+            if points_at {
+                refs.push(name.to_string());
+            }
+        }
+
+        refs.sort();
+
+        Ok(Json(refs))
+    })
+    .await
+}
+
+/// Navigates from `tree` to the directory at `path`, returning `None` when nothing exists there.
+fn subtree<'repo>(
+    repo: &'repo Repository,
+    tree: git2::Tree<'repo>,
+    path: Option<&str>,
+) -> Result<Option<git2::Tree<'repo>>, Error> {
+    let path = normalize_path(path.unwrap_or_default());
+
+    if path.is_empty() {
+        return Ok(Some(tree));
+    }
+
+    let entry = match tree.get_path(std::path::Path::new(&path)) {
+        Ok(entry) => entry,
+        Err(error) if error.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    if entry.kind() != Some(ObjectType::Tree) {
+        return Err(Error::BadRequest(format!("{path} is not a directory")));
+    }
+
+    Ok(Some(repo.find_tree(entry.id())?))
+}
+
+#[derive(Debug, Deserialize)]
+struct DirDiffParams {
+    user: Name,
+    name: Name,
+    base: String,
+    head: String,
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeStatus {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Serialize)]
+struct DirChange {
+    name: String,
+    status: ChangeStatus,
+}
+
+async fn get_dir_diff(
+    State(state): State<Arc<AppState>>,
+    Path(params): Path<DirDiffParams>,
+) -> Result<Json<Vec<DirChange>>, Error> {
+    blocking(move || {
+        let DirDiffParams {
+            user,
+            name,
+            base,
+            head,
+            path,
+        } = params;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let base = repo.find_commit(resolve_public_commit(&repo, &base)?)?;
+        let head = repo.find_commit(resolve_public_commit(&repo, &head)?)?;
+
+        let base = subtree(&repo, base.tree()?, path.as_deref())?;
+        let head = subtree(&repo, head.tree()?, path.as_deref())?;
+
+        if base.is_none() && head.is_none() {
+            return Err(Error::NotFound);
+        }
+
+        // A directory missing on one side compares like an empty one.
+        let entries = |tree: &Option<git2::Tree>| -> BTreeMap<String, (Oid, i32)> {
+            tree.iter()
+                .flat_map(|tree| tree.iter())
+                .map(|entry| {
+                    let name = entry.name().unwrap_or_default().to_string();
+
+                    (name, (entry.id(), entry.filemode()))
+                })
+                .collect()
+        };
+
+        let base = entries(&base);
+        let head = entries(&head);
+
+        let mut changes = Vec::new();
+
+        for (name, old) in &base {
+            match head.get(name) {
+                None => changes.push(DirChange {
+                    name: name.clone(),
+                    status: ChangeStatus::Removed,
+                }),
+                Some(new) if new != old => changes.push(DirChange {
+                    name: name.clone(),
+                    status: ChangeStatus::Modified,
+                }),
+                Some(_) => {}
+            }
+        }
+
+        for name in head.keys().filter(|name| !base.contains_key(*name)) {
+            changes.push(DirChange {
+                name: name.clone(),
+                status: ChangeStatus::Added,
+            });
+        }
+
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Json(changes))
+    })
+    .await
+}
+
+/// Lists the immediate entries of `tree` without descending into subdirectories.
+fn list_tree(tree: &git2::Tree) -> Vec<TreeEntry> {
+    tree.iter()
+        .map(|entry| TreeEntry {
+            name: entry.name().unwrap_or_default().to_string(),
+            kind: match entry.kind() {
+                Some(ObjectType::Tree) => EntryKind::Directory,
+                Some(ObjectType::Commit) => EntryKind::Submodule,
+                _ if entry.filemode() == i32::from(FileMode::Link) => EntryKind::Symlink,
+                _ => EntryKind::File,
+            },
+            oid: entry.id().to_string(),
+            mode: entry.filemode(),
+        })
+        .collect()
+}
+
+async fn get_extensions(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, reference)): Path<(Name, Name, String)>,
+) -> Result<Json<BTreeMap<String, usize>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tree = repo
+            .find_commit(resolve_public_commit(&repo, &reference)?)?
+            .tree()?;
+
+        if let Some(extensions) = state.extensions.get(&tree.id()) {
+            return Ok(Json(extensions));
+        }
+
+        let mut extensions = BTreeMap::new();
+
+        // Files without an extension are counted under the empty key.
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                let extension = entry
+                    .name()
+                    .and_then(|name| std::path::Path::new(name).extension())
+                    .map(|extension| extension.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                *extensions.entry(extension).or_insert(0) += 1;
+            }
+
+            TreeWalkResult::Ok
+        })?;
+
+        state.extensions.insert(tree.id(), extensions.clone());
+
+        Ok(Json(extensions))
+    })
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct RepoAge {
+    oldest: Option<i64>,
+    newest: Option<i64>,
+}
+
+async fn get_age(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Json<RepoAge>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let head = match repo.head() {
+            Ok(head) => head.peel_to_commit()?,
+            Err(error) if error.code() == git2::ErrorCode::UnbornBranch => {
+                return Ok(Json(RepoAge {
+                    oldest: None,
+                    newest: None,
+                }));
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        if first_hidden(&repo, &[head.id()])?.is_some() {
+            return Err(Error::NotFound);
+        }
+
+        let newest = head.committer().when().seconds();
+
+        // The history below a commit never changes, so once HEAD has been walked only the
+        // commits it gained since need to be.
+        let cached = state.oldest_commits.get(&repo.path().to_path_buf());
+
+        let oldest = match cached {
+            Some((tip, oldest)) if tip == head.id() => oldest,
+            _ => {
+                let mut revwalk = repo.revwalk()?;
+                revwalk.push(head.id())?;
+
+                let mut oldest = newest;
+
+                if let Some((tip, cached)) = cached
+                    && repo.graph_descendant_of(head.id(), tip)?
+                {
+                    revwalk.hide(tip)?;
+                    oldest = oldest.min(cached);
+                }
+
+                for oid in revwalk {
+                    let commit = repo.find_commit(oid?)?;
+
+                    oldest = oldest.min(commit.committer().when().seconds());
+                }
+
+                state
+                    .oldest_commits
+                    .insert(repo.path().to_path_buf(), (head.id(), oldest));
+
+                oldest
+            }
+        };
+
+        Ok(Json(RepoAge {
+            oldest: Some(oldest),
+            newest: Some(newest),
+        }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorStatsQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AuthorStats {
+    email: String,
+    name: String,
+    commits: usize,
+    additions: usize,
+    deletions: usize,
+}
+
+async fn get_author_stats(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<AuthorStatsQuery>,
+) -> Result<Json<Vec<AuthorStats>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tip = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+
+        let key = (tip, query.since, query.until);
+
+        if let Some(stats) = state.author_stats.get(&key) {
+            return Ok(Json(stats));
+        }
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TIME)?;
+        revwalk.push(tip)?;
+
+        let mut authors = HashMap::<String, AuthorStats>::new();
+
+        let mut counted = 0;
+
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+            let time = commit.committer().when().seconds();
+
+            // Commits come newest first, so everything from here on is out of range too.
+            if query.since.is_some_and(|since| time < since) {
+                break;
+            }
+
+            if query.until.is_some_and(|until| time > until) {
+                continue;
+            }
+
+            // Diffing every commit is expensive, so only the most recent commits in the range
+            // are considered.
+            if counted == MAX_AUTHOR_STATS_COMMITS {
+                break;
+            }
+
+            counted += 1;
+
+            let parent_tree = match commit.parent(0) {
+                Ok(parent) => Some(parent.tree()?),
+                Err(_) => None,
+            };
+
+            let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+            let diff_stats = diff.stats()?;
+
+            let author = commit.author();
+            let email = author.email().unwrap_or_default().to_string();
+
+            let stats = authors.entry(email.clone()).or_insert_with(|| AuthorStats {
+                email,
+                name: author.name().unwrap_or_default().to_string(),
+                commits: 0,
+                additions: 0,
+                deletions: 0,
+            });
+
+            stats.commits += 1;
+            stats.additions += diff_stats.insertions();
+            stats.deletions += diff_stats.deletions();
+        }
+
+        let mut stats: Vec<_> = authors.into_values().collect();
+        stats.sort_by(|a, b| {
+            b.commits
+                .cmp(&a.commits)
+                .then_with(|| a.email.cmp(&b.email))
+        });
+
+        state.author_stats.insert(key, stats.clone());
+
+        Ok(Json(stats))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct ContributorsQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    /// Also counts the lines each contributor added and removed.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    lines: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Contributor {
+    name: String,
+    email: String,
+    commits: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    additions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deletions: Option<usize>,
+}
+
+/// Commit counts per author over the whole history, like `git shortlog -sne`. Identities are
+/// folded through the repository's mailmap.
+async fn get_contributors(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<ContributorsQuery>,
+) -> Result<Json<Vec<Contributor>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tip = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+
+        let key = (tip, query.lines);
+
+        if let Some(contributors) = state.contributors.get(&key) {
+            return Ok(Json(contributors));
+        }
+
+        let mailmap = repo.mailmap()?;
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(tip)?;
+
+        let mut contributors = HashMap::<(String, String), Contributor>::new();
+
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+            let author = commit.author_with_mailmap(&mailmap)?;
+
+            let name = author.name().unwrap_or_default().to_string();
+            let email = author.email().unwrap_or_default().to_string();
+
+            let contributor = contributors
+                .entry((name.clone(), email.clone()))
+                .or_insert_with(|| Contributor {
+                    name,
+                    email,
+                    commits: 0,
+                    additions: query.lines.then_some(0),
+                    deletions: query.lines.then_some(0),
+                });
+
+            contributor.commits += 1;
+
+            // Merges are left out, as their changes are already counted on the merged side.
+            if query.lines && commit.parent_count() <= 1 {
+                let parent_tree = match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree()?),
+                    Err(_) => None,
+                };
+
+                let stats = repo
+                    .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?
+                    .stats()?;
+
+                *contributor.additions.get_or_insert_default() += stats.insertions();
+                *contributor.deletions.get_or_insert_default() += stats.deletions();
+            }
+        }
+
+        let mut contributors: Vec<_> = contributors.into_values().collect();
+        contributors.sort_by(|a, b| {
+            b.commits
+                .cmp(&a.commits)
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.email.cmp(&b.email))
+        });
+
+        state.contributors.insert(key, contributors.clone());
+
+        Ok(Json(contributors))
+    })
+    .await
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Interval {
+    #[default]
+    Day,
+    /// Weeks starting on Monday, as ISO 8601 has them.
+    Week,
+}
+
+impl Interval {
+    /// Start of the bucket holding `time`, in UTC.
+    fn bucket(self, time: i64) -> i64 {
+        const DAY: i64 = 24 * 60 * 60;
+        // The epoch was a Thursday.
+        const MONDAY: i64 = 4 * DAY;
+
+        match self {
+            Self::Day => time.div_euclid(DAY) * DAY,
+            Self::Week => (time - MONDAY).div_euclid(7 * DAY) * 7 * DAY + MONDAY,
+        }
+    }
+
+    fn next(self, bucket: i64) -> i64 {
+        match self {
+            Self::Day => bucket + 24 * 60 * 60,
+            Self::Week => bucket + 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    #[serde(default)]
+    interval: Interval,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActivityBucket {
+    /// Unix timestamp the bucket starts at.
+    start: i64,
+    commits: usize,
+}
+
+/// Commits per day or week by committer date, for activity graphs. Every bucket between the
+/// first and last one is listed, empty or not.
+async fn get_activity(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<Json<Vec<ActivityBucket>>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tip = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+        let interval = query.interval;
+
+        let counts = match state.activity.get(&(tip, interval)) {
+            Some(counts) => counts,
+            None => {
+                let mut revwalk = repo.revwalk()?;
+                revwalk.push(tip)?;
+
+                let mut counts = BTreeMap::new();
+
+                for oid in revwalk {
+                    let time = repo.find_commit(oid?)?.committer().when().seconds();
+
+                    *counts.entry(interval.bucket(time)).or_default() += 1;
+                }
+
+                let counts = Arc::new(counts);
+
+                state.activity.insert((tip, interval), counts.clone());
+
+                counts
+            }
+        };
+
+        let (Some(&first), Some(&last)) = (counts.keys().next(), counts.keys().next_back()) else {
+            return Ok(Json(Vec::new()));
+        };
+
+        let first = query.since.map_or(first, |since| interval.bucket(since));
+        let last = query.until.map_or(last, |until| interval.bucket(until));
+
+        let mut buckets = Vec::new();
+        let mut start = first;
+
+        while start <= last {
+            if buckets.len() == MAX_ACTIVITY_BUCKETS {
+                return Err(Error::BadRequest(format!(
+                    "Time range spans more than {MAX_ACTIVITY_BUCKETS} buckets"
+                )));
+            }
+
+            buckets.push(ActivityBucket {
+                start,
+                commits: counts.get(&start).copied().unwrap_or_default(),
+            });
+
+            start = interval.next(start);
+        }
+
+        Ok(Json(buckets))
+    })
+    .await
+}
+
+#[derive(Debug, Serialize)]
+struct FastForward {
+    fast_forward: bool,
+}
+
+async fn get_can_fast_forward(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, branch, oid)): Path<(Name, Name, String, String)>,
+) -> Result<Json<FastForward>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if !is_branch_public(&repo, &branch)? {
+            return Err(Error::NotFound);
+        }
+
+        let tip = match repo.find_branch(&branch, BranchType::Local) {
+            Ok(branch) => branch.get().peel_to_commit()?.id(),
+            Err(_) => {
+                // Anything fast-forwards a branch that HEAD points at but has no commits yet.
+                let head = repo.find_reference("HEAD")?;
+                let unborn = head.symbolic_target() == Some(&format!("refs/heads/{branch}"))
+                    && repo.head().is_err();
+
+                if unborn {
+                    return Ok(Json(FastForward { fast_forward: true }));
+                }
+
+                return Err(Error::NotFound);
+            }
+        };
+
+        let new = find_public_commit(&repo, &oid)?.id();
+
+        let fast_forward = tip == new || repo.graph_descendant_of(new, tip)?;
+
+        Ok(Json(FastForward { fast_forward }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct GitConfigQuery {
+    key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigValue {
+    key: String,
+    value: String,
+}
+
+async fn get_git_config(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<GitConfigQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ConfigValue>>, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        // Only the repository's own config; global and system files describe the server host.
+        let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        let mut values = Vec::new();
+
+        let mut entries = match &query.key {
+            Some(key) => config.multivar(key, None)?,
+            None => config.entries(None)?,
+        };
+
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            let key = entry.name().unwrap_or_default().to_string();
+            let value = redact_config_value(&key, entry.value().unwrap_or_default());
+
+            values.push(ConfigValue { key, value });
+        }
+
+        if query.key.is_some() && values.is_empty() {
+            return Err(Error::NotFound);
+        }
+
+        Ok(Json(values))
+    })
+    .await
+}
+
+/// Hides values that commonly carry credentials: secrets named by their key, extra HTTP headers
+/// (typically `Authorization`) and passwords embedded in urls.
+fn redact_config_value(key: &str, value: &str) -> String {
+    let key = key.to_ascii_lowercase();
+
+    if ["password", "token", "secret", "extraheader", "cookie"]
+        .iter()
+        .any(|needle| key.contains(needle))
+    {
+        return "<redacted>".to_string();
+    }
+
+    match url::Url::parse(value) {
+        Ok(url) if url.password().is_some() => redact_url(value),
+        _ => value.to_string(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommitSummary {
+    id: String,
+    summary: String,
+    author: String,
+    date: i64,
+}
+
+impl From<&git2::Commit<'_>> for CommitSummary {
+    fn from(commit: &git2::Commit) -> Self {
+        Self {
+            id: commit.id().to_string(),
+            summary: commit.summary().unwrap_or_default().to_string(),
+            author: commit.author().name().unwrap_or_default().to_string(),
+            date: commit.committer().when().seconds(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileChange {
+    path: String,
+    old_path: Option<String>,
+    status: &'static str,
+    additions: usize,
+    deletions: usize,
+}
+
+/// Summarizes each file touched by `diff` with its line counts.
+fn file_changes(diff: &Diff) -> Result<Vec<FileChange>, git2::Error> {
+    let mut changes = Vec::new();
+
+    for (index, delta) in diff.deltas().enumerate() {
+        let (_, additions, deletions) = match git2::Patch::from_diff(diff, index)? {
+            Some(patch) => patch.line_stats()?,
+            // Binary files have no lines to count.
+            None => (0, 0, 0),
+        };
+
+        let path =
+            |file: git2::DiffFile| file.path().map(|path| path.to_string_lossy().into_owned());
+
+        let new_path = path(delta.new_file());
+        let old_path = path(delta.old_file());
+
+        changes.push(FileChange {
+            path: new_path.clone().or(old_path.clone()).unwrap_or_default(),
+            old_path: old_path.filter(|old_path| Some(old_path) != new_path.as_ref()),
+            status: match delta.status() {
+                Delta::Added => "added",
+                Delta::Deleted => "removed",
+                Delta::Renamed => "renamed",
+                Delta::Copied => "copied",
+                Delta::Typechange => "typechange",
+                _ => "modified",
+            },
+            additions,
+            deletions,
+        });
+    }
+
+    Ok(changes)
+}
+
+/// Renders `diff` as a unified patch.
+fn diff_patch(diff: &Diff) -> Result<String, git2::Error> {
+    let mut patch = Vec::new();
+
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+
+        patch.extend_from_slice(line.content());
+
+        true
+    })?;
+
+    Ok(String::from_utf8_lossy(&patch).into_owned())
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitDetailQuery {
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    patch: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct CommitDetail {
+    #[serde(flatten)]
+    commit: CommitInfo,
+    /// One diff per parent. Root commits get a single diff against the empty tree.
+    diffs: Vec<ParentDiff>,
+}
+
+#[derive(Debug, Serialize)]
+struct ParentDiff {
+    parent: Option<String>,
+    files: Vec<FileChange>,
+    patch: Option<String>,
+}
+
+async fn get_commit(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, oid)): Path<(Name, Name, String)>,
+    Query(query): Query<CommitDetailQuery>,
+) -> Result<Json<CommitDetail>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = find_public_commit(&repo, &oid)?;
+
+        let tree = commit.tree()?;
+
+        let parents: Vec<Option<git2::Commit>> = match commit.parent_count() {
+            0 => vec![None],
+            _ => commit.parents().map(Some).collect(),
+        };
+
+        let mut diffs = Vec::new();
+
+        for parent in parents {
+            let parent_tree = parent.as_ref().map(|parent| parent.tree()).transpose()?;
+
+            let mut diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+            diff.find_similar(None)?;
+
+            diffs.push(ParentDiff {
+                parent: parent.map(|parent| parent.id().to_string()),
+                files: file_changes(&diff)?,
+                patch: query.patch.then(|| diff_patch(&diff)).transpose()?,
+            });
+        }
+
+        Ok(Json(CommitDetail {
+            commit: CommitInfo::new(&state, &repo, &commit),
+            diffs,
+        }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseDiffQuery {
+    from: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    patch: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ReleaseDiff {
+    tag: String,
+    previous: Option<String>,
+    commits: Vec<CommitSummary>,
+    files: Vec<FileChange>,
+    patch: Option<String>,
+}
+
+async fn get_release_diff(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, tag)): Path<(Name, Name, String)>,
+    Query(query): Query<ReleaseDiffQuery>,
+) -> Result<Json<ReleaseDiff>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let tag_commit = |name: &str| -> Result<git2::Commit, Error> {
+            repo.find_reference(&format!("refs/tags/{name}"))
+                .and_then(|reference| reference.peel_to_commit())
+                .map_err(|_| Error::NotFound)
+        };
+
+        let head = tag_commit(&tag)?;
+
+        let previous = match query.from {
+            Some(from) => Some(from),
+            None => previous_tag(&repo, &tag, &head)?,
+        };
+
+        let base = previous.as_deref().map(tag_commit).transpose()?;
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        revwalk.push(head.id())?;
+
+        if let Some(base) = &base {
+            revwalk.hide(base.id())?;
+        }
+
+        let commits = revwalk
+            .map(|oid| Ok(CommitSummary::from(&repo.find_commit(oid?)?)))
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+
+        // The very first release is compared against an empty tree.
+        let base_tree = base.map(|base| base.tree()).transpose()?;
+        let mut diff = repo.diff_tree_to_tree(base_tree.as_ref(), Some(&head.tree()?), None)?;
+        diff.find_similar(None)?;
+
+        Ok(Json(ReleaseDiff {
+            tag,
+            previous,
+            commits,
+            files: file_changes(&diff)?,
+            patch: query.patch.then(|| diff_patch(&diff)).transpose()?,
+        }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CompareQuery {
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    patch: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Comparison {
+    base: String,
+    head: String,
+    /// Where the diff starts: the merge base for `base...head`, `base` itself for `base..head`.
+    merge_base: Option<String>,
+    /// Commits reachable from `head` but not from `base`, newest first.
+    commits: Vec<CommitSummary>,
+    files: Vec<FileChange>,
+    patch: Option<String>,
+}
+
+/// Compares two refs given as `base...head`, diffing from their merge base like a pull request
+/// would, or as `base..head` to diff the two trees directly.
+async fn get_compare(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, spec)): Path<(Name, Name, String)>,
+    Query(query): Query<CompareQuery>,
+) -> Result<Json<Comparison>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let (base, head, three_dot) = match spec.split_once("...") {
+            Some((base, head)) => (base, head, true),
+            None => match spec.split_once("..") {
+                Some((base, head)) => (base, head, false),
+                None => {
+                    return Err(Error::BadRequest(
+                        "Expected base...head or base..head".to_string(),
+                    ));
+                }
+            },
+        };
+
+        let base_id = resolve_public_commit(&repo, base)?;
+        let head_id = resolve_public_commit(&repo, head)?;
+
+        let start = if three_dot {
+            match repo.merge_base(base_id, head_id) {
+                Ok(merge_base) => Some(merge_base),
+                Err(error) if error.code() == git2::ErrorCode::NotFound => None,
+                Err(error) => return Err(error.into()),
+            }
+        } else {
+            Some(base_id)
+        };
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        revwalk.push(head_id)?;
+        revwalk.hide(base_id)?;
+
+        let commits = revwalk
+            .map(|oid| Ok(CommitSummary::from(&repo.find_commit(oid?)?)))
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+
+        // Unrelated histories are compared against an empty tree.
+        let start_tree = start.map(|oid| repo.find_commit(oid)?.tree()).transpose()?;
+        let head_tree = repo.find_commit(head_id)?.tree()?;
+
+        let mut diff = repo.diff_tree_to_tree(start_tree.as_ref(), Some(&head_tree), None)?;
+        diff.find_similar(None)?;
+
+        Ok(Json(Comparison {
+            base: base_id.to_string(),
+            head: head_id.to_string(),
+            merge_base: start.map(|oid| oid.to_string()),
+            commits,
+            files: file_changes(&diff)?,
+            patch: query.patch.then(|| diff_patch(&diff)).transpose()?,
+        }))
+    })
+    .await
+}
+
+/// Picks the tag released right before `tag`: the greatest lower version when both parse as
+/// versions, otherwise the most recent tag committed before it.
+fn previous_tag(
+    repo: &Repository,
+    tag: &str,
+    commit: &git2::Commit,
+) -> Result<Option<String>, git2::Error> {
+    let version = parse_version(tag);
+    let time = commit.committer().when().seconds();
+
+    let mut best: Option<(String, Option<Version>, i64)> = None;
+
+    for name in repo.tag_names(None)?.iter().flatten() {
+        if name == tag {
+            continue;
+        }
+
+        let Ok(candidate) = repo
+            .find_reference(&format!("refs/tags/{name}"))
+            .and_then(|reference| reference.peel_to_commit())
+        else {
+            continue;
+        };
+
+        let candidate_version = parse_version(name);
+        let candidate_time = candidate.committer().when().seconds();
+
+        let earlier = match (&version, &candidate_version) {
+            (Some(version), Some(candidate)) => candidate < version,
+            _ => candidate_time < time,
+        };
+
+        if !earlier {
+            continue;
+        }
+
+        let better = match &best {
+            None => true,
+            Some((_, best_version, best_time)) => match (&candidate_version, best_version) {
+                (Some(candidate), Some(best)) => candidate > best,
+                _ => candidate_time > *best_time,
+            },
+        };
+
+        if better {
+            best = Some((name.to_string(), candidate_version, candidate_time));
+        }
+    }
+
+    Ok(best.map(|(name, _, _)| name))
+}
+
+/// A loosely parsed semantic version: numeric components plus an optional pre-release suffix,
+/// which orders before the plain release.
+#[derive(Debug, PartialEq, Eq)]
+struct Version {
+    components: Vec<u64>,
+    pre_release: Option<String>,
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let length = self.components.len().max(other.components.len());
+        let component = |version: &Version, index| version.components.get(index).copied();
+
+        (0..length)
+            .map(|index| {
+                component(self, index)
+                    .unwrap_or(0)
+                    .cmp(&component(other, index).unwrap_or(0))
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| match (&self.pre_release, &other.pre_release) {
+                (None, None) => std::cmp::Ordering::Equal,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+fn parse_version(tag: &str) -> Option<Version> {
+    let tag = tag.strip_prefix(['v', 'V']).unwrap_or(tag);
+
+    let (version, pre_release) = match tag.split_once('-') {
+        Some((version, pre_release)) => (version, Some(pre_release.to_string())),
+        None => (tag, None),
+    };
+
+    let components = version
+        .split('.')
+        .map(|component| component.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    Some(Version {
+        components,
+        pre_release,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct Divergence {
+    ahead: usize,
+    behind: usize,
+    diverged: bool,
+}
+
+async fn get_divergence(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, a, b)): Path<(Name, Name, String, String)>,
+) -> Result<Json<Divergence>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let a = resolve_public_commit(&repo, &a)?;
+        let b = resolve_public_commit(&repo, &b)?;
+
+        let (ahead, behind) = repo.graph_ahead_behind(a, b)?;
+
+        Ok(Json(Divergence {
+            ahead,
+            behind,
+            diverged: ahead > 0 && behind > 0,
+        }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct AheadBehindQuery {
+    /// Branch to compare against instead of the default branch.
+    base: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BranchDivergence {
+    name: String,
+    #[serde(flatten)]
+    divergence: Divergence,
+}
+
+#[derive(Debug, Serialize)]
+struct AheadBehind {
+    base: String,
+    branches: Vec<BranchDivergence>,
+}
+
+/// How far each public branch is ahead of and behind the default branch.
+async fn get_branches_ahead_behind(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<AheadBehindQuery>,
+) -> Result<Json<AheadBehind>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let base = match query.base {
+            Some(base) => base,
+            None => repo
+                .find_reference("HEAD")?
+                .symbolic_target()
+                .and_then(|target| target.strip_prefix("refs/heads/"))
+                .ok_or(Error::NotFound)?
+                .to_string(),
+        };
+
+        if !is_branch_public(&repo, &base)? {
+            return Err(Error::NotFound);
+        }
+
+        let base_id = repo
+            .find_branch(&base, BranchType::Local)
+            .map_err(|_| Error::NotFound)?
+            .get()
+            .peel_to_commit()?
+            .id();
+
+        let mut branches = Vec::new();
+
+        for branch in repo.branches(Some(BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = branch.name()?.unwrap_or_default().to_string();
+
+            if name == base || !is_branch_public(&repo, &name)? {
+                continue;
+            }
+
+            let (ahead, behind) =
+                repo.graph_ahead_behind(branch.get().peel_to_commit()?.id(), base_id)?;
+
+            branches.push(BranchDivergence {
+                name,
+                divergence: Divergence {
+                    ahead,
+                    behind,
+                    diverged: ahead > 0 && behind > 0,
+                },
+            });
+        }
+
+        Ok(Json(AheadBehind { base, branches }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitsQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    /// 1-based page number.
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Person {
+    name: String,
+    email: String,
+    date: i64,
+    /// Offset from UTC in minutes.
+    offset: i32,
+}
+
+impl From<git2::Signature<'_>> for Person {
+    fn from(signature: git2::Signature) -> Self {
+        Self {
+            name: signature.name().unwrap_or_default().to_string(),
+            email: signature.email().unwrap_or_default().to_string(),
+            date: signature.when().seconds(),
+            offset: signature.when().offset_minutes(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommitInfo {
+    id: String,
+    author: Person,
+    committer: Person,
+    message: String,
+    parents: Vec<String>,
+    /// Null for unsigned commits.
+    verification: Option<signatures::Verification>,
+}
+
+impl CommitInfo {
+    fn new(state: &AppState, repo: &Repository, commit: &git2::Commit) -> Self {
+        Self {
+            id: commit.id().to_string(),
+            author: commit.author().into(),
+            committer: commit.committer().into(),
+            message: String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+            verification: state.keys.verify(repo, commit.id()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CommitPage {
+    commits: Vec<CommitInfo>,
+    /// Page to request next, if there are more commits.
+    next_page: Option<usize>,
+}
+
+/// Lists the history of a ref, newest first.
+async fn get_commits(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<CommitsQuery>,
+) -> Result<Json<CommitPage>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let start = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_COMMITS_PAGE)
+            .clamp(1, MAX_COMMITS_PAGE);
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TIME)?;
+        revwalk.push(start)?;
+
+        // Take one extra commit to find out whether there is another page.
+        let oids = revwalk
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page + 1)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let next_page = (oids.len() > per_page).then_some(page + 1);
+
+        let commits = oids
+            .into_iter()
+            .take(per_page)
+            .map(|oid| Ok(CommitInfo::new(&state, &repo, &repo.find_commit(oid)?)))
+            .collect::<Result<_, git2::Error>>()?;
+
+        Ok(Json(CommitPage { commits, next_page }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitSearchQuery {
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    /// Part of the author's name or email.
+    author: Option<String>,
+    /// Part of the commit message.
+    message: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    /// 1-based page number.
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+/// Lists the commits of a ref matching every given filter, newest first. Text filters ignore
+/// case; the time range applies to committer dates, like `git log --since`.
+async fn search_commits(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<CommitSearchQuery>,
+) -> Result<Json<CommitPage>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let start = resolve_public_commit(&repo, query.reference.as_deref().unwrap_or("HEAD"))?;
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_COMMITS_PAGE)
+            .clamp(1, MAX_COMMITS_PAGE);
+
+        let author = query.author.as_deref().map(str::to_lowercase);
+        let message = query.message.as_deref().map(str::to_lowercase);
+
+        let matches = |commit: &git2::Commit| {
+            let time = commit.committer().when().seconds();
+
+            if query.since.is_some_and(|since| time < since)
+                || query.until.is_some_and(|until| time > until)
+            {
+                return false;
+            }
+
+            if let Some(author) = &author {
+                let signature = commit.author();
+                let name = String::from_utf8_lossy(signature.name_bytes()).to_lowercase();
+                let email = String::from_utf8_lossy(signature.email_bytes()).to_lowercase();
+
+                if !name.contains(author) && !email.contains(author) {
+                    return false;
+                }
+            }
+
+            message.as_ref().is_none_or(|message| {
+                String::from_utf8_lossy(commit.message_bytes())
+                    .to_lowercase()
+                    .contains(message)
+            })
+        };
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TIME)?;
+        revwalk.push(start)?;
+
+        let mut skip = (page - 1).saturating_mul(per_page);
+        let mut commits = Vec::new();
+        let mut next_page = None;
+
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+
+            if !matches(&commit) {
+                continue;
+            }
+
+            if skip > 0 {
+                skip -= 1;
+            } else if commits.len() == per_page {
+                next_page = Some(page + 1);
+                break;
+            } else {
+                commits.push(CommitInfo::new(&state, &repo, &commit));
+            }
+        }
+
+        Ok(Json(CommitPage { commits, next_page }))
+    })
+    .await
+}
+
+/// Streams a snapshot of a ref's tree, e.g. `archive/v1.0.tar.gz` or `archive/main.zip`.
+async fn get_archive(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, file_name)): Path<(Name, Name, String)>,
+) -> Result<Response, Error> {
+    let (reference, format) = archive::Format::from_file_name(&file_name).ok_or(Error::NotFound)?;
+    let reference = reference.to_string();
+
+    let metrics_state = state.clone();
+
+    let (path, commit, prefix) = blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let commit = resolve_public_commit(&repo, &reference)?;
+
+        // Matches the top-level directory of the archives popular forges hand out.
+        let prefix = format!(
+            "{}-{}",
+            name.trim_end_matches(".git"),
+            reference.replace('/', "-")
+        );
+
+        Ok((repo.path().to_path_buf(), commit, prefix))
+    })
+    .await?;
+
+    let disposition = format!("attachment; filename=\"{prefix}.{}\"", format.extension());
+
+    let body = BodyWriter::spawn(move |writer| {
+        let repo = Repository::open_bare(path).map_err(io::Error::other)?;
+
+        metrics_state.metrics.time("archive", || {
+            archive::write(&repo, commit, &prefix, format, writer)
+        })
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct BundleQuery {
+    /// Comma-separated refs, by full or short name.
+    refs: Option<String>,
+}
+
+/// Packs refs into a git bundle for offline transfers and backups, e.g. `bundle?refs=main,v1.0`.
+/// Without `refs`, every public branch and tag goes in, along with `HEAD`.
+async fn get_bundle(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<BundleQuery>,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let is_public = |reference: &str| match reference.strip_prefix("refs/heads/") {
+            Some(branch) => is_branch_public(&repo, branch),
+            None => Ok(true),
+        };
+
+        let mut refs = Vec::new();
+
+        match &query.refs {
+            Some(specs) => {
+                for spec in specs
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|spec| !spec.is_empty())
+                {
+                    let reference = repo
+                        .resolve_reference_from_short_name(spec)
+                        .map_err(|_| Error::NotFound)?;
+
+                    let (Some(name), Some(oid)) = (reference.name(), reference.resolve()?.target())
+                    else {
+                        return Err(Error::NotFound);
+                    };
+
+                    if !is_public(name)? {
+                        return Err(Error::NotFound);
+                    }
+
+                    refs.push((name.to_string(), oid));
+                }
+            }
+            None => {
+                if let Ok(head) = repo.head()
+                    && let (Some(name), Some(oid)) = (head.name(), head.target())
+                    && is_public(name)?
+                {
+                    refs.push(("HEAD".to_string(), oid));
+                }
+
+                for reference in repo.references()? {
+                    let reference = reference?;
+
+                    if let (Some(name), Some(oid)) = (reference.name(), reference.target())
+                        && (name.starts_with("refs/heads/") || name.starts_with("refs/tags/"))
+                        && is_public(name)?
+                    {
+                        refs.push((name.to_string(), oid));
+                    }
+                }
+            }
+        }
+
+        if refs.is_empty() {
+            return Err(Error::BadRequest("No refs to bundle".to_string()));
+        }
+
+        debug!("Bundling {} refs of {user}/{name}", refs.len());
+
+        let bundle = state
+            .metrics
+            .time("bundle", || protocol::bundle(&repo, &refs))?;
+
+        let disposition = format!(
+            "attachment; filename=\"{}.bundle\"",
+            name.trim_end_matches(".git")
+        );
+
+        Ok((
+            [
+                (header::CONTENT_TYPE, "application/x-git-bundle".to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            bundle,
+        )
+            .into_response())
+    })
+    .await
+}
+
+/// Resolves any revision spec (branch, tag, sha, ...) to the commit it points at.
+fn resolve_commit(repo: &Repository, spec: &str) -> Result<Oid, Error> {
+    repo.revparse_single(spec)
+        .and_then(|object| object.peel_to_commit())
+        .map(|commit| commit.id())
+        .map_err(|_| Error::NotFound)
+}
+
+/// Like [`resolve_commit`], but refuses commits only branches that aren't public lead to.
+fn resolve_public_commit(repo: &Repository, spec: &str) -> Result<Oid, Error> {
+    let oid = resolve_commit(repo, spec)?;
+
+    if first_hidden(repo, &[oid])?.is_some() {
+        return Err(Error::NotFound);
+    }
+
+    Ok(oid)
+}
+
+/// Finds the commit an oid or a prefix of one names, refusing ones only branches that aren't
+/// public lead to.
+fn find_public_commit<'r>(repo: &'r Repository, oid: &str) -> Result<git2::Commit<'r>, Error> {
+    let commit = repo
+        .find_commit_by_prefix(oid)
+        .map_err(|_| Error::NotFound)?;
+
+    if first_hidden(repo, &[commit.id()])?.is_some() {
+        return Err(Error::NotFound);
+    }
+
+    Ok(commit)
+}
+
+/// Finds the shortest chain of parent links leading from `from` down to `to`,
+/// giving up once `MAX_ANCESTRY_DEPTH` commits have been visited.
+fn ancestry_path(repo: &Repository, from: Oid, to: Oid) -> Result<Option<Vec<Oid>>, git2::Error> {
+    let mut previous = HashMap::from([(from, from)]);
+    let mut queue = VecDeque::from([from]);
+
+    while let Some(oid) = queue.pop_front() {
+        if oid == to {
+            let mut path = vec![to];
+
+            let mut current = to;
+            while current != from {
+                current = previous[&current];
+                path.push(current);
+            }
+
+            path.reverse();
+
+            return Ok(Some(path));
+        }
+
+        if previous.len() > MAX_ANCESTRY_DEPTH {
+            break;
+        }
+
+        for parent in repo.find_commit(oid)?.parent_ids() {
+            if let Entry::Vacant(entry) = previous.entry(parent) {
+                entry.insert(oid);
+                queue.push_back(parent);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Which refs clients may see, going by the glob patterns stored under
+/// `gitserver.publicbranches` in the repository config. Repositories without any pattern expose
+/// every branch, and refs other than branches are always visible.
+struct RefFilter {
+    patterns: Vec<String>,
+}
+
+impl RefFilter {
+    fn new(repo: &Repository) -> Result<Self, git2::Error> {
+        let mut patterns = Vec::new();
+
+        repo.config()?
+            .multivar(PUBLIC_BRANCHES_KEY, None)?
+            .for_each(|entry| {
+                if let Some(pattern) = entry.value() {
+                    patterns.push(pattern.to_string());
+                }
+            })?;
+
+        Ok(Self { patterns })
+    }
+
+    /// Whether any branch could be hidden.
+    fn is_active(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    fn is_branch_public(&self, branch: &str) -> bool {
+        !self.is_active()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), branch.as_bytes()))
+    }
+
+    /// Whether the ref with the full name `name` is visible.
+    fn is_visible(&self, name: &str) -> bool {
+        match name.strip_prefix("refs/heads/") {
+            Some(branch) => self.is_branch_public(branch),
+            None => true,
+        }
+    }
+}
+
+/// Checks `branch` against the public branch patterns of the repository.
+fn is_branch_public(repo: &Repository, branch: &str) -> Result<bool, git2::Error> {
+    Ok(RefFilter::new(repo)?.is_branch_public(branch))
+}
+
+/// The first of `objects` that no visible ref leads to, if any. Whatever only hidden branches
+/// lead to, nothing leads to anymore or a fork borrows from its original has to stay out of
+/// reach of clients, however they name it.
+fn first_hidden(repo: &Repository, objects: &[Oid]) -> Result<Option<Oid>, git2::Error> {
+    let filter = RefFilter::new(repo)?;
+
+    let mut commits = HashSet::new();
+    let mut others = HashSet::new();
+
+    for &oid in objects {
+        match repo.find_object(oid, None).map(|object| object.kind()) {
+            Ok(Some(ObjectType::Commit)) => commits.insert(oid),
+            Ok(_) => others.insert(oid),
+            Err(_) => return Ok(Some(oid)),
+        };
+    }
+
+    let mut tips = Vec::new();
+
+    for reference in repo.references()? {
+        let reference = reference?;
+
+        if let (Some(name), Some(target)) = (reference.name(), reference.target())
+            && filter.is_visible(name)
+        {
+            tips.push(target);
+        }
+    }
+
+    // A detached HEAD is visible like any ref that isn't a branch.
+    if let Ok(head) = repo.find_reference("HEAD")
+        && let Some(target) = head.target()
+    {
+        tips.push(target);
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    let mut trees = Vec::new();
+
+    for tip in tips {
+        // Annotated tags are visible along with everything they point at.
+        let mut object = repo.find_object(tip, None)?;
+
+        while let Some(tag) = object.as_tag() {
+            let target = tag.target()?;
+
+            others.remove(&object.id());
+            object = target;
+        }
+
+        match object.kind() {
+            Some(ObjectType::Commit) => {
+                commits.remove(&object.id());
+                revwalk.push(object.id())?;
+            }
+            Some(ObjectType::Tree) => trees.push(object.id()),
+            _ => {
+                others.remove(&object.id());
+            }
+        }
+    }
+
+    // Only trees and blobs need the trees of every commit to be looked through.
+    let mut seen = HashSet::new();
+    let mut visit = |others: &mut HashSet<Oid>, tree| {
+        protocol::walk_tree(repo, tree, &mut seen, &mut |oid, _| {
+            others.remove(&oid);
+            Ok(())
+        })
+    };
+
+    for tree in trees {
+        visit(&mut others, tree)?;
+    }
+
+    for commit in revwalk {
+        if commits.is_empty() && others.is_empty() {
+            break;
+        }
+
+        let commit = commit?;
+        commits.remove(&commit);
+
+        if !others.is_empty() {
+            visit(&mut others, repo.find_commit(commit)?.tree_id())?;
+        }
+    }
+
+    Ok(commits.into_iter().chain(others).next())
+}
+
+/// Minimal glob matcher supporting `*` (any run of characters) and `?` (a single character).
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::SocketAddr};
+
+    use axum::body::{Body, to_bytes};
+    use flate2::{Compression, write::GzEncoder};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    pub(crate) const ADMIN_TOKEN: &str = "test-admin-token";
+
+    fn admin_state() -> Arc<AppState> {
+        Arc::new(AppState {
+            settings: RwLock::new(Settings {
+                admin_token: Some(ADMIN_TOKEN.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// A server with its repository root in a temporary directory of its own, which goes away
+    /// along with it.
+    pub(crate) struct TestServer {
+        pub(crate) state: Arc<AppState>,
+        pub(crate) app: Router,
+    }
+
+    impl TestServer {
+        /// A server for the test called `label`, which has to be unique among the tests.
+        pub(crate) fn new(label: &str) -> Self {
+            let root = env::temp_dir().join(format!("git-server-test-{label}-{}", process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+
+            let state = Arc::new(AppState {
+                options: Options {
+                    repo_root: root,
+                    ..Default::default()
+                },
+                settings: RwLock::new(Settings {
+                    admin_token: Some(ADMIN_TOKEN.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+
+            Self {
+                app: app(state.clone()),
+                state,
+            }
+        }
+
+        pub(crate) fn root(&self) -> &std::path::Path {
+            &self.state.options.repo_root
+        }
+
+        /// Creates the repository `name` of `user`, `name` ending in `.git`.
+        pub(crate) fn init_repo(&self, user: &str, name: &str) -> Repository {
+            Repository::init_bare(self.state.repo_path(user, name)).unwrap()
+        }
+
+        /// Sends a request with `token` as its credentials and `body` as JSON, answering with
+        /// the status and body of the response.
+        pub(crate) async fn send(
+            &self,
+            method: Method,
+            uri: &str,
+            token: Option<&str>,
+            body: Option<serde_json::Value>,
+        ) -> (StatusCode, Vec<u8>) {
+            let mut request = Request::builder().method(method).uri(uri);
+
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+
+            let body = match body {
+                Some(body) => {
+                    request = request.header(header::CONTENT_TYPE, "application/json");
+                    Body::from(body.to_string())
+                }
+                None => Body::empty(),
+            };
+
+            let response = self
+                .app
+                .clone()
+                .oneshot(request.body(body).unwrap())
+                .await
+                .unwrap();
+
+            let status = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+            (status, body.to_vec())
+        }
+
+        /// Like [`TestServer::send`], with a JSON response. Empty bodies are null.
+        pub(crate) async fn json(
+            &self,
+            method: Method,
+            uri: &str,
+            token: Option<&str>,
+            body: Option<serde_json::Value>,
+        ) -> (StatusCode, serde_json::Value) {
+            let (status, body) = self.send(method, uri, token, body).await;
+
+            let body = if body.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::from_slice(&body).unwrap()
+            };
+
+            (status, body)
+        }
+
+        /// Registers `user` and issues them an access token.
+        pub(crate) async fn user_token(&self, user: &str) -> String {
+            let (status, _) = self
+                .send(
+                    Method::POST,
+                    "/users",
+                    Some(ADMIN_TOKEN),
+                    Some(serde_json::json!({ "name": user })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED);
+
+            let (status, token) = self
+                .json(
+                    Method::POST,
+                    "/tokens",
+                    Some(ADMIN_TOKEN),
+                    Some(serde_json::json!({ "name": "test", "user": user })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED);
+
+            token["token"].as_str().unwrap().to_string()
+        }
+
+        /// Issues a deploy token for the repository `name` of `user`.
+        pub(crate) async fn deploy_token(&self, user: &str, name: &str, write: bool) -> String {
+            let (status, token) = self
+                .json(
+                    Method::POST,
+                    &format!("/repo/{user}/{name}/deploy-tokens"),
+                    Some(ADMIN_TOKEN),
+                    Some(serde_json::json!({ "name": "deploy", "write": write })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED);
+
+            token["token"].as_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.root());
+        }
+    }
+
+    /// Commits `files` on top of `parents`, moving `reference` to the commit if given.
+    pub(crate) fn commit(
+        repo: &Repository,
+        reference: Option<&str>,
+        files: &[(&str, &[u8])],
+        parents: &[Oid],
+    ) -> Oid {
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+
+        let mut tree = repo.treebuilder(None).unwrap();
+        for (name, content) in files {
+            tree.insert(name, repo.blob(content).unwrap(), 0o100644)
+                .unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|&oid| repo.find_commit(oid).unwrap())
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+
+        repo.commit(reference, &signature, &signature, "commit", &tree, &parents)
+            .unwrap()
+    }
+
+    fn create_repo_request(body: Vec<u8>) -> Request {
+        Request::post("/repo")
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn repo_paths_ignore_case() {
+        let state = AppState::default();
+
+        assert_eq!(
+            state.repo_path("Alice", "Demo.git"),
+            state.repo_path("alice", "demo.git")
+        );
+        assert_eq!(
+            state.repo_path("ALICE", "demo.GIT"),
+            PathBuf::from("repos/alice/demo.git")
+        );
+    }
+
+    #[test]
+    fn names_reject_path_tricks() {
+        for name in ["alice", "demo.git", "my_repo-2"] {
+            assert!(Name::is_valid(name), "{name}");
+        }
+
+        for name in [
+            "", ".", "..", ".trash", "../etc", "a/b", "a\\b", "a\0b", "/abs",
+        ] {
+            assert!(!Name::is_valid(name), "{name:?}");
+        }
+
+        assert!(!Name::is_valid(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn trailing_slashes_resolve_like_plain_paths() {
+        let dir = env::temp_dir().join(format!("git-server-test-{}", process::id()));
+        let repo = Repository::init_bare(&dir).unwrap();
+
+        let blob = repo.blob(b"hello").unwrap();
+
+        let mut docs = repo.treebuilder(None).unwrap();
+        docs.insert("index.md", blob, 0o100644).unwrap();
+        let docs = docs.write().unwrap();
+
+        let mut root = repo.treebuilder(None).unwrap();
+        root.insert("docs", docs, 0o040000).unwrap();
+        let root = repo.find_tree(root.write().unwrap()).unwrap();
+
+        for path in ["docs", "docs/", "docs//", "/docs"] {
+            let tree = subtree(&repo, root.clone(), Some(path)).unwrap().unwrap();
+            assert_eq!(tree.id(), docs, "{path}");
+        }
+
+        assert_eq!(normalize_path("docs//index.md/"), "docs/index.md");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_branches_stay_hidden_however_spelled() {
+        let dir = env::temp_dir().join(format!("git-server-test-hidden-{}", process::id()));
+        let repo = Repository::init_bare(&dir).unwrap();
+
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+
+        let main = repo
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                "main",
+                &tree,
+                &[],
+            )
+            .unwrap();
+        let parent = repo.find_commit(main).unwrap();
+        let internal = repo
+            .commit(
+                Some("refs/heads/internal"),
+                &signature,
+                &signature,
+                "internal",
+                &tree,
+                &[&parent],
+            )
+            .unwrap();
+
+        repo.config()
+            .unwrap()
+            .set_multivar(PUBLIC_BRANCHES_KEY, "^$", "main")
+            .unwrap();
+
+        let short = internal.to_string()[..7].to_string();
+
+        for spec in [
+            "internal",
+            "heads/internal",
+            "refs/heads/internal",
+            "internal~0",
+            &internal.to_string(),
+            &short,
+        ] {
+            assert!(
+                matches!(resolve_public_commit(&repo, spec), Err(Error::NotFound)),
+                "{spec}"
+            );
+        }
+
+        for spec in ["main", "refs/heads/main", "internal~1", &main.to_string()] {
+            assert_eq!(resolve_public_commit(&repo, spec).unwrap(), main, "{spec}");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dumb_protocol_is_off_while_branches_are_hidden() {
+        let server = TestServer::new("dumb-hidden");
+        let repo = server.init_repo("test", "r.git");
+
+        let main = commit(&repo, Some("refs/heads/main"), &[("a", b"public")], &[]);
+        commit(
+            &repo,
+            Some("refs/heads/internal"),
+            &[("a", b"internal only")],
+            &[main],
+        );
+
+        let blob = repo.blob(b"internal only").unwrap().to_string();
+        let paths = [
+            "refs/heads/main".to_string(),
+            "refs/heads/internal".to_string(),
+            format!("objects/{}/{}", &blob[..2], &blob[2..]),
+        ];
+
+        for (hiding, expected) in [(false, StatusCode::OK), (true, StatusCode::NOT_FOUND)] {
+            if hiding {
+                repo.config()
+                    .unwrap()
+                    .set_multivar(PUBLIC_BRANCHES_KEY, "^$", "main")
+                    .unwrap();
+            }
+
+            for path in &paths {
+                let uri = format!("/repo/test/r.git/{path}");
+                let (status, _) = server.send(Method::GET, &uri, None, None).await;
+
+                assert_eq!(status, expected, "{path}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn age_is_only_told_of_public_heads() {
+        let server = TestServer::new("age-hidden");
+        let repo = server.init_repo("test", "r.git");
+
+        let main = commit(&repo, Some("refs/heads/main"), &[("a", b"public")], &[]);
+        commit(
+            &repo,
+            Some("refs/heads/internal"),
+            &[("a", b"internal")],
+            &[main],
+        );
+
+        repo.config()
+            .unwrap()
+            .set_multivar(PUBLIC_BRANCHES_KEY, "^$", "main")
+            .unwrap();
+
+        for (head, expected) in [
+            ("main", StatusCode::OK),
+            ("internal", StatusCode::NOT_FOUND),
+        ] {
+            repo.set_head(&format!("refs/heads/{head}")).unwrap();
+
+            let (status, _) = server
+                .send(Method::GET, "/repo/test/r.git/age", None, None)
+                .await;
+            assert_eq!(status, expected, "{head}");
+        }
+    }
+
+    #[test]
+    fn last_modified_follows_the_merged_side() {
+        let dir = env::temp_dir().join(format!("git-server-test-merged-{}", process::id()));
+        let repo = Repository::init_bare(&dir).unwrap();
+
+        let commit = |time, files: &[(&str, &[u8])], parents: &[Oid]| {
+            let signature =
+                git2::Signature::new("Test", "test@example.com", &git2::Time::new(time, 0))
+                    .unwrap();
+
+            let mut tree = repo.treebuilder(None).unwrap();
+            for (name, content) in files {
+                tree.insert(name, repo.blob(content).unwrap(), 0o100644)
+                    .unwrap();
+            }
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+            let parents: Vec<_> = parents
+                .iter()
+                .map(|&oid| repo.find_commit(oid).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+
+            repo.commit(None, &signature, &signature, "commit", &tree, &parents)
+                .unwrap()
+        };
+
+        let root = commit(1, &[("a", b"1"), ("b", b"1")], &[]);
+        let side = commit(2, &[("a", b"side"), ("b", b"1")], &[root]);
+        // The mainline changes the file after the side branch did, but the merge keeps the
+        // side's version, so that is where the file's history leads.
+        let main = commit(3, &[("a", b"main"), ("b", b"1")], &[root]);
+        let merge = commit(4, &[("a", b"side"), ("b", b"1")], &[main, side]);
+
+        let found = last_modified(&repo, &repo.find_commit(merge).unwrap()).unwrap();
+
+        assert_eq!(found["a"], side);
+        assert_eq!(found["b"], root);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readiness_needs_the_repo_root() {
+        let server = TestServer::new("readyz");
+
+        let (status, body) = server.json(Method::GET, "/readyz", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["libgit2"]["ok"], true);
+
+        fs::remove_dir_all(server.root()).unwrap();
+
+        let (status, body) = server.json(Method::GET, "/readyz", None, None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["checks"]["repo_root"]["ok"], false);
+        assert_eq!(body["checks"]["libgit2"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn create_repo_accepts_gzip_json() {
+        // The invalid remote url is rejected before anything touches the disk, and the message
+        // can only come back if the body was inflated and parsed.
+        let body =
+            gzip(br#"{"user":"test","name":"gzip","remotes":[{"name":"origin","url":"nope"}]}"#);
+
+        let response = app(admin_state())
+            .oneshot(create_repo_request(body))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "bad_request");
+        assert_eq!(body["message"], "Invalid remote url: nope");
+    }
+
+    #[tokio::test]
+    async fn create_repo_rejects_oversized_compressed_body() {
+        let body = vec![0; MAX_REQUEST_BODY_BYTES + 1];
+
+        let response = app(admin_state())
+            .oneshot(create_repo_request(body))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn dotted_repo_names_keep_their_dots() {
+        let server = TestServer::new("dotted-names");
+
+        for name in ["v1.2", "v1.7", "site.io.git"] {
+            let (status, _) = server
+                .send(
+                    Method::POST,
+                    "/repo",
+                    Some(ADMIN_TOKEN),
+                    Some(serde_json::json!({ "user": "test", "name": name })),
+                )
+                .await;
+
+            assert_eq!(status, StatusCode::OK, "{name}");
+        }
+
+        for dir in ["v1.2.git", "v1.7.git", "site.io.git"] {
+            assert!(server.root().join("test").join(dir).is_dir(), "{dir}");
+        }
+
+        // Moves name the new directory the same way.
+        let (_, moved) = server
+            .json(
+                Method::PATCH,
+                "/repo/test/v1.2.git",
+                Some(ADMIN_TOKEN),
+                Some(serde_json::json!({ "name": "v1.3" })),
+            )
+            .await;
+
+        assert_eq!(moved["name"], "v1.3.git");
+    }
+
+    #[tokio::test]
+    async fn previews_are_cut_at_the_requested_length() {
+        let server = TestServer::new("preview");
+        let repo = server.init_repo("test", "preview.git");
+        commit(
+            &repo,
+            Some("refs/heads/main"),
+            &[("big", &[0xff; 100])],
+            &[],
+        );
+
+        for (bytes, length, truncated) in [(10, 10, true), (100, 100, false), (200, 100, false)] {
+            let request = Request::get(format!(
+                "/repo/test/preview.git/preview/main/big?bytes={bytes}"
+            ))
+            .body(Body::empty())
+            .unwrap();
+
+            let response = server.app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().contains_key("truncated"),
+                truncated,
+                "{bytes}"
+            );
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body[..], [0xff; 100][..length]);
+        }
+    }
+
+    #[tokio::test]
+    async fn create_repo_requires_credentials() {
+        let request = Request::post("/repo")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"user":"test","name":"anonymous"}"#))
+            .unwrap();
+
+        let response = app(admin_state()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[test]
+    fn tokens_of_users_only_cover_their_own_repos() {
+        let token = |user: Option<&str>| auth::Principal::Token {
+            id: "id".to_string(),
+            name: "ci".to_string(),
+            user: user.map(str::to_string),
+        };
+
+        assert!(token(Some("alice")).owns("Alice"));
+        assert!(token(Some("alice")).can_write("alice", "repo"));
+        assert!(!token(Some("alice")).owns("bob"));
+        assert!(!token(Some("alice")).can_read("bob", "repo"));
+        assert!(token(None).owns("bob"));
+        assert!(auth::Principal::Admin.owns("bob"));
+    }
+
+    /// Sends a smart HTTP request for `service` of the repository `test/wire.git`.
+    async fn wire_request(
+        app: &Router,
+        service: &str,
+        version: Option<&str>,
+        body: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut request = Request::post(format!("/repo/test/wire.git/{service}"))
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .header(
+                header::CONTENT_TYPE,
+                format!("application/x-{service}-request"),
+            );
+
+        if let Some(version) = version {
+            request = request.header("git-protocol", version);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    /// The text packets of a response and whatever it sent on side-band channel 1.
+    fn wire_response(response: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut lines = Vec::new();
+        let mut pack = Vec::new();
+
+        for packet in protocol::pkt_line::Reader::new(response) {
+            match packet.unwrap().text() {
+                Some([1, data @ ..]) => pack.extend_from_slice(data),
+                Some(line) => lines.push(String::from_utf8_lossy(line).into_owned()),
+                None => {}
+            }
+        }
+
+        (lines, pack)
+    }
+
+    #[tokio::test]
+    async fn pushes_and_fetches_round_trip() {
+        let dir = env::temp_dir().join(format!("git-server-test-wire-{}", process::id()));
+        let origin = Repository::init_bare(dir.join("test").join("wire.git")).unwrap();
+
+        let app = app(Arc::new(AppState {
+            options: Options {
+                repo_root: dir.clone(),
+                ..Default::default()
+            },
+            settings: RwLock::new(Settings {
+                admin_token: Some(ADMIN_TOKEN.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+
+        // Everything pushed comes from a repository of its own.
+        let source = Repository::init_bare(dir.join("source.git")).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+
+        let mut tree = source.treebuilder(None).unwrap();
+        tree.insert("README.md", source.blob(b"hello").unwrap(), 0o100644)
+            .unwrap();
+        let tree = source.find_tree(tree.write().unwrap()).unwrap();
+        let commit = source
+            .commit(None, &signature, &signature, "first", &tree, &[])
+            .unwrap();
+
+        let mut pack = git2::Buf::new();
+        let mut builder = source.packbuilder().unwrap();
+        builder.insert_commit(commit).unwrap();
+        builder.write_buf(&mut pack).unwrap();
+
+        let zero = Oid::zero();
+        let push = |commands: &[(Oid, &str)], capabilities: &str, pack: &[u8]| {
+            let mut body = Vec::new();
+
+            for (index, (old, name)) in commands.iter().enumerate() {
+                let line = format!("{old} {commit} {name}");
+
+                match index {
+                    0 => protocol::pkt_line::write(
+                        &mut body,
+                        format!("{line}\0{capabilities}\n").as_bytes(),
+                    ),
+                    _ => protocol::pkt_line::write_line(&mut body, &line),
+                }
+            }
+
+            protocol::pkt_line::flush(&mut body);
+            body.extend_from_slice(pack);
+            body
+        };
+
+        let response = wire_request(
+            &app,
+            "git-receive-pack",
+            None,
+            push(
+                &[(zero, "refs/heads/main")],
+                "report-status side-band-64k",
+                &pack,
+            ),
+        )
+        .await;
+
+        // The report is nested inside side-band packets.
+        let (_, report) = wire_response(&response);
+        assert_eq!(
+            wire_response(&report).0,
+            ["unpack ok", "ok refs/heads/main"]
+        );
+        assert_eq!(origin.refname_to_id("refs/heads/main").unwrap(), commit);
+
+        // Claiming main doesn't exist yet is stale now, and fails the whole atomic push.
+        let response = wire_request(
+            &app,
+            "git-receive-pack",
+            None,
+            push(
+                &[(zero, "refs/heads/other"), (zero, "refs/heads/main")],
+                "report-status atomic",
+                &[],
+            ),
+        )
+        .await;
+
+        assert_eq!(
+            wire_response(&response).0,
+            [
+                "unpack ok",
+                "ng refs/heads/other stale info",
+                "ng refs/heads/main stale info"
+            ]
+        );
+        assert!(origin.find_reference("refs/heads/other").is_err());
+
+        let mut v0 = Vec::new();
+        protocol::pkt_line::write_line(&mut v0, &format!("want {commit} side-band-64k"));
+        protocol::pkt_line::flush(&mut v0);
+        protocol::pkt_line::write_line(&mut v0, "done");
+
+        let mut v2 = Vec::new();
+        protocol::pkt_line::write_line(&mut v2, "command=fetch");
+        protocol::pkt_line::delimiter(&mut v2);
+        protocol::pkt_line::write_line(&mut v2, &format!("want {commit}"));
+        protocol::pkt_line::write_line(&mut v2, "done");
+        protocol::pkt_line::flush(&mut v2);
+
+        for (version, body, expected) in [(None, v0, "NAK"), (Some("version=2"), v2, "packfile")] {
+            let response = wire_request(&app, "git-upload-pack", version, body).await;
+            let (lines, pack) = wire_response(&response);
+
+            assert_eq!(lines, [expected], "{version:?}");
+
+            let clone = Repository::init_bare(dir.join("clone.git")).unwrap();
+            let odb = clone.odb().unwrap();
+            let mut writer = odb.packwriter().unwrap();
+            writer.write_all(&pack).unwrap();
+            writer.commit().unwrap();
+
+            assert_eq!(clone.find_commit(commit).unwrap().tree_id(), tree.id());
+
+            fs::remove_dir_all(dir.join("clone.git")).unwrap();
+        }
+
+        // A v0 partial clone leaves the blob out and fetches it by oid when it needs it.
+        let blob = tree.get_name("README.md").unwrap().id();
+        let unreachable = origin.blob(b"unreachable").unwrap();
+
+        for (want, filter, expected) in [
+            (commit, Some("blob:none"), Some(false)),
+            (blob, None, Some(true)),
+            (unreachable, None, None),
+        ] {
+            let mut body = Vec::new();
+            protocol::pkt_line::write_line(&mut body, &format!("want {want} side-band-64k"));
+
+            if let Some(filter) = filter {
+                protocol::pkt_line::write_line(&mut body, &format!("filter {filter}"));
+            }
+
+            protocol::pkt_line::flush(&mut body);
+            protocol::pkt_line::write_line(&mut body, "done");
+
+            let response = wire_request(&app, "git-upload-pack", None, body).await;
+            let (lines, pack) = wire_response(&response);
+
+            let Some(has_blob) = expected else {
+                assert_eq!(lines, [format!("ERR upload-pack: not our ref {want}")]);
+                continue;
+            };
+
+            assert_eq!(lines, ["NAK"], "{want}");
+
+            let clone = Repository::init_bare(dir.join("clone.git")).unwrap();
+            let odb = clone.odb().unwrap();
+            let mut writer = odb.packwriter().unwrap();
+            writer.write_all(&pack).unwrap();
+            writer.commit().unwrap();
+
+            assert_eq!(odb.exists(blob), has_blob, "{want}");
+
+            fs::remove_dir_all(dir.join("clone.git")).unwrap();
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conditional_requests_match_weak_tags() {
+        let respond = |if_none_match: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, if_none_match.parse().unwrap());
+
+            conditional(&headers, "abc", || Ok("body")).unwrap()
+        };
+
+        for matching in [r#"W/"abc""#, r#""abc""#, r#""x", W/"abc""#, "*"] {
+            assert_eq!(
+                respond(matching).status(),
+                StatusCode::NOT_MODIFIED,
+                "{matching}"
+            );
+        }
+
+        let response = respond(r#""abcd""#);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], r#"W/"abc""#);
+    }
+
+    #[test]
+    fn webhook_signatures_are_hmac_sha1() {
+        // RFC 2202, test cases 2 and 6.
+        assert_eq!(
+            webhooks::sign(b"Jefe", b"what do ya want for nothing?"),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            webhooks::sign(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn lfs_object_ids_are_sha256() {
+        // FIPS 180-2 examples, and the lengths around the padding boundary.
+        for (message, digest) in [
+            (
+                &b"abc"[..],
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                &[b'a'; 55],
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                &[b'a'; 56],
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                &[b'a'; 64],
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+        ] {
+            assert_eq!(auth::hex(&lfs::sha256(message)), digest);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit_answers_with_retry_after() {
+        let state = Arc::new(AppState {
+            settings: RwLock::new(Settings {
+                rate_limit: Some(config::RateLimit {
+                    per_minute: 6,
+                    burst: 2,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let app = app(state);
+
+        for expected in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(rate_limit::ClientAddr(Some(
+                    SocketAddr::from(([192, 0, 2, 1], 1234)),
+                ))));
+
+            let response = app.clone().oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), expected);
+
+            if expected == StatusCode::TOO_MANY_REQUESTS {
+                assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_is_normalized_and_checked() {
+        let metadata = metadata::Metadata {
+            description: Some("  A small git server \n".to_string()),
+            homepage: Some("https://example.com/docs".to_string()),
+            topics: vec!["Rust".to_string(), "git".to_string(), "rust".to_string()],
+        }
+        .validate()
+        .unwrap();
+
+        assert_eq!(metadata.description.as_deref(), Some("A small git server"));
+        assert_eq!(metadata.topics, ["rust", "git"]);
+
+        for (homepage, topic) in [("ftp://example.com", "git"), ("https://x.org", "c++")] {
+            let metadata = metadata::Metadata {
+                description: None,
+                homepage: Some(homepage.to_string()),
+                topics: vec![topic.to_string()],
+            };
+
+            assert!(metadata.validate().is_err(), "{homepage} {topic}");
+        }
+    }
+
+    #[test]
+    fn languages_are_detected_by_extension_and_shebang() {
+        for (path, content, expected) in [
+            ("src/main.rs", &b"#!/bin/sh"[..], Some("rust")),
+            (
+                "bin/tool",
+                b"#!/usr/bin/env -S python3.12 -u\n",
+                Some("python"),
+            ),
+            ("configure", b"#!/bin/bash\nset -e\n", Some("shell")),
+            ("README", b"# not a shebang", None),
+        ] {
+            let language = highlight::detect(path, content).map(|language| language.name);
+
+            assert_eq!(language, expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn gitmodules_map_paths_to_urls() {
+        let submodules = parse_gitmodules(
+            "[core]\n\turl = nope\n\
+             [submodule \"lib\"]\n\tpath = vendor/lib/\n\turl = https://example.com/lib.git\n\
+             ; a comment\n\
+             [submodule \"docs\"]\n\tURL = \"../docs.git\"\n\tPath = docs\n\
+             [submodule \"broken\"]\n\tpath = broken\n",
+        );
+
+        assert_eq!(
+            submodules,
+            HashMap::from([
+                (
+                    "vendor/lib".to_string(),
+                    "https://example.com/lib.git".to_string()
+                ),
+                ("docs".to_string(), "../docs.git".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn markdown_is_sanitized() {
+        let html = markdown::render(
+            "# Hi <script>\n\n[x](javascript:alert(1)) [y](docs/a.md) ![z](img.png)\n",
+            &|url, image| format!("/{image}/{url}"),
+        );
+
+        assert_eq!(
+            html,
+            "<h1 id=\"hi-script\">Hi &lt;script&gt;</h1>\n\
+             <p>x <a href=\"/false/docs/a.md\">y</a> <img src=\"/true/img.png\" alt=\"z\" /></p>\n"
+        );
+    }
 }
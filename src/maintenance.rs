@@ -0,0 +1,559 @@
+//! Repository maintenance: expiring old reflog entries, repacking and pruning.
+//!
+//! `POST /repo/{user}/{name}/maintenance` packs everything reachable from the refs and their
+//! reflogs into a single new pack, then deletes the loose objects and packs it supersedes,
+//! unreachable objects included. Anything written within [`GRACE_PERIOD`] is kept unless the
+//! new pack has it, so pushes and API writes racing a repack don't lose the objects they are
+//! about to point a ref at. Packs with a `.keep` file are left alone, as git does.
+//!
+//! With `GIT_SERVER_AUTO_GC_LOOSE_OBJECTS` set, the same runs in the background once a push
+//! leaves more loose objects than that, or more than [`AUTO_PACK_LIMIT`] packs, much like
+//! `git gc --auto`. A repository is only ever maintained by one run at a time.
+//!
+//! Forks change both sides of this. A repository with forks keeps whatever the new pack
+//! doesn't have, as the forks may still need it, and a fork leaves what it borrows from the
+//! original out of its pack.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{extract::State, http::HeaderMap};
+use git2::{Oid, Repository};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    AppState, Error, Json, Name, Path, auth::require_admin, blocking, dir_size, forks, protocol,
+};
+
+/// Objects and packs younger than this are never deleted.
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// Age at which reflog entries expire, git's default for reachable ones.
+const REFLOG_EXPIRY: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+/// Packs a push may leave behind before maintenance runs, git's `gc.autoPackLimit`.
+const AUTO_PACK_LIMIT: usize = 50;
+/// Loose objects are estimated from this one of the 256 fan-out directories, as git does.
+const SAMPLE_DIR: &str = "17";
+
+/// Repositories undergoing maintenance, by path.
+#[derive(Debug, Default)]
+pub struct Locks(Mutex<HashSet<PathBuf>>);
+
+/// Held for the duration of a maintenance run.
+struct Lock<'a> {
+    locks: &'a Locks,
+    path: PathBuf,
+}
+
+impl Locks {
+    fn try_lock(&self, path: &FsPath) -> Option<Lock<'_>> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf())
+            .then(|| Lock {
+                locks: self,
+                path: path.to_path_buf(),
+            })
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    loose_objects: usize,
+    packs: usize,
+    /// Bytes taken up by the object database.
+    size: u64,
+}
+
+impl Stats {
+    fn read(objects: &FsPath) -> io::Result<Self> {
+        Ok(Self {
+            loose_objects: loose_objects(objects)?.len(),
+            packs: packs(objects)?.len(),
+            size: dir_size(objects)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    before: Stats,
+    after: Stats,
+    expired_reflog_entries: usize,
+}
+
+/// The loose objects of the object database at `objects`.
+fn loose_objects(objects: &FsPath) -> io::Result<Vec<PathBuf>> {
+    let mut loose = Vec::new();
+
+    for entry in fs::read_dir(objects)? {
+        let entry = entry?;
+
+        if is_fan_out(&entry.file_name().to_string_lossy()) {
+            loose.extend(objects_in(&entry.path())?);
+        }
+    }
+
+    Ok(loose)
+}
+
+fn is_fan_out(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The loose objects in a single fan-out directory.
+fn objects_in(dir: &FsPath) -> io::Result<Vec<PathBuf>> {
+    let mut objects = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name.len() == 38 && name.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            objects.push(entry.path());
+        }
+    }
+
+    Ok(objects)
+}
+
+/// The id of the loose object at `path`, which is named after it.
+fn loose_oid(path: &FsPath) -> Option<Oid> {
+    let dir = path.parent()?.file_name()?.to_str()?;
+    let file = path.file_name()?.to_str()?;
+
+    Oid::from_str(&format!("{dir}{file}")).ok()
+}
+
+/// The `pack-<name>.pack` files of the object database at `objects`.
+fn packs(objects: &FsPath) -> io::Result<Vec<PathBuf>> {
+    let dir = objects.join("pack");
+
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut packs = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "pack")
+        {
+            packs.push(path);
+        }
+    }
+
+    Ok(packs)
+}
+
+/// The objects listed in a version 2 pack index.
+fn indexed_objects(idx: &FsPath) -> io::Result<HashSet<Oid>> {
+    let data = fs::read(idx)?;
+
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported pack index {}", idx.display()),
+        )
+    };
+
+    // Magic, version, and 256 cumulative object counts by first byte, the last being the total.
+    if data.get(..8) != Some(b"\xfftOc\0\0\0\x02") || data.len() < 8 + 256 * 4 {
+        return Err(invalid());
+    }
+
+    let count = u32::from_be_bytes(data[8 + 255 * 4..8 + 256 * 4].try_into().unwrap()) as usize;
+
+    data.get(8 + 256 * 4..)
+        .and_then(|ids| ids.get(..count * 20))
+        .ok_or_else(invalid)?
+        .chunks(20)
+        .map(|id| Oid::from_bytes(id).map_err(|_| invalid()))
+        .collect()
+}
+
+fn is_recent(path: &FsPath, now: SystemTime) -> io::Result<bool> {
+    let modified = fs::metadata(path)?.modified()?;
+
+    Ok(now
+        .duration_since(modified)
+        .is_ok_and(|age| age < GRACE_PERIOD)
+        // Clocks going backwards make everything recent rather than everything stale.
+        || modified > now)
+}
+
+/// Drops reflog entries older than [`REFLOG_EXPIRY`], returning how many there were.
+fn expire_reflogs(repo: &Repository, names: &[String]) -> Result<usize, git2::Error> {
+    let cutoff = SystemTime::now()
+        .checked_sub(REFLOG_EXPIRY)
+        .and_then(|cutoff| cutoff.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |cutoff| cutoff.as_secs() as i64);
+
+    let mut expired = 0;
+
+    for name in names {
+        let mut reflog = repo.reflog(name)?;
+        let before = reflog.len();
+
+        // Entries are newest first, so removing from the back keeps the indices valid.
+        for index in (0..before).rev() {
+            let stale = reflog
+                .get(index)
+                .is_some_and(|entry| entry.committer().when().seconds() < cutoff);
+
+            if stale {
+                reflog.remove(index, false)?;
+            }
+        }
+
+        if reflog.len() < before {
+            expired += before - reflog.len();
+            reflog.write()?;
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Everything the new pack has to contain: what refs point at and what their reflogs still
+/// remember.
+fn tips(repo: &Repository, names: &[String]) -> Result<Vec<Oid>, git2::Error> {
+    let mut tips = HashSet::new();
+
+    for name in names {
+        if let Ok(reference) = repo.find_reference(name)
+            && let Some(target) = reference.target()
+        {
+            tips.insert(target);
+        }
+
+        for entry in repo.reflog(name)?.iter() {
+            tips.insert(entry.id_new());
+        }
+    }
+
+    // Reflogs may remember objects long gone, and the zero id of a ref's creation.
+    Ok(tips
+        .into_iter()
+        .filter(|&oid| repo.find_object(oid, None).is_ok())
+        .collect())
+}
+
+/// `HEAD` and the names of all refs.
+fn ref_names(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut names = vec!["HEAD".to_string()];
+
+    for reference in repo.references()? {
+        if let Some(name) = reference?.name() {
+            names.push(name.to_string());
+        }
+    }
+
+    Ok(names)
+}
+
+/// Packs everything a fork uses into the fork itself and stops it borrowing objects, so the
+/// original can go away.
+pub fn dissociate(repo: &Repository) -> Result<(), Error> {
+    let tips = tips(repo, &ref_names(repo)?)?;
+
+    if !tips.is_empty() {
+        protocol::repack(repo, &tips, &[])?;
+    }
+
+    fs::remove_file(repo.path().join("objects").join("info").join("alternates"))?;
+
+    Ok(())
+}
+
+/// Repacks and prunes the repository, one of those below `root`.
+fn run(repo: &Repository, root: &FsPath) -> Result<Report, Error> {
+    let objects = repo.path().join("objects");
+    let before = Stats::read(&objects)?;
+
+    let names = ref_names(repo)?;
+    let expired_reflog_entries = expire_reflogs(repo, &names)?;
+
+    // Forks may use objects that are unreachable here, so only what was repacked can go.
+    let forked = !forks::forks_of(root, &objects.canonicalize()?)?.is_empty();
+
+    let tips = tips(repo, &names)?;
+    let repacked = if tips.is_empty() {
+        None
+    } else {
+        let name = protocol::repack(repo, &tips, &forks::borrowed_tips(repo)?)?;
+        let path = objects.join("pack").join(format!("pack-{name}.pack"));
+        let packed = indexed_objects(&path.with_extension("idx"))?;
+
+        Some((path, packed))
+    };
+
+    let now = SystemTime::now();
+
+    for pack in packs(&objects)? {
+        let superseded = match &repacked {
+            Some((path, _)) if *path == pack => continue,
+            Some((_, packed)) => indexed_objects(&pack.with_extension("idx"))?.is_subset(packed),
+            None => false,
+        };
+
+        if pack.with_extension("keep").exists() || !superseded && (forked || is_recent(&pack, now)?)
+        {
+            continue;
+        }
+
+        debug!("Deleting {}", pack.display());
+
+        // The index goes first, so the pack is never listed without being there.
+        for extension in ["idx", "pack", "rev", "bitmap"] {
+            match fs::remove_file(pack.with_extension(extension)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+    }
+
+    for object in loose_objects(&objects)? {
+        let packed = match &repacked {
+            Some((_, packed)) => loose_oid(&object).is_some_and(|oid| packed.contains(&oid)),
+            None => false,
+        };
+
+        if !is_recent(&object, now)? && (packed || !forked) {
+            fs::remove_file(object)?;
+        }
+    }
+
+    for entry in fs::read_dir(&objects)? {
+        let entry = entry?;
+
+        if is_fan_out(&entry.file_name().to_string_lossy()) {
+            // Fails for directories still holding recent objects, which is fine.
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+
+    Ok(Report {
+        before,
+        after: Stats::read(&objects)?,
+        expired_reflog_entries,
+    })
+}
+
+pub async fn run_maintenance(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+) -> Result<Json<Report>, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let Some(_lock) = state.maintenance.try_lock(repo.path()) else {
+            return Err(Error::Conflict);
+        };
+
+        let report = state
+            .metrics
+            .time("maintenance", || run(&repo, &state.options.repo_root))?;
+
+        info!("Maintained {user}/{name}: {report:?}");
+
+        Ok(Json(report))
+    })
+    .await
+}
+
+/// Starts maintenance in the background if a push left the repository at `path` with more
+/// loose objects or packs than configured.
+pub fn after_push(state: &Arc<AppState>, path: &FsPath) {
+    let Some(threshold) = state.settings.read().unwrap().auto_gc_loose_objects else {
+        return;
+    };
+
+    let objects = path.join("objects");
+
+    let loose = match objects_in(&objects.join(SAMPLE_DIR)) {
+        Ok(sample) => sample.len() * 256,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+        Err(error) => {
+            warn!(
+                "Failed to count loose objects in {}: {error}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    let packs = packs(&objects).map_or(0, |packs| packs.len());
+
+    if loose <= threshold && packs <= AUTO_PACK_LIMIT {
+        return;
+    }
+
+    debug!(
+        "About {loose} loose objects and {packs} packs in {}, starting maintenance",
+        path.display()
+    );
+
+    let state = state.clone();
+    let path = path.to_path_buf();
+    let span = tracing::Span::current();
+
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+
+        let Some(_lock) = state.maintenance.try_lock(&path) else {
+            debug!("Maintenance of {} already running", path.display());
+            return;
+        };
+
+        let result = Repository::open_bare(&path)
+            .map_err(Error::from)
+            .and_then(|repo| {
+                state
+                    .metrics
+                    .time("maintenance", || run(&repo, &state.options.repo_root))
+            });
+
+        match result {
+            Ok(report) => info!("Maintained {}: {report:?}", path.display()),
+            Err(error) => warn!("Maintenance of {} failed: {error:?}", path.display()),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{ADMIN_TOKEN, TestServer, commit};
+
+    /// Makes every object and pack of `repo` look older than the grace period.
+    fn age(repo: &Repository) {
+        let then = SystemTime::now() - GRACE_PERIOD * 2;
+        let objects = repo.path().join("objects");
+
+        let mut files = loose_objects(&objects).unwrap();
+        for pack in packs(&objects).unwrap() {
+            files.extend(["idx", "pack"].map(|extension| pack.with_extension(extension)));
+        }
+
+        for file in files {
+            fs::File::open(file).unwrap().set_modified(then).unwrap();
+        }
+    }
+
+    #[test]
+    fn unreachable_objects_go_unless_recent_or_kept() {
+        let server = TestServer::new("maintenance-prune");
+        let repo = server.init_repo("alice", "r.git");
+
+        let main = commit(&repo, Some("refs/heads/main"), &[("a", b"main")], &[]);
+        let gone = repo.blob(b"gone").unwrap();
+
+        // A pack marked to be kept holds the only copy of an object.
+        let kept = repo.blob(b"kept").unwrap();
+        let mut builder = repo.packbuilder().unwrap();
+        builder.insert_object(kept, None).unwrap();
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack).unwrap();
+
+        let odb = repo.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        io::Write::write_all(&mut writer, &pack).unwrap();
+        writer.commit().unwrap();
+
+        let objects = repo.path().join("objects");
+        let kept_pack = packs(&objects).unwrap().remove(0);
+        fs::write(kept_pack.with_extension("keep"), "").unwrap();
+        fs::remove_file(
+            objects
+                .join(&kept.to_string()[..2])
+                .join(&kept.to_string()[2..]),
+        )
+        .unwrap();
+
+        age(&repo);
+        let fresh = repo.blob(b"fresh").unwrap();
+
+        let report = run(&repo, server.root()).unwrap();
+        assert_eq!(report.after.loose_objects, 1);
+        assert_eq!(report.after.packs, 2);
+
+        let repo = Repository::open_bare(repo.path()).unwrap();
+        assert_eq!(repo.find_commit(main).unwrap().tree().unwrap().len(), 1);
+        assert!(repo.find_blob(gone).is_err());
+        assert!(repo.find_blob(fresh).is_ok());
+        assert!(repo.find_blob(kept).is_ok());
+        assert!(kept_pack.exists());
+    }
+
+    #[tokio::test]
+    async fn forks_keep_what_they_borrow() {
+        let server = TestServer::new("maintenance-forks");
+        let original = server.init_repo("alice", "r.git");
+
+        let main = commit(&original, Some("refs/heads/main"), &[("a", b"main")], &[]);
+        let old = commit(&original, Some("refs/heads/old"), &[("a", b"old")], &[main]);
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/repo/alice/r.git/fork",
+                Some(ADMIN_TOKEN),
+                Some(json!({ "user": "bob" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let fork = Repository::open_bare(server.state.repo_path("bob", "r.git")).unwrap();
+        let own = commit(&fork, Some("refs/heads/main"), &[("a", b"fork")], &[main]);
+
+        // Only the fork still has a branch leading to `old`.
+        original
+            .find_reference("refs/heads/old")
+            .unwrap()
+            .delete()
+            .unwrap();
+
+        age(&original);
+        age(&fork);
+
+        run(&fork, server.root()).unwrap();
+
+        // The fork packs what it can no longer borrow and leaves out what it still can.
+        let packs = packs(&fork.path().join("objects")).unwrap();
+        assert_eq!(packs.len(), 1);
+
+        let packed = indexed_objects(&packs[0].with_extension("idx")).unwrap();
+        assert!(packed.contains(&own) && packed.contains(&old));
+        assert!(!packed.contains(&main));
+
+        run(&original, server.root()).unwrap();
+
+        let fork = Repository::open_bare(fork.path()).unwrap();
+        for commit in [main, old, own] {
+            assert!(fork.find_commit(commit).unwrap().tree().is_ok(), "{commit}");
+        }
+    }
+}
@@ -0,0 +1,1397 @@
+//! A small Markdown renderer producing HTML that is safe to embed in a page.
+//!
+//! It covers what READMEs and docs actually use: CommonMark's headings, paragraphs, lists,
+//! blockquotes, code blocks, emphasis, code spans, links and images, plus GitHub's tables,
+//! strikethrough, task lists and bare URLs. It is not a conforming CommonMark implementation:
+//! nesting and delimiter edge cases are resolved the simple way. Raw HTML is shown as text, and
+//! links and images keep only urls with a harmless scheme, so nothing in the output can run
+//! script.
+
+use std::{cell::Cell, collections::HashMap};
+
+/// Schemes links may use. Anything else, `javascript:` in particular, is dropped.
+const LINK_SCHEMES: &[&str] = &["http", "https", "mailto"];
+const IMAGE_SCHEMES: &[&str] = &["http", "https"];
+const EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkd"];
+/// Blocks and inlines nested deeper than this are shown as text, so hostile input can't
+/// exhaust the stack.
+const MAX_DEPTH: usize = 64;
+/// Longest entity name passed through, longer than any named entity.
+const MAX_ENTITY_LEN: usize = 32;
+/// Longest `(url "title")` looked for after a link's text.
+const MAX_DESTINATION_LEN: usize = 4096;
+
+/// Whether the file at `path` is written in Markdown, going by its extension.
+pub fn is_markdown(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            EXTENSIONS
+                .iter()
+                .any(|markdown| extension.eq_ignore_ascii_case(markdown))
+        })
+}
+
+/// Renders `source` to HTML. Relative urls are handed to `resolve` along with whether they
+/// point at an image, which turns them into urls of the file they refer to.
+pub fn render(source: &str, resolve: &dyn Fn(&str, bool) -> String) -> String {
+    let source = source.replace("\r\n", "\n").replace('\t', "    ");
+    let mut lines: Vec<&str> = source.lines().collect();
+
+    let mut renderer = Renderer {
+        definitions: take_definitions(&mut lines),
+        resolve,
+        slugs: HashMap::new(),
+        depth: Cell::new(0),
+        out: String::new(),
+    };
+
+    renderer.blocks(&lines, false);
+
+    renderer.out
+}
+
+struct Renderer<'a> {
+    /// Link reference definitions by normalized label: the url and optional title.
+    definitions: HashMap<String, (String, Option<String>)>,
+    resolve: &'a dyn Fn(&str, bool) -> String,
+    /// Heading ids handed out so far, and how often each was wanted.
+    slugs: HashMap<String, usize>,
+    /// How deeply the block or inline being rendered is nested.
+    depth: Cell<usize>,
+    out: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    Bullet(u8),
+    /// The delimiter after the number, `.` or `)`, and the number.
+    Ordered(u8, u64),
+}
+
+impl Marker {
+    fn same_list(self, other: Self) -> bool {
+        match (self, other) {
+            (Self::Bullet(a), Self::Bullet(b)) => a == b,
+            (Self::Ordered(a, _), Self::Ordered(b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Strips up to `width` leading spaces.
+fn dedent(line: &str, width: usize) -> &str {
+    &line[indent(line).min(width)..]
+}
+
+/// The character and length of a code fence opening `line`.
+fn fence(line: &str) -> Option<(u8, usize)> {
+    if indent(line) >= 4 {
+        return None;
+    }
+
+    let rest = line.trim_start();
+    let marker = *rest.as_bytes().first()?;
+
+    if marker != b'`' && marker != b'~' {
+        return None;
+    }
+
+    let len = run_length(rest.as_bytes(), 0, marker);
+
+    // Backticks in the info string would make it an inline code span instead.
+    (len >= 3 && !(marker == b'`' && rest[len..].contains('`'))).then_some((marker, len))
+}
+
+/// Whether `line` closes the code fence `opening`: a bare run of at least as many of the same
+/// character.
+fn closes_fence(line: &str, (marker, len): (u8, usize)) -> bool {
+    fence(line).is_some_and(|(closing, closing_len)| {
+        closing == marker && closing_len >= len && line.trim().bytes().all(|byte| byte == marker)
+    })
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    if indent(line) >= 4 {
+        return None;
+    }
+
+    let rest = line.trim_start();
+    let level = rest.bytes().take_while(|&byte| byte == b'#').count();
+
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+
+    let text = &rest[level..];
+
+    if !text.is_empty() && !text.starts_with(' ') {
+        return None;
+    }
+
+    // A closing sequence of hashes is only one if set off by a space.
+    let text = text.trim();
+    let unclosed = text.trim_end_matches('#');
+    let text = if unclosed.is_empty() || unclosed.ends_with(' ') {
+        unclosed.trim_end()
+    } else {
+        text
+    };
+
+    Some((level, text))
+}
+
+fn is_thematic_break(line: &str) -> bool {
+    if indent(line) >= 4 {
+        return false;
+    }
+
+    let rest: Vec<u8> = line.bytes().filter(|&byte| byte != b' ').collect();
+
+    rest.len() >= 3
+        && matches!(rest[0], b'*' | b'-' | b'_')
+        && rest.iter().all(|&byte| byte == rest[0])
+}
+
+/// The list marker starting `line` and the column its content starts at.
+fn list_marker(line: &str) -> Option<(Marker, usize)> {
+    let start = indent(line);
+
+    if start >= 4 {
+        return None;
+    }
+
+    let rest = &line[start..];
+    let bytes = rest.as_bytes();
+
+    let (marker, width) = match bytes.first()? {
+        byte @ (b'-' | b'*' | b'+') => (Marker::Bullet(*byte), 1),
+        _ => {
+            let digits = bytes
+                .iter()
+                .take_while(|byte| byte.is_ascii_digit())
+                .count();
+
+            if digits == 0 || digits > 9 {
+                return None;
+            }
+
+            let delimiter = *bytes.get(digits)?;
+
+            if delimiter != b'.' && delimiter != b')' {
+                return None;
+            }
+
+            (
+                Marker::Ordered(delimiter, rest[..digits].parse().ok()?),
+                digits + 1,
+            )
+        }
+    };
+
+    match bytes.get(width) {
+        None => Some((marker, start + width)),
+        Some(b' ') => {
+            // Content indented by five or more is indented code, counted from one space in.
+            let spaces = indent(&rest[width..]);
+            let spaces = if spaces > 4 || width + spaces == rest.len() {
+                1
+            } else {
+                spaces
+            };
+
+            Some((marker, start + width + spaces))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Whether `line` would start a block other than a paragraph, ending any paragraph before it.
+fn interrupts_paragraph(line: &str) -> bool {
+    fence(line).is_some()
+        || heading(line).is_some()
+        || is_thematic_break(line)
+        || line.trim_start().starts_with('>') && indent(line) < 4
+        || list_marker(line).is_some_and(|(marker, offset)| {
+            // Only lists that can't be mistaken for wrapped text interrupt, as in CommonMark.
+            !is_blank(&line[offset.min(line.len())..])
+                && matches!(marker, Marker::Bullet(_) | Marker::Ordered(_, 1))
+        })
+}
+
+fn setext_level(line: &str) -> Option<usize> {
+    if indent(line) >= 4 {
+        return None;
+    }
+
+    let rest = line.trim();
+
+    if !rest.is_empty() && rest.bytes().all(|byte| byte == b'=') {
+        Some(1)
+    } else if !rest.is_empty() && rest.bytes().all(|byte| byte == b'-') {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Splits a table row into its cells.
+fn table_cells(line: &str) -> Vec<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+
+    let mut cells = Vec::new();
+    let mut start = 0;
+    let bytes = line.as_bytes();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 1,
+            b'|' => {
+                cells.push(line[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+
+        index += 1;
+    }
+
+    cells.push(line[start..].trim());
+
+    cells
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Align {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+/// The column alignments of a table's delimiter row.
+fn table_alignments(line: &str) -> Option<Vec<Align>> {
+    if !line.contains('-') || indent(line) >= 4 {
+        return None;
+    }
+
+    table_cells(line)
+        .into_iter()
+        .map(|cell| {
+            let left = cell.starts_with(':');
+            let right = cell.ends_with(':');
+            let dashes = cell.trim_matches(':');
+
+            if dashes.is_empty() || !dashes.bytes().all(|byte| byte == b'-') {
+                return None;
+            }
+
+            Some(match (left, right) {
+                (true, true) => Align::Center,
+                (true, false) => Align::Left,
+                (false, true) => Align::Right,
+                (false, false) => Align::None,
+            })
+        })
+        .collect()
+}
+
+/// Link reference definitions are case-insensitive and ignore runs of whitespace.
+fn normalize_label(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Parses `[label]: url "title"`.
+fn definition(line: &str) -> Option<(String, String, Option<String>)> {
+    if indent(line) >= 4 {
+        return None;
+    }
+
+    let rest = line.trim().strip_prefix('[')?;
+    let (label, rest) = rest.split_once("]:")?;
+
+    if label.trim().is_empty() || label.contains(['[', ']']) {
+        return None;
+    }
+
+    let rest = rest.trim();
+    let (url, title) = match rest.split_once(char::is_whitespace) {
+        Some((url, title)) => (url, Some(title.trim())),
+        None => (rest, None),
+    };
+
+    let url = url
+        .strip_prefix('<')
+        .and_then(|url| url.strip_suffix('>'))
+        .unwrap_or(url);
+
+    if url.is_empty() {
+        return None;
+    }
+
+    let title = match title {
+        None => None,
+        Some(title) => {
+            let bytes = title.as_bytes();
+            let closing = match bytes.first() {
+                Some(b'"') => b'"',
+                Some(b'\'') => b'\'',
+                Some(b'(') => b')',
+                _ => return None,
+            };
+
+            if title.len() < 2 || bytes[title.len() - 1] != closing {
+                return None;
+            }
+
+            Some(title[1..title.len() - 1].to_string())
+        }
+    };
+
+    Some((normalize_label(label), url.to_string(), title))
+}
+
+/// Removes the link reference definitions outside code blocks from `lines`, returning them.
+/// Definitions only count where a paragraph could start.
+fn take_definitions(lines: &mut Vec<&str>) -> HashMap<String, (String, Option<String>)> {
+    let mut definitions = HashMap::new();
+    let mut kept = Vec::with_capacity(lines.len());
+    let mut open_fence = None;
+    let mut in_paragraph = false;
+
+    for &line in lines.iter() {
+        if let Some(opening) = open_fence {
+            if closes_fence(line, opening) {
+                open_fence = None;
+            }
+        } else if let Some(opening) = fence(line) {
+            open_fence = Some(opening);
+        } else if !in_paragraph && let Some((label, url, title)) = definition(line) {
+            // Like CommonMark, the first definition of a label wins.
+            definitions.entry(label).or_insert((url, title));
+            continue;
+        }
+
+        in_paragraph = open_fence.is_none() && !is_blank(line) && !interrupts_paragraph(line);
+        kept.push(line);
+    }
+
+    *lines = kept;
+
+    definitions
+}
+
+fn escape(text: &str, out: &mut String) {
+    for char in text.chars() {
+        match char {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(char),
+        }
+    }
+}
+
+/// Drops the backslashes escaping ASCII punctuation.
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        if char == '\\'
+            && let Some(&next) = chars.peek()
+            && next.is_ascii_punctuation()
+        {
+            out.push(next);
+            chars.next();
+        } else {
+            out.push(char);
+        }
+    }
+
+    out
+}
+
+/// The text of rendered HTML, without its tags.
+fn plain_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+
+    for char in html.chars() {
+        match char {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(char),
+            _ => {}
+        }
+    }
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The byte length of the run of `byte` starting at `start`.
+fn run_length(bytes: &[u8], start: usize, byte: u8) -> usize {
+    bytes[start..].iter().take_while(|&&b| b == byte).count()
+}
+
+/// Finds where inline spans close. Failed searches are remembered: a closer missing after one
+/// position is missing after any later one too, so unclosed openers can't make rendering
+/// quadratic.
+struct Spans<'t> {
+    bytes: &'t [u8],
+    /// By backtick count, the position after which no code span closes.
+    no_code: HashMap<usize, usize>,
+    /// By delimiter and length, the position after which no emphasis closes.
+    no_emphasis: HashMap<(u8, usize), usize>,
+    no_strikethrough: usize,
+    /// The `]` matching each `[` outside code spans.
+    brackets: HashMap<usize, usize>,
+}
+
+impl<'t> Spans<'t> {
+    fn new(text: &'t str) -> Self {
+        let mut spans = Self {
+            bytes: text.as_bytes(),
+            no_code: HashMap::new(),
+            no_emphasis: HashMap::new(),
+            no_strikethrough: usize::MAX,
+            brackets: HashMap::new(),
+        };
+
+        let mut open = Vec::new();
+        let mut index = 0;
+
+        while index < spans.bytes.len() {
+            match spans.bytes[index] {
+                b'\\' => index += 2,
+                b'`' => index = spans.skip_code(index),
+                byte => {
+                    if byte == b'[' {
+                        open.push(index);
+                    } else if byte == b']'
+                        && let Some(start) = open.pop()
+                    {
+                        spans.brackets.insert(start, index);
+                    }
+
+                    index += 1;
+                }
+            }
+        }
+
+        spans
+    }
+
+    /// The position after the code span starting at `start`, or after its opening backticks if
+    /// they don't open one.
+    fn skip_code(&mut self, start: usize) -> usize {
+        let len = run_length(self.bytes, start, b'`');
+
+        match self.code_closer(start + len, len) {
+            Some(end) => end + len,
+            None => start + len,
+        }
+    }
+
+    /// The run of exactly `len` backticks from `from` on closing a code span.
+    fn code_closer(&mut self, from: usize, len: usize) -> Option<usize> {
+        if self.no_code.get(&len).is_some_and(|&none| none <= from) {
+            return None;
+        }
+
+        let mut index = from;
+
+        while index < self.bytes.len() {
+            if self.bytes[index] == b'`' {
+                let run = run_length(self.bytes, index, b'`');
+
+                if run == len {
+                    return Some(index);
+                }
+
+                index += run;
+            } else {
+                index += 1;
+            }
+        }
+
+        self.no_code.insert(len, from);
+
+        None
+    }
+
+    /// The delimiters from `from` on closing emphasis opened by `len` of `delimiter`.
+    fn emphasis_closer(&mut self, from: usize, delimiter: u8, len: usize) -> Option<usize> {
+        if self
+            .no_emphasis
+            .get(&(delimiter, len))
+            .is_some_and(|&none| none <= from)
+        {
+            return None;
+        }
+
+        let mut index = from;
+
+        while index < self.bytes.len() {
+            match self.bytes[index] {
+                b'\\' => index += 2,
+                b'`' => index = self.skip_code(index),
+                byte if byte == delimiter => {
+                    let run = run_length(self.bytes, index, delimiter);
+                    let after = self.bytes.get(index + run);
+
+                    let right_flanking = !self.bytes[index - 1].is_ascii_whitespace();
+                    // Underscores inside words are just underscores.
+                    let word_boundary =
+                        delimiter != b'_' || !after.is_some_and(u8::is_ascii_alphanumeric);
+
+                    if right_flanking && word_boundary {
+                        if run == len {
+                            return Some(index);
+                        }
+
+                        // The end of a longer run can close, as in `*a **b***`.
+                        if run >= 3 && len < 3 {
+                            return Some(index + run - len);
+                        }
+                    }
+
+                    index += run;
+                }
+                _ => index += 1,
+            }
+        }
+
+        self.no_emphasis.insert((delimiter, len), from);
+
+        None
+    }
+
+    /// The `~~` from `from` on closing a strikethrough.
+    fn strikethrough_closer(&mut self, from: usize) -> Option<usize> {
+        if self.no_strikethrough <= from {
+            return None;
+        }
+
+        let found = self
+            .bytes
+            .get(from..)?
+            .windows(2)
+            .position(|pair| pair == b"~~")
+            .map(|position| from + position);
+
+        if found.is_none() {
+            self.no_strikethrough = from;
+        }
+
+        found
+    }
+}
+
+impl Renderer<'_> {
+    fn blocks(&mut self, lines: &[&str], tight: bool) {
+        if self.depth.get() >= MAX_DEPTH {
+            self.out.push_str("<p>");
+            escape(&lines.join("\n"), &mut self.out);
+            self.out.push_str("</p>\n");
+            return;
+        }
+
+        self.depth.set(self.depth.get() + 1);
+
+        let mut index = 0;
+
+        while index < lines.len() {
+            let line = lines[index];
+
+            if is_blank(line) {
+                index += 1;
+            } else if indent(line) >= 4 {
+                index = self.indented_code(lines, index);
+            } else if let Some(opening) = fence(line) {
+                index = self.fenced_code(lines, index, opening);
+            } else if let Some((level, text)) = heading(line) {
+                self.heading(level, text);
+                index += 1;
+            } else if is_thematic_break(line) {
+                self.out.push_str("<hr />\n");
+                index += 1;
+            } else if line.trim_start().starts_with('>') {
+                index = self.blockquote(lines, index);
+            } else if let Some((marker, _)) = list_marker(line) {
+                index = self.list(lines, index, marker);
+            } else if let Some(next) = self.table(lines, index) {
+                index = next;
+            } else {
+                index = self.paragraph(lines, index, tight);
+            }
+        }
+
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    fn indented_code(&mut self, lines: &[&str], start: usize) -> usize {
+        let mut end = start;
+
+        while end < lines.len() && (is_blank(lines[end]) || indent(lines[end]) >= 4) {
+            end += 1;
+        }
+
+        // Trailing blank lines separate the block from what follows instead.
+        let mut last = end;
+        while last > start && is_blank(lines[last - 1]) {
+            last -= 1;
+        }
+
+        self.out.push_str("<pre><code>");
+
+        for line in &lines[start..last] {
+            escape(dedent(line, 4), &mut self.out);
+            self.out.push('\n');
+        }
+
+        self.out.push_str("</code></pre>\n");
+
+        end
+    }
+
+    fn fenced_code(&mut self, lines: &[&str], start: usize, opening: (u8, usize)) -> usize {
+        let (_, len) = opening;
+        let width = indent(lines[start]);
+
+        let language: String = lines[start].trim_start()[len..]
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|char| char.is_ascii_alphanumeric() || "-_+.#".contains(*char))
+            .collect();
+
+        if language.is_empty() {
+            self.out.push_str("<pre><code>");
+        } else {
+            self.out.push_str("<pre><code class=\"language-");
+            escape(&language, &mut self.out);
+            self.out.push_str("\">");
+        }
+
+        let mut index = start + 1;
+
+        while index < lines.len() {
+            let line = lines[index];
+            index += 1;
+
+            if closes_fence(line, opening) {
+                break;
+            }
+
+            escape(dedent(line, width), &mut self.out);
+            self.out.push('\n');
+        }
+
+        self.out.push_str("</code></pre>\n");
+
+        index
+    }
+
+    fn heading(&mut self, level: usize, text: &str) {
+        let content = self.inline(text, false);
+
+        let slug: String = plain_text(&content)
+            .to_lowercase()
+            .chars()
+            .filter_map(|char| match char {
+                ' ' | '-' => Some('-'),
+                _ if char.is_alphanumeric() || char == '_' => Some(char),
+                _ => None,
+            })
+            .collect();
+
+        // Repeated headings get numbered ids, as on GitHub.
+        let seen = self.slugs.entry(slug.clone()).or_insert(0);
+        let id = match *seen {
+            0 => slug,
+            count => format!("{slug}-{count}"),
+        };
+        *seen += 1;
+
+        self.out.push_str(&format!("<h{level} id=\""));
+        escape(&id, &mut self.out);
+        self.out.push_str(&format!("\">{content}</h{level}>\n"));
+    }
+
+    fn blockquote(&mut self, lines: &[&str], start: usize) -> usize {
+        let mut inner = Vec::new();
+        let mut index = start;
+
+        while index < lines.len() && !is_blank(lines[index]) {
+            let line = lines[index];
+
+            match line.trim_start().strip_prefix('>') {
+                Some(rest) if indent(line) < 4 => {
+                    inner.push(rest.strip_prefix(' ').unwrap_or(rest));
+                }
+                // Paragraphs may continue without the marker.
+                _ if !interrupts_paragraph(line) && !inner.last().is_none_or(|l| is_blank(l)) => {
+                    inner.push(line);
+                }
+                _ => break,
+            }
+
+            index += 1;
+        }
+
+        self.out.push_str("<blockquote>\n");
+        self.blocks(&inner, false);
+        self.out.push_str("</blockquote>\n");
+
+        index
+    }
+
+    fn list(&mut self, lines: &[&str], start: usize, marker: Marker) -> usize {
+        let mut items: Vec<Vec<&str>> = Vec::new();
+        let mut loose = false;
+        let mut index = start;
+
+        while let Some((item_marker, offset)) = lines.get(index).and_then(|line| list_marker(line))
+            && item_marker.same_list(marker)
+        {
+            let first = lines[index];
+            let mut item = vec![first.get(offset..).unwrap_or_default()];
+            index += 1;
+
+            while index < lines.len() {
+                let line = lines[index];
+
+                if is_blank(line) {
+                    let next = (index..lines.len()).find(|&next| !is_blank(lines[next]));
+
+                    match next {
+                        Some(next) if indent(lines[next]) >= offset => {
+                            item.extend(std::iter::repeat_n("", next - index));
+                            index = next;
+                            loose = true;
+                        }
+                        _ => break,
+                    }
+                } else if indent(line) >= offset {
+                    item.push(&line[offset..]);
+                    index += 1;
+                } else if list_marker(line).is_none()
+                    && !interrupts_paragraph(line)
+                    && !item.last().is_none_or(|last| is_blank(last))
+                {
+                    // A lazy continuation of the item's paragraph.
+                    item.push(line.trim_start());
+                    index += 1;
+                } else {
+                    break;
+                }
+            }
+
+            items.push(item);
+
+            // A blank line between items makes the whole list loose.
+            if index < lines.len() && is_blank(lines[index]) {
+                let next = (index..lines.len()).find(|&next| !is_blank(lines[next]));
+
+                match next.and_then(|next| list_marker(lines[next]).map(|found| (next, found))) {
+                    Some((next, (next_marker, _)))
+                        if next_marker.same_list(marker) && !is_thematic_break(lines[next]) =>
+                    {
+                        loose = true;
+                        index = next;
+                    }
+                    _ => break,
+                }
+            }
+
+            if lines.get(index).is_some_and(|line| is_thematic_break(line)) {
+                break;
+            }
+        }
+
+        match marker {
+            Marker::Ordered(_, 1) => self.out.push_str("<ol>\n"),
+            Marker::Ordered(_, number) => self.out.push_str(&format!("<ol start=\"{number}\">\n")),
+            Marker::Bullet(_) => self.out.push_str("<ul>\n"),
+        }
+
+        for mut item in items {
+            self.out.push_str("<li>");
+
+            let task = item.first().and_then(|first| {
+                let checked = first.starts_with("[x] ") || first.starts_with("[X] ");
+
+                (checked || first.starts_with("[ ] ")).then_some(checked)
+            });
+
+            if let Some(checked) = task {
+                item[0] = &item[0][4..];
+                self.out.push_str(if checked {
+                    "<input type=\"checkbox\" checked=\"\" disabled=\"\" /> "
+                } else {
+                    "<input type=\"checkbox\" disabled=\"\" /> "
+                });
+            }
+
+            self.blocks(&item, !loose);
+
+            // Tight items end right after their text, not after a newline.
+            if self.out.ends_with('\n') && !self.out.ends_with(">\n") {
+                self.out.pop();
+            }
+
+            self.out.push_str("</li>\n");
+        }
+
+        self.out.push_str(match marker {
+            Marker::Ordered(..) => "</ol>\n",
+            Marker::Bullet(_) => "</ul>\n",
+        });
+
+        index
+    }
+
+    /// Renders the table starting at `start`, if there is one, returning the line after it.
+    fn table(&mut self, lines: &[&str], start: usize) -> Option<usize> {
+        let header = lines[start];
+
+        if !header.contains('|') {
+            return None;
+        }
+
+        let alignments = table_alignments(lines.get(start + 1)?)?;
+        let headers = table_cells(header);
+
+        if headers.len() != alignments.len() {
+            return None;
+        }
+
+        let row = |renderer: &mut Self, cells: Vec<&str>, tag: &str| {
+            renderer.out.push_str("<tr>\n");
+
+            for (column, align) in alignments.iter().enumerate() {
+                let align = match align {
+                    Align::None => "",
+                    Align::Left => " align=\"left\"",
+                    Align::Center => " align=\"center\"",
+                    Align::Right => " align=\"right\"",
+                };
+
+                let content =
+                    renderer.inline(cells.get(column).copied().unwrap_or_default(), false);
+
+                renderer
+                    .out
+                    .push_str(&format!("<{tag}{align}>{content}</{tag}>\n"));
+            }
+
+            renderer.out.push_str("</tr>\n");
+        };
+
+        self.out.push_str("<table>\n<thead>\n");
+        row(self, headers, "th");
+        self.out.push_str("</thead>\n");
+
+        let mut index = start + 2;
+        let mut body = false;
+
+        while index < lines.len() && !is_blank(lines[index]) && !interrupts_paragraph(lines[index])
+        {
+            if !body {
+                self.out.push_str("<tbody>\n");
+                body = true;
+            }
+
+            row(self, table_cells(lines[index]), "td");
+            index += 1;
+        }
+
+        if body {
+            self.out.push_str("</tbody>\n");
+        }
+
+        self.out.push_str("</table>\n");
+
+        Some(index)
+    }
+
+    fn paragraph(&mut self, lines: &[&str], start: usize, tight: bool) -> usize {
+        let mut text = vec![lines[start].trim_start()];
+        let mut index = start + 1;
+
+        while index < lines.len() {
+            let line = lines[index];
+
+            if let Some(level) = setext_level(line) {
+                self.heading(level, &text.join("\n"));
+                return index + 1;
+            }
+
+            if is_blank(line) || interrupts_paragraph(line) {
+                break;
+            }
+
+            text.push(line.trim_start());
+            index += 1;
+        }
+
+        let content = self.inline(text.join("\n").trim_end(), false);
+
+        if tight {
+            self.out.push_str(&content);
+            self.out.push('\n');
+        } else {
+            self.out.push_str(&format!("<p>{content}</p>\n"));
+        }
+
+        index
+    }
+
+    /// Checks a link or image destination, resolving relative ones.
+    fn url(&self, url: &str, image: bool) -> Option<String> {
+        let url = unescape(url.trim());
+
+        if url.starts_with('#') || url.starts_with("//") {
+            return Some(url);
+        }
+
+        let scheme_end = url.find([':', '/', '?', '#']);
+
+        match scheme_end {
+            Some(end) if url.as_bytes()[end] == b':' => {
+                let scheme = url[..end].to_ascii_lowercase();
+                let allowed = if image { IMAGE_SCHEMES } else { LINK_SCHEMES };
+
+                allowed.contains(&scheme.as_str()).then_some(url)
+            }
+            _ => Some((self.resolve)(&url, image)),
+        }
+    }
+
+    fn link(&self, text: &str, url: &str, title: Option<&str>, image: bool) -> String {
+        let Some(url) = self.url(url, image) else {
+            // Unsafe destinations lose the link but keep what it said.
+            return if image {
+                let mut alt = String::new();
+                escape(&unescape(text), &mut alt);
+                alt
+            } else {
+                self.inline(text, true)
+            };
+        };
+
+        let mut html = String::new();
+
+        if image {
+            html.push_str("<img src=\"");
+            escape(&url, &mut html);
+            html.push_str("\" alt=\"");
+            escape(&plain_text(&self.inline(text, true)), &mut html);
+            html.push('"');
+        } else {
+            html.push_str("<a href=\"");
+            escape(&url, &mut html);
+            html.push('"');
+        }
+
+        if let Some(title) = title {
+            html.push_str(" title=\"");
+            escape(&unescape(title), &mut html);
+            html.push('"');
+        }
+
+        if image {
+            html.push_str(" />");
+        } else {
+            html.push('>');
+            html.push_str(&self.inline(text, true));
+            html.push_str("</a>");
+        }
+
+        html
+    }
+
+    /// Parses the `(url "title")` after a link's text, returning them and the byte after.
+    fn inline_destination(text: &str, start: usize) -> Option<(&str, Option<&str>, usize)> {
+        // Bounded, so a line of unclosed `[a](` doesn't get scanned to its end for each of them.
+        let mut limit = text.len().min(start + MAX_DESTINATION_LEN);
+        while !text.is_char_boundary(limit) {
+            limit -= 1;
+        }
+
+        let text = &text[..limit];
+        let bytes = text.as_bytes();
+
+        if bytes.get(start) != Some(&b'(') {
+            return None;
+        }
+
+        let mut index = start + 1;
+        while bytes
+            .get(index)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            index += 1;
+        }
+
+        let url = if bytes.get(index) == Some(&b'<') {
+            let end = index + text[index..].find('>')?;
+            let url = &text[index + 1..end];
+            index = end + 1;
+            url
+        } else {
+            let url_start = index;
+            let mut depth = 0;
+
+            while let Some(&byte) = bytes.get(index) {
+                match byte {
+                    b'\\' => index += 1,
+                    b'(' => depth += 1,
+                    b')' if depth == 0 => break,
+                    b')' => depth -= 1,
+                    _ if byte.is_ascii_whitespace() => break,
+                    _ => {}
+                }
+
+                index += 1;
+            }
+
+            &text[url_start..index.min(text.len())]
+        };
+
+        while bytes
+            .get(index)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            index += 1;
+        }
+
+        let title = match bytes.get(index) {
+            Some(&quote @ (b'"' | b'\'' | b'(')) => {
+                let closing = if quote == b'(' { b')' } else { quote };
+                let end = index
+                    + 1
+                    + bytes[index + 1..]
+                        .iter()
+                        .position(|&byte| byte == closing)?;
+                let title = &text[index + 1..end];
+                index = end + 1;
+
+                while bytes
+                    .get(index)
+                    .is_some_and(|byte| byte.is_ascii_whitespace())
+                {
+                    index += 1;
+                }
+
+                Some(title)
+            }
+            _ => None,
+        };
+
+        (bytes.get(index) == Some(&b')')).then_some((url, title, index + 1))
+    }
+
+    /// Renders a link or image whose text starts with the `[` at `start` and ends with the `]`
+    /// at `close`, returning the HTML and the byte after it.
+    fn try_link(
+        &self,
+        text: &str,
+        start: usize,
+        close: usize,
+        image: bool,
+    ) -> Option<(String, usize)> {
+        let label_text = &text[start + 1..close];
+
+        if let Some((url, title, end)) = Self::inline_destination(text, close + 1) {
+            return Some((self.link(label_text, url, title, image), end));
+        }
+
+        // A reference: `[text][label]`, `[label][]` or just `[label]`.
+        let (label, end) = match text[close + 1..].strip_prefix('[') {
+            Some(rest) => {
+                let label_end = rest.find(']')?;
+                let label = &rest[..label_end];
+
+                (
+                    if label.is_empty() { label_text } else { label },
+                    close + 2 + label_end + 1,
+                )
+            }
+            None => (label_text, close + 1),
+        };
+
+        let (url, title) = self.definitions.get(&normalize_label(label))?;
+
+        Some((self.link(label_text, url, title.as_deref(), image), end))
+    }
+
+    /// Renders inline markup. Within link text, bare urls are left alone so links don't nest.
+    fn inline(&self, text: &str, in_link: bool) -> String {
+        let mut out = String::with_capacity(text.len());
+
+        if self.depth.get() >= MAX_DEPTH {
+            escape(text, &mut out);
+            return out;
+        }
+
+        self.depth.set(self.depth.get() + 1);
+
+        let bytes = text.as_bytes();
+        let mut spans = Spans::new(text);
+        let mut index = 0;
+
+        while index < bytes.len() {
+            let byte = bytes[index];
+
+            match byte {
+                b'\\' if bytes.get(index + 1) == Some(&b'\n') => {
+                    out.push_str("<br />\n");
+                    index += 2;
+                }
+                b'\\' if bytes.get(index + 1).is_some_and(u8::is_ascii_punctuation) => {
+                    escape(&text[index + 1..index + 2], &mut out);
+                    index += 2;
+                }
+                b'\n' => {
+                    // Two trailing spaces make a hard break.
+                    let hard = out.ends_with("  ");
+                    out.truncate(out.trim_end_matches(' ').len());
+                    out.push_str(if hard { "<br />\n" } else { "\n" });
+                    index += 1;
+                }
+                b'`' => {
+                    let len = run_length(bytes, index, b'`');
+
+                    match spans.code_closer(index + len, len) {
+                        Some(end) => {
+                            let code = text[index + len..end].replace('\n', " ");
+                            let code =
+                                match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+                                    Some(inner) if !inner.trim().is_empty() => inner.to_string(),
+                                    _ => code,
+                                };
+
+                            out.push_str("<code>");
+                            escape(&code, &mut out);
+                            out.push_str("</code>");
+                            index = end + len;
+                        }
+                        None => {
+                            out.push_str(&text[index..index + len]);
+                            index += len;
+                        }
+                    }
+                }
+                b'!' if bytes.get(index + 1) == Some(&b'[') => {
+                    let link = spans
+                        .brackets
+                        .get(&(index + 1))
+                        .and_then(|&close| self.try_link(text, index + 1, close, true));
+
+                    match link {
+                        Some((html, end)) => {
+                            out.push_str(&html);
+                            index = end;
+                        }
+                        None => {
+                            out.push('!');
+                            index += 1;
+                        }
+                    }
+                }
+                b'[' if !in_link => match spans
+                    .brackets
+                    .get(&index)
+                    .and_then(|&close| self.try_link(text, index, close, false))
+                {
+                    Some((html, end)) => {
+                        out.push_str(&html);
+                        index = end;
+                    }
+                    None => {
+                        out.push('[');
+                        index += 1;
+                    }
+                },
+                b'<' => {
+                    let autolink = text[index + 1..]
+                        .find(['>', '<', ' ', '\n'])
+                        .filter(|&len| bytes[index + 1 + len] == b'>')
+                        .map(|len| &text[index + 1..index + 1 + len])
+                        .filter(|target| {
+                            !in_link && (target.contains("://") || target.contains('@'))
+                        });
+
+                    match autolink {
+                        Some(target) => {
+                            let url = if target.contains("://") {
+                                target.to_string()
+                            } else {
+                                format!("mailto:{target}")
+                            };
+
+                            out.push_str(&self.link(target, &url, None, false));
+                            index += target.len() + 2;
+                        }
+                        None => {
+                            out.push_str("&lt;");
+                            index += 1;
+                        }
+                    }
+                }
+                b'&' => {
+                    // Entities pass through, as they can't spell markup.
+                    let entity = bytes[index + 1..]
+                        .iter()
+                        .take(MAX_ENTITY_LEN + 1)
+                        .position(|&byte| byte == b';')
+                        .map(|len| &text[index + 1..index + 1 + len])
+                        .filter(|name| {
+                            !name.is_empty()
+                                && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'#')
+                        });
+
+                    match entity {
+                        Some(name) => {
+                            out.push('&');
+                            out.push_str(name);
+                            out.push(';');
+                            index += name.len() + 2;
+                        }
+                        None => {
+                            out.push_str("&amp;");
+                            index += 1;
+                        }
+                    }
+                }
+                b'~' if bytes.get(index + 1) == Some(&b'~') => {
+                    let closing = spans
+                        .strikethrough_closer(index + 2)
+                        .map(|end| end - index - 2)
+                        .filter(|&len| len > 0 && !bytes[index + 2].is_ascii_whitespace());
+
+                    match closing {
+                        Some(len) => {
+                            out.push_str("<del>");
+                            out.push_str(&self.inline(&text[index + 2..index + 2 + len], in_link));
+                            out.push_str("</del>");
+                            index += len + 4;
+                        }
+                        None => {
+                            out.push_str("~~");
+                            index += 2;
+                        }
+                    }
+                }
+                b'*' | b'_' => {
+                    let run = run_length(bytes, index, byte);
+                    let len = run.min(3);
+                    let start = index + run - len;
+                    let next = bytes.get(index + run);
+
+                    let left_flanking = next.is_some_and(|next| !next.is_ascii_whitespace());
+                    let word_boundary =
+                        byte != b'_' || index == 0 || !bytes[index - 1].is_ascii_alphanumeric();
+
+                    let closing = (left_flanking && word_boundary)
+                        .then(|| spans.emphasis_closer(index + run, byte, len))
+                        .flatten();
+
+                    match closing {
+                        Some(end) => {
+                            // Runs longer than three are literal past the third character.
+                            out.push_str(&text[index..start]);
+
+                            let (open, close) = match len {
+                                1 => ("<em>", "</em>"),
+                                2 => ("<strong>", "</strong>"),
+                                _ => ("<em><strong>", "</strong></em>"),
+                            };
+
+                            out.push_str(open);
+                            out.push_str(&self.inline(&text[index + run..end], in_link));
+                            out.push_str(close);
+                            index = end + len;
+                        }
+                        None => {
+                            out.push_str(&text[index..index + run]);
+                            index += run;
+                        }
+                    }
+                }
+                b'h' | b'w'
+                    if !in_link
+                        && (index == 0 || !bytes[index - 1].is_ascii_alphanumeric())
+                        && ["https://", "http://", "www."]
+                            .iter()
+                            .any(|prefix| text[index..].starts_with(prefix)) =>
+                {
+                    let len = text[index..]
+                        .find(|char: char| char.is_whitespace() || char == '<')
+                        .unwrap_or(text.len() - index);
+                    let mut url = &text[index..index + len];
+                    let opening = url.matches('(').count();
+                    let mut closing = url.matches(')').count();
+
+                    // Trailing punctuation usually ends the sentence rather than the url, as
+                    // does a parenthesis it didn't open.
+                    while let Some(last) = url.bytes().last() {
+                        if last == b')' && closing > opening {
+                            closing -= 1;
+                        } else if !b".,:;!?*_~'\"".contains(&last) {
+                            break;
+                        }
+
+                        url = &url[..url.len() - 1];
+                    }
+
+                    let href = if url.starts_with("www.") {
+                        format!("http://{url}")
+                    } else {
+                        url.to_string()
+                    };
+
+                    out.push_str(&self.link(url, &href, None, false));
+                    index += url.len();
+                }
+                _ => {
+                    let char = text[index..].chars().next().unwrap();
+                    escape(&text[index..index + char.len_utf8()], &mut out);
+                    index += char.len_utf8();
+                }
+            }
+        }
+
+        self.depth.set(self.depth.get() - 1);
+
+        out
+    }
+}
@@ -0,0 +1,478 @@
+//! Server-side merges, cherry-picks and reverts.
+//!
+//! `POST /repo/{user}/{name}/merge` merges a head into a base branch without a working tree,
+//! using git's in-memory merge, and moves the base branch to the result. The strategies are
+//! those review tools offer: a merge commit, a fast-forward, or a squash into a single commit
+//! on top of the base. Conflicting merges leave the branch alone and are reported along with
+//! the paths that conflict. Merging takes the same credentials as pushing to the repository.
+//!
+//! `GET` on the same path with `base` and `head` in the query does the same merge without
+//! writing anything, to tell whether a merge would go through before offering one.
+//!
+//! `POST /repo/{user}/{name}/cherry-pick` and `/revert` apply a commit, or undo it, as a new
+//! commit on top of a branch, the same way and with the same credentials.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use axum::{extract::State, http::HeaderMap, response::Response};
+use git2::{Commit, Index, MergeOptions, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    AppState, Error, Json, Name, Path, Query, SignatureSpec, advance_branch, auth::require_write,
+    blocking, conditional, find_public_branch, hooks::RefUpdate, resolve_public_commit,
+    server_signature,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// A commit with both the base and the head as parents.
+    #[default]
+    Merge,
+    /// Moves the base to the head, which has to contain it.
+    FastForward,
+    /// A commit with the merged tree and only the base as its parent.
+    Squash,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    /// Branch to merge into.
+    base: String,
+    /// Branch, tag or commit to merge.
+    head: String,
+    #[serde(default)]
+    strategy: Strategy,
+    /// Message of the new commit. Defaults to one naming the head and the base.
+    message: Option<String>,
+    /// Who the new commit is recorded as authored by. It is always committed by the
+    /// repository's configured identity.
+    author: Option<SignatureSpec>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Merged {
+    branch: String,
+    strategy: Strategy,
+    /// Where the branch was before.
+    previous: String,
+    /// Where the branch is now.
+    commit: String,
+    /// False if the base already contained the head, leaving it as it was.
+    updated: bool,
+}
+
+/// Writes the tree of a merged index, refusing ones with conflicts.
+fn write_tree<'r>(repo: &'r Repository, mut index: Index) -> Result<git2::Tree<'r>, Error> {
+    if index.has_conflicts() {
+        return Err(Error::MergeConflict(conflicts(&index)?));
+    }
+
+    Ok(repo.find_tree(index.write_tree_to(repo)?)?)
+}
+
+/// Merges `head` into `base` in memory, leaving the result in the returned index.
+fn merged_index(repo: &Repository, base: &Commit, head: &Commit) -> Result<Index, git2::Error> {
+    let mut options = MergeOptions::new();
+    options.fail_on_conflict(false);
+
+    repo.merge_commits(base, head, Some(&options))
+}
+
+/// The paths with conflicts in a merged index.
+fn conflicts(index: &Index) -> Result<Vec<String>, git2::Error> {
+    let mut paths = BTreeSet::new();
+
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            paths.insert(String::from_utf8_lossy(&entry.path).into_owned());
+        }
+    }
+
+    Ok(paths.into_iter().collect())
+}
+
+pub async fn merge(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(payload): Json<MergeRequest>,
+) -> Result<Json<Merged>, Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let (reference, base) = find_public_branch(&repo, &payload.base)?;
+        let head = repo.find_commit(resolve_public_commit(&repo, &payload.head)?)?;
+
+        let merged = |commit: Oid, updated| Merged {
+            branch: payload.base.clone(),
+            strategy: payload.strategy,
+            previous: base.id().to_string(),
+            commit: commit.to_string(),
+            updated,
+        };
+
+        if base.id() == head.id() || repo.graph_descendant_of(base.id(), head.id())? {
+            return Ok(Json(merged(base.id(), false)));
+        }
+
+        let commit = match payload.strategy {
+            Strategy::FastForward => {
+                if !repo.graph_descendant_of(head.id(), base.id())? {
+                    return Err(Error::Conflict);
+                }
+
+                head.id()
+            }
+            Strategy::Merge | Strategy::Squash => {
+                let tree = write_tree(&repo, merged_index(&repo, &base, &head)?)?;
+
+                let committer = server_signature(&repo)?;
+                let author = match &payload.author {
+                    Some(identity) => git2::Signature::now(&identity.name, &identity.email)?,
+                    None => committer.clone(),
+                };
+
+                let (message, parents) = match payload.strategy {
+                    Strategy::Squash => (
+                        format!("Squash {} into {}", payload.head, payload.base),
+                        vec![&base],
+                    ),
+                    _ => (
+                        format!("Merge {} into {}", payload.head, payload.base),
+                        vec![&base, &head],
+                    ),
+                };
+
+                let message = payload.message.clone().unwrap_or(message);
+
+                repo.commit(None, &author, &committer, &message, &tree, &parents)?
+            }
+        };
+
+        advance_branch(
+            &state,
+            &repo,
+            (&user, &name),
+            RefUpdate {
+                old: base.id(),
+                new: commit,
+                name: reference,
+            },
+            &format!("merge: {}", payload.head),
+        )?;
+
+        info!(
+            "Merged {} into {} of {user}/{name} as {commit}",
+            payload.head, payload.base
+        );
+
+        Ok(Json(merged(commit, true)))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeabilityQuery {
+    base: String,
+    head: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mergeability {
+    base: String,
+    head: String,
+    merge_base: Option<String>,
+    /// The base already contains the head, so there is nothing to merge.
+    up_to_date: bool,
+    /// The base can be fast-forwarded to the head.
+    fast_forward: bool,
+    /// A merge commit or a squash would go through without conflicts.
+    mergeable: bool,
+    conflicts: Vec<String>,
+}
+
+pub async fn check_merge(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<MergeabilityQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let base = repo.find_commit(resolve_public_commit(&repo, &query.base)?)?;
+        let head = repo.find_commit(resolve_public_commit(&repo, &query.head)?)?;
+
+        // The outcome is entirely determined by the two commits.
+        conditional(
+            &headers,
+            &format!("merge-{}-{}", base.id(), head.id()),
+            || {
+                let up_to_date =
+                    base.id() == head.id() || repo.graph_descendant_of(base.id(), head.id())?;
+                let fast_forward = !up_to_date && repo.graph_descendant_of(head.id(), base.id())?;
+
+                let conflicts = if up_to_date {
+                    Vec::new()
+                } else {
+                    conflicts(&merged_index(&repo, &base, &head)?)?
+                };
+
+                Ok(Json(Mergeability {
+                    base: base.id().to_string(),
+                    head: head.id().to_string(),
+                    merge_base: repo
+                        .merge_base(base.id(), head.id())
+                        .ok()
+                        .map(|oid| oid.to_string()),
+                    up_to_date,
+                    fast_forward,
+                    mergeable: conflicts.is_empty(),
+                    conflicts,
+                }))
+            },
+        )
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApplyCommit {
+    /// Commit to cherry-pick or revert.
+    commit: String,
+    /// Branch to add the new commit to.
+    branch: String,
+    /// Parent to compare merge commits against, counting from 1, as in `git cherry-pick -m`.
+    mainline: Option<u32>,
+    /// Message of the new commit. Defaults to git's.
+    message: Option<String>,
+    /// Who the new commit is recorded as authored by. Cherry-picks default to the author of
+    /// the original, reverts to the repository's configured identity, which always commits.
+    author: Option<SignatureSpec>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Applied {
+    branch: String,
+    /// Where the branch was before.
+    previous: String,
+    /// The new commit, where the branch is now.
+    commit: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    CherryPick,
+    Revert,
+}
+
+/// Cherry-picks or reverts a commit onto a branch.
+fn apply(
+    state: &AppState,
+    (user, name): (&str, &str),
+    operation: Operation,
+    payload: ApplyCommit,
+) -> Result<Json<Applied>, Error> {
+    let repo = state.open_repo(user, name)?;
+
+    let (reference, tip) = find_public_branch(&repo, &payload.branch)?;
+    let commit = repo.find_commit(resolve_public_commit(&repo, &payload.commit)?)?;
+
+    let mainline = match (commit.parent_count(), payload.mainline) {
+        (0 | 1, None) => 0,
+        (0 | 1, Some(_)) => {
+            return Err(Error::BadRequest(
+                "Only merge commits take a mainline".to_string(),
+            ));
+        }
+        (parents, Some(mainline)) if (1..=parents as u32).contains(&mainline) => mainline,
+        (_, _) => {
+            return Err(Error::BadRequest(
+                "Merge commits need a mainline parent".to_string(),
+            ));
+        }
+    };
+
+    let options = MergeOptions::new();
+    let index = match operation {
+        Operation::CherryPick => repo.cherrypick_commit(&commit, &tip, mainline, Some(&options))?,
+        Operation::Revert => repo.revert_commit(&commit, &tip, mainline, Some(&options))?,
+    };
+
+    let tree = write_tree(&repo, index)?;
+
+    let committer = server_signature(&repo)?;
+    let author = match (&payload.author, operation) {
+        (Some(identity), _) => git2::Signature::now(&identity.name, &identity.email)?,
+        (None, Operation::CherryPick) => commit.author().to_owned(),
+        (None, Operation::Revert) => committer.clone(),
+    };
+
+    let message = match (payload.message, operation) {
+        (Some(message), _) => message,
+        (None, Operation::CherryPick) => commit.message().unwrap_or_default().to_string(),
+        (None, Operation::Revert) => format!(
+            "Revert \"{}\"\n\nThis reverts commit {}.\n",
+            commit.summary().unwrap_or_default(),
+            commit.id()
+        ),
+    };
+
+    let new = repo.commit(None, &author, &committer, &message, &tree, &[&tip])?;
+
+    let log_message = match operation {
+        Operation::CherryPick => format!("cherry-pick: {}", commit.id()),
+        Operation::Revert => format!("revert: {}", commit.id()),
+    };
+
+    advance_branch(
+        state,
+        &repo,
+        (user, name),
+        RefUpdate {
+            old: tip.id(),
+            new,
+            name: reference,
+        },
+        &log_message,
+    )?;
+
+    info!(
+        "Applied {operation:?} of {} to {} of {user}/{name} as {new}",
+        commit.id(),
+        payload.branch
+    );
+
+    Ok(Json(Applied {
+        branch: payload.branch,
+        previous: tip.id().to_string(),
+        commit: new.to_string(),
+    }))
+}
+
+pub async fn cherry_pick(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(payload): Json<ApplyCommit>,
+) -> Result<Json<Applied>, Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || apply(&state, (&user, &name), Operation::CherryPick, payload)).await
+}
+
+pub async fn revert(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    Json(payload): Json<ApplyCommit>,
+) -> Result<Json<Applied>, Error> {
+    require_write(&state, &headers, &user, &name)?;
+
+    blocking(move || apply(&state, (&user, &name), Operation::Revert, payload)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::tests::{TestServer, commit};
+
+    #[tokio::test]
+    async fn merging_needs_push_access() {
+        let server = TestServer::new("merge");
+        let repo = server.init_repo("alice", "r.git");
+
+        let base = commit(&repo, Some("refs/heads/main"), &[("a", b"1")], &[]);
+        let head = commit(&repo, Some("refs/heads/topic"), &[("a", b"2")], &[base]);
+
+        let reader = server.deploy_token("alice", "r.git", false).await;
+        let stranger = server.user_token("bob").await;
+        let writer = server.deploy_token("alice", "r.git", true).await;
+
+        let merge = json!({ "base": "main", "head": "topic", "strategy": "fast_forward" });
+
+        for token in [None, Some(reader.as_str()), Some(stranger.as_str())] {
+            let (status, _) = server
+                .send(
+                    Method::POST,
+                    "/repo/alice/r.git/merge",
+                    token,
+                    Some(merge.clone()),
+                )
+                .await;
+
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{token:?}");
+        }
+
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), base);
+
+        let (status, merged) = server
+            .json(
+                Method::POST,
+                "/repo/alice/r.git/merge",
+                Some(&writer),
+                Some(merge),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(merged["commit"], head.to_string());
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), head);
+    }
+
+    #[tokio::test]
+    async fn cherry_picks_and_reverts_need_push_access() {
+        let server = TestServer::new("cherry-pick");
+        let repo = server.init_repo("alice", "r.git");
+
+        let base = commit(&repo, Some("refs/heads/main"), &[("a", b"1")], &[]);
+        let picked = commit(&repo, Some("refs/heads/topic"), &[("a", b"2")], &[base]);
+
+        let reader = server.deploy_token("alice", "r.git", false).await;
+        let writer = server.deploy_token("alice", "r.git", true).await;
+
+        let apply = json!({ "commit": picked.to_string(), "branch": "main" });
+
+        for operation in ["cherry-pick", "revert"] {
+            let uri = format!("/repo/alice/r.git/{operation}");
+
+            for token in [None, Some(reader.as_str())] {
+                let (status, _) = server
+                    .send(Method::POST, &uri, token, Some(apply.clone()))
+                    .await;
+
+                assert_eq!(status, StatusCode::UNAUTHORIZED, "{operation} {token:?}");
+            }
+        }
+
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), base);
+
+        let (status, applied) = server
+            .json(
+                Method::POST,
+                "/repo/alice/r.git/cherry-pick",
+                Some(&writer),
+                Some(apply),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(applied["previous"], base.to_string());
+
+        let tip = repo.find_reference("refs/heads/main").unwrap();
+        assert_eq!(tip.target().unwrap().to_string(), applied["commit"]);
+        assert_eq!(
+            tip.peel_to_tree().unwrap().id(),
+            repo.find_commit(picked).unwrap().tree_id()
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Descriptive metadata of a repository: a description, a homepage and topic tags.
+//!
+//! Metadata is kept in the repository config as `gitserver.description`, `gitserver.homepage`
+//! and one `gitserver.topic` per topic, so it moves, gets trashed and gets deleted along with
+//! the repository. It shows up in the repository listings; `PUT` replaces all of it at once and
+//! `DELETE` clears it.
+
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use git2::{ConfigLevel, Repository};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{AppState, Error, Json, Name, Path, auth::Authenticated, blocking};
+
+const DESCRIPTION_KEY: &str = "gitserver.description";
+const HOMEPAGE_KEY: &str = "gitserver.homepage";
+const TOPIC_KEY: &str = "gitserver.topic";
+
+const MAX_DESCRIPTION_LEN: usize = 350;
+const MAX_TOPIC_LEN: usize = 50;
+const MAX_TOPICS: usize = 20;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Metadata {
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+impl Metadata {
+    /// Checks metadata taken from a request, trimming the description and lowercasing and
+    /// deduplicating the topics.
+    pub fn validate(self) -> Result<Self, Error> {
+        let description = self
+            .description
+            .map(|description| description.trim().to_string())
+            .filter(|description| !description.is_empty());
+
+        if let Some(description) = &description
+            && (description.chars().count() > MAX_DESCRIPTION_LEN
+                || description.chars().any(char::is_control))
+        {
+            return Err(Error::BadRequest(format!(
+                "Descriptions are a single line of at most {MAX_DESCRIPTION_LEN} characters"
+            )));
+        }
+
+        if let Some(homepage) = &self.homepage
+            && !url::Url::parse(homepage)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+        {
+            return Err(Error::BadRequest(format!(
+                "Unsupported homepage url: {homepage}"
+            )));
+        }
+
+        let mut topics: Vec<String> = Vec::new();
+
+        for topic in self.topics {
+            let topic = topic.to_lowercase();
+
+            let valid = !topic.is_empty()
+                && topic.len() <= MAX_TOPIC_LEN
+                && !topic.starts_with('-')
+                && topic
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-');
+
+            if !valid {
+                return Err(Error::BadRequest(format!("Invalid topic: {topic}")));
+            }
+
+            if !topics.contains(&topic) {
+                topics.push(topic);
+            }
+        }
+
+        if topics.len() > MAX_TOPICS {
+            return Err(Error::BadRequest(format!(
+                "At most {MAX_TOPICS} topics are allowed"
+            )));
+        }
+
+        Ok(Self {
+            description,
+            homepage: self.homepage,
+            topics,
+        })
+    }
+}
+
+/// The metadata stored in the repository config.
+pub fn read(repo: &Repository) -> Result<Metadata, git2::Error> {
+    let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+    let mut topics = Vec::new();
+
+    config.multivar(TOPIC_KEY, None)?.for_each(|entry| {
+        if let Some(topic) = entry.value() {
+            topics.push(topic.to_string());
+        }
+    })?;
+
+    Ok(Metadata {
+        description: config.get_string(DESCRIPTION_KEY).ok(),
+        homepage: config.get_string(HOMEPAGE_KEY).ok(),
+        topics,
+    })
+}
+
+fn write(repo: &Repository, metadata: &Metadata) -> Result<(), git2::Error> {
+    let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+    for (key, value) in [
+        (DESCRIPTION_KEY, &metadata.description),
+        (HOMEPAGE_KEY, &metadata.homepage),
+    ] {
+        match value {
+            Some(value) => config.set_str(key, value)?,
+            None => ignore_missing(config.remove(key))?,
+        }
+    }
+
+    ignore_missing(config.remove_multivar(TOPIC_KEY, ".*"))?;
+
+    // Topics are never empty, so the pattern never matches and every topic is added.
+    for topic in &metadata.topics {
+        config.set_multivar(TOPIC_KEY, "^$", topic)?;
+    }
+
+    Ok(())
+}
+
+fn ignore_missing(result: Result<(), git2::Error>) -> Result<(), git2::Error> {
+    match result {
+        Err(error) if error.code() != git2::ErrorCode::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+pub async fn get_metadata(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+) -> Result<Json<Metadata>, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        Ok(Json(read(&repo)?))
+    })
+    .await
+}
+
+pub async fn set_metadata(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<Metadata>,
+) -> Result<Json<Metadata>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let metadata = payload.validate()?;
+        let repo = state.open_repo(&user, &name)?;
+
+        write(&repo, &metadata)?;
+
+        info!("Updated metadata of {user}/{name}");
+
+        Ok(Json(metadata))
+    })
+    .await
+}
+
+pub async fn delete_metadata(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+) -> Result<StatusCode, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        write(&repo, &Metadata::default())?;
+
+        info!("Cleared metadata of {user}/{name}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
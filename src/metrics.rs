@@ -0,0 +1,430 @@
+//! Prometheus metrics, served in the text exposition format from `/metrics`.
+//!
+//! Requests are counted per matched route rather than per path, so the number of series stays
+//! bounded however many repositories there are. Durations are measured until the response
+//! head is ready, which for streamed bodies such as archives excludes the transfer itself.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+use tracing::info_span;
+
+use crate::AppState;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket] += 1;
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Keyed by method, route and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Keyed by method and route.
+    request_durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// Keyed by operation.
+    operation_durations: Mutex<BTreeMap<&'static str, Histogram>>,
+    connections_accepted: AtomicU64,
+    connections_closed: AtomicU64,
+    pack_bytes_sent: AtomicU64,
+    pack_bytes_received: AtomicU64,
+}
+
+impl Metrics {
+    /// Runs `operation` in a span of its own, recording how long it took under `name`.
+    pub fn time<T>(&self, name: &'static str, operation: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = info_span!("git", operation = name).in_scope(operation);
+
+        self.operation_durations
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .observe(start.elapsed());
+
+        result
+    }
+
+    pub fn pack_sent(&self, bytes: usize) {
+        self.pack_bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn pack_received(&self, bytes: usize) {
+        self.pack_bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let accepted = self.connections_accepted.load(Ordering::Relaxed);
+        let closed = self.connections_closed.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "HTTP requests handled.",
+        );
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape(method),
+                escape(route)
+            );
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time until the response head was ready.",
+        );
+        for ((method, route), histogram) in self.request_durations.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "http_request_duration_seconds",
+                &format!("method=\"{}\",route=\"{}\"", escape(method), escape(route)),
+            );
+        }
+
+        header(
+            &mut out,
+            "git_operation_duration_seconds",
+            "histogram",
+            "Time spent on repository operations.",
+        );
+        for (operation, histogram) in self.operation_durations.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "git_operation_duration_seconds",
+                &format!("operation=\"{operation}\""),
+            );
+        }
+
+        header(
+            &mut out,
+            "http_connections_active",
+            "gauge",
+            "Open client connections.",
+        );
+        let _ = writeln!(
+            out,
+            "http_connections_active {}",
+            accepted.saturating_sub(closed)
+        );
+
+        header(
+            &mut out,
+            "http_connections_total",
+            "counter",
+            "Client connections accepted.",
+        );
+        let _ = writeln!(out, "http_connections_total {accepted}");
+
+        header(
+            &mut out,
+            "git_pack_bytes_sent_total",
+            "counter",
+            "Bytes of upload-pack responses served.",
+        );
+        let _ = writeln!(
+            out,
+            "git_pack_bytes_sent_total {}",
+            self.pack_bytes_sent.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "git_pack_bytes_received_total",
+            "counter",
+            "Bytes of receive-pack requests accepted.",
+        );
+        let _ = writeln!(
+            out,
+            "git_pack_bytes_received_total {}",
+            self.pack_bytes_received.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records the count and duration of every request.
+pub async fn track(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    let metrics = &state.metrics;
+
+    *metrics
+        .requests
+        .lock()
+        .unwrap()
+        .entry((method.clone(), route.clone(), response.status().as_u16()))
+        .or_default() += 1;
+
+    metrics
+        .request_durations
+        .lock()
+        .unwrap()
+        .entry((method, route))
+        .or_default()
+        .observe(elapsed);
+
+    response
+}
+
+pub async fn serve_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+/// The socket connections are accepted on.
+pub enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listener keeping track of how many connections are open.
+pub struct Listener {
+    socket: Socket,
+    state: Arc<AppState>,
+}
+
+impl Listener {
+    pub fn new(socket: Socket, state: Arc<AppState>) -> Self {
+        Self { socket, state }
+    }
+}
+
+impl axum::serve::Listener for Listener {
+    type Io = Connection;
+    /// The peer address of TCP clients. Unix socket peers are unnamed.
+    type Addr = Option<SocketAddr>;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, address) = match &mut self.socket {
+            Socket::Tcp(listener) => {
+                let (stream, address) = axum::serve::Listener::accept(listener).await;
+                (Stream::Tcp(stream), Some(address))
+            }
+            Socket::Unix(listener) => {
+                let (stream, _) = axum::serve::Listener::accept(listener).await;
+                (Stream::Unix(stream), None)
+            }
+        };
+
+        self.state
+            .metrics
+            .connections_accepted
+            .fetch_add(1, Ordering::Relaxed);
+
+        (
+            Connection {
+                stream,
+                state: self.state.clone(),
+            },
+            address,
+        )
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr().map(Some),
+            Socket::Unix(_) => Ok(None),
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A client connection, counted as closed once dropped.
+pub struct Connection {
+    stream: Stream,
+    state: Arc<AppState>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state
+            .metrics
+            .connections_closed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.stream {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use crate::tests::TestServer;
+
+    #[tokio::test]
+    async fn requests_are_counted_per_route() {
+        let server = TestServer::new("metrics");
+        server.init_repo("test", "r.git");
+
+        for uri in [
+            "/repo/test/r.git/branches",
+            "/repo/test/missing.git/branches",
+        ] {
+            server.send(Method::GET, uri, None, None).await;
+        }
+
+        let (status, body) = server.send(Method::GET, "/metrics", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let metrics = String::from_utf8(body).unwrap();
+
+        for line in [
+            r#"http_requests_total{method="GET",route="/repo/{user}/{name}/branches",status="200"} 1"#,
+            r#"http_requests_total{method="GET",route="/repo/{user}/{name}/branches",status="404"} 1"#,
+            r#"http_request_duration_seconds_count{method="GET",route="/repo/{user}/{name}/branches"} 2"#,
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{line}");
+        }
+    }
+}
@@ -0,0 +1,784 @@
+//! Mirroring from and to repositories hosted elsewhere.
+//!
+//! `POST /repo/{user}/{name}/import` mirrors every ref of a remote repository into a new one in
+//! the background, keeping the source as its `origin` remote. `GET` on the same path reports
+//! how far along the import is, until the server restarts.
+//!
+//! Push mirrors go the other way: every time refs change, they are force-pushed to each of the
+//! repository's push mirrors, stored in its config as `pushmirror.<id>.url` along with an
+//! optional `username`. Their passwords are kept in the server's [`crate::secrets`]. Each attempt pushes the refs as they are by then, so mirrors
+//! catch up even if pushes race.
+
+use std::{
+    collections::HashMap,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract::State, http::StatusCode};
+use git2::{
+    AutotagOption, ConfigLevel, Cred, Direction, FetchOptions, PushOptions, RemoteCallbacks,
+    RemoteUpdateFlags, Repository,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    AppState, Error, Json, Name, PRIVATE_KEY, Path, announce_created,
+    auth::{Authenticated, hex},
+    blocking,
+    hooks::{Hook, Push, RefUpdate},
+    init_bare_atomic, redact_url, redirect_target,
+};
+
+/// Name of the remote the source is kept as.
+const ORIGIN: &str = "origin";
+
+const PUSH_SECTION: &str = "pushmirror";
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Username sent along with a password when none is configured. Forges only look at the
+/// password, which is usually an access token.
+const DEFAULT_USERNAME: &str = "git";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ImportState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Import {
+    /// The source, with credentials redacted.
+    url: String,
+    state: ImportState,
+    received_objects: usize,
+    total_objects: usize,
+    indexed_deltas: usize,
+    total_deltas: usize,
+    received_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Imports started since the server did, keyed by the path of the repository they create.
+#[derive(Debug, Default)]
+pub struct Imports(Mutex<HashMap<PathBuf, Import>>);
+
+impl Imports {
+    fn update(&self, path: &FsPath, update: impl FnOnce(&mut Import)) {
+        if let Some(import) = self.0.lock().unwrap().get_mut(path) {
+            update(import);
+        }
+    }
+}
+
+/// Fetches every ref of `url` into a new bare repository at `path`, pointing `HEAD` where the
+/// source's does. Nothing appears at `path` unless the whole fetch succeeds.
+fn mirror(state: &AppState, path: &FsPath, url: &str, private: bool) -> Result<(), Error> {
+    init_bare_atomic(path, |repo| {
+        if private {
+            repo.config()?.set_bool(PRIVATE_KEY, true)?;
+        }
+
+        let mut remote = repo.remote_with_fetch(ORIGIN, url, "+refs/*:refs/*")?;
+        repo.config()?
+            .set_bool(&format!("remote.{ORIGIN}.mirror"), true)?;
+
+        let mut connection = remote.connect_auth(Direction::Fetch, None, None)?;
+
+        let head = connection
+            .default_branch()
+            .ok()
+            .and_then(|branch| branch.as_str().map(str::to_string));
+
+        let mut callbacks = RemoteCallbacks::new();
+
+        callbacks.transfer_progress(|progress| {
+            state.imports.update(path, |import| {
+                import.received_objects = progress.received_objects();
+                import.total_objects = progress.total_objects();
+                import.indexed_deltas = progress.indexed_deltas();
+                import.total_deltas = progress.total_deltas();
+                import.received_bytes = progress.received_bytes();
+            });
+
+            true
+        });
+
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(callbacks);
+
+        let remote = connection.remote();
+
+        remote.download::<&str>(&[], Some(&mut options))?;
+        remote.update_tips(
+            None,
+            RemoteUpdateFlags::empty(),
+            AutotagOption::All,
+            Some("import"),
+        )?;
+
+        // Empty sources have nothing for HEAD to point at yet.
+        if let Some(head) = head
+            && repo.find_reference(&head).is_ok()
+        {
+            repo.set_head(&head)?;
+        }
+
+        Ok(())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StartImport {
+    url: String,
+    #[serde(default)]
+    private: bool,
+}
+
+pub async fn start_import(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<StartImport>,
+) -> Result<(StatusCode, Json<Import>), Error> {
+    caller.require_owner(&user)?;
+
+    // Local paths would let callers read anything on the host, and ssh needs keys the server
+    // doesn't have.
+    if !url::Url::parse(&payload.url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https" | "git") && url.has_host())
+    {
+        return Err(Error::BadRequest(format!(
+            "Unsupported import url: {}",
+            redact_url(&payload.url)
+        )));
+    }
+
+    let path = state.new_repo_path(&user, &name);
+
+    let import = Import {
+        url: redact_url(&payload.url),
+        state: ImportState::Running,
+        received_objects: 0,
+        total_objects: 0,
+        indexed_deltas: 0,
+        total_deltas: 0,
+        received_bytes: 0,
+        error: None,
+    };
+
+    let registered = {
+        let state = state.clone();
+        let path = path.clone();
+        let import = import.clone();
+
+        blocking(move || {
+            // A repository that moved away gives up its old name.
+            if redirect_target(&path).is_some() {
+                std::fs::remove_file(&path)?;
+            }
+
+            let mut imports = state.imports.0.lock().unwrap();
+
+            let running = imports
+                .get(&path)
+                .is_some_and(|import| import.state == ImportState::Running);
+
+            if running || path.exists() {
+                return Err(Error::Conflict);
+            }
+
+            imports.insert(path, import);
+
+            Ok(())
+        })
+    };
+
+    registered.await?;
+
+    info!("Importing {user}/{name} from {}", import.url);
+
+    let span = tracing::Span::current();
+    let url = payload.url;
+    let private = payload.private;
+
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+
+        match mirror(&state, &path, &url, private) {
+            Ok(()) => {
+                info!("Imported {user}/{name}");
+
+                state
+                    .imports
+                    .update(&path, |import| import.state = ImportState::Done);
+
+                announce_created(&state, &user, &name, &path, private);
+            }
+            Err(error) => {
+                let message = match &error {
+                    Error::Git(error) => error.message().to_string(),
+                    Error::Io(error) => error.to_string(),
+                    Error::Conflict => "Repository already exists".to_string(),
+                    _ => "Import failed".to_string(),
+                };
+
+                warn!("Failed to import {user}/{name}: {message}");
+
+                state.imports.update(&path, |import| {
+                    import.state = ImportState::Failed;
+                    import.error = Some(message);
+                });
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(import)))
+}
+
+pub async fn import_status(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+) -> Result<Json<Import>, Error> {
+    caller.require_owner(&user)?;
+
+    let path = state.new_repo_path(&user, &name);
+
+    state
+        .imports
+        .0
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .map(Json)
+        .ok_or(Error::NotFound)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct PushStatus {
+    /// Unix time of the last attempt to push.
+    last_attempt: Option<u64>,
+    last_success: Option<u64>,
+    /// Why the last attempt failed, until one succeeds.
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PushMirror {
+    id: String,
+    /// The target, with credentials redacted.
+    url: String,
+    /// Whether a password is configured. It is never shown again.
+    authenticated: bool,
+    #[serde(flatten)]
+    status: PushStatus,
+    #[serde(skip)]
+    target: String,
+    #[serde(skip)]
+    username: Option<String>,
+    #[serde(skip)]
+    password: Option<String>,
+}
+
+/// Key of a push mirror's password.
+fn password_key(id: &str) -> String {
+    format!("{PUSH_SECTION}.{id}")
+}
+
+/// The push mirrors configured for a repository, without their status.
+fn push_mirrors(state: &AppState, repo: &Repository) -> Result<Vec<PushMirror>, git2::Error> {
+    let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+    let mut mirrors: Vec<PushMirror> = Vec::new();
+    let mut entries = config.entries(Some(&format!("^{PUSH_SECTION}\\.")))?;
+
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+
+        let (Some(key), Some(value)) = (entry.name(), entry.value()) else {
+            continue;
+        };
+
+        let Some((id, variable)) = key[PUSH_SECTION.len() + 1..].rsplit_once('.') else {
+            continue;
+        };
+
+        let index = match mirrors.iter().position(|mirror| mirror.id == id) {
+            Some(index) => index,
+            None => {
+                mirrors.push(PushMirror {
+                    id: id.to_string(),
+                    url: String::new(),
+                    authenticated: false,
+                    status: PushStatus::default(),
+                    target: String::new(),
+                    username: None,
+                    password: None,
+                });
+                mirrors.len() - 1
+            }
+        };
+
+        let mirror = &mut mirrors[index];
+
+        match variable {
+            "url" => {
+                mirror.url = redact_url(value);
+                mirror.target = value.to_string();
+            }
+            "username" => mirror.username = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    mirrors.retain(|mirror| !mirror.target.is_empty());
+
+    for mirror in &mut mirrors {
+        mirror.password = state.secrets.get(&password_key(&mirror.id));
+        mirror.authenticated = mirror.password.is_some();
+    }
+
+    Ok(mirrors)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Pushes `refs` to `mirror` as they currently are, deleting the ones that no longer exist.
+fn push(repo: &Repository, mirror: &PushMirror, refs: &[String]) -> Result<(), git2::Error> {
+    let refspecs: Vec<String> = refs
+        .iter()
+        .map(|name| match repo.find_reference(name) {
+            Ok(_) => format!("+{name}:{name}"),
+            Err(_) => format!(":{name}"),
+        })
+        .collect();
+
+    let mut rejected = Vec::new();
+
+    // The callbacks borrow `rejected` until the push is done with them.
+    {
+        let mut callbacks = RemoteCallbacks::new();
+
+        if let Some(password) = &mirror.password {
+            let username = mirror.username.as_deref().unwrap_or(DEFAULT_USERNAME);
+            callbacks.credentials(move |_, _, _| Cred::userpass_plaintext(username, password));
+        }
+
+        callbacks.push_update_reference(|name, status| {
+            if let Some(status) = status {
+                rejected.push(format!("{name} ({status})"));
+            }
+
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        repo.remote_anonymous(&mirror.target)?
+            .push(&refspecs, Some(&mut options))?;
+    }
+
+    if !rejected.is_empty() {
+        return Err(git2::Error::from_str(&format!(
+            "Rejected {}",
+            rejected.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Status of every push mirror, keyed by repository path and mirror id. Shared between the
+/// hook doing the pushing and the handlers reporting on it.
+#[derive(Debug, Clone, Default)]
+pub struct PushMirrors(Arc<Mutex<HashMap<(PathBuf, String), PushStatus>>>);
+
+impl PushMirrors {
+    fn update(&self, repo: &FsPath, id: &str, update: impl FnOnce(&mut PushStatus)) {
+        let mut statuses = self.0.lock().unwrap();
+        update(
+            statuses
+                .entry((repo.to_path_buf(), id.to_string()))
+                .or_default(),
+        );
+    }
+
+    fn status(&self, repo: &FsPath, id: &str) -> PushStatus {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&(repo.to_path_buf(), id.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Pushes `refs` to every push mirror of the repository in the background, retrying with
+    /// exponential backoff. Failures end up in the mirror's status.
+    pub fn refs_changed(&self, state: &AppState, repo: &Repository, refs: Vec<String>) {
+        let mirrors = match push_mirrors(state, repo) {
+            Ok(mirrors) => mirrors,
+            Err(error) => {
+                warn!(
+                    "Failed to read push mirrors of {}: {error}",
+                    repo.path().display()
+                );
+                return;
+            }
+        };
+
+        if mirrors.is_empty() || refs.is_empty() {
+            return;
+        }
+
+        let refs = Arc::new(refs);
+
+        for mirror in mirrors {
+            self.spawn(repo.path().to_path_buf(), mirror, refs.clone());
+        }
+    }
+
+    /// Pushes `refs` of the repository at `path` to `mirror` on the blocking pool.
+    fn spawn(&self, path: PathBuf, mirror: PushMirror, refs: Arc<Vec<String>>) {
+        let statuses = self.clone();
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+
+            let mut backoff = INITIAL_BACKOFF;
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                statuses.update(&path, &mirror.id, |status| {
+                    status.last_attempt = Some(now());
+                });
+
+                let result =
+                    Repository::open_bare(&path).and_then(|repo| push(&repo, &mirror, &refs));
+
+                match result {
+                    Ok(()) => {
+                        debug!("Pushed {} refs to mirror {}", refs.len(), mirror.url);
+
+                        statuses.update(&path, &mirror.id, |status| {
+                            status.last_success = status.last_attempt;
+                            status.last_error = None;
+                        });
+
+                        return;
+                    }
+                    Err(error) => {
+                        warn!(
+                            "Push to mirror {} failed (attempt {attempt}): {}",
+                            mirror.url,
+                            error.message()
+                        );
+
+                        statuses.update(&path, &mirror.id, |status| {
+                            status.last_error = Some(error.message().to_string());
+                        });
+                    }
+                }
+
+                if attempt < MAX_ATTEMPTS {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+
+            warn!(
+                "Giving up on push to mirror {} after {MAX_ATTEMPTS} attempts",
+                mirror.url
+            );
+        });
+    }
+}
+
+/// Mirrors the refs moved by pushes.
+impl Hook for PushMirrors {
+    fn post_receive(&self, push: &mut Push, updates: &[&RefUpdate]) {
+        self.refs_changed(
+            push.state,
+            push.repo,
+            updates.iter().map(|update| update.name.clone()).collect(),
+        );
+    }
+}
+
+/// Every branch and tag, for bringing a new mirror up to date.
+fn all_refs(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut refs = Vec::new();
+
+    for reference in repo.references()? {
+        if let Some(name) = reference?.name()
+            && (name.starts_with("refs/heads/") || name.starts_with("refs/tags/"))
+        {
+            refs.push(name.to_string());
+        }
+    }
+
+    Ok(refs)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePushMirror {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+pub async fn list_push_mirrors(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+) -> Result<Json<Vec<PushMirror>>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        let mut mirrors = push_mirrors(&state, &repo)?;
+
+        for mirror in &mut mirrors {
+            mirror.status = state.push_mirrors.status(repo.path(), &mirror.id);
+        }
+
+        Ok(Json(mirrors))
+    })
+    .await
+}
+
+/// Registers a push mirror and pushes every branch and tag to it.
+pub async fn create_push_mirror(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<CreatePushMirror>,
+) -> Result<(StatusCode, Json<PushMirror>), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        if !url::Url::parse(&payload.url)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+        {
+            return Err(Error::BadRequest(format!(
+                "Unsupported push mirror url: {}",
+                redact_url(&payload.url)
+            )));
+        }
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let id = hex(&rand::rng().random::<[u8; 8]>());
+        let username = payload.username.filter(|username| !username.is_empty());
+        let password = payload.password.filter(|password| !password.is_empty());
+
+        if let Some(password) = &password {
+            state.secrets.set(&password_key(&id), password)?;
+        }
+
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        config.set_str(&format!("{PUSH_SECTION}.{id}.url"), &payload.url)?;
+
+        if let Some(username) = &username {
+            config.set_str(&format!("{PUSH_SECTION}.{id}.username"), username)?;
+        }
+
+        info!("Registered push mirror {id} for {user}/{name}");
+
+        let mirror = PushMirror {
+            id,
+            url: redact_url(&payload.url),
+            authenticated: password.is_some(),
+            status: PushStatus::default(),
+            target: payload.url,
+            username,
+            password,
+        };
+
+        // Empty repositories have nothing to bring the mirror up to date with yet.
+        let refs = all_refs(&repo)?;
+
+        if !refs.is_empty() {
+            state
+                .push_mirrors
+                .spawn(repo.path().to_path_buf(), mirror.clone(), Arc::new(refs));
+        }
+
+        Ok((StatusCode::CREATED, Json(mirror)))
+    })
+    .await
+}
+
+pub async fn delete_push_mirror(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, id)): Path<(Name, Name, String)>,
+    caller: Authenticated,
+) -> Result<StatusCode, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if !push_mirrors(&state, &repo)?
+            .iter()
+            .any(|mirror| mirror.id == id)
+        {
+            return Err(Error::NotFound);
+        }
+
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        for variable in ["url", "username"] {
+            match config.remove(&format!("{PUSH_SECTION}.{id}.{variable}")) {
+                Err(error) if error.code() != git2::ErrorCode::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+
+        state.secrets.remove(&password_key(&id))?;
+
+        state
+            .push_mirrors
+            .0
+            .lock()
+            .unwrap()
+            .remove(&(repo.path().to_path_buf(), id.clone()));
+
+        info!("Deleted push mirror {id} of {user}/{name}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{ADMIN_TOKEN, TestServer, commit};
+
+    #[tokio::test]
+    async fn imports_mirror_every_ref_over_http() {
+        let server = TestServer::new("mirror-import");
+        let source = server.init_repo("alice", "src.git");
+
+        let main = commit(&source, Some("refs/heads/main"), &[("a", b"main")], &[]);
+        let topic = commit(
+            &source,
+            Some("refs/heads/topic"),
+            &[("a", b"topic")],
+            &[main],
+        );
+        source.set_head("refs/heads/topic").unwrap();
+
+        for url in ["file:///etc", "/tmp/src.git", "ssh://example.com/src.git"] {
+            let (status, _) = server
+                .send(
+                    Method::POST,
+                    "/repo/bob/copy.git/import",
+                    Some(ADMIN_TOKEN),
+                    Some(json!({ "url": url })),
+                )
+                .await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+        }
+
+        // libgit2 fetches over a real socket, so the test server has to listen on one.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, server.app.clone()).into_future());
+
+        let (status, import) = server
+            .json(
+                Method::POST,
+                "/repo/bob/copy.git/import",
+                Some(ADMIN_TOKEN),
+                Some(json!({ "url": format!("http://{address}/repo/alice/src.git") })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(import["state"], "running");
+
+        let import = loop {
+            let (status, import) = server
+                .json(
+                    Method::GET,
+                    "/repo/bob/copy.git/import",
+                    Some(ADMIN_TOKEN),
+                    None,
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+
+            if import["state"] != "running" {
+                break import;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+
+        assert_eq!(import["state"], "done", "{import}");
+
+        let copy = Repository::open_bare(server.state.repo_path("bob", "copy.git")).unwrap();
+        assert_eq!(copy.refname_to_id("refs/heads/main").unwrap(), main);
+        assert_eq!(copy.head().unwrap().target(), Some(topic));
+    }
+
+    #[tokio::test]
+    async fn push_mirror_passwords_stay_out_of_the_repository() {
+        let server = TestServer::new("mirror-passwords");
+        let repo = server.init_repo("alice", "r.git");
+
+        let (status, mirror) = server
+            .json(
+                Method::POST,
+                "/repo/alice/r.git/push-mirrors",
+                Some(ADMIN_TOKEN),
+                Some(json!({
+                    "url": "https://example.com/alice/r.git",
+                    "username": "alice",
+                    "password": "MIRRORPASS",
+                })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(mirror["authenticated"], true);
+
+        let (status, config) = server
+            .send(Method::GET, "/repo/alice/r.git/config", None, None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!String::from_utf8_lossy(&config).contains("MIRRORPASS"));
+
+        let config = fs::read_to_string(repo.path().join("config")).unwrap();
+        assert!(!config.contains("MIRRORPASS"), "{config}");
+
+        // The password is still there to push with.
+        let mirrors = push_mirrors(&server.state, &repo).unwrap();
+        assert_eq!(mirrors[0].username.as_deref(), Some("alice"));
+        assert_eq!(mirrors[0].password.as_deref(), Some("MIRRORPASS"));
+    }
+}
@@ -0,0 +1,112 @@
+//! Fire-and-forget delivery of JSON notifications to external services.
+//!
+//! This is a deliberately small HTTP/1.1 client: it only speaks plain `http://`, sends a single
+//! `POST` per connection and only looks at the status line of the response.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+use url::Url;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serializes `payload` and delivers it to `url` in the background, retrying with exponential
+/// backoff. Failures are only logged so callers never have to wait on or handle them.
+pub fn spawn<T: Serialize>(url: String, payload: &T) {
+    match serde_json::to_vec(payload) {
+        Ok(body) => deliver(url, Vec::new(), body),
+        Err(error) => warn!("Failed to serialize notification for {url}: {error}"),
+    }
+}
+
+/// Like [`spawn`], but for an already serialized body sent along with extra `headers`.
+pub fn deliver(url: String, headers: Vec<(&'static str, String)>, body: Vec<u8>) {
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            match timeout(REQUEST_TIMEOUT, post_json(&url, &headers, &body)).await {
+                Ok(Ok(())) => {
+                    debug!("Delivered notification to {url}");
+                    return;
+                }
+                Ok(Err(error)) => {
+                    warn!("Notification to {url} failed (attempt {attempt}): {error}")
+                }
+                Err(_) => warn!("Notification to {url} timed out (attempt {attempt})"),
+            }
+
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        warn!("Giving up on notification to {url} after {MAX_ATTEMPTS} attempts");
+    });
+}
+
+/// Sends `body` as a JSON `POST` and succeeds on any 2xx response.
+pub async fn post_json(url: &str, headers: &[(&str, String)], body: &[u8]) -> Result<()> {
+    let url = Url::parse(url)?;
+
+    if url.scheme() != "http" {
+        bail!("Unsupported scheme {}", url.scheme());
+    }
+
+    let Some(host) = url.host_str() else {
+        bail!("Missing host in {url}");
+    };
+
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let mut head = format!(
+        "POST {target} HTTP/1.1\r\n\
+         Host: {host}:{port}\r\n\
+         User-Agent: {}/{}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let status_line = response
+        .split(|&byte| byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("Unexpected response: {}", status.trim()),
+    }
+}
@@ -0,0 +1,427 @@
+//! Git smart HTTP protocol.
+//!
+//! Clients first fetch `info/refs?service=<service>` for the ref advertisement, then POST to
+//! `/<service>` with their request. Every request is self-contained ("stateless RPC"): the
+//! client repeats whatever negotiation state the server needs.
+
+pub mod pkt_line;
+mod receive_pack;
+mod upload_pack;
+mod v2;
+
+pub use upload_pack::walk_tree;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use git2::{Oid, Reference, Repository};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    AppState, Error, Json, Name, Path, Query, RefFilter, auth::require_write, blocking,
+    first_hidden, hooks::Push, maintenance, serve_repo_file,
+};
+
+const UPLOAD_PACK: &str = "git-upload-pack";
+const RECEIVE_PACK: &str = "git-receive-pack";
+
+/// Capabilities advertised by upload-pack.
+const UPLOAD_PACK_CAPABILITIES: &[&str] = &[
+    "side-band",
+    "side-band-64k",
+    "ofs-delta",
+    "no-progress",
+    "include-tag",
+    "filter",
+    // Partial clones fetch missing blobs by oid, which only works for reachable ones.
+    "allow-reachable-sha1-in-want",
+];
+
+/// Capabilities advertised by receive-pack.
+const RECEIVE_PACK_CAPABILITIES: &[&str] = &[
+    "report-status",
+    "delete-refs",
+    "side-band-64k",
+    "quiet",
+    "atomic",
+    "ofs-delta",
+];
+
+/// Whether the client asked for protocol v2 through the `Git-Protocol` header.
+fn wants_v2(headers: &HeaderMap) -> bool {
+    headers
+        .get("git-protocol")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(':').any(|parameter| parameter == "version=2"))
+}
+
+fn agent() -> String {
+    format!(
+        "agent={}/{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// The refs a service announces to the client, along with what it is capable of.
+#[derive(Debug)]
+pub struct Advertisement {
+    pub service: &'static str,
+    pub capabilities: Vec<String>,
+    /// Refs in advertisement order, `HEAD` first. Annotated tags are followed by their peeled
+    /// target under `<name>^{}`.
+    pub refs: Vec<(String, Oid)>,
+}
+
+impl Advertisement {
+    pub fn new(repo: &Repository, service: &'static str) -> Result<Self, git2::Error> {
+        let mut capabilities: Vec<String> = match service {
+            RECEIVE_PACK => RECEIVE_PACK_CAPABILITIES,
+            _ => UPLOAD_PACK_CAPABILITIES,
+        }
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect();
+
+        // Pushes only ever target the refs themselves, so HEAD and peeled tags are left out.
+        // Pushers also need to know where hidden branches are to update them.
+        let fetching = service == UPLOAD_PACK;
+
+        let mut refs = Vec::new();
+
+        if fetching && let Some(head) = visible_head(repo)? {
+            if let Some(oid) = head.resolve().ok().and_then(|head| head.target()) {
+                refs.push(("HEAD".to_string(), oid));
+            }
+
+            if let Some(target) = head.symbolic_target() {
+                capabilities.push(format!("symref=HEAD:{target}"));
+            }
+        }
+
+        capabilities.push(agent());
+
+        let candidates = if fetching {
+            visible_refs(repo)?
+        } else {
+            repo.references()?.collect::<Result<_, _>>()?
+        };
+
+        let mut references = Vec::new();
+
+        for reference in candidates {
+            let (Some(name), Some(oid)) = (reference.name(), reference.target()) else {
+                continue;
+            };
+
+            let peeled = match repo.find_tag(oid) {
+                Ok(tag) if fetching => Some(tag.as_object().peel(git2::ObjectType::Any)?.id()),
+                _ => None,
+            };
+
+            references.push((name.to_string(), oid, peeled));
+        }
+
+        references.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, oid, peeled) in references {
+            if let Some(peeled) = peeled {
+                refs.push((name.clone(), oid));
+                refs.push((format!("{name}^{{}}"), peeled));
+            } else {
+                refs.push((name, oid));
+            }
+        }
+
+        Ok(Self {
+            service,
+            capabilities,
+            refs,
+        })
+    }
+
+    /// Encodes the advertisement as served from `info/refs`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        pkt_line::write_line(&mut buf, &format!("# service={}", self.service));
+        pkt_line::flush(&mut buf);
+
+        let capabilities = self.capabilities.join(" ");
+
+        match self.refs.split_first() {
+            Some(((name, oid), rest)) => {
+                pkt_line::write(
+                    &mut buf,
+                    format!("{oid} {name}\0{capabilities}\n").as_bytes(),
+                );
+
+                for (name, oid) in rest {
+                    pkt_line::write_line(&mut buf, &format!("{oid} {name}"));
+                }
+            }
+            // Empty repositories still need somewhere to put their capabilities.
+            None => pkt_line::write(
+                &mut buf,
+                format!("{} capabilities^{{}}\0{capabilities}\n", Oid::zero()).as_bytes(),
+            ),
+        }
+
+        pkt_line::flush(&mut buf);
+
+        buf
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InfoRefsQuery {
+    service: Option<String>,
+}
+
+fn parse_service(service: &str) -> Result<&'static str, Error> {
+    match service {
+        UPLOAD_PACK => Ok(UPLOAD_PACK),
+        RECEIVE_PACK => Ok(RECEIVE_PACK),
+        _ => Err(Error::BadRequest(format!("Unsupported service: {service}"))),
+    }
+}
+
+pub async fn info_refs(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<InfoRefsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    blocking(move || {
+        // Without a service the client speaks the dumb protocol and wants the file itself.
+        let Some(service) = query.service else {
+            return serve_repo_file(&state, &user, &name, "info/refs");
+        };
+
+        let service = parse_service(&service)?;
+
+        // Challenge pushes up front, so git asks for credentials before building a pack.
+        if service == RECEIVE_PACK {
+            require_write(&state, &headers, &user, &name)?;
+        }
+
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!("Advertising refs of {user}/{name} for {service}");
+
+        // Pushes are always served over v0.
+        let advertisement = state.metrics.time("advertise", || {
+            Ok::<_, Error>(if service == UPLOAD_PACK && wants_v2(&headers) {
+                v2::advertise()
+            } else {
+                Advertisement::new(&repo, service)?.encode()
+            })
+        })?;
+
+        Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    format!("application/x-{service}-advertisement"),
+                ),
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+            ],
+            advertisement,
+        )
+            .into_response())
+    })
+    .await
+}
+
+pub async fn upload_pack(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!("Serving upload-pack for {user}/{name}");
+
+        let response = state.metrics.time("upload-pack", || {
+            if wants_v2(&headers) {
+                v2::serve(&repo, &body)
+            } else {
+                upload_pack::serve(&repo, &body)
+            }
+        })?;
+
+        state.metrics.pack_sent(response.len());
+
+        Ok((
+            [
+                (header::CONTENT_TYPE, "application/x-git-upload-pack-result"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            response,
+        )
+            .into_response())
+    })
+    .await
+}
+
+pub async fn receive_pack(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    blocking(move || {
+        require_write(&state, &headers, &user, &name)?;
+
+        let repo = state.open_repo(&user, &name)?;
+
+        debug!("Serving receive-pack for {user}/{name}");
+
+        let mut push = Push::new(&state, &repo, &user, &name);
+        let response = state.metrics.time("receive-pack", || {
+            receive_pack::serve(&mut push, &state.hooks, &body)
+        })?;
+
+        state.metrics.pack_received(body.len());
+
+        maintenance::after_push(&state, repo.path());
+
+        Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    "application/x-git-receive-pack-result",
+                ),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            response,
+        )
+            .into_response())
+    })
+    .await
+}
+
+/// Encodes a v2 git bundle of `refs`: a header listing them, followed by a pack of everything
+/// they reach. There are no prerequisites, so the bundle can be cloned from on its own.
+pub fn bundle(repo: &Repository, refs: &[(String, Oid)]) -> Result<Vec<u8>, git2::Error> {
+    let mut bundle = b"# v2 git bundle\n".to_vec();
+
+    for (name, oid) in refs {
+        bundle.extend_from_slice(format!("{oid} {name}\n").as_bytes());
+    }
+
+    bundle.push(b'\n');
+
+    let wants: Vec<Oid> = refs.iter().map(|&(_, oid)| oid).collect();
+    bundle.extend_from_slice(&upload_pack::build_pack(repo, &wants, &[], false, None)?);
+
+    Ok(bundle)
+}
+
+/// Packs everything reachable from `tips` but not from the `hidden` commits into a new pack in
+/// the object database, returning the pack's name: the checksum it ends with.
+pub fn repack(repo: &Repository, tips: &[Oid], hidden: &[Oid]) -> Result<Oid, git2::Error> {
+    let pack = upload_pack::build_pack(repo, tips, hidden, false, None)?;
+
+    receive_pack::index_pack(repo, &pack)?;
+
+    Oid::from_bytes(&pack[pack.len().saturating_sub(20)..])
+}
+
+/// HEAD, unless it points at a branch clients may not see.
+fn visible_head(repo: &Repository) -> Result<Option<Reference<'_>>, git2::Error> {
+    let filter = RefFilter::new(repo)?;
+
+    Ok(repo.find_reference("HEAD").ok().filter(|head| {
+        head.symbolic_target()
+            .is_none_or(|target| filter.is_visible(target))
+    }))
+}
+
+/// The refs other than HEAD clients fetching from `repo` may see: all but branches that aren't
+/// public, along with symbolic refs pointing at them.
+fn visible_refs(repo: &Repository) -> Result<Vec<Reference<'_>>, git2::Error> {
+    let filter = RefFilter::new(repo)?;
+
+    let mut refs = Vec::new();
+
+    for reference in repo.references()? {
+        let reference = reference?;
+
+        let visible = reference.name().is_some_and(|name| filter.is_visible(name))
+            && reference
+                .symbolic_target()
+                .is_none_or(|target| filter.is_visible(target));
+
+        if visible {
+            refs.push(reference);
+        }
+    }
+
+    Ok(refs)
+}
+
+/// The error to answer with if any of `wants` is something the client may not fetch. Anything
+/// a visible ref leads to may be asked for, not just the refs themselves.
+fn refused_want(repo: &Repository, wants: &[Oid]) -> Result<Option<String>, git2::Error> {
+    Ok(first_hidden(repo, wants)?.map(|want| format!("ERR upload-pack: not our ref {want}")))
+}
+
+/// Decodes the hex object id at the start of `value`.
+fn parse_oid(value: &[u8]) -> Result<Oid, Error> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| Oid::from_str(value.get(..40)?).ok())
+        .ok_or_else(|| Error::BadRequest("Invalid object id".to_string()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugRefs {
+    service: &'static str,
+    capabilities: Vec<String>,
+    refs: BTreeMap<String, String>,
+}
+
+/// The upload-pack advertisement in a human readable form, for debugging negotiation issues
+/// without a git client.
+pub async fn debug_refs(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    Query(query): Query<InfoRefsQuery>,
+    headers: HeaderMap,
+) -> Result<Json<DebugRefs>, Error> {
+    blocking(move || {
+        let service = parse_service(query.service.as_deref().unwrap_or(UPLOAD_PACK))?;
+
+        // The receive-pack advertisement includes hidden branches.
+        if service == RECEIVE_PACK {
+            require_write(&state, &headers, &user, &name)?;
+        }
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let Advertisement {
+            service,
+            capabilities,
+            refs,
+        } = Advertisement::new(&repo, service)?;
+
+        Ok(Json(DebugRefs {
+            service,
+            capabilities,
+            refs: refs
+                .into_iter()
+                .map(|(name, oid)| (name, oid.to_string()))
+                .collect(),
+        }))
+    })
+    .await
+}
@@ -0,0 +1,122 @@
+//! The pkt-line framing used throughout the git wire protocol.
+//!
+//! Every packet starts with its total length (including the 4 byte header) as 4 hex digits.
+//! A handful of lengths below 4 are reserved as control packets.
+
+use std::ops::Range;
+
+/// Largest payload a single pkt-line can carry.
+pub const MAX_DATA_LEN: usize = 65516;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Data(&'a [u8]),
+    /// `0000`, terminating a section or message.
+    Flush,
+    /// `0001`, separating sections in protocol v2.
+    Delimiter,
+    /// `0002`, marking the end of a stateless response in protocol v2.
+    ResponseEnd,
+}
+
+impl<'a> Packet<'a> {
+    /// Payload of a data packet with a trailing newline removed, as most text packets carry one.
+    pub fn text(&self) -> Option<&'a [u8]> {
+        match self {
+            Packet::Data(data) => Some(data.strip_suffix(b"\n").unwrap_or(data)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidPacket;
+
+/// Iterates over the packets in a buffer.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Bytes that haven't been parsed as packets yet, such as a packfile following the commands.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn take(&mut self, range: Range<usize>) -> &'a [u8] {
+        let data = &self.data[range.clone()];
+        self.data = &self.data[range.end..];
+        data
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<Packet<'a>, InvalidPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let length = self
+            .data
+            .get(..4)
+            .and_then(|length| std::str::from_utf8(length).ok())
+            .and_then(|length| usize::from_str_radix(length, 16).ok());
+
+        let packet = match length {
+            Some(0) => Packet::Flush,
+            Some(1) => Packet::Delimiter,
+            Some(2) => Packet::ResponseEnd,
+            Some(length) if length >= 4 && length <= self.data.len() => {
+                return Some(Ok(Packet::Data(self.take(4..length))));
+            }
+            _ => {
+                self.data = &[];
+                return Some(Err(InvalidPacket));
+            }
+        };
+
+        self.take(0..4);
+
+        Some(Ok(packet))
+    }
+}
+
+/// Appends `data` as a single data packet.
+pub fn write(buf: &mut Vec<u8>, data: &[u8]) {
+    debug_assert!(data.len() <= MAX_DATA_LEN);
+
+    buf.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Appends a text packet, which git terminates with a newline.
+pub fn write_line(buf: &mut Vec<u8>, line: &str) {
+    buf.extend_from_slice(format!("{:04x}", line.len() + 5).as_bytes());
+    buf.extend_from_slice(line.as_bytes());
+    buf.push(b'\n');
+}
+
+pub fn flush(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"0000");
+}
+
+pub fn delimiter(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"0001");
+}
+
+/// Multiplexes `data` onto a side-band `channel` in packets of at most `max_len` bytes.
+pub fn write_sideband(buf: &mut Vec<u8>, channel: u8, data: &[u8], max_len: usize) {
+    for chunk in data.chunks(max_len) {
+        let mut packet = Vec::with_capacity(chunk.len() + 1);
+        packet.push(channel);
+        packet.extend_from_slice(chunk);
+
+        write(buf, &packet);
+    }
+}
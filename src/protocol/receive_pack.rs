@@ -0,0 +1,307 @@
+//! The receive-pack service, which accepts pushes.
+//!
+//! A push is a list of `<old> <new> <ref>` commands followed by a packfile with the objects
+//! the new values need. The pack is indexed into the object database before any ref moves, and
+//! every ref is only updated if it still points where the client expects.
+
+use std::{collections::HashSet, io::Write};
+
+use git2::{Oid, Reference, Repository};
+use tracing::{debug, warn};
+
+use super::{
+    parse_oid,
+    pkt_line::{self, Packet, Reader},
+};
+use crate::{
+    Error,
+    hooks::{Hooks, Push, RefUpdate},
+};
+
+/// Side-band channel carrying the status report.
+const REPORT_CHANNEL: u8 = 1;
+/// Side-band channel for messages shown to the user, such as hook output.
+const PROGRESS_CHANNEL: u8 = 2;
+
+#[derive(Debug, Default)]
+struct Request<'a> {
+    commands: Vec<RefUpdate>,
+    capabilities: HashSet<String>,
+    pack: &'a [u8],
+}
+
+fn parse_command(line: &[u8]) -> Result<RefUpdate, Error> {
+    let invalid = || Error::BadRequest("Invalid ref update command".to_string());
+
+    let mut parts = line.splitn(3, |&byte| byte == b' ');
+
+    let old = parse_oid(parts.next().ok_or_else(invalid)?)?;
+    let new = parse_oid(parts.next().ok_or_else(invalid)?)?;
+    let name = std::str::from_utf8(parts.next().ok_or_else(invalid)?)
+        .map_err(|_| invalid())?
+        .to_string();
+
+    Ok(RefUpdate { old, new, name })
+}
+
+fn parse_request(body: &[u8]) -> Result<Request<'_>, Error> {
+    let mut request = Request::default();
+    let mut reader = Reader::new(body);
+
+    for packet in reader.by_ref() {
+        let packet = packet.map_err(|_| Error::BadRequest("Invalid pkt-line".to_string()))?;
+
+        let Some(line) = packet.text() else {
+            if packet == Packet::Flush {
+                break;
+            }
+
+            continue;
+        };
+
+        // The first command carries the client's capabilities after a NUL.
+        let line = match line.iter().position(|&byte| byte == 0) {
+            Some(nul) if request.commands.is_empty() => {
+                request.capabilities = String::from_utf8_lossy(&line[nul + 1..])
+                    .split(' ')
+                    .filter(|capability| !capability.is_empty())
+                    .map(str::to_string)
+                    .collect();
+
+                &line[..nul]
+            }
+            _ => line,
+        };
+
+        // Shallow clones announce their boundaries before the commands.
+        if line.starts_with(b"shallow ") {
+            continue;
+        }
+
+        request.commands.push(parse_command(line)?);
+    }
+
+    request.pack = reader.remaining();
+
+    Ok(request)
+}
+
+/// Handles a single receive-pack request, returning the response body.
+pub fn serve(push: &mut Push, hooks: &Hooks, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let request = parse_request(body)?;
+
+    let unpacked = if request.pack.is_empty() {
+        Ok(())
+    } else {
+        index_pack(push.repo, request.pack)
+    };
+
+    let results: Vec<Result<(), String>> = match &unpacked {
+        Ok(()) => update_refs(push, hooks, &request),
+        Err(_) => request
+            .commands
+            .iter()
+            .map(|_| Err("unpacker error".to_string()))
+            .collect(),
+    };
+
+    let applied: Vec<&RefUpdate> = request
+        .commands
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.is_ok())
+        .map(|(command, _)| command)
+        .collect();
+
+    if !applied.is_empty() {
+        hooks.post_receive(push, &applied);
+    }
+
+    let mut report = Vec::new();
+
+    match &unpacked {
+        Ok(()) => pkt_line::write_line(&mut report, "unpack ok"),
+        Err(error) => {
+            warn!("Failed to unpack pushed objects: {error}");
+            pkt_line::write_line(&mut report, &format!("unpack {}", error.message()));
+        }
+    }
+
+    for (command, result) in request.commands.iter().zip(&results) {
+        match result {
+            Ok(()) => pkt_line::write_line(&mut report, &format!("ok {}", command.name)),
+            Err(reason) => {
+                pkt_line::write_line(&mut report, &format!("ng {} {reason}", command.name))
+            }
+        }
+    }
+
+    pkt_line::flush(&mut report);
+
+    if !request.capabilities.contains("report-status") {
+        return Ok(Vec::new());
+    }
+
+    if !request.capabilities.contains("side-band-64k") {
+        return Ok(report);
+    }
+
+    let mut response = Vec::new();
+
+    // Unlike progress, hook output is shown even to quiet clients, as git does.
+    if !push.output().is_empty() {
+        pkt_line::write_sideband(
+            &mut response,
+            PROGRESS_CHANNEL,
+            push.output(),
+            pkt_line::MAX_DATA_LEN - 1,
+        );
+    }
+
+    pkt_line::write_sideband(
+        &mut response,
+        REPORT_CHANNEL,
+        &report,
+        pkt_line::MAX_DATA_LEN - 1,
+    );
+    pkt_line::flush(&mut response);
+
+    Ok(response)
+}
+
+/// Writes a pack into the object database, indexing it on the way.
+pub(super) fn index_pack(repo: &Repository, pack: &[u8]) -> Result<(), git2::Error> {
+    let odb = repo.odb()?;
+    let mut writer = odb.packwriter()?;
+
+    writer
+        .write_all(pack)
+        .map_err(|error| git2::Error::from_str(&error.to_string()))?;
+
+    let oid = writer.commit()?;
+
+    debug!("Indexed pack {oid}");
+
+    Ok(())
+}
+
+/// Checks a single command against the repository before anything is written.
+fn check(repo: &Repository, command: &RefUpdate) -> Result<(), String> {
+    if !command.name.starts_with("refs/") || !Reference::is_valid_name(&command.name) {
+        return Err("funny refname".to_string());
+    }
+
+    let config = repo.config().map_err(|error| error.message().to_string())?;
+    let deny = |key: &str| config.get_bool(key).unwrap_or(false);
+
+    if command.new.is_zero() {
+        if deny("receive.denyDeletes") {
+            return Err("deletion prohibited".to_string());
+        }
+
+        return Ok(());
+    }
+
+    let Ok(new) = repo.find_object(command.new, None) else {
+        return Err("missing necessary objects".to_string());
+    };
+
+    if !command.old.is_zero()
+        && deny("receive.denyNonFastForwards")
+        && new.as_commit().is_some()
+        && !repo
+            .graph_descendant_of(command.new, command.old)
+            .unwrap_or(false)
+    {
+        return Err("non-fast-forward".to_string());
+    }
+
+    Ok(())
+}
+
+fn update_refs(push: &mut Push, hooks: &Hooks, request: &Request) -> Vec<Result<(), String>> {
+    let repo = push.repo;
+
+    let mut results: Vec<Result<(), String>> = request
+        .commands
+        .iter()
+        .map(|command| check(repo, command))
+        .collect();
+
+    let accepted: Vec<&RefUpdate> = request
+        .commands
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.is_ok())
+        .map(|(command, _)| command)
+        .collect();
+
+    if !accepted.is_empty()
+        && let Err(reason) = hooks.pre_receive(push, &accepted)
+    {
+        for result in results.iter_mut().filter(|result| result.is_ok()) {
+            *result = Err(reason.clone());
+        }
+    }
+
+    for (command, result) in request.commands.iter().zip(&mut results) {
+        if result.is_ok() {
+            *result = hooks.update(push, command);
+        }
+    }
+
+    if request.capabilities.contains("atomic") {
+        let updated = if results.iter().all(Result::is_ok) {
+            apply(repo, &request.commands.iter().collect::<Vec<_>>())
+        } else {
+            Err("atomic push failed".to_string())
+        };
+
+        return results
+            .into_iter()
+            .map(|result| result.and(updated.clone()))
+            .collect();
+    }
+
+    for (command, result) in request.commands.iter().zip(&mut results) {
+        if result.is_ok() {
+            *result = apply(repo, &[command]);
+        }
+    }
+
+    results
+}
+
+/// Moves every ref in `commands` in a single transaction, failing if any of them changed since
+/// the client last saw it.
+fn apply(repo: &Repository, commands: &[&RefUpdate]) -> Result<(), String> {
+    let message = |error: git2::Error| error.message().to_string();
+
+    let mut transaction = repo.transaction().map_err(message)?;
+
+    for command in commands {
+        transaction.lock_ref(&command.name).map_err(message)?;
+    }
+
+    for command in commands {
+        let current = match repo.refname_to_id(&command.name) {
+            Ok(oid) => oid,
+            Err(error) if error.code() == git2::ErrorCode::NotFound => Oid::zero(),
+            Err(error) => return Err(message(error)),
+        };
+
+        if current != command.old {
+            return Err("stale info".to_string());
+        }
+
+        if command.new.is_zero() {
+            transaction.remove(&command.name).map_err(message)?;
+        } else {
+            transaction
+                .set_target(&command.name, command.new, None, "push")
+                .map_err(message)?;
+        }
+    }
+
+    transaction.commit().map_err(message)
+}
@@ -0,0 +1,327 @@
+//! The upload-pack service, which sends clients the objects they ask for.
+//!
+//! Negotiation follows the basic (non multi_ack) protocol: each round the client sends its
+//! haves and gets a single `ACK` for a common commit or `NAK`. Once it sends `done`, the final
+//! response carries that acknowledgement followed by the packfile.
+//!
+//! Partial clones can send a `filter` to leave blobs out, which they fetch later on demand.
+
+use std::collections::HashSet;
+
+use git2::{ObjectType, Oid, PackBuilder, Repository};
+
+use super::{
+    parse_oid,
+    pkt_line::{self, Reader},
+    refused_want,
+};
+use crate::Error;
+
+/// Side-band channel carrying the packfile.
+const PACK_CHANNEL: u8 = 1;
+/// Side-band channel carrying fatal errors.
+const ERROR_CHANNEL: u8 = 3;
+
+#[derive(Debug, Default)]
+struct Request {
+    wants: Vec<Oid>,
+    haves: Vec<Oid>,
+    capabilities: HashSet<String>,
+    filter: Option<Vec<u8>>,
+    done: bool,
+}
+
+/// Objects left out of a pack at the request of a partial clone. Objects the client names in
+/// its wants are always sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Filter {
+    /// `blob:none`, leaving out every blob.
+    NoBlobs,
+    /// `blob:limit=<n>`, leaving out blobs of at least that many bytes.
+    BlobLimit(u64),
+}
+
+impl Filter {
+    pub(super) fn parse(spec: &[u8]) -> Result<Self, String> {
+        let spec = String::from_utf8_lossy(spec);
+
+        if spec == "blob:none" {
+            return Ok(Self::NoBlobs);
+        }
+
+        let limit = spec.strip_prefix("blob:limit=").and_then(|limit| {
+            let (digits, scale) = match limit.as_bytes().last()?.to_ascii_lowercase() {
+                b'k' => (&limit[..limit.len() - 1], 1 << 10),
+                b'm' => (&limit[..limit.len() - 1], 1 << 20),
+                b'g' => (&limit[..limit.len() - 1], 1 << 30),
+                _ => (limit, 1),
+            };
+
+            digits.parse::<u64>().ok()?.checked_mul(scale)
+        });
+
+        match limit {
+            Some(limit) => Ok(Self::BlobLimit(limit)),
+            None => Err(format!("unsupported filter {spec}")),
+        }
+    }
+
+    fn includes_blob(self, repo: &Repository, oid: Oid) -> Result<bool, git2::Error> {
+        match self {
+            Self::NoBlobs => Ok(false),
+            Self::BlobLimit(limit) => {
+                let (size, _) = repo.odb()?.read_header(oid)?;
+
+                Ok((size as u64) < limit)
+            }
+        }
+    }
+}
+
+fn parse_request(body: &[u8]) -> Result<Request, Error> {
+    let mut request = Request::default();
+
+    for packet in Reader::new(body) {
+        let packet = packet.map_err(|_| Error::BadRequest("Invalid pkt-line".to_string()))?;
+
+        let Some(line) = packet.text() else {
+            continue;
+        };
+
+        if let Some(want) = line.strip_prefix(b"want ") {
+            // The first want line carries the client's capabilities after the oid.
+            if request.wants.is_empty() {
+                request.capabilities = String::from_utf8_lossy(want.get(41..).unwrap_or_default())
+                    .split(' ')
+                    .filter(|capability| !capability.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+
+            request.wants.push(parse_oid(want)?);
+        } else if let Some(have) = line.strip_prefix(b"have ") {
+            request.haves.push(parse_oid(have)?);
+        } else if let Some(filter) = line.strip_prefix(b"filter ") {
+            request.filter = Some(filter.to_vec());
+        } else if line == b"done" {
+            request.done = true;
+        }
+    }
+
+    Ok(request)
+}
+
+/// Handles a single upload-pack request, returning the response body.
+pub fn serve(repo: &Repository, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let request = parse_request(body)?;
+
+    let mut response = Vec::new();
+
+    if let Some(error) = refused_want(repo, &request.wants)? {
+        pkt_line::write_line(&mut response, &error);
+        return Ok(response);
+    }
+
+    let common: Vec<Oid> = request
+        .haves
+        .iter()
+        .copied()
+        .filter(|have| repo.find_commit(*have).is_ok())
+        .collect();
+
+    if !request.done {
+        match common.first() {
+            Some(oid) => pkt_line::write_line(&mut response, &format!("ACK {oid}")),
+            None => pkt_line::write_line(&mut response, "NAK"),
+        }
+
+        return Ok(response);
+    }
+
+    match common.last() {
+        Some(oid) => pkt_line::write_line(&mut response, &format!("ACK {oid}")),
+        None => pkt_line::write_line(&mut response, "NAK"),
+    }
+
+    let sideband_len = if request.capabilities.contains("side-band-64k") {
+        Some(pkt_line::MAX_DATA_LEN - 1)
+    } else if request.capabilities.contains("side-band") {
+        Some(999)
+    } else {
+        None
+    };
+
+    let include_tags = request.capabilities.contains("include-tag");
+
+    let pack = request
+        .filter
+        .as_deref()
+        .map(Filter::parse)
+        .transpose()
+        .and_then(|filter| {
+            build_pack(repo, &request.wants, &common, include_tags, filter)
+                .map_err(|error| error.message().to_string())
+        });
+
+    let pack = match pack {
+        Ok(pack) => pack,
+        Err(error) => {
+            let message = format!("upload-pack: {error}");
+
+            match sideband_len {
+                Some(_) => {
+                    pkt_line::write_sideband(&mut response, ERROR_CHANNEL, message.as_bytes(), 999)
+                }
+                None => pkt_line::write_line(&mut response, &format!("ERR {message}")),
+            }
+
+            return Ok(response);
+        }
+    };
+
+    match sideband_len {
+        Some(max_len) => {
+            pkt_line::write_sideband(&mut response, PACK_CHANNEL, &pack, max_len);
+            pkt_line::flush(&mut response);
+        }
+        None => response.extend_from_slice(&pack),
+    }
+
+    Ok(response)
+}
+
+/// Packs everything reachable from `wants` that isn't reachable from `common`. With
+/// `include_tags`, annotated tags pointing at any of the packed commits come along too.
+pub(super) fn build_pack(
+    repo: &Repository,
+    wants: &[Oid],
+    common: &[Oid],
+    include_tags: bool,
+    filter: Option<Filter>,
+) -> Result<Vec<u8>, git2::Error> {
+    let mut builder = repo.packbuilder()?;
+    let mut revwalk = repo.revwalk()?;
+
+    // Trees and blobs the client already has, so filtered packs leave them out like
+    // unfiltered ones do.
+    let mut seen = HashSet::new();
+
+    if filter.is_some() {
+        for &have in common {
+            let tree = repo.find_commit(have)?.tree_id();
+            walk_tree(repo, tree, &mut seen, &mut |_, _| Ok(()))?;
+        }
+    }
+
+    let mut insert_recursive = |builder: &mut PackBuilder, oid: Oid| match filter {
+        None => builder.insert_recursive(oid, None),
+        Some(filter) => match repo.find_object(oid, None)?.kind() {
+            Some(ObjectType::Tree) => walk_tree(repo, oid, &mut seen, &mut |oid, kind| {
+                if kind == ObjectType::Tree || filter.includes_blob(repo, oid)? {
+                    builder.insert_object(oid, None)?;
+                }
+
+                Ok(())
+            }),
+            _ => builder.insert_object(oid, None),
+        },
+    };
+
+    for &want in wants {
+        let object = repo.find_object(want, None)?;
+
+        match object.kind() {
+            Some(ObjectType::Commit) => revwalk.push(want)?,
+            Some(ObjectType::Tag) => {
+                builder.insert_object(want, None)?;
+
+                let target = object.peel(ObjectType::Any)?;
+
+                match target.kind() {
+                    Some(ObjectType::Commit) => revwalk.push(target.id())?,
+                    _ => insert_recursive(&mut builder, target.id())?,
+                }
+            }
+            _ => insert_recursive(&mut builder, want)?,
+        }
+    }
+
+    for &have in common {
+        revwalk.hide(have)?;
+    }
+
+    if include_tags {
+        let mut commits = HashSet::new();
+
+        let mut tag_walk = repo.revwalk()?;
+
+        for &want in wants {
+            if let Ok(commit) = repo.find_object(want, None)?.peel(ObjectType::Commit) {
+                tag_walk.push(commit.id())?;
+            }
+        }
+
+        for &have in common {
+            tag_walk.hide(have)?;
+        }
+
+        for oid in tag_walk {
+            commits.insert(oid?);
+        }
+
+        for reference in repo.references_glob("refs/tags/*")? {
+            let Some(oid) = reference?.target() else {
+                continue;
+            };
+
+            if let Ok(tag) = repo.find_tag(oid)
+                && commits.contains(&tag.target_id())
+            {
+                builder.insert_object(oid, None)?;
+            }
+        }
+    }
+
+    if filter.is_some() {
+        for commit in revwalk {
+            let commit = repo.find_commit(commit?)?;
+
+            builder.insert_object(commit.id(), None)?;
+            insert_recursive(&mut builder, commit.tree_id())?;
+        }
+    } else {
+        builder.insert_walk(&mut revwalk)?;
+    }
+
+    let mut pack = git2::Buf::new();
+    builder.write_buf(&mut pack)?;
+
+    Ok(pack.to_vec())
+}
+
+/// Calls `visit` for `tree` and everything below it that isn't in `seen` yet, adding them.
+/// Submodule commits are skipped, as they live in other repositories.
+pub fn walk_tree(
+    repo: &Repository,
+    tree: Oid,
+    seen: &mut HashSet<Oid>,
+    visit: &mut dyn FnMut(Oid, ObjectType) -> Result<(), git2::Error>,
+) -> Result<(), git2::Error> {
+    if !seen.insert(tree) {
+        return Ok(());
+    }
+
+    visit(tree, ObjectType::Tree)?;
+
+    for entry in repo.find_tree(tree)?.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => walk_tree(repo, entry.id(), seen, visit)?,
+            Some(ObjectType::Blob) if seen.insert(entry.id()) => {
+                visit(entry.id(), ObjectType::Blob)?
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Protocol v2, which clients opt into with a `Git-Protocol: version=2` header.
+//!
+//! Instead of advertising every ref up front, the server only lists its capabilities and the
+//! client then runs `ls-refs` (usually with ref prefixes) and `fetch` as separate commands.
+
+use git2::{ObjectType, Oid, Repository};
+
+use super::{
+    agent, parse_oid,
+    pkt_line::{self, Packet, Reader},
+    refused_want,
+    upload_pack::{Filter, build_pack},
+    visible_head, visible_refs,
+};
+use crate::Error;
+
+/// Side-band channel carrying the packfile.
+const PACK_CHANNEL: u8 = 1;
+
+/// The capability advertisement served from `info/refs`.
+pub fn advertise() -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    for line in [
+        "version 2",
+        &agent(),
+        "ls-refs=unborn",
+        "fetch=filter",
+        "server-option",
+        "object-format=sha1",
+    ] {
+        pkt_line::write_line(&mut buf, line);
+    }
+
+    pkt_line::flush(&mut buf);
+
+    buf
+}
+
+#[derive(Debug, Default)]
+struct Request<'a> {
+    command: &'a [u8],
+    arguments: Vec<&'a [u8]>,
+}
+
+fn parse_request(body: &[u8]) -> Result<Request<'_>, Error> {
+    let invalid = || Error::BadRequest("Invalid pkt-line".to_string());
+
+    let mut request = Request::default();
+    let mut in_arguments = false;
+
+    for packet in Reader::new(body) {
+        match packet.map_err(|_| invalid())? {
+            Packet::Flush => break,
+            Packet::Delimiter => in_arguments = true,
+            Packet::ResponseEnd => return Err(invalid()),
+            packet => {
+                let line = packet.text().unwrap_or_default();
+
+                if in_arguments {
+                    request.arguments.push(line);
+                } else if let Some(command) = line.strip_prefix(b"command=") {
+                    request.command = command;
+                }
+                // The remaining capability lines (agent, object-format, server options) don't
+                // change how we answer.
+            }
+        }
+    }
+
+    Ok(request)
+}
+
+/// Handles a single v2 command, returning the response body.
+pub fn serve(repo: &Repository, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let request = parse_request(body)?;
+
+    match request.command {
+        b"ls-refs" => ls_refs(repo, &request.arguments),
+        b"fetch" => fetch(repo, &request.arguments),
+        command => Err(Error::BadRequest(format!(
+            "Unknown command: {}",
+            String::from_utf8_lossy(command)
+        ))),
+    }
+}
+
+fn ls_refs(repo: &Repository, arguments: &[&[u8]]) -> Result<Vec<u8>, Error> {
+    let has = |argument: &[u8]| arguments.contains(&argument);
+
+    let symrefs = has(b"symrefs");
+    let peel = has(b"peel");
+    let unborn = has(b"unborn");
+
+    let prefixes: Vec<&[u8]> = arguments
+        .iter()
+        .filter_map(|argument| argument.strip_prefix(b"ref-prefix "))
+        .collect();
+
+    let wanted = |name: &str| {
+        prefixes.is_empty()
+            || prefixes
+                .iter()
+                .any(|prefix| name.as_bytes().starts_with(prefix))
+    };
+
+    let mut response = Vec::new();
+
+    if wanted("HEAD")
+        && let Some(head) = visible_head(repo)?
+    {
+        let target = head.symbolic_target().map(str::to_string);
+
+        let mut line = match repo.refname_to_id("HEAD") {
+            Ok(oid) => format!("{oid} HEAD"),
+            Err(_) if unborn && target.is_some() => "unborn HEAD".to_string(),
+            Err(_) => String::new(),
+        };
+
+        if !line.is_empty() {
+            if let Some(target) = target.filter(|_| symrefs || line.starts_with("unborn")) {
+                line.push_str(&format!(" symref-target:{target}"));
+            }
+
+            pkt_line::write_line(&mut response, &line);
+        }
+    }
+
+    let mut references = Vec::new();
+
+    for reference in visible_refs(repo)? {
+        let Some(name) = reference.name().filter(|name| wanted(name)) else {
+            continue;
+        };
+
+        let Ok(resolved) = reference.resolve() else {
+            continue;
+        };
+
+        let Some(oid) = resolved.target() else {
+            continue;
+        };
+
+        let mut line = format!("{oid} {name}");
+
+        if symrefs && let Some(target) = reference.symbolic_target() {
+            line.push_str(&format!(" symref-target:{target}"));
+        }
+
+        if peel && let Ok(tag) = repo.find_tag(oid) {
+            let peeled = tag.as_object().peel(ObjectType::Any)?.id();
+            line.push_str(&format!(" peeled:{peeled}"));
+        }
+
+        references.push((name.to_string(), line));
+    }
+
+    references.sort();
+
+    for (_, line) in references {
+        pkt_line::write_line(&mut response, &line);
+    }
+
+    pkt_line::flush(&mut response);
+
+    Ok(response)
+}
+
+fn fetch(repo: &Repository, arguments: &[&[u8]]) -> Result<Vec<u8>, Error> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut done = false;
+    let mut include_tags = false;
+    let mut filter = None;
+
+    for &argument in arguments {
+        if let Some(want) = argument.strip_prefix(b"want ") {
+            wants.push(parse_oid(want)?);
+        } else if let Some(have) = argument.strip_prefix(b"have ") {
+            haves.push(parse_oid(have)?);
+        } else if argument == b"done" {
+            done = true;
+        } else if argument == b"include-tag" {
+            include_tags = true;
+        } else if let Some(spec) = argument.strip_prefix(b"filter ") {
+            filter = Some(spec);
+        }
+    }
+
+    let mut response = Vec::new();
+
+    if let Some(error) = refused_want(repo, &wants)? {
+        pkt_line::write_line(&mut response, &error);
+        return Ok(response);
+    }
+
+    let common: Vec<Oid> = haves
+        .into_iter()
+        .filter(|have| repo.find_commit(*have).is_ok())
+        .collect();
+
+    if !done {
+        pkt_line::write_line(&mut response, "acknowledgments");
+
+        // Like the v0 negotiation, a single common commit is considered good enough to send
+        // a pack. Without one the client keeps sending haves.
+        if common.is_empty() {
+            pkt_line::write_line(&mut response, "NAK");
+            pkt_line::flush(&mut response);
+
+            return Ok(response);
+        }
+
+        for oid in &common {
+            pkt_line::write_line(&mut response, &format!("ACK {oid}"));
+        }
+
+        pkt_line::write_line(&mut response, "ready");
+        pkt_line::delimiter(&mut response);
+    }
+
+    let pack = filter.map(Filter::parse).transpose().and_then(|filter| {
+        build_pack(repo, &wants, &common, include_tags, filter)
+            .map_err(|error| error.message().to_string())
+    });
+
+    let pack = match pack {
+        Ok(pack) => pack,
+        Err(error) => {
+            pkt_line::write_line(&mut response, &format!("ERR upload-pack: {error}"));
+            return Ok(response);
+        }
+    };
+
+    pkt_line::write_line(&mut response, "packfile");
+    pkt_line::write_sideband(
+        &mut response,
+        PACK_CHANNEL,
+        &pack,
+        pkt_line::MAX_DATA_LEN - 1,
+    );
+    pkt_line::flush(&mut response);
+
+    Ok(response)
+}
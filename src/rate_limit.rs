@@ -0,0 +1,154 @@
+//! Per-client rate limiting for every route, git and API alike.
+//!
+//! Each client gets a token bucket: it holds up to `burst` requests and refills at the
+//! configured rate. Anonymous clients are told apart by address and token holders by token, so
+//! users behind one NAT don't starve each other once they authenticate. A request finding the
+//! bucket empty gets `429 Too Many Requests` with a `Retry-After` saying when the next one
+//! would pass.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State, connect_info::Connected},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::IncomingStream,
+};
+use tracing::debug;
+
+use crate::{
+    AppState, Error,
+    auth::{self, Principal},
+    config::RateLimit,
+    metrics,
+};
+
+/// Buckets kept before full ones, which are no different from missing ones, are dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Probes and scrapes come from a handful of load balancers and monitoring hosts and must
+/// never be turned away.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+
+/// Address of the client on the other end of the connection. Unix socket clients have none,
+/// and as they are all the same local proxy as far as the server can tell, aren't limited.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub Option<SocketAddr>);
+
+impl Connected<IncomingStream<'_, metrics::Listener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, metrics::Listener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Address(IpAddr),
+    Token(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let rate = f64::from(self.limit.per_minute) / 60.0;
+
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(self.limit.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= f64::from(self.limit.burst)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Limiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl Limiter {
+    /// Takes a request out of the client's bucket, or says how many seconds until there is one.
+    fn acquire(&self, key: Key, limit: RateLimit) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+
+        bucket.refill(now);
+
+        // Applies reloaded settings from here on.
+        bucket.limit = limit;
+        bucket.tokens = bucket.tokens.min(f64::from(limit.burst));
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = (1.0 - bucket.tokens) * 60.0 / f64::from(limit.per_minute);
+
+        Err(wait.ceil() as u64)
+    }
+}
+
+pub async fn limit(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let (rate_limit, token_rate_limit) = {
+        let settings = state.settings.read().unwrap();
+        (settings.rate_limit, settings.token_rate_limit)
+    };
+
+    if rate_limit.is_none() && token_rate_limit.is_none()
+        || EXEMPT_PATHS.contains(&request.uri().path())
+    {
+        return next.run(request).await;
+    }
+
+    // Bad credentials are limited like anonymous requests, then rejected by the handler.
+    let principal = auth::authenticate(&state, request.headers()).unwrap_or(None);
+
+    let address = request
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .and_then(|ConnectInfo(ClientAddr(address))| address.map(|address| address.ip()));
+
+    let (key, limit) = match principal {
+        Some(Principal::Admin) => (None, None),
+        Some(Principal::Token { id, .. } | Principal::Deploy { id, .. }) => {
+            (Some(Key::Token(id)), token_rate_limit)
+        }
+        None => (address.map(Key::Address), rate_limit),
+    };
+
+    if let (Some(key), Some(limit)) = (key, limit)
+        && let Err(retry_after) = state.limiter.acquire(key.clone(), limit)
+    {
+        debug!("Rate limited {key:?} for {retry_after}s");
+
+        return Error::TooManyRequests { retry_after }.into_response();
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,122 @@
+//! Credentials the server has to hand to others: webhook secrets and push mirror passwords.
+//!
+//! Unlike access tokens they can't be stored hashed, and they don't go in the config of the
+//! repository they belong to either, as clients of the dumb protocol read files straight out of
+//! repository directories. They are stored in a file under the repository root instead, only
+//! readable by the server, and keyed by the config subsection of the webhook or mirror they
+//! belong to, such as `webhook.<id>`, where the ids are random.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use git2::{ConfigLevel, Repository};
+
+use crate::{Error, hidden_sibling};
+
+/// Name of the secret store within the repository root.
+pub const SECRETS_FILE: &str = ".secrets";
+
+/// Stored secrets, mirrored to disk on every change.
+#[derive(Debug, Default)]
+pub struct Secrets {
+    /// Where the secrets are persisted. Without one they only live in memory.
+    path: Option<PathBuf>,
+    secrets: Mutex<BTreeMap<String, String>>,
+}
+
+impl Secrets {
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let secrets = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            secrets: Mutex::new(secrets),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.secrets.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, secret: &str) -> io::Result<()> {
+        let mut secrets = self.secrets.lock().unwrap();
+
+        let mut updated = secrets.clone();
+        updated.insert(key.to_string(), secret.to_string());
+
+        self.save(&updated)?;
+        *secrets = updated;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        self.remove_all(&[key])
+    }
+
+    fn remove_all(&self, keys: &[&str]) -> io::Result<()> {
+        let mut secrets = self.secrets.lock().unwrap();
+
+        let mut updated = secrets.clone();
+        updated.retain(|key, _| !keys.contains(&key.as_str()));
+
+        if updated.len() == secrets.len() {
+            return Ok(());
+        }
+
+        self.save(&updated)?;
+        *secrets = updated;
+
+        Ok(())
+    }
+
+    /// Removes the secrets of a repository that is going away for good.
+    pub fn forget(&self, repo: &Repository) -> Result<(), Error> {
+        let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        let mut subsections = Vec::new();
+        let mut entries = config.entries(None)?;
+
+        while let Some(entry) = entries.next() {
+            if let Some((subsection, _)) = entry?.name().and_then(|name| name.rsplit_once('.')) {
+                subsections.push(subsection.to_string());
+            }
+        }
+
+        let keys: Vec<&str> = subsections.iter().map(String::as_str).collect();
+
+        Ok(self.remove_all(&keys)?)
+    }
+
+    fn save(&self, secrets: &BTreeMap<String, String>) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Replace the file in one step so a crash never leaves a truncated store behind.
+        let staging = hidden_sibling(path, "new");
+
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staging)?
+            .write_all(&serde_json::to_vec_pretty(secrets)?)?;
+
+        fs::rename(&staging, path)
+    }
+}
@@ -0,0 +1,72 @@
+//! Process signal handling.
+//!
+//! The runtime is built without tokio's signal driver, so signals are routed through a self-pipe:
+//! the handler only writes the signal number to the pipe, and a dedicated thread reads it back
+//! and runs the callback outside of signal context.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::fd::FromRawFd,
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+};
+
+use libc::c_int;
+
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: c_int) {
+    let byte = signal as u8;
+
+    // SAFETY: write(2) is async-signal-safe and the buffer outlives the call.
+    unsafe {
+        libc::write(PIPE.load(Ordering::Relaxed), (&raw const byte).cast(), 1);
+    }
+}
+
+/// Calls `handler` on a background thread whenever one of `signals` is received.
+///
+/// Must only be called once per process.
+pub fn spawn<F>(signals: &[c_int], handler: F) -> io::Result<()>
+where
+    F: Fn(c_int) + Send + 'static,
+{
+    let mut fds = [0; 2];
+
+    // SAFETY: `fds` has room for the two descriptors pipe(2) writes.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    PIPE.store(fds[1], Ordering::Relaxed);
+
+    for &signal in signals {
+        // SAFETY: the action is fully initialized and `on_signal` only does async-signal-safe work.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    // SAFETY: the read end was just created by pipe(2) and nothing else owns it.
+    let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let mut signal = [0];
+
+            while reader.read_exact(&mut signal).is_ok() {
+                handler(c_int::from(signal[0]));
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,712 @@
+//! Verification of signed commits against a registry of trusted keys.
+//!
+//! Registered users' OpenPGP and SSH public keys are added by the admin through `/keys` and
+//! stored in a file under the repository root. A signed commit is `verified` when its signature is
+//! good and made by a registered key, `unknown_key` when it is good but the key isn't
+//! registered, and `unverified` otherwise. Like git, the actual checking is left to `gpgv`
+//! and `ssh-keygen`, which have to be installed for signatures to verify.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD},
+};
+use git2::{Oid, Repository};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, Error, Json, Name, Path, Query,
+    auth::{hex, require_admin},
+    blocking, hidden_sibling, lfs,
+};
+
+/// Name of the key registry within the repository root.
+pub const KEYS_FILE: &str = ".keys";
+/// Namespace git signs commits in with SSH keys.
+const SSH_NAMESPACE: &str = "git";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Gpg,
+    Ssh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Verified,
+    Unverified,
+    UnknownKey,
+}
+
+/// The verification status of a signed commit.
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    status: Status,
+    format: Format,
+    /// Owner and id of the registered key that made the signature, once verified.
+    user: Option<String>,
+    key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyRecord {
+    id: String,
+    user: String,
+    title: String,
+    format: Format,
+    /// The public key as registered: an armored OpenPGP key block or an `authorized_keys` line.
+    key: String,
+    /// Primary key fingerprint in hex for OpenPGP keys, `SHA256:` and base64 for SSH keys.
+    fingerprint: String,
+    created: i64,
+}
+
+/// Registered keys, mirrored to disk on every change, and the verifications made with them.
+#[derive(Debug, Default)]
+pub struct Keys {
+    /// Where the keys are persisted. Without one they only live in memory.
+    path: Option<PathBuf>,
+    records: Mutex<Vec<KeyRecord>>,
+    /// Verification of each commit seen, cleared whenever the keys change. Commits without a
+    /// signature map to `None`.
+    verifications: Mutex<HashMap<Oid, Option<Verification>>>,
+}
+
+impl Keys {
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let records = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            records: Mutex::new(records),
+            ..Default::default()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    fn save(&self, records: &[KeyRecord]) -> io::Result<()> {
+        self.verifications.lock().unwrap().clear();
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Replace the file in one step so a crash never leaves a truncated registry behind.
+        let staging = hidden_sibling(path, "new");
+        fs::write(&staging, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&staging, path)
+    }
+
+    /// Deletes every key of `user`, returning how many there were.
+    pub fn delete_user(&self, user: &str) -> io::Result<usize> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+
+        let kept: Vec<KeyRecord> = records
+            .iter()
+            .filter(|record| record.user != user)
+            .cloned()
+            .collect();
+
+        if kept.len() < before {
+            self.save(&kept)?;
+            *records = kept;
+        }
+
+        Ok(before - records.len())
+    }
+
+    /// Verifies the signature of commit `oid`, if it has one.
+    pub fn verify(&self, repo: &Repository, oid: Oid) -> Option<Verification> {
+        if let Some(cached) = self.verifications.lock().unwrap().get(&oid) {
+            return cached.clone();
+        }
+
+        let verification = match repo.extract_signature(&oid, None) {
+            Ok((signature, data)) => Some(self.check(&signature, &data)),
+            Err(error) if error.code() == git2::ErrorCode::NotFound => None,
+            Err(error) => {
+                warn!("Failed to read the signature of {oid}: {error}");
+                return None;
+            }
+        };
+
+        self.verifications
+            .lock()
+            .unwrap()
+            .insert(oid, verification.clone());
+
+        verification
+    }
+
+    fn check(&self, signature: &[u8], data: &[u8]) -> Verification {
+        let format = if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+            Format::Ssh
+        } else {
+            Format::Gpg
+        };
+
+        let records: Vec<KeyRecord> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.format == format)
+            .cloned()
+            .collect();
+
+        let result = match format {
+            Format::Gpg => check_gpg(&records, signature, data),
+            Format::Ssh => check_ssh(&records, signature, data),
+        };
+
+        let (status, record) = result.unwrap_or_else(|error| {
+            warn!("Failed to verify a {format:?} signature: {error}");
+            (Status::Unverified, None)
+        });
+
+        Verification {
+            status,
+            format,
+            user: record.map(|record| record.user.clone()),
+            key: record.map(|record| record.id.clone()),
+        }
+    }
+}
+
+/// A scratch directory for the files handed to the verifying programs, removed on drop.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> io::Result<Self> {
+        let path = hidden_sibling(&env::temp_dir().join("git-server"), "verify");
+        fs::create_dir(&path)?;
+
+        Ok(Self(path))
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> io::Result<PathBuf> {
+        let path = self.0.join(name);
+        fs::write(&path, contents)?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `command` with `input` on its standard input, returning whether it succeeded and
+/// what it printed there.
+fn run(command: &mut Command, input: &[u8]) -> io::Result<(bool, String)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take();
+
+    // Feed the input from another thread so output filling a pipe can't deadlock us.
+    let output = thread::scope(|scope| {
+        scope.spawn(move || {
+            if let Some(stdin) = &mut stdin {
+                let _ = stdin.write_all(input);
+            }
+        });
+
+        child.wait_with_output()
+    })?;
+
+    Ok((
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+fn check_gpg<'a>(
+    records: &'a [KeyRecord],
+    signature: &[u8],
+    data: &[u8],
+) -> io::Result<(Status, Option<&'a KeyRecord>)> {
+    let scratch = Scratch::new()?;
+
+    let mut keyring = Vec::new();
+    for record in records {
+        keyring.extend(dearmor(&record.key).unwrap_or_default());
+    }
+
+    let keyring = scratch.write("keyring.gpg", &keyring)?;
+    let signature = scratch.write("signature.asc", signature)?;
+
+    let (_, status) = run(
+        Command::new("gpgv")
+            .env("GNUPGHOME", &scratch.0)
+            .arg("--keyring")
+            .arg(keyring)
+            .args(["--status-fd", "1"])
+            .arg(signature)
+            .arg("-"),
+        data,
+    )?;
+
+    let mut good = false;
+    let mut fingerprint = None;
+
+    for line in status.lines() {
+        let mut words = line.split_whitespace().skip(1);
+
+        match words.next() {
+            Some("GOODSIG") => good = true,
+            // The primary key's fingerprint comes last.
+            Some("VALIDSIG") => fingerprint = words.last(),
+            Some("NO_PUBKEY") => return Ok((Status::UnknownKey, None)),
+            _ => {}
+        }
+    }
+
+    let record = fingerprint.and_then(|fingerprint| {
+        records
+            .iter()
+            .find(|record| record.fingerprint.eq_ignore_ascii_case(fingerprint))
+    });
+
+    Ok(match record {
+        Some(record) if good => (Status::Verified, Some(record)),
+        _ => (Status::Unverified, None),
+    })
+}
+
+fn check_ssh<'a>(
+    records: &'a [KeyRecord],
+    signature: &[u8],
+    data: &[u8],
+) -> io::Result<(Status, Option<&'a KeyRecord>)> {
+    let scratch = Scratch::new()?;
+
+    // Principals are key ids, which tells which record a signature was made with.
+    let allowed: String = records
+        .iter()
+        .map(|record| {
+            format!(
+                "{} namespaces=\"{SSH_NAMESPACE}\" {}\n",
+                record.id, record.key
+            )
+        })
+        .collect();
+
+    let allowed = scratch.write("allowed_signers", allowed.as_bytes())?;
+    let signature = scratch.write("signature.sig", signature)?;
+
+    let (found, principals) = run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "find-principals", "-f"])
+            .arg(&allowed)
+            .arg("-s")
+            .arg(&signature),
+        b"",
+    )?;
+
+    let record = principals
+        .lines()
+        .find_map(|id| records.iter().find(|record| record.id == id.trim()));
+
+    let Some(record) = record.filter(|_| found) else {
+        let (valid, _) = run(
+            Command::new("ssh-keygen")
+                .args(["-Y", "check-novalidate", "-n", SSH_NAMESPACE, "-s"])
+                .arg(&signature),
+            data,
+        )?;
+
+        return Ok((
+            if valid {
+                Status::UnknownKey
+            } else {
+                Status::Unverified
+            },
+            None,
+        ));
+    };
+
+    let (valid, _) = run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-f"])
+            .arg(&allowed)
+            .args(["-I", &record.id, "-s"])
+            .arg(&signature),
+        data,
+    )?;
+
+    Ok(if valid {
+        (Status::Verified, Some(record))
+    } else {
+        (Status::Unverified, None)
+    })
+}
+
+/// The binary packets of an ASCII-armored OpenPGP block.
+fn dearmor(armored: &str) -> Option<Vec<u8>> {
+    let mut lines = armored.lines().map(str::trim);
+
+    lines.find(|line| line.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"))?;
+
+    // Armor headers run up to the first empty line.
+    lines.find(|line| line.is_empty())?;
+
+    let body: String = lines
+        .take_while(|line| !line.starts_with("-----END "))
+        // The checksum line, `=` and four characters of base64.
+        .filter(|line| !(line.len() == 5 && line.starts_with('=')))
+        .collect();
+
+    BASE64_STANDARD.decode(body).ok()
+}
+
+/// The fingerprint of the primary key of an OpenPGP key block: the SHA-1 of its first packet,
+/// which has to be a version 4 public key.
+fn openpgp_fingerprint(packets: &[u8]) -> Option<String> {
+    let header = *packets.first()?;
+
+    let (tag, body) = if header & 0x40 != 0 {
+        // New format: the tag is in the low six bits and the separate length has 1, 2 or 5
+        // bytes.
+        let (len, offset) = match *packets.get(1)? {
+            len @ 0..192 => (len as usize, 2),
+            first @ 192..224 => (
+                ((first as usize - 192) << 8) + *packets.get(2)? as usize + 192,
+                3,
+            ),
+            255 => (
+                u32::from_be_bytes(packets.get(2..6)?.try_into().ok()?) as usize,
+                6,
+            ),
+            _ => return None,
+        };
+
+        (header & 0x3f, packets.get(offset..offset + len)?)
+    } else {
+        // Old format: the tag is in bits 2 to 5 and the length type in the low two.
+        let (len, offset) = match header & 0x03 {
+            0 => (*packets.get(1)? as usize, 2),
+            1 => (
+                u16::from_be_bytes(packets.get(1..3)?.try_into().ok()?) as usize,
+                3,
+            ),
+            2 => (
+                u32::from_be_bytes(packets.get(1..5)?.try_into().ok()?) as usize,
+                5,
+            ),
+            _ => return None,
+        };
+
+        ((header >> 2) & 0x0f, packets.get(offset..offset + len)?)
+    };
+
+    if header & 0x80 == 0 || tag != 6 || body.first() != Some(&4) {
+        return None;
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update([0x99]);
+    hasher.update((body.len() as u16).to_be_bytes());
+    hasher.update(body);
+
+    Some(hex(&hasher.finalize()).to_uppercase())
+}
+
+/// The fingerprint of an `authorized_keys` style public key, as `ssh-keygen -l` shows it.
+fn ssh_fingerprint(key: &str) -> Option<String> {
+    let mut words = key.split_whitespace();
+    let kind = words.next()?;
+    let blob = BASE64_STANDARD.decode(words.next()?).ok()?;
+
+    // The blob starts with the key type again, as a length-prefixed string.
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    if blob.get(4..4 + len)? != kind.as_bytes() {
+        return None;
+    }
+
+    Some(format!(
+        "SHA256:{}",
+        BASE64_STANDARD_NO_PAD.encode(lfs::sha256(&blob))
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddKey {
+    user: Name,
+    title: String,
+    key: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyInfo {
+    id: String,
+    user: String,
+    title: String,
+    format: Format,
+    fingerprint: String,
+    created: i64,
+}
+
+impl From<&KeyRecord> for KeyInfo {
+    fn from(record: &KeyRecord) -> Self {
+        Self {
+            id: record.id.clone(),
+            user: record.user.clone(),
+            title: record.title.clone(),
+            format: record.format,
+            fingerprint: record.fingerprint.clone(),
+            created: record.created,
+        }
+    }
+}
+
+pub async fn add_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<AddKey>,
+) -> Result<(StatusCode, Json<KeyInfo>), Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let user = payload.user.to_lowercase();
+
+        if !state.users.exists(&user) {
+            return Err(Error::BadRequest(format!("Unknown user: {user}")));
+        }
+
+        let key = payload.key.trim().to_string();
+
+        let (format, fingerprint) = if key.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            (
+                Format::Gpg,
+                dearmor(&key).and_then(|packets| openpgp_fingerprint(&packets)),
+            )
+        } else {
+            (Format::Ssh, ssh_fingerprint(&key))
+        };
+
+        let Some(fingerprint) = fingerprint else {
+            return Err(Error::BadRequest(
+                "Expected an armored OpenPGP version 4 public key or an SSH public key".to_string(),
+            ));
+        };
+
+        let mut records = state.keys.records.lock().unwrap();
+
+        if records
+            .iter()
+            .any(|record| record.fingerprint == fingerprint)
+        {
+            return Err(Error::Conflict);
+        }
+
+        let record = KeyRecord {
+            id: hex(&rand::rng().random::<[u8; 8]>()),
+            user,
+            title: payload.title,
+            format,
+            // Only the key itself, as SSH comments and options have no place in the registry.
+            key: match format {
+                Format::Gpg => key,
+                Format::Ssh => key.split_whitespace().take(2).collect::<Vec<_>>().join(" "),
+            },
+            fingerprint,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+        };
+
+        records.push(record.clone());
+
+        if let Err(error) = state.keys.save(&records) {
+            records.pop();
+            return Err(error.into());
+        }
+
+        info!(
+            "Registered {:?} key {} of {} ({})",
+            record.format, record.fingerprint, record.user, record.id
+        );
+
+        Ok((StatusCode::CREATED, Json(KeyInfo::from(&record))))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeysQuery {
+    user: Option<Name>,
+}
+
+/// Lists registered keys, optionally only those of one user. Public keys are public, so
+/// anyone may.
+pub async fn list_keys(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<KeysQuery>,
+) -> Json<Vec<KeyInfo>> {
+    let records = state.keys.records.lock().unwrap();
+
+    Json(
+        records
+            .iter()
+            .filter(|record| {
+                query
+                    .user
+                    .as_ref()
+                    .is_none_or(|user| record.user == user.to_lowercase())
+            })
+            .map(KeyInfo::from)
+            .collect(),
+    )
+}
+
+pub async fn delete_key(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let mut records = state.keys.records.lock().unwrap();
+
+        let Some(index) = records.iter().position(|record| record.id == id) else {
+            return Err(Error::NotFound);
+        };
+
+        let record = records.remove(index);
+
+        if let Err(error) = state.keys.save(&records) {
+            records.insert(index, record);
+            return Err(error.into());
+        }
+
+        info!("Deleted key {id}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{ADMIN_TOKEN, TestServer, commit};
+
+    #[tokio::test]
+    async fn ssh_signatures_verify_once_their_key_is_registered() {
+        let server = TestServer::new("signatures");
+        let repo = server.init_repo("test", "r.git");
+        let unsigned = commit(&repo, Some("refs/heads/main"), &[("a", b"a")], &[]);
+
+        let key = server.root().join("key");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.find_commit(unsigned).unwrap();
+        let buffer = repo
+            .commit_create_buffer(
+                &signature,
+                &signature,
+                "signed",
+                &parent.tree().unwrap(),
+                &[&parent],
+            )
+            .unwrap();
+
+        let data = server.root().join("commit");
+        fs::write(&data, &*buffer).unwrap();
+
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+            .arg(&key)
+            .arg(&data)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let signed = fs::read_to_string(data.with_extension("sig")).unwrap();
+        let buffer = std::str::from_utf8(&buffer).unwrap();
+        let signed = repo.commit_signed(buffer, &signed, None).unwrap();
+        repo.reference("refs/heads/main", signed, true, "sign")
+            .unwrap();
+
+        let verification = async |oid: Oid| {
+            let uri = format!("/repo/test/r.git/commit/{oid}");
+            let (status, detail) = server.json(Method::GET, &uri, None, None).await;
+            assert_eq!(status, StatusCode::OK);
+
+            detail["verification"].clone()
+        };
+
+        assert!(verification(unsigned).await.is_null());
+        assert_eq!(verification(signed).await["status"], "unknown_key");
+
+        server.user_token("alice").await;
+
+        let (status, registered) = server
+            .json(
+                Method::POST,
+                "/keys",
+                Some(ADMIN_TOKEN),
+                Some(json!({
+                    "user": "alice",
+                    "title": "laptop",
+                    "key": fs::read_to_string(key.with_extension("pub")).unwrap(),
+                })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(registered["format"], "ssh");
+
+        let verification = verification(signed).await;
+        assert_eq!(verification["status"], "verified");
+        assert_eq!(verification["user"], "alice");
+        assert_eq!(verification["key"], registered["id"]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Registered users.
+//!
+//! Repositories have always been namespaced by user, but until registered here a user is only
+//! a directory below the repository root. Registered users are what access tokens and signing
+//! keys belong to, and they are stored in a file under the repository root, like the tokens.
+//! The admin creates and deletes them; anyone may list them. Deleting a user revokes their
+//! tokens and keys, and is refused while they still own repositories.
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{AppState, Error, Json, Name, Path, auth::require_admin, blocking, hidden_sibling};
+
+/// Name of the user store within the repository root.
+pub const USERS_FILE: &str = ".users";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// Lowercased, as repository paths are.
+    name: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    created: i64,
+}
+
+/// Registered users, mirrored to disk on every change.
+#[derive(Debug, Default)]
+pub struct Users {
+    /// Where the users are persisted. Without one they only live in memory.
+    path: Option<PathBuf>,
+    records: Mutex<Vec<UserRecord>>,
+}
+
+impl Users {
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let records = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            records: Mutex::new(records),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    /// Whether `name` is a registered user, in any case.
+    pub fn exists(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|record| record.name == name)
+    }
+
+    fn save(&self, records: &[UserRecord]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Replace the file in one step so a crash never leaves a truncated store behind.
+        let staging = hidden_sibling(path, "new");
+        fs::write(&staging, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&staging, path)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUser {
+    name: Name,
+    display_name: Option<String>,
+    email: Option<String>,
+}
+
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateUser>,
+) -> Result<(StatusCode, Json<UserRecord>), Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let mut records = state.users.records.lock().unwrap();
+        let name = payload.name.to_lowercase();
+
+        if records.iter().any(|record| record.name == name) {
+            return Err(Error::Conflict);
+        }
+
+        let record = UserRecord {
+            name,
+            display_name: payload.display_name,
+            email: payload.email,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+        };
+
+        records.push(record.clone());
+
+        if let Err(error) = state.users.save(&records) {
+            records.pop();
+            return Err(error.into());
+        }
+
+        info!("Created user {}", record.name);
+
+        Ok((StatusCode::CREATED, Json(record)))
+    })
+    .await
+}
+
+pub async fn list_users(State(state): State<Arc<AppState>>) -> Json<Vec<UserRecord>> {
+    Json(state.users.records.lock().unwrap().clone())
+}
+
+pub async fn get_user(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<Name>,
+) -> Result<Json<UserRecord>, Error> {
+    let name = name.to_lowercase();
+
+    state
+        .users
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|record| record.name == name)
+        .cloned()
+        .map(Json)
+        .ok_or(Error::NotFound)
+}
+
+/// Deletes a user along with their tokens and keys. Their repositories have to be deleted or
+/// moved away first.
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<Name>,
+    headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+    blocking(move || {
+        require_admin(&state, &headers)?;
+
+        let name = name.to_lowercase();
+
+        if !state.users.exists(&name) {
+            return Err(Error::NotFound);
+        }
+
+        let owns_repos = match fs::read_dir(state.options.repo_root.join(&name)) {
+            Ok(entries) => entries
+                .map(|entry| crate::listing_name(&entry?))
+                .collect::<io::Result<Vec<_>>>()?
+                .iter()
+                .any(Option::is_some),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+            Err(error) => return Err(error.into()),
+        };
+
+        if owns_repos {
+            return Err(Error::Conflict);
+        }
+
+        let revoked_tokens = state.tokens.revoke_user(&name)?;
+        let deleted_keys = state.keys.delete_user(&name)?;
+
+        let mut records = state.users.records.lock().unwrap();
+
+        let Some(index) = records.iter().position(|record| record.name == name) else {
+            return Err(Error::NotFound);
+        };
+
+        let record = records.remove(index);
+
+        if let Err(error) = state.users.save(&records) {
+            records.insert(index, record);
+            return Err(error.into());
+        }
+
+        info!(
+            "Deleted user {name}, revoking {revoked_tokens} tokens and {deleted_keys} signing keys"
+        );
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::tests::{ADMIN_TOKEN, TestServer};
+
+    #[tokio::test]
+    async fn users_go_only_once_their_repos_have() {
+        let server = TestServer::new("users");
+        let token = server.user_token("alice").await;
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/users",
+                Some(ADMIN_TOKEN),
+                Some(json!({ "name": "Alice" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/users",
+                Some(&token),
+                Some(json!({ "name": "bob" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (_, users) = server.json(Method::GET, "/users", None, None).await;
+        assert_eq!(users.as_array().unwrap().len(), 1);
+        assert_eq!(users[0]["name"], "alice");
+
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/repo",
+                Some(&token),
+                Some(json!({ "user": "alice", "name": "r" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = server
+            .send(Method::DELETE, "/users/ALICE", Some(ADMIN_TOKEN), None)
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = server
+            .send(Method::DELETE, "/repo/alice/r.git", Some(ADMIN_TOKEN), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = server
+            .send(Method::DELETE, "/users/ALICE", Some(ADMIN_TOKEN), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = server.send(Method::GET, "/users/alice", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Their tokens went with them.
+        let (status, _) = server
+            .send(
+                Method::POST,
+                "/repo",
+                Some(&token),
+                Some(json!({ "user": "alice", "name": "s" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
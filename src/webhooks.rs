@@ -0,0 +1,464 @@
+//! Webhooks announcing repository events to external services such as CI.
+//!
+//! Webhooks are registered per repository and kept in its config as `webhook.<id>.url` and
+//! `webhook.<id>.events`, so they move, get trashed and get deleted along with the repository.
+//! Their secrets are kept in the server's [`crate::secrets`] instead. Deliveries are JSON `POST`s signed with HMAC-SHA1 of the body in
+//! `X-Hub-Signature` when the webhook has a secret, and are retried by [`notify`].
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract::State, http::StatusCode};
+use git2::{ConfigLevel, Repository};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{debug, info, warn};
+
+use crate::{
+    AppState, Error, Json, Name, Path,
+    auth::{Authenticated, hex},
+    blocking,
+    hooks::{Hook, Push, RefUpdate},
+    notify,
+};
+
+const SECTION: &str = "webhook";
+const BLOCK_SIZE: usize = 64;
+
+/// Something that happened to a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A ref was updated by a push.
+    Push,
+    BranchCreate,
+    BranchDelete,
+    TagCreate,
+}
+
+impl Event {
+    const ALL: [Self; 4] = [
+        Self::Push,
+        Self::BranchCreate,
+        Self::BranchDelete,
+        Self::TagCreate,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Push => "push",
+            Self::BranchCreate => "branch_create",
+            Self::BranchDelete => "branch_delete",
+            Self::TagCreate => "tag_create",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.as_str() == name)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    id: String,
+    url: String,
+    /// Events the webhook is delivered for. Empty means all of them.
+    events: Vec<Event>,
+    /// Whether deliveries are signed. The secret itself is never shown again.
+    signed: bool,
+    #[serde(skip)]
+    secret: Option<String>,
+}
+
+/// Key of a webhook's secret.
+fn secret_key(id: &str) -> String {
+    format!("{SECTION}.{id}")
+}
+
+/// The webhooks registered for a repository.
+fn webhooks(state: &AppState, repo: &Repository) -> Result<Vec<Webhook>, git2::Error> {
+    let config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+    let mut webhooks: Vec<Webhook> = Vec::new();
+    let mut entries = config.entries(Some(&format!("^{SECTION}\\.")))?;
+
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+
+        let (Some(key), Some(value)) = (entry.name(), entry.value()) else {
+            continue;
+        };
+
+        // Subsections keep their case but git lowercases the variable name.
+        let Some((id, variable)) = key[SECTION.len() + 1..].rsplit_once('.') else {
+            continue;
+        };
+
+        let index = match webhooks.iter().position(|webhook| webhook.id == id) {
+            Some(index) => index,
+            None => {
+                webhooks.push(Webhook {
+                    id: id.to_string(),
+                    url: String::new(),
+                    events: Vec::new(),
+                    signed: false,
+                    secret: None,
+                });
+                webhooks.len() - 1
+            }
+        };
+
+        let webhook = &mut webhooks[index];
+
+        match variable {
+            "url" => webhook.url = value.to_string(),
+            "events" => {
+                webhook.events = value
+                    .split(',')
+                    .filter_map(|name| Event::parse(name.trim()))
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+
+    webhooks.retain(|webhook| !webhook.url.is_empty());
+
+    for webhook in &mut webhooks {
+        webhook.secret = state.secrets.get(&secret_key(&webhook.id));
+        webhook.signed = webhook.secret.is_some();
+    }
+
+    Ok(webhooks)
+}
+
+#[derive(Debug, Serialize)]
+struct RepoRef<'a> {
+    user: &'a str,
+    name: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: Event,
+    repository: RepoRef<'a>,
+    #[serde(rename = "ref")]
+    reference: &'a str,
+    /// Where the ref pointed before, all zeros if it didn't exist.
+    before: String,
+    /// Where the ref points now, all zeros if it was deleted.
+    after: String,
+    timestamp: u64,
+}
+
+/// Delivers `event` to every webhook of the repository subscribed to it, in the background.
+/// Problems are only logged, as the change they describe has already happened.
+pub fn dispatch(
+    state: &AppState,
+    repo: &Repository,
+    (user, name): (&str, &str),
+    event: Event,
+    update: &RefUpdate,
+) {
+    let webhooks = match webhooks(state, repo) {
+        Ok(webhooks) => webhooks,
+        Err(error) => {
+            warn!("Failed to read webhooks of {user}/{name}: {error}");
+            return;
+        }
+    };
+
+    let subscribed = webhooks
+        .into_iter()
+        .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&event));
+
+    let payload = Payload {
+        event,
+        repository: RepoRef { user, name },
+        reference: &update.name,
+        before: update.old.to_string(),
+        after: update.new.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            warn!("Failed to serialize webhook payload: {error}");
+            return;
+        }
+    };
+
+    for webhook in subscribed {
+        let mut headers = vec![
+            ("X-GitServer-Event", event.as_str().to_string()),
+            (
+                "X-GitServer-Delivery",
+                hex(&rand::rng().random::<[u8; 16]>()),
+            ),
+        ];
+
+        if let Some(secret) = &webhook.secret {
+            headers.push((
+                "X-Hub-Signature",
+                format!("sha1={}", sign(secret.as_bytes(), &body)),
+            ));
+        }
+
+        debug!(
+            "Delivering {} event for {user}/{name} to webhook {}",
+            event.as_str(),
+            webhook.id
+        );
+
+        notify::deliver(webhook.url, headers, body.clone());
+    }
+}
+
+/// Announces the refs moved by pushes.
+#[derive(Debug)]
+pub struct Deliveries;
+
+impl Hook for Deliveries {
+    fn post_receive(&self, push: &mut Push, updates: &[&RefUpdate]) {
+        for update in updates {
+            pushed(push.state, push.repo, (push.user, push.name), update);
+        }
+    }
+}
+
+/// Announces a ref moved by a push: always as a push, and additionally as the creation or
+/// deletion of a branch or the creation of a tag.
+fn pushed(state: &AppState, repo: &Repository, (user, name): (&str, &str), update: &RefUpdate) {
+    let RefUpdate {
+        old,
+        new,
+        name: reference,
+    } = update;
+
+    dispatch(state, repo, (user, name), Event::Push, update);
+
+    let event = if reference.starts_with("refs/heads/") {
+        match (old.is_zero(), new.is_zero()) {
+            (true, false) => Event::BranchCreate,
+            (false, true) => Event::BranchDelete,
+            _ => return,
+        }
+    } else if reference.starts_with("refs/tags/") && old.is_zero() && !new.is_zero() {
+        Event::TagCreate
+    } else {
+        return;
+    };
+
+    dispatch(state, repo, (user, name), event, update);
+}
+
+/// HMAC-SHA1 of `message`, hex encoded.
+pub fn sign(key: &[u8], message: &[u8]) -> String {
+    let mut block = [0; BLOCK_SIZE];
+
+    if key.len() > BLOCK_SIZE {
+        let digest = Sha1::digest(key);
+        block[..digest.len()].copy_from_slice(&digest);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let pad = |byte: u8| block.map(|key| key ^ byte);
+
+    let inner = Sha1::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+
+    hex(&Sha1::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhook {
+    url: String,
+    secret: Option<String>,
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+pub async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+) -> Result<Json<Vec<Webhook>>, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        Ok(Json(webhooks(&state, &repo)?))
+    })
+    .await
+}
+
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    Path((user, name)): Path<(Name, Name)>,
+    caller: Authenticated,
+    Json(payload): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<Webhook>), Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        // Deliveries go through a plain HTTP client.
+        if !url::Url::parse(&payload.url).is_ok_and(|url| url.scheme() == "http" && url.has_host())
+        {
+            return Err(Error::BadRequest(format!(
+                "Unsupported webhook url: {}",
+                payload.url
+            )));
+        }
+
+        let repo = state.open_repo(&user, &name)?;
+
+        let id = hex(&rand::rng().random::<[u8; 8]>());
+        let secret = payload.secret.filter(|secret| !secret.is_empty());
+
+        if let Some(secret) = &secret {
+            state.secrets.set(&secret_key(&id), secret)?;
+        }
+
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        config.set_str(&format!("{SECTION}.{id}.url"), &payload.url)?;
+
+        if !payload.events.is_empty() {
+            let events: Vec<_> = payload.events.iter().map(|event| event.as_str()).collect();
+            config.set_str(&format!("{SECTION}.{id}.events"), &events.join(","))?;
+        }
+
+        info!("Registered webhook {id} for {user}/{name}");
+
+        Ok((
+            StatusCode::CREATED,
+            Json(Webhook {
+                id,
+                url: payload.url,
+                events: payload.events,
+                signed: secret.is_some(),
+                secret,
+            }),
+        ))
+    })
+    .await
+}
+
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Path((user, name, id)): Path<(Name, Name, String)>,
+    caller: Authenticated,
+) -> Result<StatusCode, Error> {
+    caller.require_owner(&user)?;
+
+    blocking(move || {
+        let repo = state.open_repo(&user, &name)?;
+
+        if !webhooks(&state, &repo)?
+            .iter()
+            .any(|webhook| webhook.id == id)
+        {
+            return Err(Error::NotFound);
+        }
+
+        let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+
+        for variable in ["url", "events"] {
+            match config.remove(&format!("{SECTION}.{id}.{variable}")) {
+                Err(error) if error.code() != git2::ErrorCode::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+
+        state.secrets.remove(&secret_key(&id))?;
+
+        info!("Deleted webhook {id} of {user}/{name}");
+
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{ADMIN_TOKEN, TestServer};
+
+    #[tokio::test]
+    async fn secrets_stay_out_of_the_repository() {
+        let server = TestServer::new("webhook-secrets");
+        let repo = server.init_repo("alice", "r.git");
+
+        let (status, webhook) = server
+            .json(
+                Method::POST,
+                "/repo/alice/r.git/webhooks",
+                Some(ADMIN_TOKEN),
+                Some(json!({ "url": "http://ci.example.com/hook", "secret": "TOPSECRET" })),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(webhook["signed"], true);
+
+        let id = webhook["id"].as_str().unwrap();
+        assert_eq!(
+            server.state.secrets.get(&secret_key(id)).as_deref(),
+            Some("TOPSECRET")
+        );
+
+        let config = fs::read_to_string(repo.path().join("config")).unwrap();
+        assert!(!config.contains("TOPSECRET"), "{config}");
+
+        // The dumb protocol serves what clients fetch, but nothing else.
+        let (status, _) = server
+            .send(Method::GET, "/repo/alice/r.git/config", None, None)
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = server
+            .send(Method::GET, "/repo/alice/r.git/HEAD", None, None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, webhooks) = server
+            .json(
+                Method::GET,
+                "/repo/alice/r.git/webhooks",
+                Some(ADMIN_TOKEN),
+                None,
+            )
+            .await;
+        assert_eq!(webhooks[0]["signed"], true);
+
+        let (status, _) = server
+            .send(
+                Method::DELETE,
+                &format!("/repo/alice/r.git/webhooks/{id}"),
+                Some(ADMIN_TOKEN),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(server.state.secrets.get(&secret_key(id)), None);
+    }
+}